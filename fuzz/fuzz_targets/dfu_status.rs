@@ -0,0 +1,9 @@
+// GETSTATUS replies, whatever length the device sends back
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use ra1n_oxide::dfu::DfuStatus;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = DfuStatus::parse(data);
+});
@@ -0,0 +1,9 @@
+// iBoot's reply to a getenv in recovery mode
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use ra1n_oxide::recovery::parse_env_reply;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = parse_env_reply(data);
+});
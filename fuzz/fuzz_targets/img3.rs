@@ -0,0 +1,16 @@
+// Pre-A7 IMG3 images, as they come out of an IPSW
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use ra1n_oxide::img3::Img3;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(mut img3) = Img3::parse(data) {
+        let _ = img3.data();
+        let _ = img3.image_type();
+        let _ = img3.version();
+        let _ = img3.kbags();
+        let _ = img3.to_bytes();
+        img3.strip_kbags();
+    }
+});
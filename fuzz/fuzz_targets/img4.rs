@@ -0,0 +1,34 @@
+// IMG4 and its parts, as they come out of an IPSW or an SHSH blob
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use ra1n_oxide::img4::{self, Im4m, Im4p, Im4r, Img4};
+
+fn im4p(im4p: &Im4p) {
+    let _ = im4p.codec();
+    let _ = im4p.decompressed_payload();
+}
+
+fn im4m(im4m: &Im4m) {
+    let _ = im4m.ecid();
+    let _ = im4m.ap_nonce_hash();
+    let _ = im4m.sep_nonce_hash();
+    let _ = im4m.image("ibss");
+}
+
+fuzz_target!(|data: &[u8]| {
+    let _ = img4::magic(data);
+    if let Ok(img4) = Img4::parse(data) {
+        im4p(&img4.im4p);
+        if let Some(manifest) = &img4.im4m {
+            im4m(manifest);
+        }
+    }
+    if let Ok(payload) = Im4p::parse(data) {
+        im4p(&payload);
+    }
+    if let Ok(manifest) = Im4m::parse(data) {
+        im4m(&manifest);
+    }
+    let _ = Im4r::parse(data);
+});
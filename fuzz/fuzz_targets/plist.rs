@@ -0,0 +1,9 @@
+// XML and binary plists: BuildManifests, and lockdownd/usbmuxd replies
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use ra1n_oxide::plist;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = plist::parse(data);
+});
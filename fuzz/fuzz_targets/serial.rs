@@ -0,0 +1,13 @@
+// The USB serial number string, as read off any device that's plugged in
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use ra1n_oxide::serial::DeviceSerial;
+
+fuzz_target!(|data: &[u8]| {
+    // read_serial_number decodes the descriptor lossily, so can this
+    let serial = String::from_utf8_lossy(data);
+    if let Some(serial) = DeviceSerial::parse(&serial) {
+        let _ = serial.is_pwned();
+    }
+});
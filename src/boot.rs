@@ -0,0 +1,276 @@
+use crate::dfu::DfuClient;
+use crate::image::Container;
+use crate::img4::{Im4m, Im4p, Img4};
+use crate::plist;
+use crate::recovery::RecoveryClient;
+use crate::transport::Transport;
+
+// Patched images for a tethered boot, as containers or raw payloads
+#[derive(Debug, Clone, Default)]
+pub struct BootImages {
+    pub ibss: Vec<u8>,
+    pub ibec: Vec<u8>,
+    pub devicetree: Vec<u8>,
+    pub ramdisk: Option<Vec<u8>>,
+    pub trustcache: Option<Vec<u8>>,
+    pub kernelcache: Vec<u8>,
+}
+
+// Which tag each image is booted under. Booting a ramdisk uses the restore
+// variants, booting the installed system the plain ones.
+fn fourccs(ramdisk: bool) -> [&'static str; 3] {
+    if ramdisk {
+        ["rdtr", "rtsc", "rkrn"]
+    } else {
+        ["dtre", "trst", "krnl"]
+    }
+}
+
+// Accepts a raw IM4M or an SHSH blob (plist with ApImg4Ticket)
+pub fn load_im4m(data: &[u8]) -> Result<Im4m, String> {
+    if plist::Format::detect(data).is_some() {
+        let blob = plist::parse(data)?;
+        let ticket = blob
+            .get("ApImg4Ticket")
+            .and_then(|ticket| ticket.as_data())
+            .ok_or("SHSH blob has no ApImg4Ticket")?;
+        return Im4m::parse(ticket);
+    }
+    Im4m::parse(data)
+}
+
+// Wraps an image with `im4m` under `fourcc`. Anything that isn't a container
+// is taken as a raw payload. IMG3 is refused: it's signed for 32-bit devices,
+// none of which checkm8 boots, and an IM4M means nothing to their iBoot.
+pub fn stitch(data: &[u8], fourcc: &str, im4m: &Im4m) -> Result<Vec<u8>, String> {
+    let mut im4p = match Container::parse(data) {
+        Ok(Container::Img3(_)) => {
+            return Err(format!("{} is IMG3, for 32-bit devices: give its decrypted payload instead", fourcc))
+        }
+        Ok(Container::Img4(img4)) => img4.im4p,
+        Ok(Container::Im4p(im4p)) => im4p,
+        Err(_) => Im4p::new(fourcc, "", data.to_vec()),
+    };
+    if !im4p.kbags.is_empty() {
+        return Err(format!("{} is still encrypted", fourcc));
+    }
+    im4p.fourcc = fourcc.to_string();
+    Ok(Img4::stitch(im4p, im4m.clone(), None).to_der())
+}
+
+impl BootImages {
+    pub fn stitch(&self, im4m: &Im4m) -> Result<BootImages, String> {
+        let [devicetree, trustcache, kernelcache] = fourccs(self.ramdisk.is_some());
+        Ok(BootImages {
+            ibss: stitch(&self.ibss, "ibss", im4m)?,
+            ibec: stitch(&self.ibec, "ibec", im4m)?,
+            devicetree: stitch(&self.devicetree, devicetree, im4m)?,
+            ramdisk: self
+                .ramdisk
+                .as_ref()
+                .map(|ramdisk| stitch(ramdisk, "rdsk", im4m))
+                .transpose()?,
+            trustcache: self
+                .trustcache
+                .as_ref()
+                .map(|trustcache_image| stitch(trustcache_image, trustcache, im4m))
+                .transpose()?,
+            kernelcache: stitch(&self.kernelcache, kernelcache, im4m)?,
+        })
+    }
+}
+
+// MARK: steps
+// Pwned DFU: the ROM loads iBSS and the device comes back in recovery mode.
+pub fn send_ibss<T: Transport>(dfu: &mut DfuClient<T>, ibss: &[u8]) -> Result<(), String> {
+    dfu.send_image(ibss).map_err(|e| format!("sending iBSS: {}", e))
+}
+
+// iBSS: load iBEC and jump to it, after which it re-enumerates again.
+pub fn send_ibec<T: Transport>(recovery: &mut RecoveryClient<T>, ibec: &[u8]) -> Result<(), String> {
+    recovery.send_file(ibec).map_err(|e| format!("sending iBEC: {}", e))?;
+    recovery.send_final_command("go").map_err(|e| format!("go: {}", e))
+}
+
+// iBEC: each file is uploaded and then claimed by the command that loads it,
+// ending with the kernelcache and `bootx`.
+pub fn boot_kernel<T: Transport>(
+    recovery: &mut RecoveryClient<T>,
+    images: &BootImages,
+    mut report: impl FnMut(&str),
+) -> Result<(), String> {
+    let mut steps: Vec<(&str, &[u8], &str)> = vec![("devicetree", &images.devicetree, "devicetree")];
+    if let Some(ramdisk) = &images.ramdisk {
+        steps.push(("ramdisk", ramdisk, "ramdisk"));
+    }
+    if let Some(trustcache) = &images.trustcache {
+        steps.push(("trustcache", trustcache, "firmware"));
+    }
+    steps.push(("kernelcache", &images.kernelcache, "bootx"));
+
+    for (name, data, command) in steps {
+        report(&format!("Sending {} ({} bytes)", name, data.len()));
+        recovery.send_file(data).map_err(|e| format!("sending {}: {}", name, e))?;
+        if command == "bootx" {
+            recovery.send_final_command(command)
+        } else {
+            recovery.send_command(command)
+        }
+        .map_err(|e| format!("{}: {}", command, e))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::img3::{self, Img3, Img3Tag};
+    use crate::img4::Kbag;
+    use crate::transport::TransferError;
+
+    // iBEC's side of boot_kernel: each upload as its size, each command as
+    // itself. The transfer numbered `fail`, from 0, gets `error` instead.
+    #[derive(Default)]
+    struct Ibec {
+        log: Vec<String>,
+        transfers: usize,
+        fail: Option<(usize, TransferError)>,
+    }
+
+    impl Ibec {
+        fn failing(at: usize, error: TransferError) -> Ibec {
+            Ibec {
+                fail: Some((at, error)),
+                ..Ibec::default()
+            }
+        }
+
+        fn transfer(&mut self) -> Result<(), TransferError> {
+            self.transfers += 1;
+            match &self.fail {
+                Some((at, error)) if *at == self.transfers - 1 => Err(error.clone()),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    impl Transport for Ibec {
+        fn control_transfer(&mut self, bm_request_type: u8, _: u8, _: u16, _: u16, data: &mut [u8], _: u32) -> Result<usize, TransferError> {
+            self.transfer()?;
+            match bm_request_type {
+                0x41 => self.log.push("upload 0".to_string()),
+                0x40 => self.log.push(String::from_utf8(data.strip_suffix(b"\0").unwrap().to_vec()).unwrap()),
+                _ => panic!("unexpected request type 0x{:02x}", bm_request_type),
+            }
+            Ok(data.len())
+        }
+
+        fn bulk_transfer(&mut self, _: u8, data: &mut [u8], _: u32) -> Result<usize, TransferError> {
+            self.transfer()?;
+            let upload = self.log.last_mut().and_then(|last| last.strip_prefix("upload ")?.parse::<usize>().ok());
+            let upload = upload.expect("bulk data without an upload");
+            *self.log.last_mut().unwrap() = format!("upload {}", upload + data.len());
+            Ok(data.len())
+        }
+
+        fn reset(&mut self) -> Result<(), TransferError> {
+            Ok(())
+        }
+
+        fn serial_number(&mut self) -> Option<String> {
+            None
+        }
+    }
+
+    fn images(ramdisk: bool) -> BootImages {
+        BootImages {
+            devicetree: vec![1; 10],
+            ramdisk: ramdisk.then(|| vec![2; 20]),
+            trustcache: ramdisk.then(|| vec![3; 30]),
+            kernelcache: vec![4; 40],
+            ..BootImages::default()
+        }
+    }
+
+    #[test]
+    fn boots_in_order() {
+        for (ramdisk, expected) in [
+            (false, &["upload 10", "devicetree", "upload 40", "bootx"][..]),
+            (
+                true,
+                &["upload 10", "devicetree", "upload 20", "ramdisk", "upload 30", "firmware", "upload 40", "bootx"],
+            ),
+        ] {
+            let mut recovery = RecoveryClient::new(Ibec::default());
+            let mut reports = Vec::new();
+            boot_kernel(&mut recovery, &images(ramdisk), |report| reports.push(report.to_string())).unwrap();
+            assert_eq!(recovery.into_inner().log, expected);
+            assert_eq!(reports.first().unwrap(), "Sending devicetree (10 bytes)");
+            assert_eq!(reports.last().unwrap(), "Sending kernelcache (40 bytes)");
+            assert_eq!(reports.len(), if ramdisk { 4 } else { 2 });
+        }
+    }
+
+    #[test]
+    fn stops_at_a_failed_step() {
+        // an upload is its control request then one bulk transfer here
+        for (at, error, message, log) in [
+            (0, TransferError::Stall, "sending devicetree: ", &[][..]),
+            (1, TransferError::Timeout, "sending devicetree: ", &["upload 0"][..]),
+            (2, TransferError::Stall, "devicetree: ", &["upload 10"][..]),
+            (5, TransferError::Stall, "ramdisk: ", &["upload 10", "devicetree", "upload 20"][..]),
+            (
+                10,
+                TransferError::NoDevice,
+                "sending kernelcache: ",
+                &["upload 10", "devicetree", "upload 20", "ramdisk", "upload 30", "firmware", "upload 0"][..],
+            ),
+        ] {
+            let mut recovery = RecoveryClient::new(Ibec::failing(at, error.clone()));
+            let result = boot_kernel(&mut recovery, &images(true), |_| {});
+            assert_eq!(result, Err(format!("{}{}", message, error)), "transfer {}", at);
+            let ibec = recovery.into_inner();
+            assert_eq!(ibec.log, log, "transfer {}", at);
+            // nothing goes out after the failure
+            assert_eq!(ibec.transfers, at + 1);
+        }
+
+        // iBEC is gone once bootx takes, which isn't an error
+        for error in [TransferError::NoDevice, TransferError::Io] {
+            let mut recovery = RecoveryClient::new(Ibec::failing(11, error));
+            assert_eq!(boot_kernel(&mut recovery, &images(true), |_| {}), Ok(()));
+        }
+        let mut recovery = RecoveryClient::new(Ibec::failing(11, TransferError::Stall));
+        assert_eq!(boot_kernel(&mut recovery, &images(true), |_| {}), Err(format!("bootx: {}", TransferError::Stall)));
+    }
+
+    #[test]
+    fn stitches_images() {
+        let im4m = Im4m::dummy(0x1A2B3C4D5E6F70);
+        // a raw payload is wrapped, a container gets its fourcc replaced
+        let raw = stitch(b"payload", "krnl", &im4m).unwrap();
+        let im4p = Im4p::new("rkrn", "KernelCache", b"payload".to_vec()).to_der();
+        for data in [raw, stitch(&im4p, "krnl", &im4m).unwrap()] {
+            let img4 = Img4::parse(&data).unwrap();
+            assert_eq!((img4.im4p.fourcc.as_str(), img4.im4p.payload.as_slice()), ("krnl", &b"payload"[..]));
+            assert_eq!(img4.im4m.unwrap().ecid(), Some(0x1A2B3C4D5E6F70));
+        }
+
+        let mut encrypted = Im4p::new("ibss", "iBoot", vec![0; 16]);
+        encrypted.kbags.push(Kbag {
+            kind: 1,
+            iv: vec![0; 16],
+            key: vec![0; 32],
+        });
+        assert_eq!(stitch(&encrypted.to_der(), "ibss", &im4m), Err("ibss is still encrypted".to_string()));
+
+        let img3 = Img3 {
+            ident: u32::from_be_bytes(*b"ibss"),
+            tags: vec![Img3Tag::new(img3::TAG_DATA, vec![0; 16])],
+        };
+        assert_eq!(
+            stitch(&img3.to_bytes(), "ibss", &im4m),
+            Err("ibss is IMG3, for 32-bit devices: give its decrypted payload instead".to_string())
+        );
+    }
+}
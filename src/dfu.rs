@@ -0,0 +1,114 @@
+use crate::transport::{TransferError, Transport};
+use crate::{
+    DFU_ABORT, DFU_CLRSTATUS, DFU_DNLOAD, DFU_GETSTATUS, DFU_MAX_TRANSFER_SIZE, DFU_STATE_DNLOAD_IDLE,
+    DFU_STATE_MANIFEST, DFU_STATE_MANIFEST_SYNC, DFU_STATE_MANIFEST_WAIT_RESET, DFU_STATUS_OK, DFU_UPLOAD,
+    USB_TIMEOUT,
+};
+
+// DFU requests are short, but uploads/downloads of a full 0x800 block need more
+// than the 10ms the exploit uses.
+pub const DFU_TIMEOUT: u32 = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DfuStatus {
+    pub status: u8,
+    pub poll_timeout: u32,
+    pub state: u8,
+    pub string_index: u8,
+}
+
+impl DfuStatus {
+    // GETSTATUS reply: bStatus, bwPollTimeout[3], bState, iString
+    pub fn parse(data: &[u8]) -> Option<DfuStatus> {
+        if data.len() < 6 {
+            return None;
+        }
+        Some(DfuStatus {
+            status: data[0],
+            poll_timeout: u32::from_le_bytes([data[1], data[2], data[3], 0]),
+            state: data[4],
+            string_index: data[5],
+        })
+    }
+}
+
+pub struct DfuClient<T: Transport> {
+    transport: T,
+}
+
+impl<T: Transport> DfuClient<T> {
+    pub fn new(transport: T) -> DfuClient<T> {
+        DfuClient { transport }
+    }
+
+    pub fn transport(&mut self) -> &mut T {
+        &mut self.transport
+    }
+
+    pub fn into_inner(self) -> T {
+        self.transport
+    }
+
+    pub fn dnload(&mut self, data: &[u8]) -> Result<usize, TransferError> {
+        let mut data = data.to_vec();
+        self.transport
+            .control_transfer(0x21, DFU_DNLOAD, 0, 0, &mut data, DFU_TIMEOUT)
+    }
+
+    pub fn upload(&mut self, w_value: u16, len: usize) -> Result<Vec<u8>, TransferError> {
+        let mut data = vec![0u8; len];
+        let got = self
+            .transport
+            .control_transfer(0xA1, DFU_UPLOAD, w_value, 0, &mut data, DFU_TIMEOUT)?;
+        data.truncate(got);
+        Ok(data)
+    }
+
+    pub fn get_status(&mut self) -> Result<DfuStatus, TransferError> {
+        let mut data = [0u8; 6];
+        let got = self
+            .transport
+            .control_transfer(0xA1, DFU_GETSTATUS, 0, 0, &mut data, DFU_TIMEOUT)?;
+        DfuStatus::parse(&data[..got])
+            .ok_or_else(|| TransferError::Other(format!("short GETSTATUS reply ({} bytes)", got)))
+    }
+
+    pub fn clr_status(&mut self) -> Result<(), TransferError> {
+        self.transport
+            .control_transfer(0x21, DFU_CLRSTATUS, 0, 0, &mut [], USB_TIMEOUT)?;
+        Ok(())
+    }
+
+    pub fn abort(&mut self) -> Result<(), TransferError> {
+        self.transport
+            .control_transfer(0x21, DFU_ABORT, 0, 0, &mut [], USB_TIMEOUT)?;
+        Ok(())
+    }
+
+    // Downloads a whole image and asks for it to be booted: every block must
+    // leave the device in dfuDNLOAD-IDLE, then an empty DNLOAD and three
+    // GETSTATUS walk it through manifestation before the reset.
+    pub fn send_image(&mut self, image: &[u8]) -> Result<(), String> {
+        for (i, block) in image.chunks(DFU_MAX_TRANSFER_SIZE.into()).enumerate() {
+            let sent = self.dnload(block).map_err(|e| format!("DNLOAD block {}: {}", i, e))?;
+            if sent != block.len() {
+                return Err(format!("short DNLOAD in block {} ({} of {} bytes)", i, sent, block.len()));
+            }
+            let status = self.get_status().map_err(|e| format!("GETSTATUS: {}", e))?;
+            if status.status != DFU_STATUS_OK || status.state != DFU_STATE_DNLOAD_IDLE {
+                return Err(format!(
+                    "block {} left the device in status {} state {}",
+                    i, status.status, status.state
+                ));
+            }
+        }
+        self.dnload(&[]).map_err(|e| format!("final DNLOAD: {}", e))?;
+        for expected in [DFU_STATE_MANIFEST_SYNC, DFU_STATE_MANIFEST, DFU_STATE_MANIFEST_WAIT_RESET] {
+            let status = self.get_status().map_err(|e| format!("GETSTATUS: {}", e))?;
+            if status.state != expected {
+                return Err(format!("expected DFU state {}, got {}", expected, status.state));
+            }
+        }
+        self.transport.reset().map_err(|e| format!("reset: {}", e))
+    }
+}
@@ -0,0 +1,234 @@
+use crate::pwned_dfu::{PwnedDfu, REPLY_LEN};
+use crate::transport::Transport;
+use crate::DFU_MAX_TRANSFER_SIZE;
+use std::path::Path;
+use tracing::{debug, info, warn};
+
+pub const DUMP_RETRIES: usize = 5;
+// What's reserved up front, the rest grows as chunks come in
+const DUMP_PREALLOC: u64 = 16 << 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Region {
+    Rom,
+    Sram,
+}
+
+impl Region {
+    pub fn from_name(name: &str) -> Option<Region> {
+        match name {
+            "rom" => Some(Region::Rom),
+            "sram" => Some(Region::Sram),
+            _ => None,
+        }
+    }
+}
+
+pub fn dump_region<T: Transport>(
+    pwned: &mut PwnedDfu<T>,
+    base: u64,
+    size: u64,
+) -> Result<Vec<u8>, String> {
+    // one handler command per chunk, so a retry only repeats that command
+    let chunk_size = (usize::from(DFU_MAX_TRANSFER_SIZE) - REPLY_LEN) as u64;
+    let end = base
+        .checked_add(size)
+        .ok_or_else(|| format!("0x{:x} bytes from 0x{:x} runs past the end of memory", size, base))?;
+    let mut out = Vec::with_capacity(size.min(DUMP_PREALLOC) as usize);
+    let mut addr = base;
+    while addr < end {
+        let len = chunk_size.min(end - addr) as usize;
+        let mut attempt = 0;
+        let chunk = loop {
+            match pwned.read(addr, len) {
+                Ok(chunk) => break chunk,
+                Err(e) if attempt + 1 < DUMP_RETRIES => {
+                    warn!("Read at 0x{:x} failed ({}), retrying", addr, e);
+                    // get the DFU state machine back to idle before trying again
+                    let _ = pwned.dfu().clr_status();
+                    attempt += 1;
+                }
+                Err(e) => return Err(format!("read at 0x{:x} failed: {}", addr, e)),
+            }
+        };
+        out.extend_from_slice(&chunk);
+        addr += len as u64;
+        // every chunk at debug, every tenth of the way at info
+        let done = addr - base;
+        debug!("0x{:x} / 0x{:x}", done, size);
+        if done * 10 / size != (done - len as u64) * 10 / size {
+            info!("0x{:x} / 0x{:x}", done, size);
+        }
+    }
+    Ok(out)
+}
+
+// SecureROM embeds the same iBoot-NNNN version string that the serial number
+// reports in SRTG, so a dump that doesn't contain it is from the wrong place.
+pub fn verify_rom_version(rom: &[u8], srtg: &str) -> Result<(), String> {
+    let needle = srtg.as_bytes();
+    if needle.is_empty() || !rom.windows(needle.len()).any(|window| window == needle) {
+        return Err(format!("ROM dump does not contain version string {}", srtg));
+    }
+    Ok(())
+}
+
+pub fn dump_to_file<T: Transport>(
+    pwned: &mut PwnedDfu<T>,
+    region: Region,
+    path: &Path,
+) -> Result<(), String> {
+    let soc = pwned.soc();
+    let (base, size) = match region {
+        Region::Rom => (soc.rom_base, soc.rom_size),
+        Region::Sram => (soc.sram_base, soc.sram_size),
+    };
+    info!("Dumping 0x{:x} bytes from 0x{:x} ({})", size, base, soc.name);
+    let data = dump_region(pwned, base, size)?;
+    if region == Region::Rom {
+        let srtg = pwned
+            .serial()
+            .srtg
+            .clone()
+            .ok_or("serial has no SRTG tag to verify against")?;
+        if srtg != soc.srtg {
+            warn!("SRTG {} differs from known {} for {}", srtg, soc.srtg, soc.name);
+        }
+        verify_rom_version(&data, &srtg)?;
+        info!("SecureROM version {} verified", srtg);
+    }
+    std::fs::write(path, &data).map_err(|e| format!("{}: {}", path.display(), e))?;
+    info!("Wrote {}", path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pwned_dfu::{encode_reply, Command};
+    use crate::transport::TransferError;
+    use crate::{DFU_CLRSTATUS, DFU_DNLOAD, DFU_GETSTATUS, DFU_STATE_DNLOAD_IDLE, DFU_UPLOAD};
+
+    const SERIAL: &str =
+        "CPID:8010 CPRV:11 CPFM:03 SCEP:01 BDID:0C ECID:001A2B3C4D5E6F70 IBFL:3C SRTG:[iBoot-2696.0.0.1.33] PWND:[checkm8]";
+    const CHUNK: usize = DFU_MAX_TRANSFER_SIZE as usize - REPLY_LEN;
+
+    fn byte_at(addr: u64) -> u8 {
+        (addr ^ addr >> 8) as u8
+    }
+
+    // Just enough of the stage 2 handler for reads, like the pwned_dfu mock:
+    // every address reads as byte_at, and the next `failures` UPLOADs time out
+    #[derive(Default)]
+    struct Handler {
+        command: Vec<u8>,
+        reply: Vec<u8>,
+        lens: Vec<u64>,
+        failures: usize,
+        clears: usize,
+    }
+
+    impl Transport for Handler {
+        fn control_transfer(
+            &mut self,
+            bm_request_type: u8,
+            b_request: u8,
+            _w_value: u16,
+            _w_index: u16,
+            data: &mut [u8],
+            _timeout: u32,
+        ) -> Result<usize, TransferError> {
+            match (bm_request_type, b_request) {
+                (0x21, DFU_DNLOAD) => {
+                    self.command = data.to_vec();
+                    Ok(data.len())
+                }
+                (0xA1, DFU_GETSTATUS) => {
+                    let Some(Command::Memcpy { src, len, .. }) = Command::decode(&self.command) else {
+                        panic!("not a memcpy: {:02x?}", self.command);
+                    };
+                    self.lens.push(len);
+                    self.reply = encode_reply(0).to_vec();
+                    self.reply.extend((src..src + len).map(byte_at));
+                    let status = [0, 0, 0, 0, DFU_STATE_DNLOAD_IDLE, 0];
+                    data[..status.len()].copy_from_slice(&status);
+                    Ok(status.len())
+                }
+                (0xA1, DFU_UPLOAD) if self.failures > 0 => {
+                    self.failures -= 1;
+                    Err(TransferError::Timeout)
+                }
+                (0xA1, DFU_UPLOAD) => {
+                    data.copy_from_slice(&self.reply[..data.len()]);
+                    Ok(data.len())
+                }
+                (0x21, DFU_CLRSTATUS) => {
+                    self.clears += 1;
+                    Ok(0)
+                }
+                _ => Err(TransferError::Stall),
+            }
+        }
+
+        fn bulk_transfer(&mut self, _endpoint: u8, _data: &mut [u8], _timeout: u32) -> Result<usize, TransferError> {
+            Err(TransferError::Stall)
+        }
+
+        fn reset(&mut self) -> Result<(), TransferError> {
+            Ok(())
+        }
+
+        fn serial_number(&mut self) -> Option<String> {
+            Some(SERIAL.to_string())
+        }
+    }
+
+    fn open(failures: usize) -> PwnedDfu<Handler> {
+        PwnedDfu::open(Handler {
+            failures,
+            ..Handler::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn reads_a_command_at_a_time() {
+        let base = 0x1_0000_0000;
+        let size = 3 * CHUNK as u64 + 5;
+        let mut pwned = open(0);
+        let data = dump_region(&mut pwned, base, size).unwrap();
+        assert_eq!(data, (base..base + size).map(byte_at).collect::<Vec<_>>());
+        let chunk = CHUNK as u64;
+        assert_eq!(pwned.dfu().transport().lens, [chunk, chunk, chunk, 5]);
+    }
+
+    #[test]
+    fn clears_the_status_and_retries() {
+        let base = 0x1_8000_0000;
+        let mut pwned = open(DUMP_RETRIES - 1);
+        let data = dump_region(&mut pwned, base, 0x40).unwrap();
+        assert_eq!(data, (base..base + 0x40).map(byte_at).collect::<Vec<_>>());
+        let handler = pwned.dfu().transport();
+        assert_eq!(handler.clears, DUMP_RETRIES - 1);
+        assert_eq!(handler.lens, [0x40; DUMP_RETRIES]);
+
+        // one failure too many gives up without clearing again
+        let mut pwned = open(DUMP_RETRIES);
+        let error = dump_region(&mut pwned, base, 0x40).unwrap_err();
+        assert!(error.starts_with("read at 0x180000000 failed: UPLOAD"), "{}", error);
+        assert_eq!(pwned.dfu().transport().clears, DUMP_RETRIES - 1);
+    }
+
+    #[test]
+    fn rejects_ranges_past_the_end_of_memory() {
+        let mut pwned = open(0);
+        assert_eq!(
+            dump_region(&mut pwned, u64::MAX - 0xF, 0x20),
+            Err("0x20 bytes from 0xfffffffffffffff0 runs past the end of memory".to_string())
+        );
+        assert!(pwned.dfu().transport().lens.is_empty());
+        // a size no Vec could hold fails at the device, not the allocator
+        let mut pwned = open(DUMP_RETRIES);
+        assert!(dump_region(&mut pwned, 0, u64::MAX).is_err());
+    }
+}
@@ -0,0 +1,567 @@
+// The whole jailbreak as a walk over device modes. Every transition makes the
+// device re-enumerate, so nothing is carried over from one step to the next:
+// the host re-detects the device and opens a fresh handle for each step.
+
+use crate::journal::{self, Journal, Stage};
+use crate::serial::DeviceSerial;
+use std::fmt;
+use std::path::PathBuf;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use tracing::{field, info_span, Instrument};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// A journal older than this no longer says anything about what the device is
+// running, it has likely been rebooted since
+const JOURNAL_STALE_AFTER: u64 = 60 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Normal,
+    Recovery,
+    Dfu,
+    PwnedDfu,
+    // Recovery mode, but running our iBSS/iBEC. Only the journal can tell.
+    IBss,
+    IBec,
+    Pongo,
+    Booted,
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Mode::Normal => "normal mode",
+            Mode::Recovery => "recovery mode",
+            Mode::Dfu => "DFU",
+            Mode::PwnedDfu => "pwned DFU",
+            Mode::IBss => "iBSS",
+            Mode::IBec => "iBEC",
+            Mode::Pongo => "pongoOS",
+            Mode::Booted => "booted",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transition {
+    EnterRecovery,
+    EnterDfu,
+    Exploit,
+    BootPongo,
+    SendIbss,
+    SendIbec,
+    BootKernel,
+}
+
+impl Transition {
+    pub fn to(self) -> Mode {
+        match self {
+            Transition::EnterRecovery => Mode::Recovery,
+            Transition::EnterDfu => Mode::Dfu,
+            Transition::Exploit => Mode::PwnedDfu,
+            Transition::BootPongo => Mode::Pongo,
+            Transition::SendIbss => Mode::IBss,
+            Transition::SendIbec => Mode::IBec,
+            Transition::BootKernel => Mode::Booted,
+        }
+    }
+
+    // How long the device gets to show up in the new mode once the
+    // transition itself is done
+    pub fn timeout(self) -> Duration {
+        match self {
+            // the user may still be holding buttons
+            Transition::EnterDfu => Duration::from_secs(30),
+            _ => Duration::from_secs(20),
+        }
+    }
+
+    // Whether the device drops off the bus before showing up in the new mode.
+    // The exploit leaves it in place, just with PWND in the serial.
+    pub fn reenumerates(self) -> bool {
+        self != Transition::Exploit
+    }
+
+    pub fn stage(self) -> Option<Stage> {
+        match self {
+            Transition::EnterRecovery => Some(Stage::LeftNormal),
+            Transition::Exploit => Some(Stage::Pwned),
+            Transition::SendIbss => Some(Stage::IbssSent),
+            Transition::SendIbec => Some(Stage::IbecSent),
+            Transition::BootPongo => Some(Stage::PongoBooted),
+            Transition::BootKernel => Some(Stage::Booted),
+            Transition::EnterDfu => None,
+        }
+    }
+}
+
+impl fmt::Display for Transition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Transition::EnterRecovery => "entering recovery mode",
+            Transition::EnterDfu => "entering DFU",
+            Transition::Exploit => "exploiting with checkm8",
+            Transition::BootPongo => "booting pongoOS",
+            Transition::SendIbss => "sending iBSS",
+            Transition::SendIbec => "sending iBEC",
+            Transition::BootKernel => "booting the kernel",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// The transitions that take a device from `from` to `target`. Modes only move
+// forward, so e.g. nothing gets a pwned device back to normal mode.
+pub fn plan(from: Mode, target: Mode) -> Option<Vec<Transition>> {
+    let mut transitions = Vec::new();
+    let mut mode = from;
+    while mode != target {
+        let next = match mode {
+            Mode::Normal => Transition::EnterRecovery,
+            Mode::Recovery => Transition::EnterDfu,
+            Mode::Dfu => Transition::Exploit,
+            Mode::PwnedDfu if target == Mode::Pongo => Transition::BootPongo,
+            Mode::PwnedDfu if target == Mode::Booted => Transition::SendIbss,
+            Mode::IBss if target == Mode::Booted => Transition::SendIbec,
+            Mode::IBec if target == Mode::Booted => Transition::BootKernel,
+            _ => return None,
+        };
+        transitions.push(next);
+        mode = next.to();
+    }
+    Some(transitions)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    Detected(Mode),
+    Resumed(Mode),
+    Started(Transition),
+    Waiting(Mode),
+    Completed(Transition),
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Event::Detected(mode) => write!(f, "Device is in {}", mode),
+            Event::Resumed(mode) => write!(f, "Resuming from {} (journal)", mode),
+            Event::Started(transition) => write!(f, "Started {}", transition),
+            Event::Waiting(mode) => write!(f, "Waiting for {}...", mode),
+            Event::Completed(transition) => write!(f, "Done {}", transition),
+        }
+    }
+}
+
+// What the host can see of the device: its USB-level mode, and its identity
+// when it can tell (the iBoot serial in DFU and recovery, lockdownd in normal
+// mode).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Detection {
+    pub mode: Mode,
+    pub serial: Option<DeviceSerial>,
+}
+
+// What the flow needs from whatever is driving the device
+pub trait Host {
+    // None while nothing is attached (as during a re-enumeration)
+    fn detect(&mut self) -> Option<Detection>;
+
+    // Performs one transition, opening the device itself. It doesn't need to
+    // wait for the device to come back.
+    async fn perform(&mut self, transition: Transition) -> Result<(), String>;
+
+    // What a transition used, for the journal: hashes of the payload/images
+    // it sent, or the iOS version a device left normal mode from
+    fn details(&self, transition: Transition) -> Vec<(String, String)>;
+}
+
+pub struct Flow<H: Host, F: FnMut(&Event)> {
+    host: H,
+    on_event: F,
+    journal_dir: Option<PathBuf>,
+    journal: Option<Journal>,
+    // set when this run starts from scratch, so a journal opened later (once
+    // the device has a serial) gets cleared rather than resumed from
+    fresh: bool,
+    // in place of each transition's own
+    timeout: Option<Duration>,
+}
+
+impl<H: Host, F: FnMut(&Event)> Flow<H, F> {
+    pub fn new(host: H, on_event: F) -> Flow<H, F> {
+        Flow {
+            host,
+            on_event,
+            journal_dir: None,
+            journal: None,
+            fresh: false,
+            timeout: None,
+        }
+    }
+
+    // Keeps a journal per ECID in `dir` and resumes from it
+    pub fn with_journal(mut self, dir: PathBuf) -> Flow<H, F> {
+        self.journal_dir = Some(dir);
+        self
+    }
+
+    // Gives every transition `timeout` to land, rather than its own
+    pub fn with_timeout(mut self, timeout: Duration) -> Flow<H, F> {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub async fn run(&mut self, target: Mode) -> Result<(), String> {
+        let detection = self.host.detect().ok_or("no device found")?;
+        (self.on_event)(&Event::Detected(detection.mode));
+        self.open_journal(&detection)?;
+        let mode = self.resolve(&detection);
+        if mode != detection.mode {
+            (self.on_event)(&Event::Resumed(mode));
+        } else if matches!(mode, Mode::Normal | Mode::Recovery | Mode::Dfu) {
+            // starting over, whatever the journal says happened before
+            self.fresh = true;
+            if let Some(journal) = &mut self.journal {
+                journal.clear()?;
+            }
+        }
+
+        let transitions = plan(mode, target).ok_or(format!("can't get from {} to {}", mode, target))?;
+        for transition in transitions {
+            (self.on_event)(&Event::Started(transition));
+            // what the host logs while performing it goes under this
+            let span = info_span!("transition", transition = %transition, elapsed_us = field::Empty);
+            let started = Instant::now();
+            let result = self.host.perform(transition).instrument(span.clone()).await;
+            span.record("elapsed_us", started.elapsed().as_micros() as u64);
+            result.map_err(|e| format!("{} failed: {}", transition, e))?;
+            self.record(transition)?;
+            // a booted device is whatever the OS makes of it, nothing to wait for
+            if transition.to() != Mode::Booted {
+                (self.on_event)(&Event::Waiting(transition.to()));
+                self.wait_for(transition)?;
+            }
+            (self.on_event)(&Event::Completed(transition));
+        }
+        Ok(())
+    }
+
+    fn open_journal(&mut self, detection: &Detection) -> Result<(), String> {
+        if self.journal.is_some() {
+            return Ok(());
+        }
+        if let (Some(dir), Some(serial)) = (&self.journal_dir, &detection.serial) {
+            let mut journal = Journal::open(dir, serial.ecid)?;
+            if self.fresh {
+                journal.clear()?;
+            }
+            self.journal = Some(journal);
+        }
+        Ok(())
+    }
+
+    fn record(&mut self, transition: Transition) -> Result<(), String> {
+        let (Some(journal), Some(stage)) = (&mut self.journal, transition.stage()) else {
+            return Ok(());
+        };
+        journal.record(stage, self.host.details(transition))
+    }
+
+    // Recovery mode is ours if the journal says we just sent iBSS or iBEC,
+    // with the same images we'd send now.
+    fn resolve(&self, detection: &Detection) -> Mode {
+        if detection.mode != Mode::Recovery {
+            return detection.mode;
+        }
+        let Some(last) = self.journal.as_ref().and_then(|journal| journal.last()) else {
+            return detection.mode;
+        };
+        let (transition, mode) = match last.stage {
+            Stage::IbssSent => (Transition::SendIbss, Mode::IBss),
+            Stage::IbecSent => (Transition::SendIbec, Mode::IBec),
+            _ => return detection.mode,
+        };
+        if last.age() > JOURNAL_STALE_AFTER || last.details != self.host.details(transition) {
+            return detection.mode;
+        }
+        mode
+    }
+
+    fn wait_for(&mut self, transition: Transition) -> Result<(), String> {
+        let mode = transition.to();
+        let start = Instant::now();
+        let mut gone = !transition.reenumerates();
+        let mut last = None;
+        while start.elapsed() < self.timeout.unwrap_or(transition.timeout()) {
+            let detection = self.host.detect();
+            match &detection {
+                None => gone = true,
+                Some(detection) if gone => {
+                    self.open_journal(detection)?;
+                    last = Some(self.resolve(detection));
+                    if last == Some(mode) {
+                        return Ok(());
+                    }
+                }
+                Some(_) => {}
+            }
+            sleep(POLL_INTERVAL);
+        }
+        match last {
+            Some(last) => Err(format!("timed out waiting for {}, device is in {}", mode, last)),
+            None if gone => Err(format!("timed out waiting for {}, device is gone", mode)),
+            None => Err(format!("timed out waiting for {}, device never disconnected", mode)),
+        }
+    }
+}
+
+// Journal detail for something a transition sent
+pub fn fingerprint(name: &str, data: &[u8]) -> (String, String) {
+    (name.to_string(), journal::sha256_hex(data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    const SERIAL: &str = "CPID:8010 CPRV:11 CPFM:03 SCEP:01 BDID:0C ECID:001A2B3C4D5E6F70 IBFL:3C";
+
+    // What a transition does to the fake device
+    #[derive(Clone, Copy)]
+    enum Outcome {
+        Fails,
+        // drops off the bus for a couple of polls, if the transition does
+        // that, then shows up in this mode
+        Lands(Mode),
+        Vanishes,
+        Stays,
+    }
+
+    struct FakeHost {
+        // where the device really is, and for how many more polls it's off
+        // the bus
+        mode: Mode,
+        gone: u32,
+        outcomes: Vec<(Transition, Outcome)>,
+        // what details() reports, the same for every transition
+        details: Vec<(String, String)>,
+    }
+
+    impl FakeHost {
+        fn new(mode: Mode) -> FakeHost {
+            FakeHost {
+                mode,
+                gone: 0,
+                outcomes: Vec::new(),
+                details: vec![fingerprint("image", b"ibss")],
+            }
+        }
+
+        fn with(mut self, transition: Transition, outcome: Outcome) -> FakeHost {
+            self.outcomes.push((transition, outcome));
+            self
+        }
+    }
+
+    impl Host for FakeHost {
+        fn detect(&mut self) -> Option<Detection> {
+            if self.gone > 0 {
+                self.gone -= 1;
+                return None;
+            }
+            // our iBSS and iBEC look like any recovery mode
+            let mode = match self.mode {
+                Mode::IBss | Mode::IBec => Mode::Recovery,
+                mode => mode,
+            };
+            Some(Detection {
+                mode,
+                serial: DeviceSerial::parse(SERIAL),
+            })
+        }
+
+        async fn perform(&mut self, transition: Transition) -> Result<(), String> {
+            let outcome = self
+                .outcomes
+                .iter()
+                .find(|(own, _)| *own == transition)
+                .map_or(Outcome::Lands(transition.to()), |(_, outcome)| *outcome);
+            match outcome {
+                Outcome::Fails => return Err("device said no".to_string()),
+                Outcome::Lands(mode) => {
+                    if transition.reenumerates() {
+                        self.gone = 2;
+                    }
+                    self.mode = mode;
+                }
+                Outcome::Vanishes => self.gone = u32::MAX,
+                Outcome::Stays => {}
+            }
+            Ok(())
+        }
+
+        fn details(&self, _: Transition) -> Vec<(String, String)> {
+            self.details.clone()
+        }
+    }
+
+    // A journal directory of its own for each test
+    fn journal_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ra1n-oxide-flow-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    // The events of a run to `target`, and how it ended
+    fn run(host: FakeHost, target: Mode, dir: &Path) -> (Vec<Event>, Result<(), String>) {
+        let mut events = Vec::new();
+        let mut flow = Flow::new(host, |event: &Event| events.push(event.clone()))
+            .with_journal(dir.to_path_buf())
+            .with_timeout(Duration::from_millis(500));
+        let result = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(flow.run(target));
+        drop(flow);
+        (events, result)
+    }
+
+    fn steps(transitions: &[Transition]) -> Vec<Event> {
+        transitions
+            .iter()
+            .flat_map(|transition| {
+                let mut events = vec![Event::Started(*transition)];
+                if transition.to() != Mode::Booted {
+                    events.push(Event::Waiting(transition.to()));
+                }
+                events.push(Event::Completed(*transition));
+                events
+            })
+            .collect()
+    }
+
+    #[test]
+    fn plans_forward_only() {
+        use Transition::*;
+        assert_eq!(
+            plan(Mode::Normal, Mode::Booted),
+            Some(vec![EnterRecovery, EnterDfu, Exploit, SendIbss, SendIbec, BootKernel])
+        );
+        assert_eq!(plan(Mode::Recovery, Mode::Pongo), Some(vec![EnterDfu, Exploit, BootPongo]));
+        assert_eq!(plan(Mode::IBec, Mode::Booted), Some(vec![BootKernel]));
+        assert_eq!(plan(Mode::Dfu, Mode::Dfu), Some(Vec::new()));
+        assert_eq!(plan(Mode::PwnedDfu, Mode::Normal), None);
+        assert_eq!(plan(Mode::IBss, Mode::Pongo), None);
+        assert_eq!(plan(Mode::Booted, Mode::Booted), Some(Vec::new()));
+    }
+
+    #[test]
+    fn boots_from_normal_mode() {
+        use Transition::*;
+        let dir = journal_dir("normal");
+        let (events, result) = run(FakeHost::new(Mode::Normal), Mode::Booted, &dir);
+        assert_eq!(result, Ok(()));
+        let mut expected = vec![Event::Detected(Mode::Normal)];
+        expected.extend(steps(&[EnterRecovery, EnterDfu, Exploit, SendIbss, SendIbec, BootKernel]));
+        assert_eq!(events, expected);
+
+        // everything but entering DFU is in the journal
+        let serial = DeviceSerial::parse(SERIAL).unwrap();
+        let journal = Journal::open(&dir, serial.ecid).unwrap();
+        assert_eq!(journal.last().map(|entry| entry.stage), Some(Stage::Booted));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn resumes_from_the_journal() {
+        use Transition::*;
+        let dir = journal_dir("resume");
+        // a run that got as far as iBSS
+        let host = FakeHost::new(Mode::Dfu).with(SendIbec, Outcome::Fails);
+        let (_, result) = run(host, Mode::Booted, &dir);
+        assert_eq!(result, Err("sending iBEC failed: device said no".to_string()));
+
+        // the device is still in our iBSS, which only the journal can tell
+        let (events, result) = run(FakeHost::new(Mode::IBss), Mode::Booted, &dir);
+        assert_eq!(result, Ok(()));
+        let mut expected = vec![Event::Detected(Mode::Recovery), Event::Resumed(Mode::IBss)];
+        expected.extend(steps(&[SendIbec, BootKernel]));
+        assert_eq!(events, expected);
+
+        // with other images it's just recovery mode, and starts over
+        let mut host = FakeHost::new(Mode::IBss).with(EnterDfu, Outcome::Fails);
+        host.details = vec![fingerprint("image", b"another ibss")];
+        let (events, result) = run(host, Mode::Booted, &dir);
+        assert_eq!(events[..2], [Event::Detected(Mode::Recovery), Event::Started(EnterDfu)]);
+        assert_eq!(result, Err("entering DFU failed: device said no".to_string()));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn stops_at_a_failed_transition() {
+        let dir = journal_dir("failed");
+        let host = FakeHost::new(Mode::Dfu).with(Transition::SendIbss, Outcome::Fails);
+        let (events, result) = run(host, Mode::Booted, &dir);
+        assert_eq!(result, Err("sending iBSS failed: device said no".to_string()));
+        let mut expected = vec![Event::Detected(Mode::Dfu)];
+        expected.extend(steps(&[Transition::Exploit]));
+        expected.push(Event::Started(Transition::SendIbss));
+        assert_eq!(events, expected);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn times_out() {
+        use Transition::*;
+        for (host, error) in [
+            (
+                FakeHost::new(Mode::Dfu).with(SendIbss, Outcome::Vanishes),
+                "timed out waiting for iBSS, device is gone",
+            ),
+            (
+                FakeHost::new(Mode::Recovery).with(EnterDfu, Outcome::Stays),
+                "timed out waiting for DFU, device never disconnected",
+            ),
+            // checkm8 didn't take
+            (
+                FakeHost::new(Mode::Dfu).with(Exploit, Outcome::Stays),
+                "timed out waiting for pwned DFU, device is in DFU",
+            ),
+            // iBSS didn't take and the ROM came back
+            (
+                FakeHost::new(Mode::PwnedDfu).with(SendIbss, Outcome::Lands(Mode::Dfu)),
+                "timed out waiting for iBSS, device is in DFU",
+            ),
+            (
+                FakeHost::new(Mode::Normal).with(EnterRecovery, Outcome::Lands(Mode::Normal)),
+                "timed out waiting for recovery mode, device is in normal mode",
+            ),
+        ] {
+            let dir = journal_dir("timeout");
+            let started = Instant::now();
+            let (events, result) = run(host, Mode::Booted, &dir);
+            assert_eq!(result, Err(error.to_string()));
+            assert!(started.elapsed() >= Duration::from_millis(500));
+            assert!(matches!(events.last(), Some(Event::Waiting(_))), "{:?}", events);
+            let _ = std::fs::remove_dir_all(&dir);
+        }
+    }
+
+    #[test]
+    fn refuses_what_it_cant_do() {
+        let dir = journal_dir("refuses");
+        let (events, result) = run(FakeHost::new(Mode::Pongo), Mode::Booted, &dir);
+        assert_eq!(result, Err("can't get from pongoOS to booted".to_string()));
+        assert_eq!(events, [Event::Detected(Mode::Pongo)]);
+
+        let mut host = FakeHost::new(Mode::Dfu);
+        host.gone = u32::MAX;
+        assert_eq!(run(host, Mode::Booted, &dir), (Vec::new(), Err("no device found".to_string())));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
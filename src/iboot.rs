@@ -0,0 +1,558 @@
+// Patchfinder/patcher for decrypted 64-bit iBoot, iBSS and iBEC images, along
+// the lines of iBoot64Patcher/kairos. Everything is found by pattern, so each
+// patch fails loudly rather than guessing when a pattern matches 0 or 2+ times.
+
+use crate::decrypt::{self, PayloadKind};
+
+const RET: u32 = 0xD65F03C0;
+const PACIBSP: u32 = 0xD503237F;
+const MOV_X0_0: u32 = 0xD2800000;
+const MOV_X0_1: u32 = 0xD2800020;
+
+// The load address sits at 0x300 up to iOS 13 and at 0x318 from iOS 14 on
+const BASE_ADDRESS_OFFSETS: [usize; 2] = [0x300, 0x318];
+
+// How far back from a match we look for the prologue of its function
+const MAX_FUNCTION_SCAN: usize = 0x2000;
+// How far after a string xref we look for the call that uses it
+const MAX_CALL_SCAN: usize = 8;
+
+// 'BNCH', the boot nonce hash tag checked by the Image4 property callback
+const TAG_BNCH: u32 = 0x424E4348;
+const BOOT_ARGS_PREFIX: &[u8] = b"rd=md0 nand-enable-reformat=1";
+const DEBUG_ENABLED: &[u8] = b"debug-enabled\0";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Patch {
+    pub address: u64,
+    pub offset: usize,
+    pub original: Vec<u8>,
+    pub patched: Vec<u8>,
+    pub description: String,
+}
+
+// MARK: instruction decoding
+fn sign_extend(value: u64, bits: u32) -> i64 {
+    let shift = 64 - bits;
+    ((value << shift) as i64) >> shift
+}
+
+fn rd(insn: u32) -> u32 {
+    insn & 0x1F
+}
+
+fn rn(insn: u32) -> u32 {
+    (insn >> 5) & 0x1F
+}
+
+fn adrp_target(insn: u32, pc: u64) -> Option<u64> {
+    if insn & 0x9F000000 != 0x90000000 {
+        return None;
+    }
+    let imm = (u64::from((insn >> 5) & 0x7FFFF) << 2) | u64::from((insn >> 29) & 3);
+    Some((pc & !0xFFF).wrapping_add((sign_extend(imm, 21) << 12) as u64))
+}
+
+fn adr_target(insn: u32, pc: u64) -> Option<u64> {
+    if insn & 0x9F000000 != 0x10000000 {
+        return None;
+    }
+    let imm = (u64::from((insn >> 5) & 0x7FFFF) << 2) | u64::from((insn >> 29) & 3);
+    Some(pc.wrapping_add(sign_extend(imm, 21) as u64))
+}
+
+// add xd, xn, #imm{, lsl #12}
+fn add_imm(insn: u32) -> Option<u64> {
+    if insn & 0xFF800000 != 0x91000000 {
+        return None;
+    }
+    let imm = u64::from((insn >> 10) & 0xFFF);
+    Some(if insn & (1 << 22) != 0 { imm << 12 } else { imm })
+}
+
+// (imm16, shift) of a movz/movk, 32 or 64-bit
+fn move_wide(insn: u32, opcode: u32) -> Option<(u32, u32)> {
+    if insn & 0x7F800000 != opcode {
+        return None;
+    }
+    Some(((insn >> 5) & 0xFFFF, ((insn >> 21) & 3) * 16))
+}
+
+fn movz(insn: u32) -> Option<(u32, u32)> {
+    move_wide(insn, 0x52800000)
+}
+
+fn movk(insn: u32) -> Option<(u32, u32)> {
+    move_wide(insn, 0x72800000)
+}
+
+fn is_bl(insn: u32) -> bool {
+    insn & 0xFC000000 == 0x94000000
+}
+
+// add x29, sp, #imm
+fn is_frame_setup(insn: u32) -> bool {
+    insn & 0xFFC003FF == 0x910003FD
+}
+
+// Anything that can sit between the function entry and the frame setup
+fn is_prologue(insn: u32) -> bool {
+    insn == PACIBSP
+        // sub sp, sp, #imm
+        || insn & 0xFF8003FF == 0xD10003FF
+        // stp x/d registers relative to sp, any addressing mode
+        || insn & 0xFC4003E0 == 0xA80003E0
+        || insn & 0xFC4003E0 == 0x6C0003E0
+}
+
+pub struct IBoot {
+    data: Vec<u8>,
+    base: u64,
+    patches: Vec<Patch>,
+}
+
+impl IBoot {
+    pub fn new(data: Vec<u8>) -> Result<IBoot, String> {
+        if decrypt::identify_payload(&data) != Some(PayloadKind::IBoot) {
+            return Err("not a decrypted iBoot image".to_string());
+        }
+        let base = BASE_ADDRESS_OFFSETS
+            .iter()
+            .filter_map(|offset| data.get(*offset..offset + 8))
+            .map(|bytes| u64::from_le_bytes(bytes.try_into().unwrap()))
+            .find(|base| *base != 0 && base & 0xFFF == 0 && base >> 40 == 0)
+            .ok_or("couldn't find the iBoot load address, is this a 64-bit image?")?;
+        Ok(IBoot {
+            data,
+            base,
+            patches: Vec::new(),
+        })
+    }
+
+    pub fn base(&self) -> u64 {
+        self.base
+    }
+
+    // "iBSS for d10, Copyright 2007-2016, Apple Inc." etc.
+    pub fn banner(&self) -> String {
+        let banner = &self.data[0x200..];
+        let end = banner.iter().position(|b| *b == 0).unwrap_or(banner.len()).min(0x80);
+        String::from_utf8_lossy(&banner[..end]).into_owned()
+    }
+
+    pub fn patches(&self) -> &[Patch] {
+        &self.patches
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+
+    fn address(&self, offset: usize) -> u64 {
+        self.base + offset as u64
+    }
+
+    fn insn(&self, offset: usize) -> u32 {
+        u32::from_le_bytes(self.data[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn insn_count(&self) -> usize {
+        self.data.len() / 4
+    }
+
+    fn apply(&mut self, offset: usize, patched: &[u8], description: String) {
+        let original = self.data[offset..offset + patched.len()].to_vec();
+        self.data[offset..offset + patched.len()].copy_from_slice(patched);
+        self.patches.push(Patch {
+            address: self.address(offset),
+            offset,
+            original,
+            patched: patched.to_vec(),
+            description,
+        });
+    }
+
+    fn apply_insns(&mut self, offset: usize, insns: &[u32], description: String) {
+        let bytes: Vec<u8> = insns.iter().flat_map(|insn| insn.to_le_bytes()).collect();
+        self.apply(offset, &bytes, description);
+    }
+
+    // MARK: pattern search
+    fn find_bytes(&self, needle: &[u8]) -> Vec<usize> {
+        self.data
+            .windows(needle.len())
+            .enumerate()
+            .filter(|(_, window)| *window == needle)
+            .map(|(offset, _)| offset)
+            .collect()
+    }
+
+    fn find_unique_bytes(&self, needle: &[u8], what: &str) -> Result<usize, String> {
+        match self.find_bytes(needle)[..] {
+            [offset] => Ok(offset),
+            [] => Err(format!("{} not found", what)),
+            ref offsets => Err(format!("{} is ambiguous, found {} times", what, offsets.len())),
+        }
+    }
+
+    // Offsets of instructions completing a movz/movk sequence for `value`
+    fn find_constant_loads(&self, value: u32) -> Vec<usize> {
+        let mut found = Vec::new();
+        for i in 0..self.insn_count() {
+            let insn = self.insn(i * 4);
+            let Some((imm, shift)) = movz(insn) else { continue };
+            let reg = rd(insn);
+            let mut loaded = u64::from(imm) << shift;
+            for j in i + 1..(i + 4).min(self.insn_count()) {
+                let next = self.insn(j * 4);
+                if let Some((imm, shift)) = movk(next).filter(|_| rd(next) == reg) {
+                    loaded = (loaded & !(0xFFFF << shift)) | (u64::from(imm) << shift);
+                    if loaded == u64::from(value) {
+                        found.push(j * 4);
+                        break;
+                    }
+                }
+            }
+        }
+        found
+    }
+
+    // Offsets of adrp+add / adr instructions that materialize `target`
+    fn find_xrefs(&self, target: u64) -> Vec<usize> {
+        let mut found = Vec::new();
+        for i in 0..self.insn_count() {
+            let insn = self.insn(i * 4);
+            let pc = self.address(i * 4);
+            if adr_target(insn, pc) == Some(target) {
+                found.push(i * 4);
+                continue;
+            }
+            let Some(page) = adrp_target(insn, pc) else { continue };
+            for j in i + 1..(i + 4).min(self.insn_count()) {
+                let next = self.insn(j * 4);
+                if let Some(imm) = add_imm(next).filter(|_| rn(next) == rd(insn)) {
+                    if page.wrapping_add(imm) == target {
+                        found.push(j * 4);
+                    }
+                    break;
+                }
+            }
+        }
+        found
+    }
+
+    // Walks back from an instruction to the entry of its function: the
+    // `add x29, sp` frame setup, then over the pacibsp/sub/stp run before it.
+    fn function_start(&self, offset: usize) -> Result<usize, String> {
+        let lowest = offset.saturating_sub(MAX_FUNCTION_SCAN);
+        let mut start = (lowest..=offset)
+            .rev()
+            .step_by(4)
+            .find(|offset| is_frame_setup(self.insn(*offset)))
+            .ok_or(format!("no function prologue before 0x{:x}", self.address(offset)))?;
+        while start >= 4 && is_prologue(self.insn(start - 4)) {
+            start -= 4;
+        }
+        if start == offset || !is_prologue(self.insn(start)) {
+            return Err(format!("no function prologue before 0x{:x}", self.address(offset)));
+        }
+        Ok(start)
+    }
+
+    // MARK: patches
+    // The Image4 property callback checks BNCH/ECID/etc. against the device.
+    // Making it return 0 lets any (or a dummy) IM4M through.
+    pub fn patch_image4_validation(&mut self) -> Result<(), String> {
+        // loads outside a framed function can't be the callback
+        let mut functions: Vec<usize> = self
+            .find_constant_loads(TAG_BNCH)
+            .into_iter()
+            .filter_map(|offset| self.function_start(offset).ok())
+            .collect();
+        functions.sort_unstable();
+        functions.dedup();
+        let start = match functions[..] {
+            [start] => start,
+            [] => return Err("image4 property callback not found".to_string()),
+            _ => {
+                return Err(format!(
+                    "image4 property callback is ambiguous, {} candidates",
+                    functions.len()
+                ))
+            }
+        };
+        // keep pacibsp so the function still looks signed to anything scanning it
+        let at = if self.insn(start) == PACIBSP { start + 4 } else { start };
+        self.apply_insns(at, &[MOV_X0_0, RET], "image4 property callback returns 0".to_string());
+        Ok(())
+    }
+
+    // Overwrites the restore boot-args string in place. iBEC only.
+    pub fn patch_boot_args(&mut self, args: &str) -> Result<(), String> {
+        let offset = self.find_unique_bytes(BOOT_ARGS_PREFIX, "boot-args string")?;
+        let available = self.data[offset..]
+            .iter()
+            .position(|b| *b == 0)
+            .ok_or("boot-args string isn't terminated")?;
+        if args.len() > available {
+            return Err(format!(
+                "boot-args are {} bytes, only {} fit in place",
+                args.len(),
+                available
+            ));
+        }
+        if self.find_xrefs(self.address(offset)).is_empty() {
+            return Err("boot-args string has no references".to_string());
+        }
+        let mut patched = args.as_bytes().to_vec();
+        patched.resize(available, 0);
+        self.apply(offset, &patched, format!("boot-args \"{}\"", args));
+        Ok(())
+    }
+
+    // Each "debug-enabled" lookup is followed by the call that reads it,
+    // which we replace with `mov x0, #1`.
+    pub fn patch_debug_enabled(&mut self) -> Result<(), String> {
+        let string = self.find_unique_bytes(DEBUG_ENABLED, "debug-enabled string")?;
+        let xrefs = self.find_xrefs(self.address(string));
+        if xrefs.is_empty() {
+            return Err("debug-enabled string has no references".to_string());
+        }
+        for xref in xrefs {
+            let call = (1..=MAX_CALL_SCAN)
+                .map(|i| xref + i * 4)
+                .take_while(|offset| offset + 4 <= self.data.len())
+                .find(|offset| is_bl(self.insn(*offset)))
+                .ok_or(format!("no call after debug-enabled reference at 0x{:x}", self.address(xref)))?;
+            self.apply_insns(call, &[MOV_X0_1], "debug-enabled check returns 1".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: u64 = 0x1_800B_0000;
+    const STP_X29_X30: u32 = 0xA9BF7BFD;
+    const ADD_X29_SP: u32 = 0x910003FD;
+    const NOP: u32 = 0xD503201F;
+    const BL: u32 = 0x94000010;
+
+    // A blank iBEC with the iOS 14 banner and load address, for code and
+    // strings to be placed in
+    struct Fixture(Vec<u8>);
+
+    impl Fixture {
+        fn new() -> Fixture {
+            let mut data = vec![0u8; 0x4000];
+            let banner = b"iBEC for d10, Copyright 2007-2020, Apple Inc.";
+            data[0x200..0x200 + banner.len()].copy_from_slice(banner);
+            data[0x318..0x320].copy_from_slice(&BASE.to_le_bytes());
+            Fixture(data)
+        }
+
+        fn bytes(mut self, offset: usize, bytes: &[u8]) -> Fixture {
+            self.0[offset..offset + bytes.len()].copy_from_slice(bytes);
+            self
+        }
+
+        fn code(self, offset: usize, insns: &[u32]) -> Fixture {
+            let bytes: Vec<u8> = insns.iter().flat_map(|insn| insn.to_le_bytes()).collect();
+            self.bytes(offset, &bytes)
+        }
+
+        fn iboot(self) -> IBoot {
+            IBoot::new(self.0).unwrap()
+        }
+    }
+
+    fn imm21(insn: u32, imm: i64, rd: u32) -> u32 {
+        let imm = imm as u32 & 0x1F_FFFF;
+        insn | ((imm & 3) << 29) | ((imm >> 2) << 5) | rd
+    }
+
+    // adrp x`rd` + add x`rd`, at `pc`, for the address of `target`, both
+    // offsets into the image
+    fn adrp_add(pc: usize, target: usize, rd: u32) -> [u32; 2] {
+        let page = |offset: usize| (BASE + offset as u64) as i64 >> 12;
+        let adrp = imm21(0x90000000, page(target) - page(pc), rd);
+        let add = 0x91000000 | ((target as u32 & 0xFFF) << 10) | (rd << 5) | rd;
+        [adrp, add]
+    }
+
+    fn adr(pc: usize, target: usize, rd: u32) -> u32 {
+        imm21(0x10000000, target as i64 - pc as i64, rd)
+    }
+
+    // movz/movk w8 with 'BNCH'
+    const LOAD_BNCH: [u32; 2] = [0x52800000 | (0x4348 << 5) | 8, 0x72A00000 | (0x424E << 5) | 8];
+
+    fn function(load: bool) -> Vec<u32> {
+        let mut insns = vec![PACIBSP, STP_X29_X30, ADD_X29_SP, NOP];
+        if load {
+            insns.extend_from_slice(&LOAD_BNCH);
+        }
+        insns.push(RET);
+        insns
+    }
+
+    #[test]
+    fn reads_the_header() {
+        let iboot = Fixture::new().iboot();
+        assert_eq!(iboot.base(), BASE);
+        assert_eq!(iboot.banner(), "iBEC for d10, Copyright 2007-2020, Apple Inc.");
+        // iOS 13 and earlier
+        let old = Fixture::new().bytes(0x318, &[0; 8]).bytes(0x300, &BASE.to_le_bytes()).iboot();
+        assert_eq!(old.base(), BASE);
+
+        let not_iboot = IBoot::new(vec![0; 0x1000]).err();
+        assert_eq!(not_iboot.as_deref(), Some("not a decrypted iBoot image"));
+        for base in [0, BASE + 4, 1 << 40] {
+            let error = IBoot::new(Fixture::new().bytes(0x318, &u64::to_le_bytes(base)).0).err();
+            assert_eq!(error.as_deref(), Some("couldn't find the iBoot load address, is this a 64-bit image?"));
+        }
+    }
+
+    #[test]
+    fn patches_image4_validation() {
+        let mut iboot = Fixture::new().code(0x1000, &function(true)).iboot();
+        iboot.patch_image4_validation().unwrap();
+        // after pacibsp
+        assert_eq!(
+            iboot.patches(),
+            [Patch {
+                address: BASE + 0x1004,
+                offset: 0x1004,
+                original: [STP_X29_X30.to_le_bytes(), ADD_X29_SP.to_le_bytes()].concat(),
+                patched: [MOV_X0_0.to_le_bytes(), RET.to_le_bytes()].concat(),
+                description: "image4 property callback returns 0".to_string(),
+            }]
+        );
+
+        // without pointer auth, from the entry
+        let mut insns = function(true);
+        insns[0] = NOP;
+        let mut iboot = Fixture::new().code(0x1000, &insns).iboot();
+        iboot.patch_image4_validation().unwrap();
+        assert_eq!(iboot.patches()[0].offset, 0x1004);
+        let bytes = iboot.into_bytes();
+        assert_eq!(&bytes[0x1004..0x100C], [MOV_X0_0.to_le_bytes(), RET.to_le_bytes()].concat());
+
+        // two loads in one function are still one callback
+        let mut insns = function(true);
+        insns.extend_from_slice(&LOAD_BNCH);
+        let mut iboot = Fixture::new().code(0x1000, &insns).iboot();
+        assert_eq!(iboot.patch_image4_validation(), Ok(()));
+    }
+
+    #[test]
+    fn image4_validation_errors() {
+        for (fixture, error) in [
+            (Fixture::new().code(0x1000, &function(false)), "image4 property callback not found".to_string()),
+            // loaded, but not in anything that looks like a function
+            (Fixture::new().code(0x1000, &LOAD_BNCH), "image4 property callback not found".to_string()),
+            (
+                Fixture::new().code(0x1000, &function(true)).code(0x2000, &function(true)),
+                "image4 property callback is ambiguous, 2 candidates".to_string(),
+            ),
+        ] {
+            let mut iboot = fixture.iboot();
+            assert_eq!(iboot.patch_image4_validation(), Err(error));
+            assert!(iboot.patches().is_empty());
+        }
+    }
+
+    const BOOT_ARGS: &[u8] = b"rd=md0 nand-enable-reformat=1 -progress\0";
+
+    #[test]
+    fn patches_boot_args() {
+        let fixture = || Fixture::new().bytes(0x3000, BOOT_ARGS).code(0x1100, &adrp_add(0x1100, 0x3000, 1));
+        let mut iboot = fixture().iboot();
+        iboot.patch_boot_args("rd=md0 -v").unwrap();
+        let patch = &iboot.patches()[0];
+        assert_eq!((patch.address, patch.description.as_str()), (BASE + 0x3000, "boot-args \"rd=md0 -v\""));
+        assert_eq!(patch.original, &BOOT_ARGS[..BOOT_ARGS.len() - 1]);
+        let bytes = iboot.into_bytes();
+        // padded out to the old string with NULs
+        assert_eq!(&bytes[0x3000..0x3000 + BOOT_ARGS.len()], [&b"rd=md0 -v"[..], &vec![0; BOOT_ARGS.len() - 9]].concat());
+
+        // exactly as long fits
+        let longest = "x".repeat(BOOT_ARGS.len() - 1);
+        assert_eq!(fixture().iboot().patch_boot_args(&longest), Ok(()));
+        assert_eq!(
+            fixture().iboot().patch_boot_args(&format!("{}x", longest)),
+            Err(format!("boot-args are {} bytes, only {} fit in place", BOOT_ARGS.len(), BOOT_ARGS.len() - 1))
+        );
+    }
+
+    #[test]
+    fn boot_args_errors() {
+        let unterminated = Fixture::new().0.len() - BOOT_ARGS.len() + 1;
+        for (fixture, error) in [
+            (Fixture::new(), "boot-args string not found"),
+            (
+                Fixture::new().bytes(0x3000, BOOT_ARGS).bytes(0x3100, BOOT_ARGS),
+                "boot-args string is ambiguous, found 2 times",
+            ),
+            (Fixture::new().bytes(0x3000, BOOT_ARGS), "boot-args string has no references"),
+            // referenced from the wrong place
+            (
+                Fixture::new().bytes(0x3000, BOOT_ARGS).code(0x1100, &adrp_add(0x1100, 0x3004, 1)),
+                "boot-args string has no references",
+            ),
+            (
+                Fixture::new().bytes(unterminated, &BOOT_ARGS[..BOOT_ARGS.len() - 1]),
+                "boot-args string isn't terminated",
+            ),
+        ] {
+            let mut iboot = fixture.iboot();
+            assert_eq!(iboot.patch_boot_args("-v"), Err(error.to_string()));
+            assert!(iboot.patches().is_empty());
+        }
+    }
+
+    #[test]
+    fn patches_debug_enabled() {
+        let [adrp, add] = adrp_add(0x1200, 0x3100, 0);
+        let mut iboot = Fixture::new()
+            .bytes(0x3100, DEBUG_ENABLED)
+            // adrp+add, then the call a couple of instructions on
+            .code(0x1200, &[adrp, add, NOP, BL])
+            // adr, straight into the call
+            .code(0x1300, &[adr(0x1300, 0x3100, 0), BL])
+            .iboot();
+        iboot.patch_debug_enabled().unwrap();
+        assert_eq!(
+            iboot.patches().iter().map(|patch| patch.offset).collect::<Vec<_>>(),
+            [0x120C, 0x1304]
+        );
+        assert!(iboot.patches().iter().all(|patch| patch.original == BL.to_le_bytes()
+            && patch.patched == MOV_X0_1.to_le_bytes()
+            && patch.description == "debug-enabled check returns 1"));
+    }
+
+    #[test]
+    fn debug_enabled_errors() {
+        let string = || Fixture::new().bytes(0x3100, DEBUG_ENABLED);
+        let mut too_far = vec![adr(0x1300, 0x3100, 0)];
+        too_far.extend_from_slice(&[NOP; MAX_CALL_SCAN]);
+        too_far.push(BL);
+        for (fixture, error) in [
+            (Fixture::new(), "debug-enabled string not found".to_string()),
+            // as part of a longer name it's still found, but twice
+            (
+                string().bytes(0x3200, b"x-debug-enabled\0"),
+                "debug-enabled string is ambiguous, found 2 times".to_string(),
+            ),
+            (string(), "debug-enabled string has no references".to_string()),
+            (
+                string().code(0x1300, &too_far),
+                format!("no call after debug-enabled reference at 0x{:x}", BASE + 0x1300),
+            ),
+        ] {
+            assert_eq!(fixture.iboot().patch_debug_enabled(), Err(error));
+        }
+    }
+}
@@ -0,0 +1,138 @@
+use crate::img3::{self, Img3};
+use crate::{compression, hex};
+use crate::img4::{self, Im4p, Img4, Kbag};
+
+// KBAG kind, the other one (2) is for development fused devices
+pub const KBAG_PRODUCTION: u64 = 1;
+
+// Boot images in whichever container their SoC uses. Anything that patches,
+// decrypts or uploads an image goes through this rather than caring about
+// IMG3 vs Image4.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Container {
+    Img4(Img4),
+    Im4p(Im4p),
+    Img3(Img3),
+}
+
+impl Container {
+    pub fn parse(data: &[u8]) -> Result<Container, String> {
+        if data.get(..4) == Some(&img3::IMG3_MAGIC.to_le_bytes()[..]) {
+            return Ok(Container::Img3(Img3::parse(data)?));
+        }
+        match img4::magic(data).as_deref() {
+            Some("IMG4") => Ok(Container::Img4(Img4::parse(data)?)),
+            Some("IM4P") => Ok(Container::Im4p(Im4p::parse(data)?)),
+            _ => Err("unrecognized image container".to_string()),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            Container::Img4(img4) => img4.to_der(),
+            Container::Im4p(im4p) => im4p.to_der(),
+            Container::Img3(img3) => img3.to_bytes(),
+        }
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Container::Img4(_) => "IMG4",
+            Container::Im4p(_) => "IM4P",
+            Container::Img3(_) => "IMG3",
+        }
+    }
+
+    pub fn image_type(&self) -> Option<String> {
+        match self {
+            Container::Img4(img4) => Some(img4.im4p.fourcc.clone()),
+            Container::Im4p(im4p) => Some(im4p.fourcc.clone()),
+            Container::Img3(img3) => img3.image_type(),
+        }
+    }
+
+    pub fn payload(&self) -> Option<&[u8]> {
+        match self {
+            Container::Img4(img4) => Some(&img4.im4p.payload),
+            Container::Im4p(im4p) => Some(&im4p.payload),
+            Container::Img3(img3) => img3.data(),
+        }
+    }
+
+    // Swaps in a new (e.g. decrypted and patched) payload. Key bags are
+    // dropped since they no longer describe how the payload is stored.
+    pub fn set_payload(&mut self, payload: Vec<u8>) {
+        match self {
+            Container::Img4(img4) => {
+                img4.im4p.payload = payload;
+                img4.im4p.kbags.clear();
+            }
+            Container::Im4p(im4p) => {
+                im4p.payload = payload;
+                im4p.kbags.clear();
+            }
+            Container::Img3(img3) => {
+                img3.set_data(payload);
+                img3.strip_kbags();
+            }
+        }
+    }
+
+    pub fn decompressed_payload(&self) -> Result<Vec<u8>, String> {
+        match self {
+            Container::Img4(img4) => img4.im4p.decompressed_payload(),
+            Container::Im4p(im4p) => im4p.decompressed_payload(),
+            Container::Img3(img3) => compression::decompress(img3.data().ok_or("IMG3 has no DATA")?),
+        }
+    }
+
+    pub fn set_decompressed_payload(&mut self, data: &[u8]) {
+        match self {
+            Container::Img4(img4) => img4.im4p.set_decompressed_payload(data),
+            Container::Im4p(im4p) => im4p.set_decompressed_payload(data),
+            Container::Img3(img3) => {
+                let codec = compression::detect(img3.data().unwrap_or_default());
+                img3.set_data(compression::compress(data, codec));
+            }
+        }
+    }
+
+    pub fn kbags(&self) -> Result<Vec<Kbag>, String> {
+        match self {
+            Container::Img4(img4) => Ok(img4.im4p.kbags.clone()),
+            Container::Im4p(im4p) => Ok(im4p.kbags.clone()),
+            Container::Img3(img3) => img3.kbags(),
+        }
+    }
+
+    // IV and key of the KBAGs wrapped with the production GID key, as hex
+    // the way decrypt-kbag takes them
+    pub fn production_kbags(&self) -> Result<Vec<String>, String> {
+        Ok(self
+            .kbags()?
+            .into_iter()
+            .filter(|kbag| kbag.kind == KBAG_PRODUCTION)
+            .map(|kbag| hex::encode(&[kbag.iv, kbag.key].concat()))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kbags_from_either_container() {
+        let img3 = include_bytes!("../fuzz/corpus/img3/seed-ibss");
+        let im4p = include_bytes!("../fuzz/corpus/img4/seed-im4p-kbags");
+        for data in [img3.as_slice(), im4p.as_slice()] {
+            let container = Container::parse(data).unwrap();
+            let kbags = container.kbags().unwrap();
+            assert_eq!(kbags.iter().map(|kbag| kbag.kind).collect::<Vec<_>>(), [1, 2], "{}", container.kind());
+            let production = container.production_kbags().unwrap();
+            assert_eq!(production, [hex::encode(&[kbags[0].iv.clone(), kbags[0].key.clone()].concat())]);
+        }
+        let unencrypted = Container::parse(include_bytes!("../fuzz/corpus/img4/seed-im4p-raw")).unwrap();
+        assert!(unencrypted.production_kbags().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,309 @@
+use crate::img4::Kbag;
+
+// Pre-A7 image container. Everything is little endian, and fourccs are stored
+// as u32s, so 'Img3' shows up in a hex dump as "3gmI".
+//
+// header: magic, full size, size without header, signed area size, ident
+// tag:    magic, total length, data length, data, padding
+
+pub const IMG3_MAGIC: u32 = 0x496D6733; // 'Img3'
+pub const IMG3_HEADER_LEN: usize = 20;
+pub const IMG3_TAG_HEADER_LEN: usize = 12;
+
+pub const TAG_DATA: u32 = 0x44415441; // 'DATA'
+pub const TAG_KBAG: u32 = 0x4B424147; // 'KBAG'
+pub const TAG_SHSH: u32 = 0x53485348; // 'SHSH'
+pub const TAG_CERT: u32 = 0x43455254; // 'CERT'
+pub const TAG_TYPE: u32 = 0x54595045; // 'TYPE'
+pub const TAG_VERS: u32 = 0x56455253; // 'VERS'
+
+pub fn fourcc_to_string(fourcc: u32) -> String {
+    fourcc.to_be_bytes().iter().map(|byte| *byte as char).collect()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Img3Tag {
+    pub magic: u32,
+    pub data: Vec<u8>,
+    pub padding: Vec<u8>,
+}
+
+impl Img3Tag {
+    pub fn new(magic: u32, data: Vec<u8>) -> Img3Tag {
+        let padding = vec![0u8; (4 - data.len() % 4) % 4];
+        Img3Tag {
+            magic,
+            data,
+            padding,
+        }
+    }
+
+    fn total_len(&self) -> usize {
+        IMG3_TAG_HEADER_LEN + self.data.len() + self.padding.len()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Img3 {
+    pub ident: u32,
+    pub tags: Vec<Img3Tag>,
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, String> {
+    let bytes = data
+        .get(offset..offset + 4)
+        .ok_or(format!("truncated IMG3 at offset 0x{:x}", offset))?;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+impl Img3 {
+    pub fn parse(data: &[u8]) -> Result<Img3, String> {
+        if read_u32(data, 0)? != IMG3_MAGIC {
+            return Err("not an IMG3 file".to_string());
+        }
+        let full_size = read_u32(data, 4)? as usize;
+        let ident = read_u32(data, 16)?;
+        if full_size > data.len() || full_size < IMG3_HEADER_LEN {
+            return Err(format!("IMG3 size 0x{:x} does not match file", full_size));
+        }
+
+        let mut tags = Vec::new();
+        let mut offset = IMG3_HEADER_LEN;
+        while offset < full_size {
+            let magic = read_u32(data, offset)?;
+            let total_len = read_u32(data, offset + 4)? as usize;
+            let data_len = read_u32(data, offset + 8)? as usize;
+            if total_len < IMG3_TAG_HEADER_LEN + data_len || offset + total_len > full_size {
+                return Err(format!(
+                    "bad length for tag {} at offset 0x{:x}",
+                    fourcc_to_string(magic),
+                    offset
+                ));
+            }
+            let data_start = offset + IMG3_TAG_HEADER_LEN;
+            tags.push(Img3Tag {
+                magic,
+                data: data[data_start..data_start + data_len].to_vec(),
+                padding: data[data_start + data_len..offset + total_len].to_vec(),
+            });
+            offset += total_len;
+        }
+        Ok(Img3 { ident, tags })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let body_len: usize = self.tags.iter().map(Img3Tag::total_len).sum();
+        // the signature covers every tag before SHSH
+        let signed_len: usize = self
+            .tags
+            .iter()
+            .take_while(|tag| tag.magic != TAG_SHSH)
+            .map(Img3Tag::total_len)
+            .sum();
+
+        let mut out = Vec::with_capacity(IMG3_HEADER_LEN + body_len);
+        out.extend_from_slice(&IMG3_MAGIC.to_le_bytes());
+        out.extend_from_slice(&((IMG3_HEADER_LEN + body_len) as u32).to_le_bytes());
+        out.extend_from_slice(&(body_len as u32).to_le_bytes());
+        out.extend_from_slice(&(signed_len as u32).to_le_bytes());
+        out.extend_from_slice(&self.ident.to_le_bytes());
+        for tag in &self.tags {
+            out.extend_from_slice(&tag.magic.to_le_bytes());
+            out.extend_from_slice(&(tag.total_len() as u32).to_le_bytes());
+            out.extend_from_slice(&(tag.data.len() as u32).to_le_bytes());
+            out.extend_from_slice(&tag.data);
+            out.extend_from_slice(&tag.padding);
+        }
+        out
+    }
+
+    pub fn tag(&self, magic: u32) -> Option<&Img3Tag> {
+        self.tags.iter().find(|tag| tag.magic == magic)
+    }
+
+    pub fn data(&self) -> Option<&[u8]> {
+        self.tag(TAG_DATA).map(|tag| tag.data.as_slice())
+    }
+
+    // Replaces DATA, e.g. after patching. The old signature no longer covers
+    // it, so SHSH and CERT are dropped along with it.
+    pub fn set_data(&mut self, data: Vec<u8>) {
+        self.tags
+            .retain(|tag| tag.magic != TAG_SHSH && tag.magic != TAG_CERT);
+        match self.tags.iter_mut().find(|tag| tag.magic == TAG_DATA) {
+            Some(tag) => *tag = Img3Tag::new(TAG_DATA, data),
+            None => self.tags.push(Img3Tag::new(TAG_DATA, data)),
+        }
+    }
+
+    pub fn image_type(&self) -> Option<String> {
+        let data = &self.tag(TAG_TYPE)?.data;
+        Some(fourcc_to_string(read_u32(data, 0).ok()?))
+    }
+
+    pub fn version(&self) -> Option<String> {
+        let data = &self.tag(TAG_VERS)?.data;
+        let len = read_u32(data, 0).ok()? as usize;
+        let bytes = data.get(4..4 + len)?;
+        Some(String::from_utf8_lossy(bytes).into_owned())
+    }
+
+    // KBAG: crypt state, AES key size in bits, IV, key
+    pub fn kbags(&self) -> Result<Vec<Kbag>, String> {
+        self.tags
+            .iter()
+            .filter(|tag| tag.magic == TAG_KBAG)
+            .map(|tag| {
+                let state = read_u32(&tag.data, 0)?;
+                let key_len = read_u32(&tag.data, 4)? as usize / 8;
+                let iv = tag.data.get(8..24).ok_or("truncated KBAG IV")?;
+                let key = tag.data.get(24..24 + key_len).ok_or("truncated KBAG key")?;
+                Ok(Kbag {
+                    kind: state.into(),
+                    iv: iv.to_vec(),
+                    key: key.to_vec(),
+                })
+            })
+            .collect()
+    }
+
+    // Removes the KBAGs, for re-packing a payload that's now stored decrypted.
+    pub fn strip_kbags(&mut self) {
+        self.tags.retain(|tag| tag.magic != TAG_KBAG);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Header and tags written out by hand: TYPE, VERS, DATA needing padding,
+    // then the signature
+    fn sample() -> Vec<u8> {
+        let mut tags = Vec::new();
+        for (magic, data, padding) in [
+            (TAG_TYPE, &b"ssbi"[..], 0),
+            (TAG_VERS, b"\x0a\x00\x00\x00iBoot-1145", 2),
+            (TAG_DATA, b"\x01\x02\x03\x04\x05", 3),
+            (TAG_SHSH, &[0xAA; 8], 0),
+            (TAG_CERT, &[0xCC; 4], 0),
+        ] {
+            tags.extend_from_slice(&magic.to_le_bytes());
+            tags.extend_from_slice(&((12 + data.len() + padding) as u32).to_le_bytes());
+            tags.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            tags.extend_from_slice(data);
+            tags.extend(std::iter::repeat_n(0, padding));
+        }
+        let mut out = b"3gmI".to_vec();
+        out.extend_from_slice(&((20 + tags.len()) as u32).to_le_bytes());
+        out.extend_from_slice(&(tags.len() as u32).to_le_bytes());
+        // TYPE, VERS and DATA are signed
+        out.extend_from_slice(&(16u32 + 28 + 20).to_le_bytes());
+        out.extend_from_slice(b"ssbi");
+        out.extend_from_slice(&tags);
+        out
+    }
+
+    #[test]
+    fn parses() {
+        let img3 = Img3::parse(&sample()).unwrap();
+        assert_eq!(fourcc_to_string(img3.ident), "ibss");
+        assert_eq!(
+            img3.tags.iter().map(|tag| fourcc_to_string(tag.magic)).collect::<Vec<_>>(),
+            ["TYPE", "VERS", "DATA", "SHSH", "CERT"]
+        );
+        assert_eq!(img3.image_type().as_deref(), Some("ibss"));
+        assert_eq!(img3.version().as_deref(), Some("iBoot-1145"));
+        assert_eq!(img3.data(), Some(&[1, 2, 3, 4, 5][..]));
+        assert_eq!(img3.tag(TAG_DATA).unwrap().padding, [0, 0, 0]);
+        assert_eq!(img3.kbags(), Ok(Vec::new()));
+        assert_eq!(img3.tag(TAG_KBAG), None);
+    }
+
+    #[test]
+    fn round_trips() {
+        let sample = sample();
+        assert_eq!(Img3::parse(&sample).unwrap().to_bytes(), sample);
+        for seed in [
+            include_bytes!("../fuzz/corpus/img3/seed-ibss").as_slice(),
+            include_bytes!("../fuzz/corpus/img3/seed-krnl-lzss"),
+        ] {
+            assert_eq!(Img3::parse(seed).unwrap().to_bytes(), seed);
+        }
+        // whatever padding there was is kept, even if it isn't zeros
+        let mut odd = sample.clone();
+        odd[20 + 16 + 26] = 0xEE;
+        assert_eq!(Img3::parse(&odd).unwrap().to_bytes(), odd);
+    }
+
+    #[test]
+    fn sets_data() {
+        let mut img3 = Img3::parse(&sample()).unwrap();
+        img3.set_data(vec![9; 6]);
+        assert_eq!(img3.data(), Some(&[9; 6][..]));
+        assert_eq!(img3.tag(TAG_DATA).unwrap().padding, [0, 0]);
+        // the signature is dropped with the data it covered
+        assert_eq!(img3.tag(TAG_SHSH), None);
+        assert_eq!(img3.tag(TAG_CERT), None);
+
+        let bytes = img3.to_bytes();
+        // TYPE 16, VERS 28, DATA 12 + 6 + 2
+        assert_eq!(&bytes[4..8], &(20u32 + 64).to_le_bytes());
+        assert_eq!(&bytes[8..12], &64u32.to_le_bytes());
+        // no SHSH, so everything counts as signed
+        assert_eq!(&bytes[12..16], &64u32.to_le_bytes());
+        assert_eq!(Img3::parse(&bytes), Ok(img3));
+
+        let mut empty = Img3 { ident: 0, tags: Vec::new() };
+        empty.set_data(b"abcd".to_vec());
+        assert_eq!(empty.tags, [Img3Tag::new(TAG_DATA, b"abcd".to_vec())]);
+        assert!(empty.tags[0].padding.is_empty());
+    }
+
+    #[test]
+    fn reads_kbags() {
+        let mut img3 = Img3::parse(&sample()).unwrap();
+        let mut kbag = 1u32.to_le_bytes().to_vec();
+        kbag.extend_from_slice(&256u32.to_le_bytes());
+        kbag.extend_from_slice(&[0x11; 16]);
+        kbag.extend_from_slice(&[0x22; 32]);
+        img3.tags.insert(2, Img3Tag::new(TAG_KBAG, kbag.clone()));
+        let kbags = img3.kbags().unwrap();
+        assert_eq!((kbags[0].kind, kbags[0].iv.clone(), kbags[0].key.clone()), (1, vec![0x11; 16], vec![0x22; 32]));
+
+        kbag.truncate(8 + 16 + 31);
+        img3.tags[2] = Img3Tag::new(TAG_KBAG, kbag);
+        assert_eq!(img3.kbags(), Err("truncated KBAG key".to_string()));
+        img3.strip_kbags();
+        assert_eq!(img3.kbags(), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn rejects_bad_lengths() {
+        let sample = sample();
+        let with = |offset: usize, value: u32| {
+            let mut data = sample.clone();
+            data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+            data
+        };
+        // DATA's tag starts after the header, TYPE and VERS
+        let data_tag = 20 + 16 + 28;
+        for (data, error) in [
+            (b"Img3".to_vec(), "not an IMG3 file".to_string()),
+            (sample[..3].to_vec(), "truncated IMG3 at offset 0x0".to_string()),
+            (sample[..12].to_vec(), "truncated IMG3 at offset 0x10".to_string()),
+            (with(4, sample.len() as u32 + 1), format!("IMG3 size 0x{:x} does not match file", sample.len() + 1)),
+            (with(4, 19), "IMG3 size 0x13 does not match file".to_string()),
+            // data longer than the tag
+            (with(data_tag + 8, 9), "bad length for tag DATA at offset 0x40".to_string()),
+            // tag past the end of the image
+            (with(data_tag + 4, 0x1000), "bad length for tag DATA at offset 0x40".to_string()),
+            (with(data_tag + 4, 11), "bad length for tag DATA at offset 0x40".to_string()),
+            // the image ends partway into a tag header
+            (with(4, data_tag as u32 + 8), format!("bad length for tag DATA at offset 0x{:x}", data_tag)),
+        ] {
+            assert_eq!(Img3::parse(&data), Err(error));
+        }
+        assert!(Img3::parse(&with(data_tag + 8, u32::MAX)).is_err());
+    }
+}
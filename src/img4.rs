@@ -0,0 +1,570 @@
+use crate::compression::{self, Codec};
+use crate::der::{self, Element, Tag, TAG_IA5_STRING, TAG_OCTET_STRING, TAG_SEQUENCE, TAG_SET};
+
+// IMG4 ::= SEQUENCE { "IMG4", IM4P, [0] IM4M OPTIONAL, [1] IM4R OPTIONAL }
+// IM4P ::= SEQUENCE { "IM4P", type, description, payload, kbag OPTIONAL, compression OPTIONAL }
+// IM4M ::= SEQUENCE { "IM4M", version, SET { MANB }, signature, certificate chain }
+// IM4R ::= SEQUENCE { "IM4R", SET { properties } }
+
+pub const COMPRESSION_LZFSE: u64 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PropertyValue {
+    Integer(u64),
+    Boolean(bool),
+    Data(Vec<u8>),
+    String(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Property {
+    pub name: String,
+    pub value: PropertyValue,
+}
+
+impl Property {
+    // [PRIVATE fourcc] SEQUENCE { IA5String fourcc, value }
+    fn parse(element: &Element) -> Result<Property, String> {
+        let fields = element.inner()?.expect(TAG_SEQUENCE)?.children()?;
+        let [name, value] = fields.as_slice() else {
+            return Err("property must have a name and a value".to_string());
+        };
+        let name = name.as_string()?;
+        let value = if value.tag.is(der::TAG_INTEGER) {
+            PropertyValue::Integer(value.as_u64()?)
+        } else if value.tag.is(der::TAG_BOOLEAN) {
+            PropertyValue::Boolean(value.as_bool()?)
+        } else if value.tag.is(TAG_OCTET_STRING) {
+            PropertyValue::Data(value.as_bytes()?.to_vec())
+        } else if value.tag.is(TAG_IA5_STRING) {
+            PropertyValue::String(value.as_string()?)
+        } else {
+            return Err(format!("unsupported value type for property {}", name));
+        };
+        Ok(Property { name, value })
+    }
+
+    fn to_der(&self) -> Vec<u8> {
+        let value = match &self.value {
+            PropertyValue::Integer(value) => der::integer(*value),
+            PropertyValue::Boolean(value) => der::boolean(*value),
+            PropertyValue::Data(value) => der::octet_string(value),
+            PropertyValue::String(value) => der::ia5_string(value),
+        };
+        let inner = der::sequence(&[der::ia5_string(&self.name), value]);
+        der::encode(Tag::private(&self.name), &inner)
+    }
+}
+
+fn find_property<'a>(properties: &'a [Property], name: &str) -> Option<&'a PropertyValue> {
+    properties
+        .iter()
+        .find(|property| property.name == name)
+        .map(|property| &property.value)
+}
+
+// SEQUENCE { IA5String name, SET { [PRIVATE] ... } }
+fn parse_property_set(element: Element, expected: Option<&str>) -> Result<(String, Vec<Property>), String> {
+    let fields = element.expect(TAG_SEQUENCE)?.children()?;
+    let [name, set] = fields.as_slice() else {
+        return Err("malformed property set".to_string());
+    };
+    let name = name.as_string()?;
+    if let Some(expected) = expected {
+        if name != expected {
+            return Err(format!("expected {}, found {}", expected, name));
+        }
+    }
+    let properties = set
+        .expect(TAG_SET)?
+        .children()?
+        .iter()
+        .map(Property::parse)
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok((name, properties))
+}
+
+fn property_set_to_der(name: &str, properties: &[Property]) -> Vec<u8> {
+    let properties: Vec<Vec<u8>> = properties.iter().map(Property::to_der).collect();
+    der::sequence(&[der::ia5_string(name), der::set(&properties)])
+}
+
+fn expect_magic(element: &Element, magic: &str) -> Result<(), String> {
+    let found = element.as_string()?;
+    if found != magic {
+        return Err(format!("expected {} magic, found {}", magic, found));
+    }
+    Ok(())
+}
+
+// MARK: IM4P
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Kbag {
+    // 1 = production, 2 = development
+    pub kind: u64,
+    pub iv: Vec<u8>,
+    pub key: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Compression {
+    pub algorithm: u64,
+    pub uncompressed_size: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Im4p {
+    pub fourcc: String,
+    pub description: String,
+    pub payload: Vec<u8>,
+    pub kbags: Vec<Kbag>,
+    pub compression: Option<Compression>,
+    // anything after the known fields (e.g. PAYP), kept so it survives a rebuild
+    pub extra: Vec<Vec<u8>>,
+}
+
+impl Im4p {
+    pub fn new(fourcc: &str, description: &str, payload: Vec<u8>) -> Im4p {
+        Im4p {
+            fourcc: fourcc.to_string(),
+            description: description.to_string(),
+            payload,
+            kbags: Vec::new(),
+            compression: None,
+            extra: Vec::new(),
+        }
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Im4p, String> {
+        let (element, _) = der::parse(data)?;
+        Im4p::from_element(element)
+    }
+
+    fn from_element(element: Element) -> Result<Im4p, String> {
+        let fields = element.expect(TAG_SEQUENCE)?.children()?;
+        if fields.len() < 4 {
+            return Err("IM4P is missing fields".to_string());
+        }
+        expect_magic(&fields[0], "IM4P")?;
+        let mut im4p = Im4p::new(
+            &fields[1].as_string()?,
+            &fields[2].as_string()?,
+            fields[3].as_bytes()?.to_vec(),
+        );
+        for field in &fields[4..] {
+            if field.tag.is(TAG_OCTET_STRING) && im4p.kbags.is_empty() {
+                im4p.kbags = parse_kbags(field.content)?;
+            } else if field.tag.is(TAG_SEQUENCE) && im4p.compression.is_none() {
+                let info = field.children()?;
+                let [algorithm, size] = info.as_slice() else {
+                    return Err("malformed compression info".to_string());
+                };
+                im4p.compression = Some(Compression {
+                    algorithm: algorithm.as_u64()?,
+                    uncompressed_size: size.as_u64()?,
+                });
+            } else {
+                im4p.extra.push(der::encode(field.tag, field.content));
+            }
+        }
+        Ok(im4p)
+    }
+
+    pub fn to_der(&self) -> Vec<u8> {
+        let mut fields = vec![
+            der::ia5_string("IM4P"),
+            der::ia5_string(&self.fourcc),
+            der::ia5_string(&self.description),
+            der::octet_string(&self.payload),
+        ];
+        if !self.kbags.is_empty() {
+            let kbags: Vec<Vec<u8>> = self
+                .kbags
+                .iter()
+                .map(|kbag| {
+                    der::sequence(&[
+                        der::integer(kbag.kind),
+                        der::octet_string(&kbag.iv),
+                        der::octet_string(&kbag.key),
+                    ])
+                })
+                .collect();
+            fields.push(der::octet_string(&der::sequence(&kbags)));
+        }
+        if let Some(compression) = &self.compression {
+            fields.push(der::sequence(&[
+                der::integer(compression.algorithm),
+                der::integer(compression.uncompressed_size),
+            ]));
+        }
+        fields.extend(self.extra.iter().cloned());
+        der::sequence(&fields)
+    }
+}
+
+// MARK: IM4P compression
+impl Im4p {
+    pub fn codec(&self) -> Codec {
+        compression::detect(&self.payload)
+    }
+
+    // Payload with complzss/LZFSE stripped, e.g. a plain Mach-O kernelcache
+    pub fn decompressed_payload(&self) -> Result<Vec<u8>, String> {
+        let data = compression::decompress(&self.payload)?;
+        if let Some(info) = &self.compression {
+            if info.uncompressed_size != data.len() as u64 {
+                return Err(format!(
+                    "payload decompressed to {} bytes, IM4P says {}",
+                    data.len(),
+                    info.uncompressed_size
+                ));
+            }
+        }
+        Ok(data)
+    }
+
+    // Stores `data` compressed the same way as the current payload, keeping
+    // the IM4P compression info in step.
+    pub fn set_decompressed_payload(&mut self, data: &[u8]) {
+        let codec = self.codec();
+        self.payload = compression::compress(data, codec);
+        self.compression = match codec {
+            Codec::Lzfse => Some(Compression {
+                algorithm: COMPRESSION_LZFSE,
+                uncompressed_size: data.len() as u64,
+            }),
+            _ => None,
+        };
+    }
+}
+
+fn parse_kbags(data: &[u8]) -> Result<Vec<Kbag>, String> {
+    let (element, _) = der::parse(data)?;
+    element
+        .expect(TAG_SEQUENCE)?
+        .children()?
+        .iter()
+        .map(|kbag| {
+            let fields = kbag.expect(TAG_SEQUENCE)?.children()?;
+            let [kind, iv, key] = fields.as_slice() else {
+                return Err("malformed KBAG".to_string());
+            };
+            Ok(Kbag {
+                kind: kind.as_u64()?,
+                iv: iv.as_bytes()?.to_vec(),
+                key: key.as_bytes()?.to_vec(),
+            })
+        })
+        .collect()
+}
+
+// MARK: IM4M
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManifestImage {
+    pub fourcc: String,
+    pub properties: Vec<Property>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Im4m {
+    pub version: u64,
+    // MANP
+    pub properties: Vec<Property>,
+    pub images: Vec<ManifestImage>,
+    pub signature: Vec<u8>,
+    // DER SEQUENCE of certificates, kept as-is
+    pub cert_chain: Vec<u8>,
+}
+
+impl Im4m {
+    // Unsigned manifest for images whose signature checks are patched out
+    // (pwned SecureROM, patched iBSS/iBEC). Only the ECID is filled in.
+    pub fn dummy(ecid: u64) -> Im4m {
+        Im4m {
+            version: 0,
+            properties: vec![Property {
+                name: "ECID".to_string(),
+                value: PropertyValue::Integer(ecid),
+            }],
+            images: Vec::new(),
+            signature: Vec::new(),
+            cert_chain: der::sequence(&[]),
+        }
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Im4m, String> {
+        let (element, _) = der::parse(data)?;
+        Im4m::from_element(element)
+    }
+
+    fn from_element(element: Element) -> Result<Im4m, String> {
+        let fields = element.expect(TAG_SEQUENCE)?.children()?;
+        let [magic, version, body, signature, cert_chain] = fields.as_slice() else {
+            return Err("IM4M must have 5 fields".to_string());
+        };
+        expect_magic(magic, "IM4M")?;
+
+        let manb = body.expect(TAG_SET)?.inner()?;
+        if manb.tag != Tag::private("MANB") {
+            return Err("IM4M body is not MANB".to_string());
+        }
+        let manb_fields = manb.inner()?.expect(TAG_SEQUENCE)?.children()?;
+        let [manb_magic, manb_set] = manb_fields.as_slice() else {
+            return Err("malformed MANB".to_string());
+        };
+        expect_magic(manb_magic, "MANB")?;
+
+        let mut properties = Vec::new();
+        let mut images = Vec::new();
+        for entry in manb_set.expect(TAG_SET)?.children()? {
+            let (fourcc, entry_properties) = parse_property_set(entry.inner()?, None)?;
+            if fourcc == "MANP" {
+                properties = entry_properties;
+            } else {
+                images.push(ManifestImage {
+                    fourcc,
+                    properties: entry_properties,
+                });
+            }
+        }
+
+        Ok(Im4m {
+            version: version.as_u64()?,
+            properties,
+            images,
+            signature: signature.as_bytes()?.to_vec(),
+            cert_chain: der::encode(cert_chain.tag, cert_chain.content),
+        })
+    }
+
+    pub fn to_der(&self) -> Vec<u8> {
+        let mut entries = vec![der::encode(
+            Tag::private("MANP"),
+            &property_set_to_der("MANP", &self.properties),
+        )];
+        for image in &self.images {
+            entries.push(der::encode(
+                Tag::private(&image.fourcc),
+                &property_set_to_der(&image.fourcc, &image.properties),
+            ));
+        }
+        let manb = der::sequence(&[der::ia5_string("MANB"), der::set(&entries)]);
+        der::sequence(&[
+            der::ia5_string("IM4M"),
+            der::integer(self.version),
+            der::set(&[der::encode(Tag::private("MANB"), &manb)]),
+            der::octet_string(&self.signature),
+            self.cert_chain.clone(),
+        ])
+    }
+
+    pub fn property(&self, name: &str) -> Option<&PropertyValue> {
+        find_property(&self.properties, name)
+    }
+
+    pub fn ecid(&self) -> Option<u64> {
+        match self.property("ECID")? {
+            PropertyValue::Integer(ecid) => Some(*ecid),
+            _ => None,
+        }
+    }
+
+    // SHA hash of the AP nonce the manifest was signed for
+    pub fn ap_nonce_hash(&self) -> Option<&[u8]> {
+        match self.property("BNCH")? {
+            PropertyValue::Data(hash) => Some(hash),
+            _ => None,
+        }
+    }
+
+    pub fn sep_nonce_hash(&self) -> Option<&[u8]> {
+        match self.property("snon")? {
+            PropertyValue::Data(hash) => Some(hash),
+            _ => None,
+        }
+    }
+
+    pub fn image(&self, fourcc: &str) -> Option<&ManifestImage> {
+        self.images.iter().find(|image| image.fourcc == fourcc)
+    }
+}
+
+// MARK: IM4R
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Im4r {
+    pub properties: Vec<Property>,
+}
+
+impl Im4r {
+    // IM4R carrying the boot nonce generator, as needed for restores
+    pub fn with_boot_nonce(generator: u64) -> Im4r {
+        Im4r {
+            properties: vec![Property {
+                name: "BNCN".to_string(),
+                value: PropertyValue::Data(generator.to_le_bytes().to_vec()),
+            }],
+        }
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Im4r, String> {
+        let (element, _) = der::parse(data)?;
+        Im4r::from_element(element)
+    }
+
+    fn from_element(element: Element) -> Result<Im4r, String> {
+        let (_, properties) = parse_property_set(element, Some("IM4R"))?;
+        Ok(Im4r { properties })
+    }
+
+    pub fn to_der(&self) -> Vec<u8> {
+        property_set_to_der("IM4R", &self.properties)
+    }
+}
+
+// MARK: IMG4
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Img4 {
+    pub im4p: Im4p,
+    pub im4m: Option<Im4m>,
+    pub im4r: Option<Im4r>,
+}
+
+impl Img4 {
+    // Wraps a payload with a manifest (and optionally restore info) into a
+    // bootable IMG4.
+    pub fn stitch(im4p: Im4p, im4m: Im4m, im4r: Option<Im4r>) -> Img4 {
+        Img4 {
+            im4p,
+            im4m: Some(im4m),
+            im4r,
+        }
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Img4, String> {
+        let (element, _) = der::parse(data)?;
+        let fields = element.expect(TAG_SEQUENCE)?.children()?;
+        if fields.len() < 2 {
+            return Err("IMG4 is missing its IM4P".to_string());
+        }
+        expect_magic(&fields[0], "IMG4")?;
+        let mut img4 = Img4 {
+            im4p: Im4p::from_element(fields[1])?,
+            im4m: None,
+            im4r: None,
+        };
+        for field in &fields[2..] {
+            if field.tag == Tag::context(0) {
+                img4.im4m = Some(Im4m::from_element(field.inner()?)?);
+            } else if field.tag == Tag::context(1) {
+                img4.im4r = Some(Im4r::from_element(field.inner()?)?);
+            } else {
+                return Err(format!("unexpected IMG4 field {:?}", field.tag));
+            }
+        }
+        Ok(img4)
+    }
+
+    pub fn to_der(&self) -> Vec<u8> {
+        let mut fields = vec![der::ia5_string("IMG4"), self.im4p.to_der()];
+        if let Some(im4m) = &self.im4m {
+            fields.push(der::encode(Tag::context(0), &im4m.to_der()));
+        }
+        if let Some(im4r) = &self.im4r {
+            fields.push(der::encode(Tag::context(1), &im4r.to_der()));
+        }
+        der::sequence(&fields)
+    }
+}
+
+// The IA5String magic of the outermost SEQUENCE, e.g. "IMG4" or "IM4P"
+pub fn magic(data: &[u8]) -> Option<String> {
+    let (element, _) = der::parse(data).ok()?;
+    let (first, _) = der::parse(element.expect(TAG_SEQUENCE).ok()?.content).ok()?;
+    first.as_string().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    macro_rules! seed {
+        ($name:literal) => {
+            include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/fuzz/corpus/img4/", $name)).as_slice()
+        };
+    }
+
+    #[test]
+    fn im4p_round_trips() {
+        for seed in [
+            seed!("seed-im4p-raw"),
+            seed!("seed-im4p-kbags"),
+            seed!("seed-im4p-lzss"),
+            seed!("seed-im4p-lzfse"),
+        ] {
+            let im4p = Im4p::parse(seed).unwrap();
+            assert_eq!(im4p.to_der(), seed, "{} {}", im4p.fourcc, im4p.description);
+        }
+    }
+
+    #[test]
+    fn im4p_fields() {
+        let im4p = Im4p::parse(seed!("seed-im4p-kbags")).unwrap();
+        assert_eq!(im4p.fourcc, "ibss");
+        assert_eq!(im4p.kbags.len(), 2);
+        assert_eq!(im4p.kbags.iter().map(|kbag| kbag.kind).collect::<Vec<_>>(), [1, 2]);
+        assert!(im4p.kbags.iter().all(|kbag| kbag.iv.len() == 16 && kbag.key.len() == 32));
+
+        let lzss = Im4p::parse(seed!("seed-im4p-lzss")).unwrap();
+        assert_eq!(lzss.codec(), Codec::Lzss);
+        let lzfse = Im4p::parse(seed!("seed-im4p-lzfse")).unwrap();
+        assert_eq!(lzfse.codec(), Codec::Lzfse);
+        let compression = lzfse.compression.unwrap();
+        assert_eq!(compression.algorithm, COMPRESSION_LZFSE);
+        assert_eq!(lzfse.decompressed_payload().unwrap().len() as u64, compression.uncompressed_size);
+    }
+
+    #[test]
+    fn im4m_round_trips() {
+        let seed = seed!("seed-im4m");
+        let im4m = Im4m::parse(seed).unwrap();
+        assert_eq!(im4m.to_der(), seed);
+        assert!(im4m.ecid().is_some());
+        assert!(im4m.ap_nonce_hash().is_some());
+    }
+
+    #[test]
+    fn im4r_round_trips() {
+        let seed = seed!("seed-im4r");
+        assert_eq!(Im4r::parse(seed).unwrap().to_der(), seed);
+    }
+
+    #[test]
+    fn img4_round_trips() {
+        for seed in [seed!("seed-img4-dummy"), seed!("seed-img4-im4r")] {
+            let img4 = Img4::parse(seed).unwrap();
+            assert!(img4.im4m.is_some());
+            assert_eq!(img4.to_der(), seed);
+        }
+        let with_im4r = Img4::parse(seed!("seed-img4-im4r")).unwrap();
+        assert!(with_im4r.im4r.is_some());
+    }
+
+    #[test]
+    fn dummy_im4m() {
+        let ecid = 0x001A2B3C4D5E6F70;
+        let im4m = Im4m::dummy(ecid);
+        assert_eq!(im4m.ecid(), Some(ecid));
+        assert_eq!(im4m.ap_nonce_hash(), None);
+        assert!(im4m.images.is_empty() && im4m.signature.is_empty());
+
+        let der = im4m.to_der();
+        assert_eq!(magic(&der).as_deref(), Some("IM4M"));
+        assert_eq!(Im4m::parse(&der).unwrap(), im4m);
+
+        let im4p = Im4p::new("ibss", "iBoot-2696.0.0.1.33", vec![0xAA; 0x40]);
+        let img4 = Img4::stitch(im4p, im4m.clone(), Some(Im4r::with_boot_nonce(0x1111111111111111)));
+        let parsed = Img4::parse(&img4.to_der()).unwrap();
+        assert_eq!(parsed, img4);
+        assert_eq!(parsed.im4m.and_then(|im4m| im4m.ecid()), Some(ecid));
+    }
+}
@@ -0,0 +1,347 @@
+use crate::plist::{self, Value};
+use crate::serial::DeviceSerial;
+use std::fs::File;
+use std::io::{Read, Seek};
+use std::path::Path;
+
+pub const BUILD_MANIFEST: &str = "BuildManifest.plist";
+
+// What we need to boot a ramdisk, in upload order. Trust caches only exist
+// on iOS 12 and later.
+pub const BOOT_COMPONENTS: [&str; 6] = [
+    "iBSS",
+    "iBEC",
+    "RestoreDeviceTree",
+    "RestoreKernelCache",
+    "RestoreRamDisk",
+    "RestoreTrustCache",
+];
+
+// Which device we're looking for. Any field left as None matches anything.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeviceIdentity {
+    pub product_type: Option<String>,
+    pub board_config: Option<String>,
+    pub chip_id: Option<u16>,
+    pub board_id: Option<u8>,
+}
+
+impl DeviceIdentity {
+    pub fn from_serial(serial: &DeviceSerial) -> DeviceIdentity {
+        DeviceIdentity {
+            chip_id: Some(serial.cpid),
+            board_id: Some(serial.bdid),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestoreBehavior {
+    Erase,
+    Update,
+}
+
+impl RestoreBehavior {
+    fn name(self) -> &'static str {
+        match self {
+            RestoreBehavior::Erase => "Erase",
+            RestoreBehavior::Update => "Update",
+        }
+    }
+}
+
+// Manifest values like ApChipID are hex strings ("0x8010")
+fn hex_value(value: Option<&Value>) -> Option<u64> {
+    match value? {
+        Value::String(text) => u64::from_str_radix(text.trim_start_matches("0x"), 16).ok(),
+        Value::Integer(value) => Some(*value as u64),
+        _ => None,
+    }
+}
+
+// One entry of BuildIdentities
+#[derive(Debug, Clone, PartialEq)]
+pub struct BuildIdentity(Value);
+
+impl BuildIdentity {
+    fn info(&self, key: &str) -> Option<&str> {
+        self.0.get("Info")?.get(key)?.as_str()
+    }
+
+    pub fn board_config(&self) -> Option<&str> {
+        self.info("DeviceClass")
+    }
+
+    pub fn variant(&self) -> Option<&str> {
+        self.info("Variant")
+    }
+
+    pub fn restore_behavior(&self) -> Option<&str> {
+        self.info("RestoreBehavior")
+    }
+
+    pub fn chip_id(&self) -> Option<u16> {
+        hex_value(self.0.get("ApChipID")).and_then(|value| u16::try_from(value).ok())
+    }
+
+    pub fn board_id(&self) -> Option<u8> {
+        hex_value(self.0.get("ApBoardID")).and_then(|value| u8::try_from(value).ok())
+    }
+
+    // Path of a component inside the IPSW, e.g. "Firmware/dfu/iBSS.d10.RELEASE.im4p"
+    pub fn component_path(&self, name: &str) -> Option<&str> {
+        self.0.get("Manifest")?.get(name)?.get("Info")?.get("Path")?.as_str()
+    }
+
+    fn matches(&self, device: &DeviceIdentity) -> bool {
+        if let Some(board_config) = &device.board_config {
+            if !self.board_config().is_some_and(|own| own.eq_ignore_ascii_case(board_config)) {
+                return false;
+            }
+        }
+        if device.chip_id.is_some() && self.chip_id() != device.chip_id {
+            return false;
+        }
+        if device.board_id.is_some() && self.board_id() != device.board_id {
+            return false;
+        }
+        true
+    }
+}
+
+pub struct Ipsw<R> {
+    archive: zip::ZipArchive<R>,
+    manifest: Value,
+}
+
+impl Ipsw<File> {
+    pub fn open(path: &Path) -> Result<Ipsw<File>, String> {
+        let file = File::open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Ipsw::new(file)
+    }
+}
+
+impl<R: Read + Seek> Ipsw<R> {
+    pub fn new(reader: R) -> Result<Ipsw<R>, String> {
+        let mut archive = zip::ZipArchive::new(reader).map_err(|e| format!("not an IPSW: {}", e))?;
+        let manifest = read_entry(&mut archive, BUILD_MANIFEST)?;
+        let manifest = plist::parse(&manifest).map_err(|e| format!("{}: {}", BUILD_MANIFEST, e))?;
+        Ok(Ipsw { archive, manifest })
+    }
+
+    pub fn product_version(&self) -> Option<&str> {
+        self.manifest.get("ProductVersion")?.as_str()
+    }
+
+    pub fn build_version(&self) -> Option<&str> {
+        self.manifest.get("ProductBuildVersion")?.as_str()
+    }
+
+    pub fn supported_product_types(&self) -> Vec<&str> {
+        self.manifest
+            .get("SupportedProductTypes")
+            .and_then(|types| types.as_array())
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|product_type| product_type.as_str())
+            .collect()
+    }
+
+    pub fn build_identities(&self) -> Vec<BuildIdentity> {
+        self.manifest
+            .get("BuildIdentities")
+            .and_then(|identities| identities.as_array())
+            .unwrap_or_default()
+            .iter()
+            .map(|identity| BuildIdentity(identity.clone()))
+            .collect()
+    }
+
+    // Picks the identity for this device with the wanted restore behavior.
+    // Older manifests don't set RestoreBehavior, so fall back to any match.
+    pub fn select_identity(
+        &self,
+        device: &DeviceIdentity,
+        behavior: RestoreBehavior,
+    ) -> Result<BuildIdentity, String> {
+        if let Some(product_type) = &device.product_type {
+            let supported = self.supported_product_types();
+            if !supported.iter().any(|own| own.eq_ignore_ascii_case(product_type)) {
+                return Err(format!(
+                    "IPSW is for {}, not {}",
+                    supported.join(", "),
+                    product_type
+                ));
+            }
+        }
+        let candidates: Vec<BuildIdentity> = self
+            .build_identities()
+            .into_iter()
+            .filter(|identity| identity.matches(device))
+            .collect();
+        candidates
+            .iter()
+            .find(|identity| identity.restore_behavior() == Some(behavior.name()))
+            .or_else(|| candidates.first())
+            .cloned()
+            .ok_or("no build identity in the IPSW matches the device".to_string())
+    }
+
+    pub fn read(&mut self, path: &str) -> Result<Vec<u8>, String> {
+        read_entry(&mut self.archive, path)
+    }
+
+    pub fn read_component(&mut self, identity: &BuildIdentity, name: &str) -> Result<Vec<u8>, String> {
+        let path = identity
+            .component_path(name)
+            .ok_or(format!("build identity has no {}", name))?;
+        self.read(path)
+    }
+}
+
+fn read_entry<R: Read + Seek>(archive: &mut zip::ZipArchive<R>, path: &str) -> Result<Vec<u8>, String> {
+    let mut entry = archive.by_name(path).map_err(|e| format!("{}: {}", path, e))?;
+    let mut data = Vec::with_capacity(entry.size().min(1 << 30) as usize);
+    entry.read_to_end(&mut data).map_err(|e| format!("{}: {}", path, e))?;
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Write};
+    use zip::write::{SimpleFileOptions, ZipWriter};
+
+    const MANIFEST: &str = include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/vectors/ipsw/BuildManifest.plist"));
+    const IBSS: &str = "Firmware/dfu/iBSS.d10.RELEASE.im4p";
+
+    // An IPSW with `files` in it
+    fn archive(files: &[(&str, &[u8])]) -> Cursor<Vec<u8>> {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, data) in files {
+            zip.start_file(*name, SimpleFileOptions::default()).unwrap();
+            zip.write_all(data).unwrap();
+        }
+        Cursor::new(zip.finish().unwrap().into_inner())
+    }
+
+    fn ipsw() -> Ipsw<Cursor<Vec<u8>>> {
+        Ipsw::new(archive(&[(BUILD_MANIFEST, MANIFEST.as_bytes()), (IBSS, b"ibss")])).unwrap()
+    }
+
+    fn device(chip_id: u16, board_id: u8) -> DeviceIdentity {
+        DeviceIdentity {
+            chip_id: Some(chip_id),
+            board_id: Some(board_id),
+            ..DeviceIdentity::default()
+        }
+    }
+
+    #[test]
+    fn reads_the_manifest() {
+        let ipsw = ipsw();
+        assert_eq!((ipsw.product_version(), ipsw.build_version()), (Some("14.3"), Some("18C66")));
+        assert_eq!(ipsw.supported_product_types(), ["iPhone9,1", "iPhone9,3"]);
+        let identities = ipsw.build_identities();
+        assert_eq!(
+            identities
+                .iter()
+                .map(|identity| (identity.chip_id(), identity.board_id(), identity.board_config(), identity.restore_behavior()))
+                .collect::<Vec<_>>(),
+            [
+                (Some(0x8010), Some(0x08), Some("d10ap"), Some("Update")),
+                (Some(0x8010), Some(0x08), Some("d10ap"), Some("Erase")),
+                (Some(0x8010), Some(0x0C), Some("d101ap"), None),
+            ]
+        );
+        assert_eq!(identities[1].variant(), Some("Customer Erase Install (IPSW)"));
+    }
+
+    #[test]
+    fn selects_by_chip_and_board() {
+        let ipsw = ipsw();
+        for (behavior, variant) in [
+            (RestoreBehavior::Erase, "Customer Erase Install (IPSW)"),
+            (RestoreBehavior::Update, "Customer Upgrade Install (IPSW)"),
+        ] {
+            let identity = ipsw.select_identity(&device(0x8010, 0x08), behavior).unwrap();
+            assert_eq!((identity.board_config(), identity.variant()), (Some("d10ap"), Some(variant)));
+        }
+        // what DFU mode tells us
+        let serial = DeviceSerial::parse("CPID:8010 CPRV:11 CPFM:03 SCEP:01 BDID:0C ECID:001A2B3C4D5E6F70 IBFL:3C").unwrap();
+        let identity = ipsw.select_identity(&DeviceIdentity::from_serial(&serial), RestoreBehavior::Erase).unwrap();
+        assert_eq!(identity.board_config(), Some("d101ap"));
+        // by board config and product type instead, any case
+        let by_name = DeviceIdentity {
+            product_type: Some("iphone9,1".to_string()),
+            board_config: Some("D10AP".to_string()),
+            ..DeviceIdentity::default()
+        };
+        let identity = ipsw.select_identity(&by_name, RestoreBehavior::Update).unwrap();
+        assert_eq!(identity.restore_behavior(), Some("Update"));
+    }
+
+    #[test]
+    fn falls_back_without_a_restore_behavior() {
+        let ipsw = ipsw();
+        for behavior in [RestoreBehavior::Erase, RestoreBehavior::Update] {
+            let identity = ipsw.select_identity(&device(0x8010, 0x0C), behavior).unwrap();
+            assert_eq!((identity.board_config(), identity.restore_behavior()), (Some("d101ap"), None));
+        }
+        // nothing set matches anything, the wanted behavior first
+        let identity = ipsw.select_identity(&DeviceIdentity::default(), RestoreBehavior::Erase).unwrap();
+        assert_eq!(identity.restore_behavior(), Some("Erase"));
+    }
+
+    #[test]
+    fn rejects_other_devices() {
+        let ipsw = ipsw();
+        let iphone_x = DeviceIdentity {
+            product_type: Some("iPhone10,3".to_string()),
+            ..device(0x8010, 0x08)
+        };
+        assert_eq!(
+            ipsw.select_identity(&iphone_x, RestoreBehavior::Erase),
+            Err("IPSW is for iPhone9,1, iPhone9,3, not iPhone10,3".to_string())
+        );
+        let unmatched = [
+            device(0x8015, 0x08),
+            device(0x8010, 0x0A),
+            DeviceIdentity {
+                board_config: Some("d11ap".to_string()),
+                ..DeviceIdentity::default()
+            },
+        ];
+        for device in unmatched {
+            assert_eq!(
+                ipsw.select_identity(&device, RestoreBehavior::Erase),
+                Err("no build identity in the IPSW matches the device".to_string()),
+                "{:?}",
+                device
+            );
+        }
+    }
+
+    #[test]
+    fn reads_components() {
+        let mut ipsw = ipsw();
+        let identity = ipsw.select_identity(&device(0x8010, 0x08), RestoreBehavior::Erase).unwrap();
+        assert_eq!(identity.component_path("iBSS"), Some(IBSS));
+        assert_eq!(ipsw.read_component(&identity, "iBSS"), Ok(b"ibss".to_vec()));
+        assert_eq!(ipsw.read_component(&identity, "iBEC"), Err("build identity has no iBEC".to_string()));
+        // in the manifest, not in the archive
+        assert!(ipsw.read_component(&identity, "RestoreRamDisk").unwrap_err().starts_with("038-44337-083.dmg: "));
+    }
+
+    #[test]
+    fn rejects_broken_archives() {
+        let error = |reader| Ipsw::new(reader).err().unwrap();
+        assert!(error(Cursor::new(b"PK not really".to_vec())).starts_with("not an IPSW: "));
+        assert!(error(archive(&[(IBSS, b"ibss")])).starts_with("BuildManifest.plist: "));
+        assert_eq!(
+            error(archive(&[(BUILD_MANIFEST, b"<plist><dict>")])),
+            format!("{}: {}", BUILD_MANIFEST, plist::parse(b"<plist><dict>").unwrap_err())
+        );
+    }
+}
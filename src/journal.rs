@@ -0,0 +1,182 @@
+// Per-device record of how far the flow got, so a run that dies halfway (host
+// crash, cable pulled) can pick up where it left off instead of starting over.
+// One file per ECID, one line per completed stage:
+//
+//   <stage> <unix time> [<name>=<value>]...
+//
+// where the values are hashes of the payload/images the stage used, or what
+// the device was running when it left normal mode.
+
+use crate::hex;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    LeftNormal,
+    Pwned,
+    IbssSent,
+    IbecSent,
+    PongoBooted,
+    Booted,
+}
+
+const STAGES: [(Stage, &str); 6] = [
+    (Stage::LeftNormal, "left-normal"),
+    (Stage::Pwned, "pwned"),
+    (Stage::IbssSent, "ibss-sent"),
+    (Stage::IbecSent, "ibec-sent"),
+    (Stage::PongoBooted, "pongo-booted"),
+    (Stage::Booted, "booted"),
+];
+
+impl Stage {
+    pub fn name(self) -> &'static str {
+        STAGES.iter().find(|(stage, _)| *stage == self).unwrap().1
+    }
+
+    pub fn from_name(name: &str) -> Option<Stage> {
+        STAGES.iter().find(|(_, own)| *own == name).map(|(stage, _)| *stage)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub stage: Stage,
+    pub time: u64,
+    pub details: Vec<(String, String)>,
+}
+
+impl Entry {
+    fn parse(line: &str) -> Option<Entry> {
+        let mut fields = line.split_whitespace();
+        let stage = Stage::from_name(fields.next()?)?;
+        let time = fields.next()?.parse().ok()?;
+        let details = fields
+            .map(|field| field.split_once('=').map(|(name, value)| (name.to_string(), value.to_string())))
+            .collect::<Option<Vec<_>>>()?;
+        Some(Entry { stage, time, details })
+    }
+
+    fn to_line(&self) -> String {
+        let mut line = format!("{} {}", self.stage.name(), self.time);
+        for (name, value) in &self.details {
+            line.push_str(&format!(" {}={}", name, value));
+        }
+        line
+    }
+
+    pub fn age(&self) -> u64 {
+        now().saturating_sub(self.time)
+    }
+}
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    hex::encode(&Sha256::digest(data))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
+// ~/.ra1n-oxide/journal, or the working directory without a HOME
+pub fn default_dir() -> PathBuf {
+    let home = std::env::var_os("HOME").map(PathBuf::from).unwrap_or_default();
+    home.join(".ra1n-oxide").join("journal")
+}
+
+pub struct Journal {
+    path: PathBuf,
+    entries: Vec<Entry>,
+}
+
+impl Journal {
+    // Loads the journal for `ecid`, skipping lines it can't make sense of.
+    pub fn open(dir: &Path, ecid: u64) -> Result<Journal, String> {
+        let path = dir.join(format!("{:016X}.journal", ecid));
+        let entries = match fs::read_to_string(&path) {
+            Ok(text) => text.lines().filter_map(Entry::parse).collect(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(format!("{}: {}", path.display(), e)),
+        };
+        Ok(Journal { path, entries })
+    }
+
+    pub fn last(&self) -> Option<&Entry> {
+        self.entries.last()
+    }
+
+    // Appends and syncs straight away, the point is to survive a crash.
+    // Whitespace in values becomes '_' so the line splits back the same way.
+    pub fn record(&mut self, stage: Stage, details: Vec<(String, String)>) -> Result<(), String> {
+        let details = details
+            .into_iter()
+            .map(|(name, value)| (name, value.split_whitespace().collect::<Vec<_>>().join("_")))
+            .collect();
+        let entry = Entry {
+            stage,
+            time: now(),
+            details,
+        };
+        let error = |e: std::io::Error| format!("{}: {}", self.path.display(), e);
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir).map_err(error)?;
+        }
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(error)?;
+        writeln!(file, "{}", entry.to_line()).map_err(error)?;
+        file.sync_all().map_err(error)?;
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    // Starts a new run, dropping what the last one did
+    pub fn clear(&mut self) -> Result<(), String> {
+        self.entries.clear();
+        match fs::remove_file(&self.path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(format!("{}: {}", self.path.display(), e)),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn survives_a_reopen() {
+        let dir = std::env::temp_dir().join(format!("ra1n-oxide-journal-{}", std::process::id()));
+        let ecid = 0x001A2B3C4D5E6F;
+        let mut journal = Journal::open(&dir, ecid).unwrap();
+        assert!(journal.last().is_none());
+        journal.record(Stage::Pwned, Vec::new()).unwrap();
+        journal
+            .record(Stage::IbssSent, vec![("ibss".to_string(), "a b\tc".to_string())])
+            .unwrap();
+        // a line from something else gets skipped, not treated as the last stage
+        let path = dir.join("00001A2B3C4D5E6F.journal");
+        fs::OpenOptions::new().append(true).open(&path).unwrap().write_all(b"garbage\n").unwrap();
+
+        let mut reopened = Journal::open(&dir, ecid).unwrap();
+        let last = reopened.last().unwrap();
+        assert_eq!(last.stage, Stage::IbssSent);
+        assert_eq!(last.details, [("ibss".to_string(), "a_b_c".to_string())]);
+        assert!(last.age() < 60);
+        assert_eq!(reopened.entries.len(), 2);
+
+        reopened.clear().unwrap();
+        assert!(!path.exists());
+        assert!(Journal::open(&dir, ecid).unwrap().last().is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,102 @@
+use crate::pwned_dfu::PwnedDfu;
+use crate::soc::{Soc, SOCS};
+use crate::transport::Transport;
+use crate::{hex, DFU_MAX_TRANSFER_SIZE};
+
+// aes_crypto_cmd arguments, as gaster passes them
+pub const AES_CMD_DEC: u64 = 0x1;
+pub const AES_CMD_CBC: u64 = 0x10;
+pub const AES_KEY_SZ_256: u64 = 0x20000000;
+pub const AES_KEY_TYPE_GID0: u64 = 0x200;
+
+// IV (16) + AES-256 key (32)
+pub const KBAG_LEN: usize = 48;
+
+pub fn decrypt_kbag<T: Transport>(pwned: &mut PwnedDfu<T>, kbag: &[u8]) -> Result<Vec<u8>, String> {
+    if kbag.len() != KBAG_LEN {
+        return Err(format!("KBAG must be {} bytes, got {}", KBAG_LEN, kbag.len()));
+    }
+    let soc = pwned.soc();
+    let aes_crypto_cmd = supported(soc)?;
+    // past anything a command can DNLOAD into the load area
+    let buffer = soc.insecure_memory_base + u64::from(DFU_MAX_TRANSFER_SIZE);
+
+    pwned.write(buffer, kbag)?;
+    let ret = pwned.call(
+        aes_crypto_cmd,
+        &[
+            AES_CMD_DEC | AES_CMD_CBC,
+            buffer,
+            buffer,
+            KBAG_LEN as u64,
+            AES_KEY_SZ_256 | AES_KEY_TYPE_GID0,
+            0,
+            0,
+        ],
+    )?;
+    if ret != 0 {
+        return Err(format!("aes_crypto_cmd returned 0x{:x}", ret));
+    }
+    pwned.read(buffer, KBAG_LEN)
+}
+
+// The SoCs we know the ROM's aes_crypto_cmd for. gaster has it for more of
+// the checkm8 targets, but none of those are in soc.rs yet.
+pub fn supported_socs() -> Vec<&'static str> {
+    SOCS.iter().filter(|soc| soc.aes_crypto_cmd.is_some()).map(|soc| soc.name).collect()
+}
+
+// Where the ROM's aes_crypto_cmd is, for the SoCs we know it for
+pub fn supported(soc: &Soc) -> Result<u64, String> {
+    soc.aes_crypto_cmd.ok_or_else(|| {
+        format!(
+            "decrypting KBAGs isn't supported on {} (CPID 0x{:04x}): the ROM's aes_crypto_cmd is only known on {}",
+            soc.name,
+            soc.cpid,
+            supported_socs().join(", ")
+        )
+    })
+}
+
+pub fn parse_kbags(kbags: &[String]) -> Result<Vec<Vec<u8>>, String> {
+    kbags
+        .iter()
+        .map(|kbag| match hex::decode(kbag) {
+            Some(bytes) if bytes.len() == KBAG_LEN => Ok(bytes),
+            Some(bytes) => Err(format!("KBAG must be {} bytes, got {}: {}", KBAG_LEN, bytes.len(), kbag)),
+            None => Err(format!("invalid hex KBAG: {}", kbag)),
+        })
+        .collect()
+}
+
+// Decrypts each KBAG in turn, returning IV+key as hex in the same order.
+pub fn decrypt_kbags<T: Transport>(pwned: &mut PwnedDfu<T>, kbags: &[Vec<u8>]) -> Result<Vec<String>, String> {
+    supported(pwned.soc())?;
+    let mut out = Vec::with_capacity(kbags.len());
+    for kbag in kbags {
+        out.push(hex::encode(&decrypt_kbag(pwned, kbag)?));
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::soc::soc_for_cpid;
+
+    #[test]
+    fn only_socs_with_aes_crypto_cmd_are_supported() {
+        assert_eq!(supported(soc_for_cpid(0x8010).unwrap()), Ok(0x10000DC98));
+        let error = supported(soc_for_cpid(0x8015).unwrap()).unwrap_err();
+        assert!(error.contains("t8015") && error.contains("only known on t8010"), "{}", error);
+        assert_eq!(supported_socs(), ["t8010"]);
+    }
+
+    #[test]
+    fn kbags_are_checked_before_the_device() {
+        let kbag = "00".repeat(KBAG_LEN);
+        assert_eq!(parse_kbags(&[kbag]).unwrap(), [vec![0u8; KBAG_LEN]]);
+        assert!(parse_kbags(&["00".repeat(KBAG_LEN - 1)]).is_err());
+        assert!(parse_kbags(&["zz".to_string()]).is_err());
+    }
+}
@@ -0,0 +1,40 @@
+// The USB side and the file formats, everything the parsers of device and
+// file input need, as a library so the fuzz targets under fuzz/ and the tests
+// under tests/ can link it. The commands and the flow stay in main.rs.
+
+pub mod compression;
+pub mod der;
+pub mod dfu;
+pub mod img3;
+pub mod img4;
+pub mod lzfse;
+pub mod lzss;
+pub mod plist;
+pub mod pwned_dfu;
+pub mod recovery;
+pub mod serial;
+pub mod soc;
+pub mod timing;
+pub mod transport;
+#[cfg(feature = "usbfs")]
+pub mod usbfs;
+pub mod usbip;
+
+// MARK: constants
+pub const DFU_DNLOAD: u8 = 1;
+pub const DFU_UPLOAD: u8 = 2;
+pub const DFU_GETSTATUS: u8 = 3;
+pub const DFU_CLRSTATUS: u8 = 4;
+pub const DFU_GETSTATE: u8 = 5;
+pub const DFU_ABORT: u8 = 6;
+pub const DFU_FILE_SUFFIX_LENGTH: usize = 16;
+pub const EP0_MAX_PACKET_SIZE: u16 = 0x40;
+pub const DFU_MAX_TRANSFER_SIZE: u16 = 0x800;
+pub const DFU_STATUS_OK: u8 = 0;
+pub const DFU_STATE_DNLOAD_IDLE: u8 = 5;
+pub const DFU_STATE_MANIFEST_SYNC: u8 = 6;
+pub const DFU_STATE_MANIFEST: u8 = 7;
+pub const DFU_STATE_MANIFEST_WAIT_RESET: u8 = 8;
+
+// USB constants
+pub const USB_TIMEOUT: u32 = 10;
@@ -0,0 +1,306 @@
+// What lockdownd tells us about a normal mode device before we reboot it out
+// of the OS, where we can still reach it without button presses. Goes through
+// libimobiledevice, or our own usbmuxd client when built with that instead.
+
+use crate::serial::DeviceSerial;
+use crate::soc::{self, Soc};
+
+#[cfg(any(feature = "libimobiledevice", feature = "usbmuxd"))]
+pub const LOCKDOWN_LABEL: &str = "ra1n-oxide";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceInfo {
+    pub udid: String,
+    pub product_type: String,
+    pub hardware_model: String,
+    pub product_version: String,
+    pub build_version: String,
+    pub ecid: u64,
+    pub cpid: u16,
+    pub bdid: u8,
+}
+
+// lockdownd's error names, with the ones a user can fix spelled out
+#[cfg(any(feature = "libimobiledevice", feature = "usbmuxd"))]
+pub fn describe_error(error: &str) -> String {
+    match error {
+        "PasswordProtected" => "the device is locked, unlock it and try again".to_string(),
+        "PairingDialogResponsePending" => "tap Trust on the device and try again".to_string(),
+        "UserDeniedPairing" => "pairing was denied on the device, replug it and tap Trust".to_string(),
+        "InvalidHostID" | "InvalidPairRecord" | "MissingHostID" | "MissingPairRecord" | "PairingFailed" => {
+            "the device isn't paired with this computer, replug it and tap Trust".to_string()
+        }
+        "SSLError" => "TLS with lockdownd failed, the pair record may be stale".to_string(),
+        "MuxError" => "couldn't talk to usbmuxd, is it running?".to_string(),
+        error => format!("lockdownd error {}", error),
+    }
+}
+
+// DeviceInfo from lockdownd's values, however the backend fetches them
+#[cfg(any(feature = "libimobiledevice", feature = "usbmuxd"))]
+fn device_info(
+    udid: &str,
+    string: impl Fn(&str) -> Result<String, String>,
+    uint: impl Fn(&str) -> Result<u64, String>,
+) -> Result<DeviceInfo, String> {
+    let cpid = uint("ChipID")?;
+    let bdid = uint("BoardId")?;
+    Ok(DeviceInfo {
+        udid: udid.to_string(),
+        product_type: string("ProductType")?,
+        hardware_model: string("HardwareModel")?,
+        product_version: string("ProductVersion")?,
+        build_version: string("BuildVersion")?,
+        ecid: uint("UniqueChipID")?,
+        cpid: u16::try_from(cpid).map_err(|_| format!("bad ChipID 0x{:x}", cpid))?,
+        bdid: u8::try_from(bdid).map_err(|_| format!("bad BoardId 0x{:x}", bdid))?,
+    })
+}
+
+// MARK: libimobiledevice
+#[cfg(feature = "libimobiledevice")]
+mod backend {
+    use super::{device_info, DeviceInfo, LOCKDOWN_LABEL};
+    use rusty_libimobiledevice::error::LockdowndError;
+    use rusty_libimobiledevice::idevice::{self, Device};
+    use rusty_libimobiledevice::services::lockdownd::LockdowndClient;
+
+    fn describe_error(error: LockdowndError) -> String {
+        let name = match error {
+            LockdowndError::PasswordProtected => "PasswordProtected",
+            LockdowndError::PairingDialogueRepsonsePending => "PairingDialogResponsePending",
+            LockdowndError::UserDeniedPairing => "UserDeniedPairing",
+            LockdowndError::MissingPairRecord => "MissingPairRecord",
+            LockdowndError::InvalidPairRecord => "InvalidPairRecord",
+            LockdowndError::InvalidHostId => "InvalidHostID",
+            LockdowndError::MissingHostId => "MissingHostID",
+            LockdowndError::PairingFailed => "PairingFailed",
+            LockdowndError::SslError => "SSLError",
+            LockdowndError::MuxError => "MuxError",
+            error => return format!("lockdownd error {:?}", error),
+        };
+        super::describe_error(name)
+    }
+
+    fn device(udid: &str) -> Result<Device, String> {
+        idevice::get_devices()
+            .map_err(|e| format!("{:?}", e))?
+            .into_iter()
+            .find(|device| device.get_udid() == udid)
+            .ok_or(format!("{} is gone", udid))
+    }
+
+    pub fn devices() -> Result<Vec<String>, String> {
+        let devices = idevice::get_devices().map_err(|e| format!("{:?}", e))?;
+        Ok(devices.iter().map(Device::get_udid).collect())
+    }
+
+    pub fn query(udid: &str) -> Result<DeviceInfo, String> {
+        let device = device(udid)?;
+        let client = LockdowndClient::new(&device, LOCKDOWN_LABEL).map_err(describe_error)?;
+        let value = |key: &str| {
+            client
+                .get_value(key, "")
+                .map_err(|e| format!("{}: {}", key, describe_error(e)))
+        };
+        device_info(
+            udid,
+            |key| value(key)?.get_string_val().map_err(|_| format!("{} is not a string", key)),
+            |key| value(key)?.get_uint_val().map_err(|_| format!("{} is not an integer", key)),
+        )
+    }
+
+    pub fn enter_recovery(udid: &str) -> Result<(), String> {
+        let device = device(udid)?;
+        let client = LockdowndClient::new(&device, LOCKDOWN_LABEL).map_err(describe_error)?;
+        client.enter_recovery().map_err(describe_error)
+    }
+}
+
+// MARK: native
+#[cfg(all(feature = "usbmuxd", not(feature = "libimobiledevice")))]
+mod backend {
+    use super::{device_info, DeviceInfo, LOCKDOWN_LABEL};
+    use crate::lockdownd::{LockdownClient, LOCKDOWN_PORT};
+    use crate::plist::Value;
+    use crate::usbmux::{Socket, UsbmuxClient};
+
+    // A session with the device's lockdownd, as the paired host
+    fn connect(udid: &str) -> Result<LockdownClient<Socket>, String> {
+        let mut mux = UsbmuxClient::new(Socket::connect_default()?);
+        let device = mux
+            .list_devices()?
+            .into_iter()
+            .find(|device| device.udid == udid)
+            .ok_or(format!("{} is gone", udid))?;
+        let record = mux
+            .read_pair_record(udid)
+            .map_err(|e| format!("{}, pair it with idevicepair or Finder first", e))?;
+        let mut client = LockdownClient::new(mux.connect(device.device_id, LOCKDOWN_PORT)?, LOCKDOWN_LABEL);
+        client.query_type()?;
+        client.start_session(&record)?;
+        Ok(client)
+    }
+
+    // Only USB: a device on the network can't be put in DFU anyway
+    pub fn devices() -> Result<Vec<String>, String> {
+        let mut mux = UsbmuxClient::new(Socket::connect_default()?);
+        let mut udids: Vec<String> = mux
+            .list_devices()?
+            .into_iter()
+            .filter(|device| device.connection_type == "USB")
+            .map(|device| device.udid)
+            .collect();
+        udids.dedup();
+        Ok(udids)
+    }
+
+    pub fn query(udid: &str) -> Result<DeviceInfo, String> {
+        let mut client = connect(udid)?;
+        // the whole global domain in one go
+        let values = client.get_value(None, None)?;
+        let info = device_info(
+            udid,
+            |key| match values.get(key) {
+                Some(Value::String(value)) => Ok(value.clone()),
+                Some(_) => Err(format!("{} is not a string", key)),
+                None => Err(format!("{}: no value", key)),
+            },
+            |key| match values.get(key) {
+                Some(Value::Integer(value)) => u64::try_from(*value).map_err(|_| format!("{} is negative", key)),
+                Some(_) => Err(format!("{} is not an integer", key)),
+                None => Err(format!("{}: no value", key)),
+            },
+        )?;
+        client.stop_session()?;
+        Ok(info)
+    }
+
+    pub fn enter_recovery(udid: &str) -> Result<(), String> {
+        connect(udid)?.enter_recovery()
+    }
+}
+
+// MARK: neither
+#[cfg(not(any(feature = "libimobiledevice", feature = "usbmuxd")))]
+mod backend {
+    use super::DeviceInfo;
+
+    const UNSUPPORTED: &str = "built without normal mode support, rebuild with the libimobiledevice or usbmuxd feature";
+
+    pub fn devices() -> Result<Vec<String>, String> {
+        Err(UNSUPPORTED.to_string())
+    }
+
+    pub fn query(_udid: &str) -> Result<DeviceInfo, String> {
+        Err(UNSUPPORTED.to_string())
+    }
+
+    pub fn enter_recovery(_udid: &str) -> Result<(), String> {
+        Err(UNSUPPORTED.to_string())
+    }
+}
+
+// UDIDs of the devices attached in normal mode
+pub fn devices() -> Result<Vec<String>, String> {
+    backend::devices()
+}
+
+pub fn enter_recovery(udid: &str) -> Result<(), String> {
+    backend::enter_recovery(udid)
+}
+
+impl DeviceInfo {
+    pub fn query(udid: &str) -> Result<DeviceInfo, String> {
+        backend::query(udid)
+    }
+
+    // Only SoCs we have offsets for count; that's a subset of what checkm8 hits
+    pub fn soc(&self) -> Option<&'static Soc> {
+        soc::soc_for_cpid(self.cpid)
+    }
+
+    pub fn has_home_button(&self) -> bool {
+        !soc::no_home_button(self.cpid, self.bdid)
+    }
+
+    // The identity fields iBoot would report in its serial, so a normal mode
+    // device can be matched up with what it later becomes
+    pub fn serial(&self) -> DeviceSerial {
+        DeviceSerial {
+            cpid: self.cpid,
+            bdid: self.bdid,
+            ecid: self.ecid,
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(all(test, feature = "usbmuxd", not(feature = "libimobiledevice")))]
+mod tests {
+    use super::*;
+    use crate::lockdownd::tests::{read_request, request_name, start_session, write_reply};
+    use crate::plist::Value;
+    use crate::usbmux::tests::{fake_usbmuxd, UDID};
+    use std::os::unix::net::UnixListener;
+    use std::thread;
+
+    // usbmuxd on a socket of its own, found through USBMUXD_SOCKET_ADDRESS,
+    // with an iPhone 7 behind it
+    #[test]
+    fn queries_through_usbmuxd() {
+        let path = std::env::temp_dir().join(format!("ra1n-oxide-usbmuxd-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        std::env::set_var("USBMUXD_SOCKET_ADDRESS", format!("UNIX:{}", path.display()));
+        let fake = thread::spawn(move || {
+            // devices(), then query()'s connection through to lockdownd
+            for _ in 0..2 {
+                let (stream, _) = listener.accept().unwrap();
+                let Some((mut device, _)) = fake_usbmuxd(stream) else {
+                    continue;
+                };
+                assert_eq!(request_name(&read_request(&mut device)), "QueryType");
+                write_reply(&mut device, vec![("Type", Value::from("com.apple.mobile.lockdown"))]);
+                start_session(&mut device, false);
+                let request = read_request(&mut device);
+                assert_eq!(request_name(&request), "GetValue");
+                assert!(request.get("Key").is_none());
+                let values = vec![
+                    ("ProductType", Value::from("iPhone9,3")),
+                    ("HardwareModel", Value::from("D101AP")),
+                    ("ProductVersion", Value::from("15.8")),
+                    ("BuildVersion", Value::from("19H370")),
+                    ("UniqueChipID", Value::from(0x269E20846003Ai64)),
+                    ("ChipID", Value::from(0x8010i64)),
+                    ("BoardId", Value::from(0x0Ci64)),
+                ];
+                let values = Value::Dictionary(values.into_iter().map(|(key, value)| (key.to_string(), value)).collect());
+                write_reply(&mut device, vec![("Value", values)]);
+                assert_eq!(request_name(&read_request(&mut device)), "StopSession");
+                write_reply(&mut device, vec![]);
+            }
+        });
+
+        // the Wi-Fi entry for the same device is left out
+        assert_eq!(devices().unwrap(), [UDID]);
+        let info = DeviceInfo::query(UDID).unwrap();
+        fake.join().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            info,
+            DeviceInfo {
+                udid: UDID.to_string(),
+                product_type: "iPhone9,3".to_string(),
+                hardware_model: "D101AP".to_string(),
+                product_version: "15.8".to_string(),
+                build_version: "19H370".to_string(),
+                ecid: 0x269E20846003A,
+                cpid: 0x8010,
+                bdid: 0x0C,
+            }
+        );
+        assert!(!info.has_home_button());
+        assert_eq!(info.soc().unwrap().name, "t8010");
+    }
+}
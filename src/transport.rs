@@ -0,0 +1,338 @@
+use rusb::ffi::{libusb_bulk_transfer, libusb_control_transfer, libusb_error_name};
+use rusb::constants::{LIBUSB_ERROR_IO, LIBUSB_ERROR_NO_DEVICE, LIBUSB_ERROR_PIPE, LIBUSB_ERROR_TIMEOUT};
+use std::ffi::CStr;
+use std::fmt;
+
+// Everything that talks to a device over USB goes through this, so the same
+// code can run against a real handle or something that only pretends to be one.
+pub trait Transport {
+    // `data` is written for host-to-device requests and filled for
+    // device-to-host ones, depending on bit 7 of bm_request_type.
+    fn control_transfer(
+        &mut self,
+        bm_request_type: u8,
+        b_request: u8,
+        w_value: u16,
+        w_index: u16,
+        data: &mut [u8],
+        timeout: u32,
+    ) -> Result<usize, TransferError>;
+
+    // Direction comes from bit 7 of `endpoint`, as for control transfers.
+    fn bulk_transfer(&mut self, endpoint: u8, data: &mut [u8], timeout: u32) -> Result<usize, TransferError>;
+
+    fn reset(&mut self) -> Result<(), TransferError>;
+
+    fn serial_number(&mut self) -> Option<String>;
+
+    // How much of the last transfer got through before it timed out, where
+    // the backend can tell. A control transfer cut short in its data phase
+    // has still moved the packets before that.
+    fn timed_out_length(&self) -> Option<usize> {
+        None
+    }
+}
+
+// So a client can borrow a transport for a while and hand it back
+impl<T: Transport + ?Sized> Transport for &mut T {
+    fn control_transfer(
+        &mut self,
+        bm_request_type: u8,
+        b_request: u8,
+        w_value: u16,
+        w_index: u16,
+        data: &mut [u8],
+        timeout: u32,
+    ) -> Result<usize, TransferError> {
+        (**self).control_transfer(bm_request_type, b_request, w_value, w_index, data, timeout)
+    }
+
+    fn bulk_transfer(&mut self, endpoint: u8, data: &mut [u8], timeout: u32) -> Result<usize, TransferError> {
+        (**self).bulk_transfer(endpoint, data, timeout)
+    }
+
+    fn reset(&mut self) -> Result<(), TransferError> {
+        (**self).reset()
+    }
+
+    fn serial_number(&mut self) -> Option<String> {
+        (**self).serial_number()
+    }
+
+    fn timed_out_length(&self) -> Option<usize> {
+        (**self).timed_out_length()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransferError {
+    Timeout,
+    Stall,
+    NoDevice,
+    Io,
+    Other(String),
+}
+
+impl fmt::Display for TransferError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransferError::Timeout => write!(f, "transfer timed out"),
+            TransferError::Stall => write!(f, "endpoint stalled"),
+            TransferError::NoDevice => write!(f, "device disconnected"),
+            TransferError::Io => write!(f, "I/O error"),
+            TransferError::Other(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+// Linux URB status values, negative errno
+const EINPROGRESS: i32 = -115;
+const ESHUTDOWN: i32 = -108;
+const ECONNRESET: i32 = -104;
+const EPROTO: i32 = -71;
+const EPIPE: i32 = -32;
+const ENODEV: i32 = -19;
+const ENOENT: i32 = -2;
+const EIO: i32 = -5;
+
+impl TransferError {
+    // What a URB that ended like this completes with, as usbmon and USB/IP
+    // carry it. A timeout is the URB being unlinked.
+    pub fn urb_status(&self) -> i32 {
+        match self {
+            TransferError::Timeout => ECONNRESET,
+            TransferError::Stall => EPIPE,
+            TransferError::NoDevice => ENODEV,
+            TransferError::Io => EPROTO,
+            TransferError::Other(_) => EIO,
+        }
+    }
+
+    pub fn from_urb_status(status: i32) -> TransferError {
+        match status {
+            ECONNRESET | ENOENT => TransferError::Timeout,
+            EPIPE => TransferError::Stall,
+            ENODEV | ESHUTDOWN => TransferError::NoDevice,
+            EPROTO => TransferError::Io,
+            _ => TransferError::Other(format!("URB status {}", status)),
+        }
+    }
+
+    pub const URB_IN_PROGRESS: i32 = EINPROGRESS;
+
+    fn from_libusb(ret: i32) -> TransferError {
+        match ret {
+            LIBUSB_ERROR_TIMEOUT => TransferError::Timeout,
+            LIBUSB_ERROR_PIPE => TransferError::Stall,
+            LIBUSB_ERROR_NO_DEVICE => TransferError::NoDevice,
+            LIBUSB_ERROR_IO => TransferError::Io,
+            _ => {
+                let name = unsafe { CStr::from_ptr(libusb_error_name(ret)) };
+                TransferError::Other(name.to_string_lossy().into_owned())
+            }
+        }
+    }
+}
+
+pub const GET_DESCRIPTOR: u8 = 6;
+pub const DEVICE_DESCRIPTOR: u16 = 0x0100;
+pub const STRING_DESCRIPTOR: u16 = 0x0300;
+const LANGUAGE_EN_US: u16 = 0x0409;
+
+// The serial string the way libusb reads it, for transports that only have
+// control transfers to go on
+pub fn read_serial_number<T: Transport + ?Sized>(transport: &mut T) -> Option<String> {
+    let mut descriptor = [0u8; 18];
+    transport
+        .control_transfer(0x80, GET_DESCRIPTOR, DEVICE_DESCRIPTOR, 0, &mut descriptor, 1000)
+        .ok()?;
+    let index = descriptor[16];
+    if index == 0 {
+        return None;
+    }
+    let mut string = [0u8; 255];
+    let length = transport
+        .control_transfer(0x80, GET_DESCRIPTOR, STRING_DESCRIPTOR | u16::from(index), LANGUAGE_EN_US, &mut string, 1000)
+        .ok()?;
+    let length = usize::from(*string.first()?).min(length);
+    let units: Vec<u16> = string
+        .get(2..length)?
+        .chunks_exact(2)
+        .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+        .collect();
+    String::from_utf16(&units).ok()
+}
+
+// MARK: libusb
+pub struct RusbTransport {
+    handle: rusb::DeviceHandle<rusb::Context>,
+}
+
+impl RusbTransport {
+    pub fn new(handle: rusb::DeviceHandle<rusb::Context>) -> RusbTransport {
+        RusbTransport { handle }
+    }
+
+    pub fn handle(&self) -> &rusb::DeviceHandle<rusb::Context> {
+        &self.handle
+    }
+
+    // Recovery mode iBoot takes files over a bulk endpoint on interface 1,
+    // alternate setting 1.
+    pub fn claim_interface(&mut self, interface: u8, alt_setting: u8) -> Result<(), TransferError> {
+        let map = |e: rusb::Error| TransferError::Other(format!("interface {}: {}", interface, e));
+        self.handle.claim_interface(interface).map_err(map)?;
+        if alt_setting != 0 {
+            self.handle.set_alternate_setting(interface, alt_setting).map_err(map)?;
+        }
+        Ok(())
+    }
+}
+
+impl Transport for RusbTransport {
+    fn control_transfer(
+        &mut self,
+        bm_request_type: u8,
+        b_request: u8,
+        w_value: u16,
+        w_index: u16,
+        data: &mut [u8],
+        timeout: u32,
+    ) -> Result<usize, TransferError> {
+        let w_length: u16 = data
+            .len()
+            .try_into()
+            .map_err(|_| TransferError::Other("wLength too large".to_string()))?;
+        let ret = unsafe {
+            libusb_control_transfer(
+                self.handle.as_raw(),
+                bm_request_type,
+                b_request,
+                w_value,
+                w_index,
+                if data.is_empty() {
+                    std::ptr::null_mut()
+                } else {
+                    data.as_mut_ptr()
+                },
+                w_length,
+                timeout,
+            )
+        };
+        if ret < 0 {
+            return Err(TransferError::from_libusb(ret));
+        }
+        Ok(ret as usize)
+    }
+
+    fn bulk_transfer(&mut self, endpoint: u8, data: &mut [u8], timeout: u32) -> Result<usize, TransferError> {
+        let length: i32 = data
+            .len()
+            .try_into()
+            .map_err(|_| TransferError::Other("bulk transfer too large".to_string()))?;
+        let mut transferred = 0;
+        let ret = unsafe {
+            libusb_bulk_transfer(
+                self.handle.as_raw(),
+                endpoint,
+                data.as_mut_ptr(),
+                length,
+                &mut transferred,
+                timeout,
+            )
+        };
+        if ret < 0 {
+            return Err(TransferError::from_libusb(ret));
+        }
+        Ok(transferred as usize)
+    }
+
+    fn reset(&mut self) -> Result<(), TransferError> {
+        match self.handle.reset() {
+            // the device going away is the point of resetting it
+            Ok(()) | Err(rusb::Error::NotFound) | Err(rusb::Error::NoDevice) => Ok(()),
+            Err(e) => Err(TransferError::Other(e.to_string())),
+        }
+    }
+
+    fn serial_number(&mut self) -> Option<String> {
+        let device_descriptor = self.handle.device().device_descriptor().ok()?;
+        self.handle
+            .read_serial_number_string_ascii(&device_descriptor)
+            .ok()
+    }
+}
+
+// MARK: backends
+// Where a device handle came from: this machine's libusb (or usbfs, built
+// with that feature), or a USB/IP server exporting a device plugged into
+// another one
+pub enum Backend {
+    Local(RusbTransport),
+    Usbip(crate::usbip::UsbipTransport),
+    #[cfg(feature = "usbfs")]
+    Usbfs(crate::usbfs::UsbfsTransport),
+}
+
+impl Backend {
+    // Bus number and device address, as the machine the device is on sees them
+    pub fn address(&self) -> (u16, u8) {
+        match self {
+            Backend::Local(transport) => {
+                let device = transport.handle().device();
+                (device.bus_number().into(), device.address())
+            }
+            Backend::Usbip(transport) => {
+                let device = transport.device();
+                (device.busnum as u16, device.devnum as u8)
+            }
+            #[cfg(feature = "usbfs")]
+            Backend::Usbfs(transport) => transport.address(),
+        }
+    }
+
+    pub fn claim_interface(&mut self, interface: u8, alt_setting: u8) -> Result<(), TransferError> {
+        match self {
+            Backend::Local(transport) => transport.claim_interface(interface, alt_setting),
+            Backend::Usbip(transport) => transport.set_interface(interface, alt_setting),
+            #[cfg(feature = "usbfs")]
+            Backend::Usbfs(transport) => transport.claim_interface(interface, alt_setting),
+        }
+    }
+
+    fn inner(&mut self) -> &mut dyn Transport {
+        match self {
+            Backend::Local(transport) => transport,
+            Backend::Usbip(transport) => transport,
+            #[cfg(feature = "usbfs")]
+            Backend::Usbfs(transport) => transport,
+        }
+    }
+}
+
+impl Transport for Backend {
+    fn control_transfer(
+        &mut self,
+        bm_request_type: u8,
+        b_request: u8,
+        w_value: u16,
+        w_index: u16,
+        data: &mut [u8],
+        timeout: u32,
+    ) -> Result<usize, TransferError> {
+        self.inner()
+            .control_transfer(bm_request_type, b_request, w_value, w_index, data, timeout)
+    }
+
+    fn bulk_transfer(&mut self, endpoint: u8, data: &mut [u8], timeout: u32) -> Result<usize, TransferError> {
+        self.inner().bulk_transfer(endpoint, data, timeout)
+    }
+
+    fn reset(&mut self) -> Result<(), TransferError> {
+        self.inner().reset()
+    }
+
+    fn serial_number(&mut self) -> Option<String> {
+        self.inner().serial_number()
+    }
+}
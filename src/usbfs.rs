@@ -0,0 +1,576 @@
+// Linux usbfs (/dev/bus/usb/BBB/DDD) without libusb. Every transfer is one
+// URB: SUBMITURB, then REAPURBNDELAY until it's back or the timeout is up,
+// then DISCARDURB and reap what's left of it. The timeout is ours to time
+// here, which is what checkm8's cut-short requests are made of, so how long a
+// timeout unit lasts and how often the URB is looked at are knobs (Timing).
+//
+// The ioctls go through Usbdevfs, so everything above them runs against
+// something other than a device node too.
+
+use crate::transport::{read_serial_number, TransferError, Transport};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read};
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+const USBFS_ROOT: &str = "/dev/bus/usb";
+
+const URB_TYPE_CONTROL: u8 = 2;
+const URB_TYPE_BULK: u8 = 3;
+
+// struct usbdevfs_urb, without the iso packet descriptors we never use
+#[repr(C)]
+pub struct Urb {
+    pub urb_type: u8,
+    pub endpoint: u8,
+    pub status: i32,
+    pub flags: u32,
+    pub buffer: *mut u8,
+    pub buffer_length: i32,
+    pub actual_length: i32,
+    pub start_frame: i32,
+    pub number_of_packets: i32,
+    pub error_count: i32,
+    pub signr: u32,
+    pub usercontext: *mut libc::c_void,
+}
+
+#[repr(C)]
+struct SetInterface {
+    interface: u32,
+    alt_setting: u32,
+}
+
+// _IO, _IOR and _IOW for 'U'
+const fn ioctl_number(direction: libc::c_ulong, number: libc::c_ulong, size: usize) -> libc::c_ulong {
+    (direction << 30) | ((size as libc::c_ulong) << 16) | ((b'U' as libc::c_ulong) << 8) | number
+}
+const IOC_NONE: libc::c_ulong = 0;
+const IOC_WRITE: libc::c_ulong = 1;
+const IOC_READ: libc::c_ulong = 2;
+
+const USBDEVFS_SETINTERFACE: libc::c_ulong = ioctl_number(IOC_READ, 4, size_of::<SetInterface>());
+const USBDEVFS_SUBMITURB: libc::c_ulong = ioctl_number(IOC_READ, 10, size_of::<Urb>());
+const USBDEVFS_DISCARDURB: libc::c_ulong = ioctl_number(IOC_NONE, 11, 0);
+const USBDEVFS_REAPURBNDELAY: libc::c_ulong = ioctl_number(IOC_WRITE, 13, size_of::<*mut Urb>());
+const USBDEVFS_CLAIMINTERFACE: libc::c_ulong = ioctl_number(IOC_READ, 15, size_of::<u32>());
+const USBDEVFS_RESET: libc::c_ulong = ioctl_number(IOC_NONE, 20, 0);
+
+// The ioctls the transport is made of. The URB passed to submit stays put
+// until reap hands it back.
+pub trait Usbdevfs {
+    fn submit(&mut self, urb: *mut Urb) -> io::Result<()>;
+    // EINVAL when the URB isn't in flight any more
+    fn discard(&mut self, urb: *mut Urb) -> io::Result<()>;
+    // A finished URB, or None (EAGAIN) while there isn't one
+    fn reap(&mut self) -> io::Result<Option<*mut Urb>>;
+    fn reset(&mut self) -> io::Result<()>;
+    fn claim_interface(&mut self, interface: u8) -> io::Result<()>;
+    fn set_interface(&mut self, interface: u8, alt_setting: u8) -> io::Result<()>;
+}
+
+// A device node
+pub struct DevFile {
+    file: File,
+}
+
+impl DevFile {
+    pub fn open(path: &Path) -> io::Result<DevFile> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        Ok(DevFile { file })
+    }
+
+    fn ioctl(&self, request: libc::c_ulong, argument: *mut libc::c_void) -> io::Result<()> {
+        // usbfs only reads and writes `argument` as `request` says
+        match unsafe { libc::ioctl(self.file.as_raw_fd(), request as _, argument) } {
+            -1 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Usbdevfs for DevFile {
+    fn submit(&mut self, urb: *mut Urb) -> io::Result<()> {
+        self.ioctl(USBDEVFS_SUBMITURB, urb.cast())
+    }
+
+    fn discard(&mut self, urb: *mut Urb) -> io::Result<()> {
+        self.ioctl(USBDEVFS_DISCARDURB, urb.cast())
+    }
+
+    fn reap(&mut self) -> io::Result<Option<*mut Urb>> {
+        let mut urb: *mut Urb = std::ptr::null_mut();
+        match self.ioctl(USBDEVFS_REAPURBNDELAY, (&mut urb as *mut *mut Urb).cast()) {
+            Ok(()) => Ok(Some(urb)),
+            Err(e) if e.raw_os_error() == Some(libc::EAGAIN) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn reset(&mut self) -> io::Result<()> {
+        self.ioctl(USBDEVFS_RESET, std::ptr::null_mut())
+    }
+
+    fn claim_interface(&mut self, interface: u8) -> io::Result<()> {
+        let mut interface = u32::from(interface);
+        self.ioctl(USBDEVFS_CLAIMINTERFACE, (&mut interface as *mut u32).cast())
+    }
+
+    fn set_interface(&mut self, interface: u8, alt_setting: u8) -> io::Result<()> {
+        let mut setting = SetInterface {
+            interface: interface.into(),
+            alt_setting: alt_setting.into(),
+        };
+        self.ioctl(USBDEVFS_SETINTERFACE, (&mut setting as *mut SetInterface).cast())
+    }
+}
+
+// How the transport times a transfer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timing {
+    // One unit of a transfer's timeout. Below a millisecond, checkm8's 1ms
+    // aborts come sooner than libusb could make them.
+    pub timeout_unit: Duration,
+    // Between looks at an in-flight URB; zero spins
+    pub poll_interval: Duration,
+    // How long a discarded URB gets to come back before the device is given up on
+    pub discard_timeout: Duration,
+}
+
+impl Default for Timing {
+    fn default() -> Timing {
+        Timing {
+            timeout_unit: Duration::from_millis(1),
+            poll_interval: Duration::from_micros(20),
+            discard_timeout: Duration::from_secs(1),
+        }
+    }
+}
+
+impl Timing {
+    // "unit=<us>,poll=<us>,discard=<ms>", any of them, the rest as default
+    pub fn parse(text: &str) -> Option<Timing> {
+        let mut timing = Timing::default();
+        for knob in text.split(',') {
+            let (name, value) = knob.split_once('=')?;
+            let value: u64 = value.parse().ok()?;
+            match name {
+                "unit" => timing.timeout_unit = Duration::from_micros(value),
+                "poll" => timing.poll_interval = Duration::from_micros(value),
+                "discard" => timing.discard_timeout = Duration::from_millis(value),
+                _ => return None,
+            }
+        }
+        Some(timing)
+    }
+}
+
+fn transfer_error(e: io::Error) -> TransferError {
+    match e.raw_os_error() {
+        Some(libc::ENODEV) | Some(libc::ESHUTDOWN) => TransferError::NoDevice,
+        Some(libc::EPIPE) => TransferError::Stall,
+        _ => TransferError::Other(e.to_string()),
+    }
+}
+
+pub struct UsbfsTransport<D: Usbdevfs = DevFile> {
+    device: D,
+    timing: Timing,
+    bus: u16,
+    address: u8,
+    // what a discarded URB had moved, for timed_out_length
+    timed_out: Option<usize>,
+}
+
+impl<D: Usbdevfs> UsbfsTransport<D> {
+    pub fn new(device: D, bus: u16, address: u8) -> UsbfsTransport<D> {
+        UsbfsTransport {
+            device,
+            timing: Timing::default(),
+            bus,
+            address,
+            timed_out: None,
+        }
+    }
+
+    pub fn with_timing(mut self, timing: Timing) -> UsbfsTransport<D> {
+        self.timing = timing;
+        self
+    }
+
+    pub fn address(&self) -> (u16, u8) {
+        (self.bus, self.address)
+    }
+
+    pub fn claim_interface(&mut self, interface: u8, alt_setting: u8) -> Result<(), TransferError> {
+        let map = |e: io::Error| TransferError::Other(format!("interface {}: {}", interface, e));
+        self.device.claim_interface(interface).map_err(map)?;
+        if alt_setting != 0 {
+            self.device.set_interface(interface, alt_setting).map_err(map)?;
+        }
+        Ok(())
+    }
+
+    // Runs one URB over `buffer` to completion or its timeout, and hands the
+    // buffer back with how much of it moved
+    fn run_urb(
+        &mut self,
+        urb_type: u8,
+        endpoint: u8,
+        mut buffer: Vec<u8>,
+        timeout: u32,
+    ) -> Result<(usize, Vec<u8>), TransferError> {
+        let mut urb = Box::new(Urb {
+            urb_type,
+            endpoint,
+            status: 0,
+            flags: 0,
+            buffer: buffer.as_mut_ptr(),
+            buffer_length: buffer
+                .len()
+                .try_into()
+                .map_err(|_| TransferError::Other("transfer too large".to_string()))?,
+            actual_length: 0,
+            start_frame: 0,
+            number_of_packets: 0,
+            error_count: 0,
+            signr: 0,
+            usercontext: std::ptr::null_mut(),
+        });
+        let urb_ptr: *mut Urb = &mut *urb;
+        self.timed_out = None;
+        self.device.submit(urb_ptr).map_err(transfer_error)?;
+        match self.wait(urb_ptr, timeout) {
+            Ok(()) => {
+                let result = urb_result(&urb);
+                if result == Err(TransferError::Timeout) {
+                    self.timed_out = Some(urb.actual_length.max(0) as usize);
+                }
+                result.map(|length| (length, buffer))
+            }
+            Err(e) => {
+                // not reaped, so the kernel could still write to them: they're
+                // never freed
+                std::mem::forget(urb);
+                std::mem::forget(buffer);
+                Err(e)
+            }
+        }
+    }
+
+    // Until the submitted `urb` is reaped, discarding it once the timeout is
+    // up. An error means it may still be in flight.
+    fn wait(&mut self, urb: *mut Urb, timeout: u32) -> Result<(), TransferError> {
+        let started = Instant::now();
+        let deadline = (timeout != 0).then(|| started + self.timing.timeout_unit * timeout);
+        loop {
+            if self.reap(urb)? {
+                return Ok(());
+            }
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                break;
+            }
+            self.pause();
+        }
+
+        match self.device.discard(urb) {
+            // finished on its own in the meantime
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) => {}
+            result => result.map_err(transfer_error)?,
+        }
+        let discarded = Instant::now();
+        while !self.reap(urb)? {
+            if discarded.elapsed() >= self.timing.discard_timeout {
+                return Err(TransferError::NoDevice);
+            }
+            self.pause();
+        }
+        Ok(())
+    }
+
+    // Whether `urb` is back. Only one is ever in flight.
+    fn reap(&mut self, urb: *mut Urb) -> Result<bool, TransferError> {
+        match self.device.reap().map_err(transfer_error)? {
+            Some(reaped) if reaped == urb => Ok(true),
+            Some(_) => Err(TransferError::Other("usbfs: reaped a URB we didn't submit".to_string())),
+            None => Ok(false),
+        }
+    }
+
+    fn pause(&self) {
+        if self.timing.poll_interval.is_zero() {
+            std::hint::spin_loop();
+        } else {
+            std::thread::sleep(self.timing.poll_interval);
+        }
+    }
+}
+
+// A discarded URB comes back with -ENOENT or -ECONNRESET, which is a timeout
+fn urb_result(urb: &Urb) -> Result<usize, TransferError> {
+    if urb.status != 0 {
+        return Err(TransferError::from_urb_status(urb.status));
+    }
+    Ok(urb.actual_length.max(0) as usize)
+}
+
+impl<D: Usbdevfs> Transport for UsbfsTransport<D> {
+    fn control_transfer(
+        &mut self,
+        bm_request_type: u8,
+        b_request: u8,
+        w_value: u16,
+        w_index: u16,
+        data: &mut [u8],
+        timeout: u32,
+    ) -> Result<usize, TransferError> {
+        let w_length: u16 = data
+            .len()
+            .try_into()
+            .map_err(|_| TransferError::Other("wLength too large".to_string()))?;
+        // the setup packet goes in front of the data
+        let mut buffer = vec![bm_request_type, b_request];
+        buffer.extend_from_slice(&w_value.to_le_bytes());
+        buffer.extend_from_slice(&w_index.to_le_bytes());
+        buffer.extend_from_slice(&w_length.to_le_bytes());
+        buffer.extend_from_slice(data);
+        let (transferred, buffer) = self.run_urb(URB_TYPE_CONTROL, 0, buffer, timeout)?;
+        let transferred = transferred.min(data.len());
+        if bm_request_type & 0x80 != 0 {
+            data[..transferred].copy_from_slice(&buffer[8..8 + transferred]);
+        }
+        Ok(transferred)
+    }
+
+    fn bulk_transfer(&mut self, endpoint: u8, data: &mut [u8], timeout: u32) -> Result<usize, TransferError> {
+        let (transferred, buffer) = self.run_urb(URB_TYPE_BULK, endpoint, data.to_vec(), timeout)?;
+        let transferred = transferred.min(data.len());
+        if endpoint & 0x80 != 0 {
+            data[..transferred].copy_from_slice(&buffer[..transferred]);
+        }
+        Ok(transferred)
+    }
+
+    fn reset(&mut self) -> Result<(), TransferError> {
+        match self.device.reset().map_err(transfer_error) {
+            // the device going away is the point of resetting it
+            Ok(()) | Err(TransferError::NoDevice) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn serial_number(&mut self) -> Option<String> {
+        read_serial_number(self)
+    }
+
+    fn timed_out_length(&self) -> Option<usize> {
+        self.timed_out
+    }
+}
+
+// MARK: devices
+// A device node and what its descriptor says it is
+pub struct DeviceNode {
+    pub path: PathBuf,
+    pub bus: u16,
+    pub address: u8,
+    pub vendor_id: u16,
+    pub product_id: u16,
+}
+
+impl DeviceNode {
+    pub fn open(&self, timing: Timing) -> Result<UsbfsTransport, String> {
+        let device = DevFile::open(&self.path).map_err(|e| format!("{}: {}", self.path.display(), e))?;
+        Ok(UsbfsTransport::new(device, self.bus, self.address).with_timing(timing))
+    }
+}
+
+// Reading a node gives its descriptors, the device descriptor first
+fn read_node(path: &Path, bus: u16, address: u8) -> Option<DeviceNode> {
+    let mut descriptor = [0u8; 18];
+    File::open(path).ok()?.read_exact(&mut descriptor).ok()?;
+    Some(DeviceNode {
+        path: path.to_path_buf(),
+        bus,
+        address,
+        vendor_id: u16::from_le_bytes([descriptor[8], descriptor[9]]),
+        product_id: u16::from_le_bytes([descriptor[10], descriptor[11]]),
+    })
+}
+
+// Every device under /dev/bus/usb we're allowed to read
+pub fn devices() -> Vec<DeviceNode> {
+    let mut nodes = Vec::new();
+    let Ok(buses) = fs::read_dir(USBFS_ROOT) else {
+        return nodes;
+    };
+    for bus in buses.flatten() {
+        let Some(bus_number) = bus.file_name().to_str().and_then(|name| name.parse().ok()) else {
+            continue;
+        };
+        let Ok(entries) = fs::read_dir(bus.path()) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let address = entry.file_name().to_str().and_then(|name| name.parse().ok());
+            if let Some(node) = address.and_then(|address| read_node(&entry.path(), bus_number, address)) {
+                nodes.push(node);
+            }
+        }
+    }
+    nodes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Stands in for a device node, with one URB in flight at a time that
+    // finishes the way the test sets up
+    #[derive(Default)]
+    struct Shim {
+        in_flight: Option<*mut Urb>,
+        // finished and waiting to be reaped
+        done: bool,
+        // reaps before it finishes by itself, None for never
+        reaps_left: Option<usize>,
+        // finishes just as the discard comes in, so the discard gets EINVAL
+        finishes_on_discard: bool,
+        // reap hands back a URB that was never submitted
+        foreign: bool,
+        // what an IN transfer gets back
+        reply: Vec<u8>,
+        calls: Vec<&'static str>,
+    }
+
+    impl Shim {
+        fn finish(&mut self, status: i32) {
+            // run_urb keeps the URB and its buffer until they're reaped
+            let urb = unsafe { &mut *self.in_flight.unwrap() };
+            let start = if urb.urb_type == URB_TYPE_CONTROL { 8 } else { 0 };
+            let buffer = unsafe { std::slice::from_raw_parts_mut(urb.buffer, urb.buffer_length as usize) };
+            let length = if status == 0 { self.reply.len().min(buffer.len() - start) } else { 0 };
+            buffer[start..start + length].copy_from_slice(&self.reply[..length]);
+            urb.status = status;
+            urb.actual_length = length as i32;
+            self.done = true;
+        }
+    }
+
+    impl Usbdevfs for Shim {
+        fn submit(&mut self, urb: *mut Urb) -> io::Result<()> {
+            self.calls.push("submit");
+            self.in_flight = Some(urb);
+            Ok(())
+        }
+
+        fn discard(&mut self, urb: *mut Urb) -> io::Result<()> {
+            self.calls.push("discard");
+            assert_eq!(Some(urb), self.in_flight);
+            if self.done {
+                return Err(io::Error::from_raw_os_error(libc::EINVAL));
+            }
+            if self.finishes_on_discard {
+                self.finish(0);
+                return Err(io::Error::from_raw_os_error(libc::EINVAL));
+            }
+            self.finish(-libc::ENOENT);
+            Ok(())
+        }
+
+        fn reap(&mut self) -> io::Result<Option<*mut Urb>> {
+            if self.foreign {
+                self.calls.push("reap");
+                return Ok(Some(std::ptr::NonNull::dangling().as_ptr()));
+            }
+            if !self.done {
+                match self.reaps_left {
+                    Some(0) => self.finish(0),
+                    Some(reaps) => {
+                        self.reaps_left = Some(reaps - 1);
+                        return Ok(None);
+                    }
+                    None => return Ok(None),
+                }
+            }
+            self.calls.push("reap");
+            self.done = false;
+            Ok(self.in_flight.take())
+        }
+
+        fn reset(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn claim_interface(&mut self, _interface: u8) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn set_interface(&mut self, _interface: u8, _alt_setting: u8) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn transport(shim: Shim) -> UsbfsTransport<Shim> {
+        let timing = Timing {
+            timeout_unit: Duration::from_millis(1),
+            poll_interval: Duration::ZERO,
+            discard_timeout: Duration::from_millis(100),
+        };
+        UsbfsTransport::new(shim, 1, 2).with_timing(timing)
+    }
+
+    #[test]
+    fn completes() {
+        let mut usbfs = transport(Shim {
+            reaps_left: Some(3),
+            reply: vec![1, 2, 3, 4],
+            ..Default::default()
+        });
+        let mut data = [0u8; 6];
+        assert_eq!(usbfs.control_transfer(0xa1, 3, 0, 0, &mut data, 100), Ok(4));
+        assert_eq!(data, [1, 2, 3, 4, 0, 0]);
+        assert_eq!(usbfs.bulk_transfer(0x81, &mut data, 100), Ok(4));
+        assert_eq!(usbfs.device.calls, ["submit", "reap", "submit", "reap"]);
+    }
+
+    #[test]
+    fn discards_on_timeout() {
+        let mut usbfs = transport(Shim::default());
+        let started = Instant::now();
+        assert_eq!(usbfs.control_transfer(0x80, 6, 0x304, 0x40a, &mut [0u8; 0xc0], 2), Err(TransferError::Timeout));
+        assert!(started.elapsed() >= Duration::from_millis(2));
+        assert_eq!(usbfs.device.calls, ["submit", "discard", "reap"]);
+        assert_eq!(usbfs.timed_out_length(), Some(0));
+    }
+
+    // Finished between the last look and the discard: what it did counts
+    #[test]
+    fn finished_before_the_discard() {
+        let mut usbfs = transport(Shim {
+            finishes_on_discard: true,
+            reply: vec![0x12, 0x01],
+            ..Default::default()
+        });
+        let mut data = [0u8; 2];
+        assert_eq!(usbfs.control_transfer(0x80, 6, 0x100, 0, &mut data, 1), Ok(2));
+        assert_eq!(data, [0x12, 0x01]);
+        assert_eq!(usbfs.device.calls, ["submit", "discard", "reap"]);
+    }
+
+    // Ours is still in flight then, so it's given up on without being freed
+    #[test]
+    fn reaps_a_foreign_urb() {
+        let mut usbfs = transport(Shim {
+            foreign: true,
+            ..Default::default()
+        });
+        assert_eq!(
+            usbfs.control_transfer(0x80, 6, 0x100, 0, &mut [0u8; 18], 100),
+            Err(TransferError::Other("usbfs: reaped a URB we didn't submit".to_string()))
+        );
+        assert_eq!(usbfs.device.calls, ["submit", "reap"]);
+        assert!(usbfs.device.in_flight.is_some());
+    }
+}
@@ -0,0 +1,824 @@
+// USB/IP, for devices plugged into another machine: usbipd there exports
+// them over TCP (port 3240), and each URB travels as a CMD_SUBMIT answered by
+// a RET_SUBMIT. There is no timeout in the protocol; a client that gives up on
+// a URB sends CMD_UNLINK for it, which is how checkm8's cut-short requests
+// get through. All fields are big-endian except the setup packet.
+//
+// serve() is a small server for one Transport, so the client can be run
+// against the simulator.
+
+use crate::transport::{
+    read_serial_number, TransferError, Transport, DEVICE_DESCRIPTOR, GET_DESCRIPTOR, STRING_DESCRIPTOR,
+};
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::{Duration, Instant};
+use tracing::{debug, info};
+
+pub const USBIP_PORT: u16 = 3240;
+const USBIP_VERSION: u16 = 0x0111;
+
+const OP_REQ_DEVLIST: u16 = 0x8005;
+const OP_REP_DEVLIST: u16 = 0x0005;
+const OP_REQ_IMPORT: u16 = 0x8003;
+const OP_REP_IMPORT: u16 = 0x0003;
+const OP_HEADER_LEN: usize = 8;
+
+const CMD_SUBMIT: u32 = 1;
+const CMD_UNLINK: u32 = 2;
+const RET_SUBMIT: u32 = 3;
+const RET_UNLINK: u32 = 4;
+const DIR_OUT: u32 = 0;
+const DIR_IN: u32 = 1;
+// basic header, then command specific fields padded to the same size
+const URB_HEADER_LEN: usize = 48;
+
+const PATH_LEN: usize = 256;
+const BUSID_LEN: usize = 32;
+const DEVICE_LEN: usize = PATH_LEN + BUSID_LEN + 24;
+const INTERFACE_LEN: usize = 4;
+
+// How long to wait for the unlink once a URB has been given up on
+const UNLINK_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Requests usbip-host handles itself rather than passing them to the device
+const SET_FEATURE: u8 = 3;
+const PORT_RESET: u16 = 4;
+const SET_INTERFACE: u8 = 0x0B;
+
+// "host" or "host:port"
+fn with_port(address: &str) -> String {
+    if address.contains(':') {
+        address.to_string()
+    } else {
+        format!("{}:{}", address, USBIP_PORT)
+    }
+}
+
+fn connect(address: &str) -> Result<TcpStream, String> {
+    let address = with_port(address);
+    let stream = TcpStream::connect(&address).map_err(|e| format!("USB/IP server {}: {}", address, e))?;
+    // URBs are small and the exploit times them, Nagle would hold them back
+    stream.set_nodelay(true).map_err(|e| e.to_string())?;
+    Ok(stream)
+}
+
+fn be16(data: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn be32(data: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn fixed_string(data: &[u8]) -> String {
+    let end = data.iter().position(|&byte| byte == 0).unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..end]).into_owned()
+}
+
+fn op_header(code: u16, status: u32) -> Vec<u8> {
+    let mut header = USBIP_VERSION.to_be_bytes().to_vec();
+    header.extend_from_slice(&code.to_be_bytes());
+    header.extend_from_slice(&status.to_be_bytes());
+    header
+}
+
+// The device as the server describes it in its list and import replies
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExportedDevice {
+    pub path: String,
+    pub busid: String,
+    pub busnum: u32,
+    pub devnum: u32,
+    pub speed: u32,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub bcd_device: u16,
+    pub device_class: u8,
+    pub device_subclass: u8,
+    pub device_protocol: u8,
+    pub configuration_value: u8,
+    pub num_configurations: u8,
+    pub num_interfaces: u8,
+    // class, subclass, protocol; only in the device list
+    pub interfaces: Vec<(u8, u8, u8)>,
+}
+
+impl ExportedDevice {
+    fn parse(data: &[u8]) -> Option<ExportedDevice> {
+        if data.len() < DEVICE_LEN {
+            return None;
+        }
+        let at = PATH_LEN + BUSID_LEN;
+        Some(ExportedDevice {
+            path: fixed_string(&data[..PATH_LEN]),
+            busid: fixed_string(&data[PATH_LEN..at]),
+            busnum: be32(data, at),
+            devnum: be32(data, at + 4),
+            speed: be32(data, at + 8),
+            vendor_id: be16(data, at + 12),
+            product_id: be16(data, at + 14),
+            bcd_device: be16(data, at + 16),
+            device_class: data[at + 18],
+            device_subclass: data[at + 19],
+            device_protocol: data[at + 20],
+            configuration_value: data[at + 21],
+            num_configurations: data[at + 22],
+            num_interfaces: data[at + 23],
+            interfaces: Vec::new(),
+        })
+    }
+
+    fn to_bytes(&self, with_interfaces: bool) -> Vec<u8> {
+        let mut data = vec![0u8; PATH_LEN + BUSID_LEN];
+        let path = self.path.as_bytes();
+        let busid = self.busid.as_bytes();
+        data[..path.len().min(PATH_LEN - 1)].copy_from_slice(&path[..path.len().min(PATH_LEN - 1)]);
+        data[PATH_LEN..PATH_LEN + busid.len().min(BUSID_LEN - 1)]
+            .copy_from_slice(&busid[..busid.len().min(BUSID_LEN - 1)]);
+        for value in [self.busnum, self.devnum, self.speed] {
+            data.extend_from_slice(&value.to_be_bytes());
+        }
+        for value in [self.vendor_id, self.product_id, self.bcd_device] {
+            data.extend_from_slice(&value.to_be_bytes());
+        }
+        data.extend_from_slice(&[
+            self.device_class,
+            self.device_subclass,
+            self.device_protocol,
+            self.configuration_value,
+            self.num_configurations,
+            self.num_interfaces,
+        ]);
+        if with_interfaces {
+            for (class, subclass, protocol) in &self.interfaces {
+                data.extend_from_slice(&[*class, *subclass, *protocol, 0]);
+            }
+        }
+        data
+    }
+
+    // What the kernel calls the device: bus number in the high half
+    pub fn devid(&self) -> u32 {
+        (self.busnum << 16) | (self.devnum & 0xffff)
+    }
+}
+
+fn read_op_reply(stream: &mut TcpStream, code: u16) -> Result<(), String> {
+    let error = |e: io::Error| format!("USB/IP: {}", e);
+    let mut header = [0u8; OP_HEADER_LEN];
+    stream.read_exact(&mut header).map_err(error)?;
+    if be16(&header, 2) != code {
+        return Err(format!("USB/IP: expected reply 0x{:04x}, got 0x{:04x}", code, be16(&header, 2)));
+    }
+    match be32(&header, 4) {
+        0 => Ok(()),
+        status => Err(format!("USB/IP: request refused (status {})", status)),
+    }
+}
+
+// How often readable() looks. A socket read timeout can't be trusted below a
+// scheduler tick, several milliseconds on some kernels, where checkm8 times
+// requests to the millisecond.
+const POLL_INTERVAL: Duration = Duration::from_micros(100);
+
+// Whether something arrives within `timeout`, None waiting for good
+fn readable(stream: &TcpStream, timeout: Option<Duration>) -> io::Result<bool> {
+    let Some(timeout) = timeout else {
+        return stream.peek(&mut [0u8; 1]).and_then(|read| match read {
+            0 => Err(io::ErrorKind::UnexpectedEof.into()),
+            _ => Ok(true),
+        });
+    };
+    let deadline = Instant::now() + timeout;
+    stream.set_nonblocking(true)?;
+    let result = loop {
+        match stream.peek(&mut [0u8; 1]) {
+            Ok(0) => break Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(_) => break Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                if Instant::now() >= deadline {
+                    break Ok(false);
+                }
+                std::thread::sleep(POLL_INTERVAL);
+            }
+            Err(e) => break Err(e),
+        }
+    };
+    stream.set_nonblocking(false)?;
+    result
+}
+
+// MARK: client
+pub fn list_devices(address: &str) -> Result<Vec<ExportedDevice>, String> {
+    let error = |e: io::Error| format!("USB/IP: {}", e);
+    let mut stream = connect(address)?;
+    stream.write_all(&op_header(OP_REQ_DEVLIST, 0)).map_err(error)?;
+    read_op_reply(&mut stream, OP_REP_DEVLIST)?;
+    let mut count = [0u8; 4];
+    stream.read_exact(&mut count).map_err(error)?;
+    let mut devices = Vec::new();
+    for _ in 0..u32::from_be_bytes(count) {
+        let mut data = [0u8; DEVICE_LEN];
+        stream.read_exact(&mut data).map_err(error)?;
+        let mut device = ExportedDevice::parse(&data).ok_or("USB/IP: bad device entry")?;
+        for _ in 0..device.num_interfaces {
+            let mut interface = [0u8; INTERFACE_LEN];
+            stream.read_exact(&mut interface).map_err(error)?;
+            device.interfaces.push((interface[0], interface[1], interface[2]));
+        }
+        devices.push(device);
+    }
+    Ok(devices)
+}
+
+// A RET_SUBMIT or RET_UNLINK
+enum Reply {
+    Submit { seqnum: u32, status: i32, data: Vec<u8>, actual_length: usize },
+    Unlink { seqnum: u32 },
+}
+
+// An imported device. The connection is the device from here on: closing it
+// hands the device back to the server.
+pub struct UsbipTransport {
+    stream: TcpStream,
+    device: ExportedDevice,
+    seqnum: u32,
+    // what an unlinked URB had moved, when its RET_SUBMIT beat the RET_UNLINK
+    timed_out: Option<usize>,
+}
+
+impl UsbipTransport {
+    pub fn import(address: &str, busid: &str) -> Result<UsbipTransport, String> {
+        let error = |e: io::Error| format!("USB/IP: {}", e);
+        let mut stream = connect(address)?;
+        let mut request = op_header(OP_REQ_IMPORT, 0);
+        let mut field = [0u8; BUSID_LEN];
+        field[..busid.len().min(BUSID_LEN - 1)].copy_from_slice(&busid.as_bytes()[..busid.len().min(BUSID_LEN - 1)]);
+        request.extend_from_slice(&field);
+        stream.write_all(&request).map_err(error)?;
+        read_op_reply(&mut stream, OP_REP_IMPORT).map_err(|e| format!("importing {}: {}", busid, e))?;
+        let mut data = [0u8; DEVICE_LEN];
+        stream.read_exact(&mut data).map_err(error)?;
+        let device = ExportedDevice::parse(&data).ok_or("USB/IP: bad import reply")?;
+        Ok(UsbipTransport {
+            stream,
+            device,
+            seqnum: 0,
+            timed_out: None,
+        })
+    }
+
+    pub fn device(&self) -> &ExportedDevice {
+        &self.device
+    }
+
+    // usbip-host turns SET_INTERFACE into the real thing on its side
+    pub fn set_interface(&mut self, interface: u8, alt_setting: u8) -> Result<(), TransferError> {
+        self.control_transfer(0x01, SET_INTERFACE, alt_setting.into(), interface.into(), &mut [], 5000)
+            .map(|_| ())
+    }
+
+    fn next_seqnum(&mut self) -> u32 {
+        self.seqnum = self.seqnum.wrapping_add(1).max(1);
+        self.seqnum
+    }
+
+    fn send(&mut self, message: &[u8]) -> Result<(), TransferError> {
+        self.stream.write_all(message).map_err(|_| TransferError::NoDevice)
+    }
+
+    // `in_seqnum` is the IN URB whose reply carries data, into a buffer of
+    // `in_length` bytes
+    fn read_reply(&mut self, in_seqnum: u32, in_length: usize) -> io::Result<Reply> {
+        let mut header = [0u8; URB_HEADER_LEN];
+        self.stream.read_exact(&mut header)?;
+        let seqnum = be32(&header, 4);
+        let status = be32(&header, 20) as i32;
+        match be32(&header, 0) {
+            RET_SUBMIT => {
+                let actual_length = be32(&header, 24) as usize;
+                let mut data = Vec::new();
+                if seqnum == in_seqnum {
+                    // the server can't send more than was asked for, and a
+                    // length that says otherwise isn't worth allocating
+                    if actual_length > in_length {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("{} bytes back for a {} byte URB", actual_length, in_length),
+                        ));
+                    }
+                    data = vec![0u8; actual_length];
+                    self.stream.read_exact(&mut data)?;
+                }
+                Ok(Reply::Submit { seqnum, status, data, actual_length })
+            }
+            RET_UNLINK => Ok(Reply::Unlink { seqnum }),
+            command => Err(io::Error::new(io::ErrorKind::InvalidData, format!("unexpected command {}", command))),
+        }
+    }
+
+    fn submit(&mut self, endpoint: u8, setup: [u8; 8], data: &mut [u8], timeout: u32) -> Result<usize, TransferError> {
+        let seqnum = self.next_seqnum();
+        self.timed_out = None;
+        let direction = if endpoint & 0x80 != 0 { DIR_IN } else { DIR_OUT };
+        let length: i32 = data
+            .len()
+            .try_into()
+            .map_err(|_| TransferError::Other("transfer too large".to_string()))?;
+        let mut message = Vec::with_capacity(URB_HEADER_LEN + data.len());
+        for value in [CMD_SUBMIT, seqnum, self.device.devid(), direction, u32::from(endpoint & 0x7f)] {
+            message.extend_from_slice(&value.to_be_bytes());
+        }
+        // transfer flags, length, start frame, iso packets, interval
+        for value in [0, length, 0, 0, 0] {
+            message.extend_from_slice(&value.to_be_bytes());
+        }
+        message.extend_from_slice(&setup);
+        if direction == DIR_OUT {
+            message.extend_from_slice(data);
+        }
+        self.send(&message)?;
+
+        let in_seqnum = if direction == DIR_IN { seqnum } else { 0 };
+        let deadline = (timeout != 0).then(|| Duration::from_millis(timeout.into()));
+        if !readable(&self.stream, deadline).map_err(lost)? {
+            return self.unlink(seqnum, in_seqnum, data);
+        }
+        match self.read_reply(in_seqnum, data.len()).map_err(lost)? {
+            Reply::Submit { seqnum: done, status, data: reply, actual_length } if done == seqnum => {
+                complete(status, &reply, actual_length, data)
+            }
+            _ => Err(TransferError::Other("USB/IP: reply for another URB".to_string())),
+        }
+    }
+
+    // Gives up on `seqnum`. It may have completed in the meantime, in which
+    // case its RET_SUBMIT comes first and that's the result.
+    fn unlink(&mut self, seqnum: u32, in_seqnum: u32, data: &mut [u8]) -> Result<usize, TransferError> {
+        let unlink_seqnum = self.next_seqnum();
+        let mut message = Vec::with_capacity(URB_HEADER_LEN);
+        for value in [CMD_UNLINK, unlink_seqnum, self.device.devid(), DIR_OUT, 0, seqnum] {
+            message.extend_from_slice(&value.to_be_bytes());
+        }
+        message.resize(URB_HEADER_LEN, 0);
+        self.send(&message)?;
+
+        let mut result = Err(TransferError::Timeout);
+        loop {
+            if !readable(&self.stream, Some(UNLINK_TIMEOUT)).map_err(lost)? {
+                return Err(TransferError::Other("USB/IP: unlink never answered".to_string()));
+            }
+            match self.read_reply(in_seqnum, data.len()).map_err(lost)? {
+                Reply::Submit { seqnum: done, status, data: reply, actual_length } if done == seqnum => {
+                    result = complete(status, &reply, actual_length, data);
+                    if result == Err(TransferError::Timeout) {
+                        self.timed_out = Some(actual_length.min(data.len()));
+                    }
+                }
+                Reply::Unlink { seqnum: done } if done == unlink_seqnum => return result,
+                _ => return Err(TransferError::Other("USB/IP: reply for another URB".to_string())),
+            }
+        }
+    }
+}
+
+// A connection that broke is the device going away, one that sent nonsense
+// is worth saying so
+fn lost(error: io::Error) -> TransferError {
+    match error.kind() {
+        io::ErrorKind::InvalidData => TransferError::Other(format!("USB/IP: {}", error)),
+        _ => TransferError::NoDevice,
+    }
+}
+
+// A RET_SUBMIT as the result of the transfer, IN data copied into `data`
+fn complete(status: i32, reply: &[u8], actual_length: usize, data: &mut [u8]) -> Result<usize, TransferError> {
+    if status != 0 {
+        return Err(TransferError::from_urb_status(status));
+    }
+    let length = reply.len().min(data.len());
+    data[..length].copy_from_slice(&reply[..length]);
+    Ok(actual_length.min(data.len()))
+}
+
+fn setup_packet(bm_request_type: u8, b_request: u8, w_value: u16, w_index: u16, w_length: u16) -> [u8; 8] {
+    let mut setup = [0u8; 8];
+    setup[0] = bm_request_type;
+    setup[1] = b_request;
+    setup[2..4].copy_from_slice(&w_value.to_le_bytes());
+    setup[4..6].copy_from_slice(&w_index.to_le_bytes());
+    setup[6..8].copy_from_slice(&w_length.to_le_bytes());
+    setup
+}
+
+impl Transport for UsbipTransport {
+    fn control_transfer(
+        &mut self,
+        bm_request_type: u8,
+        b_request: u8,
+        w_value: u16,
+        w_index: u16,
+        data: &mut [u8],
+        timeout: u32,
+    ) -> Result<usize, TransferError> {
+        let w_length: u16 = data
+            .len()
+            .try_into()
+            .map_err(|_| TransferError::Other("wLength too large".to_string()))?;
+        let setup = setup_packet(bm_request_type, b_request, w_value, w_index, w_length);
+        self.submit(bm_request_type & 0x80, setup, data, timeout)
+    }
+
+    fn bulk_transfer(&mut self, endpoint: u8, data: &mut [u8], timeout: u32) -> Result<usize, TransferError> {
+        self.submit(endpoint, [0u8; 8], data, timeout)
+    }
+
+    // SET_FEATURE(PORT_RESET) to the device is what usbip-host resets on
+    fn reset(&mut self) -> Result<(), TransferError> {
+        match self.control_transfer(0x23, SET_FEATURE, PORT_RESET, 0, &mut [], 5000) {
+            Ok(_) | Err(TransferError::NoDevice) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn serial_number(&mut self) -> Option<String> {
+        read_serial_number(self)
+    }
+
+    fn timed_out_length(&self) -> Option<usize> {
+        self.timed_out
+    }
+}
+
+// MARK: server
+// The index serve() gives the serial string
+const SERIAL_INDEX: u8 = 3;
+
+// A URB waits this long for its unlink before serve() runs it. A client that
+// unlinks within it had a timeout that short, and the URB gets run with it.
+const UNLINK_WINDOW: Duration = Duration::from_millis(25);
+
+fn device_descriptor(exported: &ExportedDevice) -> Vec<u8> {
+    let mut descriptor = vec![18, 1, 0x00, 0x02];
+    descriptor.extend_from_slice(&[exported.device_class, exported.device_subclass, exported.device_protocol, 64]);
+    descriptor.extend_from_slice(&exported.vendor_id.to_le_bytes());
+    descriptor.extend_from_slice(&exported.product_id.to_le_bytes());
+    descriptor.extend_from_slice(&exported.bcd_device.to_le_bytes());
+    descriptor.extend_from_slice(&[0, 0, SERIAL_INDEX, exported.num_configurations]);
+    descriptor
+}
+
+fn string_descriptor(text: &str) -> Vec<u8> {
+    let mut descriptor = vec![0, 3];
+    for unit in text.encode_utf16().take(126) {
+        descriptor.extend_from_slice(&unit.to_le_bytes());
+    }
+    descriptor[0] = descriptor.len() as u8;
+    descriptor
+}
+
+// Serves `device` as `exported` to one client at a time, until accepting
+// fails. The descriptor requests a client makes to find the serial are
+// answered here; a port reset and SET_INTERFACE are handled the way
+// usbip-host handles them; everything else goes to the device.
+pub fn serve<T: Transport>(listener: TcpListener, device: &mut T, exported: &ExportedDevice) -> Result<(), String> {
+    for stream in listener.incoming() {
+        let stream = stream.map_err(|e| e.to_string())?;
+        let peer = stream.peer_addr().map(|address| address.to_string()).unwrap_or_default();
+        match serve_connection(stream, device, exported) {
+            Ok(()) => debug!("{} disconnected", peer),
+            Err(e) => info!("{}: {}", peer, e),
+        }
+    }
+    Ok(())
+}
+
+fn serve_connection<T: Transport>(mut stream: TcpStream, device: &mut T, exported: &ExportedDevice) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    let mut header = [0u8; OP_HEADER_LEN];
+    stream.read_exact(&mut header)?;
+    match be16(&header, 2) {
+        OP_REQ_DEVLIST => {
+            let mut reply = op_header(OP_REP_DEVLIST, 0);
+            reply.extend_from_slice(&1u32.to_be_bytes());
+            reply.extend_from_slice(&exported.to_bytes(true));
+            stream.write_all(&reply)
+        }
+        OP_REQ_IMPORT => {
+            let mut busid = [0u8; BUSID_LEN];
+            stream.read_exact(&mut busid)?;
+            if fixed_string(&busid) != exported.busid {
+                return stream.write_all(&op_header(OP_REP_IMPORT, 1));
+            }
+            let mut reply = op_header(OP_REP_IMPORT, 0);
+            reply.extend_from_slice(&exported.to_bytes(false));
+            stream.write_all(&reply)?;
+            info!("{} imported by {}", exported.busid, stream.peer_addr()?);
+            serve_urbs(&mut stream, device, exported)
+        }
+        code => Err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown request 0x{:04x}", code))),
+    }
+}
+
+// A CMD_SUBMIT as it came in
+struct Submit {
+    seqnum: u32,
+    direction: u32,
+    endpoint: u8,
+    setup: [u8; 8],
+    data: Vec<u8>,
+}
+
+fn serve_urbs<T: Transport>(stream: &mut TcpStream, device: &mut T, exported: &ExportedDevice) -> io::Result<()> {
+    loop {
+        let mut header = [0u8; URB_HEADER_LEN];
+        match stream.read_exact(&mut header) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            result => result?,
+        }
+        let seqnum = be32(&header, 4);
+        if be32(&header, 0) == CMD_UNLINK {
+            // already answered, nothing left to unlink
+            write_ret_unlink(stream, seqnum, 0)?;
+            continue;
+        }
+        if be32(&header, 0) != CMD_SUBMIT {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "expected CMD_SUBMIT"));
+        }
+        let direction = be32(&header, 12);
+        let length = be32(&header, 24) as usize;
+        // checked before it's allocated: a control transfer moves no more
+        // than its wLength, and nothing here moves more than 64k
+        let w_length = u16::from_le_bytes([header[46], header[47]]);
+        let limit = if be32(&header, 16) == 0 { w_length.into() } else { usize::from(u16::MAX) };
+        if length > limit {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("transfer buffer length {} is over {}", length, limit),
+            ));
+        }
+        let mut submit = Submit {
+            seqnum,
+            direction,
+            endpoint: be32(&header, 16) as u8 | if direction == DIR_IN { 0x80 } else { 0 },
+            setup: header[40..48].try_into().unwrap(),
+            data: vec![0u8; length],
+        };
+        if direction == DIR_OUT {
+            stream.read_exact(&mut submit.data)?;
+        }
+
+        let started = Instant::now();
+        let unlink = wait_for_unlink(stream, UNLINK_WINDOW)?;
+        let timeout = match unlink {
+            Some(_) => (started.elapsed().as_millis() as u32).max(1),
+            None => 0,
+        };
+        let result = run_urb(device, exported, &mut submit, timeout);
+        match (unlink, &result) {
+            (Some(unlink), Err(TransferError::Timeout)) => {
+                write_ret_unlink(stream, unlink, TransferError::Timeout.urb_status())?
+            }
+            (Some(unlink), _) => {
+                write_ret_submit(stream, &submit, &result)?;
+                write_ret_unlink(stream, unlink, 0)?;
+            }
+            // never completes, the client has to give up on it
+            (None, Err(TransferError::Timeout)) => {
+                let unlink = wait_for_unlink(stream, UNLINK_TIMEOUT)?
+                    .ok_or(io::Error::new(io::ErrorKind::TimedOut, "URB never unlinked"))?;
+                write_ret_unlink(stream, unlink, TransferError::Timeout.urb_status())?;
+            }
+            (None, _) => write_ret_submit(stream, &submit, &result)?,
+        }
+    }
+}
+
+// The seqnum of the CMD_UNLINK if one arrives within `window`
+fn wait_for_unlink(stream: &mut TcpStream, window: Duration) -> io::Result<Option<u32>> {
+    if !readable(stream, Some(window))? {
+        return Ok(None);
+    }
+    let mut header = [0u8; URB_HEADER_LEN];
+    stream.read_exact(&mut header)?;
+    if be32(&header, 0) != CMD_UNLINK {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "one URB at a time"));
+    }
+    Ok(Some(be32(&header, 4)))
+}
+
+fn run_urb<T: Transport>(
+    device: &mut T,
+    exported: &ExportedDevice,
+    submit: &mut Submit,
+    timeout: u32,
+) -> Result<usize, TransferError> {
+    if submit.endpoint & 0x7f != 0 {
+        return device.bulk_transfer(submit.endpoint, &mut submit.data, timeout);
+    }
+    let setup = submit.setup;
+    let (bm_request_type, b_request) = (setup[0], setup[1]);
+    let w_value = u16::from_le_bytes([setup[2], setup[3]]);
+    let w_index = u16::from_le_bytes([setup[4], setup[5]]);
+    let answer = |data: &mut Vec<u8>, reply: Vec<u8>| {
+        let length = reply.len().min(data.len());
+        data[..length].copy_from_slice(&reply[..length]);
+        Ok(length)
+    };
+    match (bm_request_type, b_request, w_value) {
+        (0x23, SET_FEATURE, PORT_RESET) => device.reset().map(|_| 0),
+        (0x01, SET_INTERFACE, _) => Ok(0),
+        (0x80, GET_DESCRIPTOR, DEVICE_DESCRIPTOR) => answer(&mut submit.data, device_descriptor(exported)),
+        (0x80, GET_DESCRIPTOR, STRING_DESCRIPTOR) => answer(&mut submit.data, vec![4, 3, 0x09, 0x04]),
+        (0x80, GET_DESCRIPTOR, value) if value == STRING_DESCRIPTOR | u16::from(SERIAL_INDEX) => {
+            let serial = device.serial_number().unwrap_or_default();
+            answer(&mut submit.data, string_descriptor(&serial))
+        }
+        _ => device.control_transfer(bm_request_type, b_request, w_value, w_index, &mut submit.data, timeout),
+    }
+}
+
+fn write_ret_submit(stream: &mut TcpStream, submit: &Submit, result: &Result<usize, TransferError>) -> io::Result<()> {
+    let (status, actual_length) = match result {
+        Ok(length) => (0, *length),
+        Err(e) => (e.urb_status(), 0),
+    };
+    let mut message = Vec::with_capacity(URB_HEADER_LEN + actual_length);
+    for value in [RET_SUBMIT, submit.seqnum, 0, 0, 0] {
+        message.extend_from_slice(&value.to_be_bytes());
+    }
+    // status, actual length, start frame, iso packets, error count
+    for value in [status, actual_length as i32, 0, 0, 0] {
+        message.extend_from_slice(&value.to_be_bytes());
+    }
+    message.resize(URB_HEADER_LEN, 0);
+    if submit.direction == DIR_IN {
+        message.extend_from_slice(&submit.data[..actual_length]);
+    }
+    stream.write_all(&message)
+}
+
+fn write_ret_unlink(stream: &mut TcpStream, seqnum: u32, status: i32) -> io::Result<()> {
+    let mut message = Vec::with_capacity(URB_HEADER_LEN);
+    for value in [RET_UNLINK, seqnum, 0, 0, 0] {
+        message.extend_from_slice(&value.to_be_bytes());
+    }
+    message.extend_from_slice(&status.to_be_bytes());
+    message.resize(URB_HEADER_LEN, 0);
+    stream.write_all(&message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    const SERIAL: &str = "CPID:8010 CPRV:11 CPFM:03 SCEP:01 BDID:0C ECID:001A2B3C4D5E6F70 IBFL:3C SRTG:[iBoot-2696.0.0.1.33]";
+
+    // Answers IN requests with their wValue's low byte, and stalls bulk
+    struct Stub;
+
+    impl Transport for Stub {
+        fn control_transfer(
+            &mut self,
+            bm_request_type: u8,
+            _b_request: u8,
+            w_value: u16,
+            _w_index: u16,
+            data: &mut [u8],
+            _timeout: u32,
+        ) -> Result<usize, TransferError> {
+            if bm_request_type & 0x80 != 0 {
+                data.fill(w_value as u8);
+            }
+            Ok(data.len())
+        }
+
+        fn bulk_transfer(&mut self, _endpoint: u8, _data: &mut [u8], _timeout: u32) -> Result<usize, TransferError> {
+            Err(TransferError::Stall)
+        }
+
+        fn reset(&mut self) -> Result<(), TransferError> {
+            Ok(())
+        }
+
+        fn serial_number(&mut self) -> Option<String> {
+            Some(SERIAL.to_string())
+        }
+    }
+
+    fn exported() -> ExportedDevice {
+        ExportedDevice {
+            path: "/sys/devices/stub/usb1/1-1".to_string(),
+            busid: "1-1".to_string(),
+            busnum: 1,
+            devnum: 2,
+            speed: 3,
+            vendor_id: 0x5ac,
+            product_id: 0x1227,
+            bcd_device: 0,
+            device_class: 0,
+            device_subclass: 0,
+            device_protocol: 0,
+            configuration_value: 1,
+            num_configurations: 1,
+            num_interfaces: 1,
+            interfaces: vec![(0xfe, 1, 0)],
+        }
+    }
+
+    // serve() with the stub behind it, on a port of its own
+    fn stub_server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || serve(listener, &mut Stub, &exported()));
+        address
+    }
+
+    fn submit_header(direction: u32, endpoint: u32, length: u32, setup: [u8; 8]) -> Vec<u8> {
+        let mut message = Vec::new();
+        for value in [CMD_SUBMIT, 1, 0x10002, direction, endpoint, 0, length, 0, 0, 0] {
+            message.extend_from_slice(&value.to_be_bytes());
+        }
+        message.extend_from_slice(&setup);
+        message
+    }
+
+    #[test]
+    fn round_trip_through_serve() {
+        let address = stub_server();
+        let devices = list_devices(&address).unwrap();
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].interfaces, [(0xfe, 1, 0)]);
+
+        let mut device = UsbipTransport::import(&address, "1-1").unwrap();
+        assert_eq!(device.device().product_id, 0x1227);
+        assert_eq!(device.serial_number().as_deref(), Some(SERIAL));
+        let mut data = [0u8; 6];
+        assert_eq!(device.control_transfer(0xa1, 3, 0x42, 0, &mut data, 1000), Ok(6));
+        assert_eq!(data, [0x42; 6]);
+        assert_eq!(device.control_transfer(0x21, 1, 0, 0, &mut [1, 2, 3], 1000), Ok(3));
+        assert_eq!(device.bulk_transfer(0x81, &mut [0u8; 4], 1000), Err(TransferError::Stall));
+        assert_eq!(device.reset(), Ok(()));
+        // one client at a time, the next one is served once this one's gone
+        drop(device);
+        assert!(UsbipTransport::import(&address, "2-1").is_err());
+    }
+
+    // A server claiming more data than the URB asked for gets an error, not
+    // an allocation of whatever it said
+    #[test]
+    fn rejects_an_oversized_reply() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0u8; OP_HEADER_LEN + BUSID_LEN];
+            stream.read_exact(&mut request).unwrap();
+            let mut reply = op_header(OP_REP_IMPORT, 0);
+            reply.extend_from_slice(&exported().to_bytes(false));
+            stream.write_all(&reply).unwrap();
+            let mut submit = [0u8; URB_HEADER_LEN];
+            stream.read_exact(&mut submit).unwrap();
+            let mut message = Vec::new();
+            for value in [RET_SUBMIT, be32(&submit, 4), 0, 0, 0, 0, 0x7fff_ffff] {
+                message.extend_from_slice(&value.to_be_bytes());
+            }
+            message.resize(URB_HEADER_LEN, 0);
+            stream.write_all(&message).unwrap();
+        });
+
+        let mut device = UsbipTransport::import(&address, "1-1").unwrap();
+        let result = device.control_transfer(0x80, GET_DESCRIPTOR, DEVICE_DESCRIPTOR, 0, &mut [0u8; 18], 1000);
+        server.join().unwrap();
+        assert_eq!(
+            result,
+            Err(TransferError::Other("USB/IP: 2147483647 bytes back for a 18 byte URB".to_string()))
+        );
+    }
+
+    // A CMD_SUBMIT whose buffer is bigger than its wLength, or than any
+    // transfer, ends the connection before anything is allocated for it
+    #[test]
+    fn refuses_an_oversized_submit() {
+        let address = stub_server();
+        for (endpoint, length, setup) in [
+            (0, 0x12, [0x80, GET_DESCRIPTOR, 0, 1, 0, 0, 0x08, 0]),
+            (0, 0x7fff_ffff, [0x80, GET_DESCRIPTOR, 0, 1, 0, 0, 0xff, 0xff]),
+            (1, 0x10000, [0; 8]),
+        ] {
+            let mut stream = TcpStream::connect(&address).unwrap();
+            let mut request = op_header(OP_REQ_IMPORT, 0);
+            request.extend_from_slice(b"1-1");
+            request.resize(OP_HEADER_LEN + BUSID_LEN, 0);
+            stream.write_all(&request).unwrap();
+            read_op_reply(&mut stream, OP_REP_IMPORT).unwrap();
+            stream.read_exact(&mut [0u8; DEVICE_LEN]).unwrap();
+            stream.write_all(&submit_header(DIR_IN, endpoint, length, setup)).unwrap();
+            let mut rest = Vec::new();
+            stream.read_to_end(&mut rest).unwrap();
+            assert!(rest.is_empty(), "endpoint {} length 0x{:x} was answered", endpoint, length);
+        }
+    }
+}
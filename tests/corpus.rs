@@ -0,0 +1,109 @@
+// Every seed under fuzz/corpus through what its fuzz target calls, so the
+// parsers are held to not panicking on them without cargo fuzz. The bodies
+// follow fuzz/fuzz_targets; keep them in step.
+
+use ra1n_oxide::dfu::DfuStatus;
+use ra1n_oxide::img3::Img3;
+use ra1n_oxide::img4::{self, Im4m, Im4p, Im4r, Img4};
+use ra1n_oxide::plist;
+use ra1n_oxide::recovery::parse_env_reply;
+use ra1n_oxide::serial::DeviceSerial;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+
+// Runs `target` on each seed of the corpus, naming the ones it panics on
+fn corpus(name: &str, target: fn(&[u8])) {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("fuzz/corpus").join(name);
+    let mut seeds: Vec<PathBuf> = std::fs::read_dir(&dir)
+        .unwrap_or_else(|e| panic!("{}: {}", dir.display(), e))
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    seeds.sort();
+    assert!(!seeds.is_empty(), "{} has no seeds", dir.display());
+    let panicked: Vec<String> = seeds
+        .iter()
+        .filter(|seed| {
+            let data = std::fs::read(seed).unwrap();
+            panic::catch_unwind(AssertUnwindSafe(|| target(&data))).is_err()
+        })
+        .map(|seed| seed.display().to_string())
+        .collect();
+    assert!(panicked.is_empty(), "panicked on {:?}", panicked);
+}
+
+#[test]
+fn dfu_status() {
+    corpus("dfu_status", |data| {
+        let _ = DfuStatus::parse(data);
+    });
+}
+
+#[test]
+fn env_reply() {
+    corpus("env_reply", |data| {
+        let _ = parse_env_reply(data);
+    });
+}
+
+#[test]
+fn img3() {
+    corpus("img3", |data| {
+        if let Ok(mut img3) = Img3::parse(data) {
+            let _ = img3.data();
+            let _ = img3.image_type();
+            let _ = img3.version();
+            let _ = img3.kbags();
+            let _ = img3.to_bytes();
+            img3.strip_kbags();
+        }
+    });
+}
+
+fn im4p(im4p: &Im4p) {
+    let _ = im4p.codec();
+    let _ = im4p.decompressed_payload();
+}
+
+fn im4m(im4m: &Im4m) {
+    let _ = im4m.ecid();
+    let _ = im4m.ap_nonce_hash();
+    let _ = im4m.sep_nonce_hash();
+    let _ = im4m.image("ibss");
+}
+
+#[test]
+fn img4() {
+    corpus("img4", |data| {
+        let _ = img4::magic(data);
+        if let Ok(img4) = Img4::parse(data) {
+            im4p(&img4.im4p);
+            if let Some(manifest) = &img4.im4m {
+                im4m(manifest);
+            }
+        }
+        if let Ok(payload) = Im4p::parse(data) {
+            im4p(&payload);
+        }
+        if let Ok(manifest) = Im4m::parse(data) {
+            im4m(&manifest);
+        }
+        let _ = Im4r::parse(data);
+    });
+}
+
+#[test]
+fn plist() {
+    corpus("plist", |data| {
+        let _ = plist::parse(data);
+    });
+}
+
+#[test]
+fn serial() {
+    corpus("serial", |data| {
+        let serial = String::from_utf8_lossy(data);
+        if let Some(serial) = DeviceSerial::parse(&serial) {
+            let _ = serial.is_pwned();
+        }
+    });
+}
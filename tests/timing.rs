@@ -0,0 +1,132 @@
+// Host controller detection against sysfs trees under tests/vectors/sysfs,
+// one machine each, used the way --sysfs-root uses them, and the profile
+// config parser.
+
+use ra1n_oxide::timing::{self, ControllerKind, Profiles, TimingProfile};
+use std::path::PathBuf;
+
+fn root(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/vectors/sysfs").join(name)
+}
+
+#[test]
+fn detects_controllers() {
+    for (tree, bus, kind, ids, driver, profile, shown) in [
+        ("xhci-intel", 1, ControllerKind::Xhci, Some((0x8086, 0xa36d)), "xhci_hcd", "xhci-intel", "xHCI 8086:a36d (xhci_hcd)"),
+        ("xhci-amd", 3, ControllerKind::Xhci, Some((0x1022, 0x149c)), "xhci_hcd", "xhci-amd", "xHCI 1022:149c (xhci_hcd)"),
+        ("xhci-asmedia", 5, ControllerKind::Xhci, Some((0x1b21, 0x2142)), "xhci_hcd", "xhci-asmedia", "xHCI 1b21:2142 (xhci_hcd)"),
+        ("ehci", 2, ControllerKind::Ehci, Some((0x8086, 0x1e26)), "ehci-pci", "ehci", "EHCI 8086:1e26 (ehci-pci)"),
+        // not on PCI, only the driver's name says what it is
+        ("platform", 1, ControllerKind::Xhci, None, "xhci-hcd", "xhci", "xHCI (xhci-hcd)"),
+        ("vhci", 7, ControllerKind::Vhci, None, "vhci_hcd", "generic", "USB/IP (vhci_hcd)"),
+    ] {
+        let controller = timing::detect(&root(tree), bus).unwrap_or_else(|| panic!("{}: nothing on bus {}", tree, bus));
+        assert_eq!(controller.kind, kind, "{}", tree);
+        assert_eq!(controller.vendor_id.zip(controller.device_id), ids, "{}", tree);
+        assert_eq!(controller.driver.as_deref(), Some(driver), "{}", tree);
+        assert_eq!(controller.to_string(), shown, "{}", tree);
+        assert_eq!(Profiles::builtin().for_controller(Some(&controller)).name, profile, "{}", tree);
+    }
+}
+
+#[test]
+fn detects_nothing_on_a_missing_bus() {
+    assert_eq!(timing::detect(&root("xhci-intel"), 2), None);
+    assert_eq!(timing::detect(&root("nonexistent"), 1), None);
+    assert_eq!(Profiles::builtin().for_controller(None).name, "generic");
+}
+
+#[test]
+fn finds_port_paths() {
+    for (tree, bus, address, port) in [
+        ("xhci-intel", 1, 4, Some("1-2")),
+        ("xhci-amd", 3, 2, Some("3-1")),
+        // behind a hub
+        ("xhci-asmedia", 5, 3, Some("5-2.3")),
+        ("xhci-asmedia", 5, 2, Some("5-2")),
+        ("ehci", 2, 5, Some("2-1.4")),
+        ("platform", 1, 2, Some("1-1")),
+        ("vhci", 7, 2, Some("7-1")),
+        ("vhci", 7, 1, Some("usb7")),
+        ("xhci-intel", 1, 9, None),
+        ("xhci-intel", 2, 4, None),
+    ] {
+        assert_eq!(timing::port_path(&root(tree), bus, address).as_deref(), port, "{} {}-{}", tree, bus, address);
+    }
+}
+
+// MARK: config
+fn applied(text: &str) -> Result<Profiles, String> {
+    let mut profiles = Profiles::builtin();
+    profiles.apply(text)?;
+    Ok(profiles)
+}
+
+#[test]
+fn overrides_a_profile() {
+    let profiles = applied("# slower controller here\n[xhci-amd]\nstall_attempts = 800\nuaf_abort = 1-30  # wider\n").unwrap();
+    let builtin = Profiles::builtin();
+    let amd = profiles.get("xhci-amd").unwrap();
+    assert_eq!(
+        amd,
+        &TimingProfile {
+            stall_attempts: 800,
+            uaf_abort: (1, 30),
+            ..builtin.get("xhci-amd").unwrap().clone()
+        }
+    );
+    // the others are left alone
+    assert_eq!(profiles.all().len(), builtin.all().len());
+    assert_eq!(profiles.get("xhci-intel"), builtin.get("xhci-intel"));
+}
+
+#[test]
+fn adds_a_profile() {
+    let profiles = applied("[bench]\nextra_leaks = 2\nuaf_abort = 5\n").unwrap();
+    let generic = Profiles::builtin().get("generic").unwrap().clone();
+    assert_eq!(
+        profiles.get("bench").unwrap(),
+        &TimingProfile {
+            name: "bench".to_string(),
+            extra_leaks: 2,
+            uaf_abort: (5, 5),
+            ..generic
+        }
+    );
+    assert_eq!(profiles.all().last().unwrap().name, "bench");
+    // and round trips
+    assert_eq!(applied(&profiles.get("bench").unwrap().to_config()).unwrap().get("bench"), profiles.get("bench"));
+}
+
+#[test]
+fn rejects_bad_config() {
+    for (text, error) in [
+        ("stall_attempts = 800\n", "line 1: setting outside a [profile] section"),
+        ("\n# before any section\nuaf_abort = 1-30\n[xhci]\n", "line 3: setting outside a [profile] section"),
+        ("[xhci]\nuaf_abort = 30-1\n", "line 2: invalid uaf_abort 30-1"),
+        ("[xhci]\nuaf_abort = 10-\n", "line 2: invalid uaf_abort "),
+        ("[xhci]\nstall_abort\n", "line 2: expected key = value, got stall_abort"),
+        ("[xhci]\nspeed = 3\n", "line 2: unknown setting speed"),
+        // 0ms would never time out
+        ("[xhci]\nstall_abort = 0\n", "line 2: invalid stall_abort 0"),
+        ("[xhci]\nuaf_abort = 0-10\n", "line 2: invalid uaf_abort 0"),
+        ("[xhci]\nuaf_abort = 0\n", "line 2: invalid uaf_abort 0"),
+        ("[xhci]\nrequest_timeout = 0\n", "line 2: invalid request_timeout 0"),
+    ] {
+        assert_eq!(applied(text).err().as_deref(), Some(error), "{:?}", text);
+    }
+}
+
+#[test]
+fn cycles_uaf_aborts() {
+    let profile = |uaf_abort| TimingProfile {
+        uaf_abort,
+        ..Profiles::builtin().get("generic").unwrap().clone()
+    };
+    let delays = |uaf_abort, attempts: &[u32]| attempts.iter().map(|&attempt| profile(uaf_abort).uaf_abort_for(attempt)).collect::<Vec<_>>();
+    assert_eq!(delays((1, 3), &[0, 1, 2, 3, 4, 5, 6]), [1, 2, 3, 1, 2, 3, 1]);
+    assert_eq!(delays((7, 7), &[0, 1, u32::MAX]), [7, 7, 7]);
+    // every u32 in the range, which doesn't fit a u32 count
+    assert_eq!(delays((0, u32::MAX), &[0, 5, u32::MAX]), [0, 5, u32::MAX]);
+    assert_eq!(delays((1, u32::MAX), &[0, u32::MAX - 1, u32::MAX]), [1, u32::MAX, 1]);
+}
@@ -0,0 +1,373 @@
+#!/usr/bin/env python3
+# Writes the LZFSE reference vectors next to this script. They're produced
+# from the format as Apple's reference lzfse lays it out (lzfse_encode_base.c,
+# lzfse_fse.c, lzvn_decode_base.c), independently of src/lzfse.rs, so the
+# decoder is checked against streams it didn't write itself:
+#
+#   plain.bin  the decoded bytes
+#   bvx2.bin   two FSE coded bvx2 blocks, the second matching back into the first
+#   bvxn.bin   one LZVN block, opcodes picked so every kind shows up
+#   mixed.bin  a bvx- raw block, an LZVN block and a bvx2 block in one stream
+import os
+import struct
+
+HERE = os.path.dirname(os.path.abspath(__file__))
+
+# MARK: input
+def plain():
+    words = [b"kernel", b"cache", b"__TEXT", b"__DATA", b"iBoot", b"SecureROM", b"IMG4", b"IM4P",
+             b"dfu", b"usb", b"0x1800b0000", b"arm64", b"t8010", b"nonce", b"kbag", b"lzfse"]
+    state = 0x1234567
+    def rand():
+        nonlocal state
+        state = (state * 1103515245 + 12345) & 0x7FFFFFFF
+        return state >> 8
+    # an incompressible header, repeated near the end from far back
+    out = bytearray(rand() & 0xFF for _ in range(256))
+    repeated = False
+    while len(out) < 24000:
+        r = rand()
+        if len(out) > 20000 and not repeated:
+            out += out[:256]
+            repeated = True
+        elif r % 11 == 0:
+            # incompressible run, for literal heavy stretches
+            out += bytes(rand() & 0xFF for _ in range(r % 300))
+        elif r % 13 == 0:
+            # long repeat, far back
+            if len(out) > 5000:
+                start = r % (len(out) - 3000)
+                out += out[start:start + 2000 + r % 500]
+        elif r % 17 == 0:
+            # a table, each row one byte off the last so matches repeat their distance
+            for row in range(r % 40):
+                out += b"    .quad 0x00000001800%05x\n" % (0x10000 + row * 8)
+        else:
+            out += words[r % len(words)] + (b" " if r & 0x100 else b"\n")
+    return bytes(out[:24000])
+
+# MARK: LZ parse
+L_BASE = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 20, 28, 60]
+L_EXTRA = [0] * 16 + [2, 3, 5, 8]
+M_BASE = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 24, 56, 312]
+M_EXTRA = [0] * 16 + [3, 5, 8, 11]
+D_EXTRA = [e for e in range(16) for _ in range(4)]
+D_BASE = []
+base = 0
+for e in D_EXTRA:
+    D_BASE.append(base)
+    base += 1 << e
+# the reference starts the D bases at 0, 1, 2, 3, 4, 6, 8...
+assert D_BASE[:8] == [0, 1, 2, 3, 4, 6, 8, 10]
+
+L_MAX = L_BASE[-1] + (1 << L_EXTRA[-1]) - 1
+M_MAX = M_BASE[-1] + (1 << M_EXTRA[-1]) - 1
+D_MAX = D_BASE[-1] + (1 << D_EXTRA[-1]) - 1
+
+def parse(data, start, end, max_distance=D_MAX, window_start=0):
+    """(literals, [(L, M, D)]) for data[start:end], matching back to window_start."""
+    head = {}
+    chain = {}
+    def insert(i):
+        if i + 3 <= len(data):
+            key = data[i:i + 3]
+            chain[i] = head.get(key)
+            head[key] = i
+    for i in range(max(window_start, start - 65536), start):
+        insert(i)
+    literals = bytearray()
+    lmd = []
+    pending = 0
+    pos = start
+    while pos < end:
+        best_len, best_d = 0, 0
+        cand = head.get(data[pos:pos + 3]) if pos + 3 <= end else None
+        tries = 0
+        while cand is not None and pos - cand <= max_distance and tries < 16:
+            n = 0
+            while pos + n < end and n < M_MAX and data[cand + n] == data[pos + n]:
+                n += 1
+            if n > best_len:
+                best_len, best_d = n, pos - cand
+            cand = chain[cand]
+            tries += 1
+        if best_len >= 3:
+            lmd.append((pending, best_len, best_d))
+            pending = 0
+            for i in range(pos, pos + best_len):
+                insert(i)
+            pos += best_len
+        else:
+            literals.append(data[pos])
+            pending += 1
+            if pending == L_MAX:
+                lmd.append((pending, 0, 0))
+                pending = 0
+            insert(pos)
+            pos += 1
+    if pending:
+        lmd.append((pending, 0, 0))
+    return literals, lmd
+
+# MARK: FSE
+def clz32(x):
+    return 32 - x.bit_length()
+
+def normalize(counts, nstates):
+    total = sum(counts)
+    if total == 0:
+        return [0] * len(counts)
+    freq = [0 if c == 0 else max(1, (c * nstates + total // 2) // total) for c in counts]
+    # hand out or take back the difference from the most frequent symbols
+    while sum(freq) != nstates:
+        order = sorted(range(len(freq)), key=lambda i: -freq[i])
+        if sum(freq) < nstates:
+            freq[order[0]] += 1
+        else:
+            for i in order:
+                if freq[i] > 1:
+                    freq[i] -= 1
+                    break
+    return freq
+
+def encoder_table(nstates, freq):
+    # fse_init_encoder_table
+    table = {}
+    offset = 0
+    n_clz = clz32(nstates)
+    for symbol, f in enumerate(freq):
+        if f == 0:
+            continue
+        k = clz32(f) - n_clz
+        s0 = (f << k) - nstates
+        delta0 = offset - f + (nstates >> k)
+        delta1 = offset - f + (nstates >> (k - 1)) if k > 0 else 0
+        table[symbol] = (s0, k, delta0, delta1)
+        offset += f
+    return table
+
+class Out:
+    # fse_out_stream, 64-bit accumulator, bytes written forwards
+    def __init__(self):
+        self.buf = bytearray()
+        self.accum = 0
+        self.nbits = 0
+
+    def push(self, n, bits):
+        assert bits < (1 << n) or n == 0 and bits == 0
+        self.accum |= bits << self.nbits
+        self.nbits += n
+        assert self.nbits <= 64
+
+    def flush(self):
+        n = self.nbits & ~7
+        self.buf += (self.accum & ((1 << 64) - 1)).to_bytes(8, "little")[:n // 8]
+        self.accum >>= n
+        self.nbits -= n
+
+    def finish(self):
+        n = (self.nbits + 7) & ~7
+        self.buf += self.accum.to_bytes(8, "little")[:n // 8]
+        self.accum = 0
+        self.nbits -= n
+        return self.nbits
+
+def fse_encode(state, table, out, symbol):
+    s0, k, delta0, delta1 = table[symbol]
+    hi = state >= s0
+    nbits = k if hi else k - 1
+    out.push(nbits, state & ((1 << nbits) - 1))
+    return (delta0 if hi else delta1) + (state >> nbits)
+
+def symbol_of(value, bases):
+    return max(i for i, b in enumerate(bases) if b <= value)
+
+# MARK: bvx2
+def freq_code(value):
+    # lzfse_encode_v1_freq_value
+    small = {0: (2, 0), 1: (2, 2), 2: (3, 1), 3: (3, 5), 4: (5, 3), 5: (5, 11), 6: (5, 19), 7: (5, 27)}
+    if value in small:
+        return small[value]
+    if value < 24:
+        return 8, 7 + ((value - 8) << 4)
+    return 14, 15 + ((value - 24) << 4)
+
+def bvx2_block(n_raw, literals, lmd):
+    literals = bytearray(literals)
+    while len(literals) % 4:
+        literals.append(0)
+    assert len(literals) <= 40000 and len(lmd) <= 10000
+
+    # D is 0 when it repeats the previous match's distance
+    coded = []
+    previous = 0
+    for l, m, d in lmd:
+        if m == 0 or d == previous:
+            coded.append((l, m, 0))
+        else:
+            coded.append((l, m, d))
+            previous = d
+
+    def counts(values, bases, n):
+        c = [0] * n
+        for v in values:
+            c[symbol_of(v, bases)] += 1
+        return c
+    l_freq = normalize(counts([l for l, _, _ in coded], L_BASE, 20), 64)
+    m_freq = normalize(counts([m for _, m, _ in coded], M_BASE, 20), 64)
+    d_freq = normalize(counts([d for _, _, d in coded], D_BASE, 64), 256)
+    lit_counts = [0] * 256
+    for byte in literals:
+        lit_counts[byte] += 1
+    lit_freq = normalize(lit_counts, 1024)
+
+    # literals, last to first so they decode first to last
+    table = encoder_table(1024, lit_freq)
+    out = Out()
+    states = [0, 0, 0, 0]
+    for i in range(len(literals) - 4, -1, -4):
+        for j in (3, 2, 1, 0):
+            states[j] = fse_encode(states[j], table, out, literals[i + j])
+        out.flush()
+    literal_bits = out.finish()
+    literal_payload = bytes(out.buf)
+
+    # L, M, D, again backwards: each value's extra bits, then its state bits
+    tables = [encoder_table(64, l_freq), encoder_table(64, m_freq), encoder_table(256, d_freq)]
+    bases = [(L_BASE, L_EXTRA), (M_BASE, M_EXTRA), (D_BASE, D_EXTRA)]
+    out = Out()
+    lmd_states = [0, 0, 0]
+    for triple in reversed(coded):
+        for which in (2, 1, 0):
+            value = triple[which]
+            base_values, extra = bases[which]
+            symbol = symbol_of(value, base_values)
+            out.push(extra[symbol], value - base_values[symbol])
+            lmd_states[which] = fse_encode(lmd_states[which], tables[which], out, symbol)
+        out.flush()
+    lmd_bits = out.finish()
+    lmd_payload = bytes(out.buf)
+
+    accum, accum_bits, packed = 0, 0, bytearray()
+    for f in l_freq + m_freq + d_freq + lit_freq:
+        n, code = freq_code(f)
+        accum |= code << accum_bits
+        accum_bits += n
+        while accum_bits >= 8:
+            packed.append(accum & 0xFF)
+            accum >>= 8
+            accum_bits -= 8
+    if accum_bits:
+        packed.append(accum)
+    header_size = 32 + len(packed)
+
+    v0 = len(literals) | len(literal_payload) << 20 | len(coded) << 40 | (literal_bits + 7) << 60
+    v1 = (states[0] | states[1] << 10 | states[2] << 20 | states[3] << 30
+          | len(lmd_payload) << 40 | (lmd_bits + 7) << 60)
+    v2 = header_size | lmd_states[0] << 32 | lmd_states[1] << 42 | lmd_states[2] << 52
+    return (b"bvx2" + struct.pack("<IQQQ", n_raw, v0, v1, v2) + packed
+            + literal_payload + lmd_payload)
+
+# MARK: bvxn
+def lzvn_literals(ops, literals, used):
+    for i in range(0, len(literals), 271):
+        chunk = literals[i:i + 271]
+        if len(chunk) < 16:
+            ops.append(0xE0 | len(chunk))
+            used.add("sml_l")
+        else:
+            ops += bytes([0xE0, len(chunk) - 16])
+            used.add("lrg_l")
+        ops += chunk
+
+def lzvn_repeat(ops, m, used):
+    while m:
+        if m < 16:
+            ops.append(0xF0 | m)
+            used.add("sml_m")
+            m = 0
+        else:
+            n = min(m, 271)
+            ops += bytes([0xF0, n - 16])
+            used.add("lrg_m")
+            m -= n
+
+def lzvn_short(m, f):
+    # sml_d, pre_d and lrg_d carry 3-10 bytes, fewer with literals folded in:
+    # past these the opcode is undefined or med_d, a literal or a match op
+    return min(m, [10, 8, 6, 4][f])
+
+def lzvn_block(data, start, end, used):
+    """An LZVN block for data[start:end], every kind of opcode it emits counted in `used`."""
+    literals, lmd = parse(data, start, end, max_distance=0xFFFF, window_start=start)
+    ops = bytearray(b"\x0e\x16")                 # nops
+    used.add("nop")
+    pos = 0
+    previous = 0
+    for l, m, d in lmd:
+        lit = literals[pos:pos + l]
+        pos += l
+        if m == 0:
+            lzvn_literals(ops, lit, used)
+            continue
+        f = min(l, 3)
+        lzvn_literals(ops, lit[:l - f], used)
+        folded = lit[l - f:]
+        if d == previous and f == 0:
+            lzvn_repeat(ops, m, used)
+            continue
+        if d == previous:
+            first = lzvn_short(m, f)
+            ops.append(f << 6 | (first - 3) << 3 | 6)
+            used.add("pre_d")
+        elif d < 0x600 and m <= 10 and lzvn_short(m, f) == m:
+            first = m
+            ops += bytes([f << 6 | (first - 3) << 3 | d >> 8, d & 0xFF])
+            used.add("sml_d")
+        elif d < 0x4000:
+            first = min(m, 34)
+            ops.append(0xA0 | f << 3 | (first - 3) >> 2)
+            ops += struct.pack("<H", d << 2 | (first - 3) & 3)
+            used.add("med_d")
+        else:
+            first = lzvn_short(m, f)
+            ops.append(f << 6 | (first - 3) << 3 | 7)
+            ops += struct.pack("<H", d)
+            used.add("lrg_d")
+        ops += folded
+        previous = d
+        lzvn_repeat(ops, m - first, used)
+    ops += b"\x06" + bytes(7)                     # eos
+    return b"bvxn" + struct.pack("<II", end - start, len(ops)) + ops
+
+def main():
+    data = plain()
+    with open(os.path.join(HERE, "plain.bin"), "wb") as f:
+        f.write(data)
+
+    half = len(data) // 2
+    out = bytearray()
+    for start, end in ((0, half), (half, len(data))):
+        literals, lmd = parse(data, start, end)
+        out += bvx2_block(end - start, literals, lmd)
+    out += b"bvx$"
+    with open(os.path.join(HERE, "bvx2.bin"), "wb") as f:
+        f.write(out)
+
+    used = set()
+    out = lzvn_block(data, 0, len(data), used) + b"bvx$"
+    with open(os.path.join(HERE, "bvxn.bin"), "wb") as f:
+        f.write(out)
+
+    # raw, then LZVN matching only within itself, then bvx2 matching back into both
+    out = bytearray(b"bvx-" + struct.pack("<I", 1000) + data[:1000])
+    out += lzvn_block(data, 1000, 9000, used)
+    literals, lmd = parse(data, 9000, len(data))
+    out += bvx2_block(len(data) - 9000, literals, lmd) + b"bvx$"
+    with open(os.path.join(HERE, "mixed.bin"), "wb") as f:
+        f.write(out)
+    kinds = {"nop", "sml_l", "lrg_l", "sml_m", "lrg_m", "sml_d", "med_d", "lrg_d", "pre_d"}
+    assert used == kinds, kinds - used
+
+if __name__ == "__main__":
+    main()